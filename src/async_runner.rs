@@ -0,0 +1,478 @@
+//! Runtime agnostic async execution: executors return plain `Future`s, which are driven by a future polling up to N of
+//! them concurrently. It works with any executor (Tokio, smol, async-std, a simple `block_on`), as it only relies on
+//! wakers.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::common::*;
+use super::slots::*;
+use super::topological_batch_provider::*;
+
+pub type BoxFuture<'a, O> = Pin<Box<dyn Future<Output = O> + Send + 'a>>;
+
+/// Async counterpart of `CallableByID`. The ID is passed by value, so the returned future does not borrow it.
+pub trait AsyncCallableByID<T> {
+    fn call(&self, id: T) -> BoxFuture<'_, Result<(), Error>>;
+}
+
+pub struct AsyncRunner {
+    concurrency: usize,
+}
+
+impl AsyncRunner {
+    /// At most `concurrency` node futures are in flight at a time - the same as the thread pool runner's
+    /// `max_in_flight`, as there are no threads to limit.
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            concurrency: concurrency.max(1),
+        }
+    }
+
+    /// Future executing all nodes of the provider, each after all of its dependencies completed. Failures are handled
+    /// as with `ThreadPoolRunner::run`.
+    pub fn run<'a, T, E>(
+        &self,
+        topological_batch_provider: TopologicalBatchProvider<T>,
+        node_executor: &'a E,
+    ) -> RunFuture<'a, T, E>
+    where
+        T: Hash + PartialEq + Eq + Clone,
+        E: AsyncCallableByID<T> + ?Sized,
+    {
+        RunFuture {
+            driver: Driver::new(topological_batch_provider, node_executor, self.concurrency),
+            report: Some(RunReport::new()),
+        }
+    }
+
+    /// Same execution as `run`, but instead of a final report it yields an event as every node finishes:
+    ///
+    /// ```ignore
+    /// let mut events = runner.stream(provider, &executor);
+    /// while let Some(event) = events.next().await {
+    ///     // ...
+    /// }
+    /// ```
+    ///
+    /// With the `futures` feature it also implements `futures_core::Stream`.
+    pub fn stream<'a, T, E>(
+        &self,
+        topological_batch_provider: TopologicalBatchProvider<T>,
+        node_executor: &'a E,
+    ) -> RunStream<'a, T, E>
+    where
+        T: Hash + PartialEq + Eq + Clone,
+        E: AsyncCallableByID<T> + ?Sized,
+    {
+        RunStream {
+            driver: Driver::new(topological_batch_provider, node_executor, self.concurrency),
+            events: VecDeque::new(),
+            finished: false,
+        }
+    }
+}
+
+/// A node finishing during an async run.
+#[derive(Debug)]
+pub enum CompletionEvent<T> {
+    Completed(T),
+    Failed(T, Error),
+    /// Never executed, as one of its dependencies failed.
+    Skipped(T),
+}
+
+/// A dispatched node, with its slots and future.
+type InFlight<'a, T> = (T, Claims, BoxFuture<'a, Result<(), Error>>);
+
+/// Dispatching and polling of the node futures, shared by `RunFuture` and `RunStream`.
+struct Driver<'a, T, E: ?Sized> {
+    provider: TopologicalBatchProvider<T>,
+    node_executor: &'a E,
+    concurrency: usize,
+    in_flight: Vec<InFlight<'a, T>>,
+    slots: SlotLedger<T>,
+    /// Popped nodes waiting for their slots.
+    deferred: VecDeque<T>,
+}
+
+impl<'a, T, E> Driver<'a, T, E>
+where
+    T: Hash + PartialEq + Eq + Clone,
+    E: AsyncCallableByID<T> + ?Sized,
+{
+    fn new(
+        provider: TopologicalBatchProvider<T>,
+        node_executor: &'a E,
+        concurrency: usize,
+    ) -> Self {
+        Self {
+            provider,
+            node_executor,
+            concurrency,
+            in_flight: vec![],
+            slots: SlotLedger::new(vec![]),
+            deferred: VecDeque::new(),
+        }
+    }
+
+    /// The first deferred node whose slots are free, else the first such node popped, deferring the others.
+    fn next_node(&mut self) -> Option<(T, Claims)> {
+        for position in 0..self.deferred.len() {
+            if let Some(claims) = self.slots.try_acquire(&self.deferred[position]) {
+                return Some((self.deferred.remove(position).unwrap(), claims));
+            }
+        }
+
+        while let Some(node) = self.provider.pop() {
+            if let Some(claims) = self.slots.try_acquire(&node) {
+                return Some((node, claims));
+            }
+            self.deferred.push_back(node);
+        }
+
+        None
+    }
+
+    /// Dispatches available nodes and polls the in-flight ones, pushing an event for every finished node. Returns
+    /// `Ready(true)` when the run is over, `Ready(false)` when some nodes finished and `Pending` when all in-flight
+    /// futures are waiting.
+    fn poll_events(
+        &mut self,
+        cx: &mut Context<'_>,
+        events: &mut VecDeque<CompletionEvent<T>>,
+    ) -> Poll<bool> {
+        while self.in_flight.len() < self.concurrency {
+            let Some((node, claims)) = self.next_node() else {
+                break;
+            };
+
+            let future = self.node_executor.call(node.clone());
+            self.in_flight.push((node, claims, future));
+        }
+
+        // Nothing in flight means nothing can make new nodes available anymore.
+        if self.provider.is_empty() || self.in_flight.is_empty() {
+            return Poll::Ready(true);
+        }
+
+        let mut progressed = false;
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            let Poll::Ready(result) = self.in_flight[i].2.as_mut().poll(cx) else {
+                i += 1;
+                continue;
+            };
+
+            let (node, claims, _) = self.in_flight.swap_remove(i);
+            self.slots.release(&claims);
+            match result {
+                Ok(()) => {
+                    let _ = self.provider.complete(node.clone());
+                    events.push_back(CompletionEvent::Completed(node));
+                }
+                Err(err) => {
+                    let skipped = self.provider.fail(node.clone()).unwrap_or_default();
+                    events.push_back(CompletionEvent::Failed(node, err));
+                    events.extend(skipped.into_iter().map(CompletionEvent::Skipped));
+                }
+            }
+            progressed = true;
+        }
+
+        if progressed {
+            Poll::Ready(false)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Future returned by `AsyncRunner::run`.
+pub struct RunFuture<'a, T, E: ?Sized> {
+    driver: Driver<'a, T, E>,
+    report: Option<RunReport<T>>,
+}
+
+// The node futures are pinned on their own, nothing is ever projected out of a pinned `RunFuture` or `RunStream`.
+impl<T, E: ?Sized> Unpin for RunFuture<'_, T, E> {}
+
+impl<'a, T, E> RunFuture<'a, T, E>
+where
+    T: Hash + PartialEq + Eq + Clone,
+    E: AsyncCallableByID<T> + ?Sized,
+{
+    /// Only starts a node once its claims fit in the free capacity of their slots, see `Slots`. To be called before
+    /// the future is polled.
+    pub fn with_slots(mut self, slots: Slots<T>) -> Self {
+        self.driver.slots.push(slots);
+        self
+    }
+}
+
+impl<'a, T, E> Future for RunFuture<'a, T, E>
+where
+    T: Hash + PartialEq + Eq + Clone,
+    E: AsyncCallableByID<T> + ?Sized,
+{
+    type Output = RunReport<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut events = VecDeque::new();
+
+        loop {
+            let poll = this.driver.poll_events(cx, &mut events);
+
+            let report = this
+                .report
+                .as_mut()
+                .expect("RunFuture polled after completion");
+            for event in events.drain(..) {
+                match event {
+                    CompletionEvent::Completed(node) => report.completed.push(node),
+                    CompletionEvent::Failed(node, err) => report.failed.push((node, err)),
+                    CompletionEvent::Skipped(node) => report.skipped.push(node),
+                }
+            }
+
+            match poll {
+                Poll::Ready(true) => return Poll::Ready(this.report.take().unwrap()),
+                Poll::Ready(false) => continue,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Stream of completion events returned by `AsyncRunner::stream`.
+pub struct RunStream<'a, T, E: ?Sized> {
+    driver: Driver<'a, T, E>,
+    events: VecDeque<CompletionEvent<T>>,
+    finished: bool,
+}
+
+impl<T, E: ?Sized> Unpin for RunStream<'_, T, E> {}
+
+impl<'a, T, E> RunStream<'a, T, E>
+where
+    T: Hash + PartialEq + Eq + Clone,
+    E: AsyncCallableByID<T> + ?Sized,
+{
+    /// See `RunFuture::with_slots`.
+    pub fn with_slots(mut self, slots: Slots<T>) -> Self {
+        self.driver.slots.push(slots);
+        self
+    }
+
+    /// `None` once every node finished.
+    pub fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<CompletionEvent<T>>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(event) = this.events.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+
+            if this.finished {
+                return Poll::Ready(None);
+            }
+
+            match this.driver.poll_events(cx, &mut this.events) {
+                Poll::Ready(finished) => this.finished = finished,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    /// The next event, `None` once every node finished.
+    pub async fn next(&mut self) -> Option<CompletionEvent<T>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<'a, T, E> futures_core::Stream for RunStream<'a, T, E>
+where
+    T: Hash + PartialEq + Eq + Clone,
+    E: AsyncCallableByID<T> + ?Sized,
+{
+    type Item = CompletionEvent<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        RunStream::poll_next(self, cx)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        task::{Wake, Waker},
+        thread::{self, Thread},
+    };
+
+    use super::*;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Minimal executor, to show there is no runtime needed.
+    pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    /// Returns `Pending` once before completing.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    struct AsyncExecutorExample {
+        dependency_graph: HashMap<usize, Vec<usize>>,
+        seen: Mutex<Vec<usize>>,
+        in_flight: Mutex<(usize, usize)>,
+    }
+
+    impl AsyncCallableByID<usize> for AsyncExecutorExample {
+        fn call(&self, id: usize) -> BoxFuture<'_, Result<(), Error>> {
+            Box::pin(async move {
+                {
+                    let mut in_flight = self.in_flight.lock().unwrap();
+                    in_flight.0 += 1;
+                    in_flight.1 = in_flight.1.max(in_flight.0);
+                }
+
+                YieldOnce(false).await;
+
+                let mut seen = self.seen.lock().unwrap();
+                for dep in &self.dependency_graph[&id] {
+                    assert!(seen.contains(dep));
+                }
+                seen.push(id);
+                self.in_flight.lock().unwrap().0 -= 1;
+
+                if id == 5 {
+                    Err("Failing on purpose.".into())
+                } else {
+                    Ok(())
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn it_runs_futures_concurrently() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1]);
+        nodes.insert(4, vec![]);
+        nodes.insert(5, vec![]);
+        nodes.insert(6, vec![2, 3]);
+        nodes.insert(7, vec![3, 5]);
+
+        let executor = AsyncExecutorExample {
+            dependency_graph: nodes.clone(),
+            seen: Mutex::new(vec![]),
+            in_flight: Mutex::new((0, 0)),
+        };
+        let runner = AsyncRunner::new(2);
+
+        let report = block_on(runner.run(TopologicalBatchProvider::new(nodes).unwrap(), &executor));
+
+        assert_eq!(5, report.completed.len());
+        assert_eq!(vec![7], report.skipped);
+        assert_eq!(2, executor.in_flight.lock().unwrap().1);
+    }
+
+    #[test]
+    fn it_streams_completion_events() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(5, vec![1]);
+        nodes.insert(6, vec![5]);
+
+        let executor = AsyncExecutorExample {
+            dependency_graph: nodes.clone(),
+            seen: Mutex::new(vec![]),
+            in_flight: Mutex::new((0, 0)),
+        };
+        let runner = AsyncRunner::new(4);
+
+        let events = block_on(async {
+            let mut stream =
+                runner.stream(TopologicalBatchProvider::new(nodes).unwrap(), &executor);
+            let mut events = vec![];
+            while let Some(event) = stream.next().await {
+                events.push(match event {
+                    CompletionEvent::Completed(id) => format!("completed {}", id),
+                    CompletionEvent::Failed(id, _) => format!("failed {}", id),
+                    CompletionEvent::Skipped(id) => format!("skipped {}", id),
+                });
+            }
+            events
+        });
+
+        assert_eq!(vec!["completed 1", "failed 5", "skipped 6"], events);
+    }
+
+    #[test]
+    fn it_waits_for_free_slots() {
+        let nodes = (1..=4)
+            .map(|i| (i, vec![]))
+            .collect::<HashMap<usize, Vec<usize>>>();
+        let executor = AsyncExecutorExample {
+            dependency_graph: nodes.clone(),
+            seen: Mutex::new(vec![]),
+            in_flight: Mutex::new((0, 0)),
+        };
+        let gpu =
+            Slots::new(Arc::new(|_: &usize| vec![("gpu".to_string(), 1)])).with_capacity("gpu", 1);
+
+        let report = block_on(
+            AsyncRunner::new(4)
+                .run(TopologicalBatchProvider::new(nodes).unwrap(), &executor)
+                .with_slots(gpu),
+        );
+
+        assert_eq!(4, report.completed.len());
+        assert_eq!(1, executor.in_flight.lock().unwrap().1);
+    }
+}
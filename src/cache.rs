@@ -0,0 +1,209 @@
+//! Memoization of node executions: a node whose fingerprint (content hash of its inputs) is found in the cache store
+//! is marked complete without calling the executor. This turns the runner into an incremental build runner.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Content hash of a node's inputs. Nodes returning `None` are not cacheable and always executed.
+///
+/// The hash should be stable across processes when used with a persistent store (`DefaultHasher` is not).
+pub trait Fingerprint<T> {
+    fn fingerprint(&self, id: &T) -> Option<u64>;
+}
+
+/// Storage of the fingerprints of successful executions.
+pub trait CacheStore {
+    fn contains(&self, fingerprint: u64) -> bool;
+
+    /// Called after a successful execution.
+    fn record(&self, fingerprint: u64);
+}
+
+/// The fingerprint hook and the store, as used by `ThreadPoolRunner::run_cached`.
+pub struct NodeCache<T> {
+    pub fingerprint: Arc<dyn Fingerprint<T> + Send + Sync>,
+    pub store: Arc<dyn CacheStore + Send + Sync>,
+}
+
+impl<T> NodeCache<T> {
+    pub fn new(
+        fingerprint: Arc<dyn Fingerprint<T> + Send + Sync>,
+        store: Arc<dyn CacheStore + Send + Sync>,
+    ) -> Self {
+        Self { fingerprint, store }
+    }
+}
+
+impl<T> Clone for NodeCache<T> {
+    fn clone(&self) -> Self {
+        Self {
+            fingerprint: self.fingerprint.clone(),
+            store: self.store.clone(),
+        }
+    }
+}
+
+/// Cache store living as long as the process, eg for watch-mode tools.
+#[derive(Debug, Default)]
+pub struct MemoryCacheStore {
+    fingerprints: Mutex<HashSet<u64>>,
+}
+
+impl MemoryCacheStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CacheStore for MemoryCacheStore {
+    fn contains(&self, fingerprint: u64) -> bool {
+        self.fingerprints.lock().unwrap().contains(&fingerprint)
+    }
+
+    fn record(&self, fingerprint: u64) {
+        self.fingerprints.lock().unwrap().insert(fingerprint);
+    }
+}
+
+/// Cache store persisting to a directory, one file per fingerprint, so incremental skipping works across processes.
+/// Every file records a successful execution. The runner records bare successes, executors producing an output store
+/// it themselves with `record_output` and read it back with `output` when the node is skipped on a later run.
+///
+/// Files are written to a temporary name unique to the write first and then renamed, so an interrupted write is never
+/// taken as a hit and concurrent writes of the same fingerprint don't mix.
+#[derive(Debug)]
+pub struct FileCacheStore {
+    dir: PathBuf,
+}
+
+const SUCCESS_HEADER: &[u8] = b"success\n";
+
+/// Makes the temporary files of concurrent writes within the process distinct.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl FileCacheStore {
+    /// Uses (and creates when missing) `dir` for the cache files.
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Records a successful execution together with its output.
+    pub fn record_output(&self, fingerprint: u64, output: &[u8]) -> io::Result<()> {
+        let path = self.path(fingerprint);
+        let temp_path = path.with_extension(format!(
+            "tmp{}_{}",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut content = Vec::with_capacity(SUCCESS_HEADER.len() + output.len());
+        content.extend_from_slice(SUCCESS_HEADER);
+        content.extend_from_slice(output);
+
+        fs::write(&temp_path, content)?;
+        fs::rename(temp_path, path)
+    }
+
+    /// The recorded output of a successful execution, `None` when the fingerprint is not in the cache.
+    pub fn output(&self, fingerprint: u64) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(fingerprint)) {
+            Ok(content) => match content.strip_prefix(SUCCESS_HEADER) {
+                Some(output) => Ok(Some(output.to_vec())),
+                None => Ok(None),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn path(&self, fingerprint: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}", fingerprint))
+    }
+}
+
+impl CacheStore for FileCacheStore {
+    fn contains(&self, fingerprint: u64) -> bool {
+        matches!(self.output(fingerprint), Ok(Some(_)))
+    }
+
+    /// Write errors are ignored: they only cost a cache miss next time.
+    fn record(&self, fingerprint: u64) {
+        if !self.contains(fingerprint) {
+            let _ = self.record_output(fingerprint, &[]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn temp_dir() -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir().join(format!(
+            "topological_batch_cache_{}_{}",
+            std::process::id(),
+            nanos
+        ))
+    }
+
+    #[test]
+    fn it_persists_to_files() {
+        let dir = temp_dir();
+
+        {
+            let store = FileCacheStore::new(&dir).unwrap();
+            assert!(!store.contains(1));
+
+            store.record(1);
+            store.record_output(2, b"artifact").unwrap();
+        }
+
+        let store = FileCacheStore::new(&dir).unwrap();
+        assert!(store.contains(1));
+        assert_eq!(Some(vec![]), store.output(1).unwrap());
+        assert_eq!(Some(b"artifact".to_vec()), store.output(2).unwrap());
+        assert_eq!(None, store.output(3).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_concurrent_writes_apart() {
+        let dir = temp_dir();
+        let store = Arc::new(FileCacheStore::new(&dir).unwrap());
+        let outputs = (0..8u8).map(|i| vec![i; 64 * 1024]).collect::<Vec<_>>();
+
+        let handles = outputs
+            .iter()
+            .cloned()
+            .map(|output| {
+                let store = store.clone();
+                std::thread::spawn(move || store.record_output(1, &output).unwrap())
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(outputs.contains(&store.output(1).unwrap().unwrap()));
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+//! Cooperative cancellation of runs. A cancelled run stops dispatching: nodes already executing are let finish, and
+//! the nodes never started are reported as `RunReport::cancelled`.
+
+#[cfg(feature = "signals")]
+use super::common::*;
+use std::{
+    error, fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Error of work interrupted by a cancellation, eg a killed command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled.")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+/// Shared flag, cloning gives another handle to the same token. See `RunOptions::with_cancellation`.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels every run (and executor) holding this token. Can be called from any thread, any number of times.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Cancels the token on Ctrl-C (SIGINT) and SIGTERM, instead of the process being terminated. Available with the
+    /// `signals` feature.
+    ///
+    /// There is a single signal handler per process, so this fails when one was already installed (eg by an earlier
+    /// call). Share the token instead of calling it again.
+    #[cfg(feature = "signals")]
+    pub fn cancel_on_signals(&self) -> Result<(), Error> {
+        let token = self.clone();
+        ctrlc::set_handler(move || token.cancel())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_shares_cancellation_between_clones() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+
+        assert!(!token.is_cancelled());
+        clone.cancel();
+        assert!(token.is_cancelled());
+        assert!(!CancellationToken::new().is_cancelled());
+    }
+}
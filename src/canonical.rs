@@ -0,0 +1,99 @@
+//! Canonical hash of a dependency graph: the same nodes and edges hash the same, whatever order they were declared,
+//! iterated or stored in. Meant for cache keys of whole graphs and to tell whether persisted progress belongs to the
+//! graph about to be resumed.
+//!
+//! The hash is computed with FNV-1a over the `Display` form of the IDs, lengths and counts being encoded as little
+//! endian `u64`s, so unlike `HashMap` keys it is the same between processes, crate versions and platforms. IDs with
+//! the same `Display` form hash the same.
+
+use super::rng::*;
+use super::topological_batch_provider::*;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    hash::{Hash, Hasher},
+};
+
+fn write_u64(hasher: &mut StableHasher, value: u64) {
+    hasher.write(&value.to_le_bytes());
+}
+
+fn id_hash<T: Display>(id: &T) -> u64 {
+    let id = id.to_string();
+    let mut hasher = StableHasher::new();
+    write_u64(&mut hasher, id.len() as u64);
+    hasher.write(id.as_bytes());
+    hasher.finish()
+}
+
+fn list_hash(head: Option<u64>, values: &[u64]) -> u64 {
+    let mut hasher = StableHasher::new();
+    if let Some(head) = head {
+        write_u64(&mut hasher, head);
+    }
+    write_u64(&mut hasher, values.len() as u64);
+    for value in values {
+        write_u64(&mut hasher, *value);
+    }
+    hasher.finish()
+}
+
+/// Hash of the dependency map (same shape as `TopologicalBatchProvider::new` expects). The order of the nodes and of
+/// the dependencies, and duplicated dependencies, don't change it.
+pub fn canonical_hash<T: Display>(graph: &HashMap<T, Vec<T>>) -> u64 {
+    let mut nodes = graph
+        .iter()
+        .map(|(node, dependencies)| {
+            let mut dependencies = dependencies.iter().map(id_hash).collect::<Vec<_>>();
+            dependencies.sort_unstable();
+            dependencies.dedup();
+            list_hash(Some(id_hash(node)), &dependencies)
+        })
+        .collect::<Vec<_>>();
+    nodes.sort_unstable();
+
+    list_hash(None, &nodes)
+}
+
+impl<T: Hash + PartialEq + Eq + Clone + Display> TopologicalBatchProvider<T> {
+    /// `canonical_hash` of the graph, regardless of the progress. Edges of pruned nodes (see `prune_completed`) are
+    /// no longer known, so they don't count.
+    pub fn canonical_hash(&self) -> u64 {
+        canonical_hash(&self.dependencies_by_id())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_hashes_regardless_of_order() {
+        let graph = HashMap::from([(1, vec![]), (2, vec![1]), (3, vec![1, 2])]);
+        let reordered = HashMap::from([(3, vec![2, 1, 2]), (1, vec![]), (2, vec![1])]);
+        assert_eq!(canonical_hash(&graph), canonical_hash(&reordered));
+        assert_eq!(
+            canonical_hash(&graph),
+            TopologicalBatchProvider::new(reordered)
+                .unwrap()
+                .canonical_hash()
+        );
+
+        let rewired = HashMap::from([(1, vec![]), (2, vec![]), (3, vec![1, 2])]);
+        assert_ne!(canonical_hash(&graph), canonical_hash(&rewired));
+        let renamed = HashMap::from([(1, vec![]), (2, vec![1]), (4, vec![1, 2])]);
+        assert_ne!(canonical_hash(&graph), canonical_hash(&renamed));
+    }
+
+    #[test]
+    fn it_hashes_ids_by_their_display_form() {
+        let numbers = HashMap::from([(1u8, vec![]), (2, vec![1])]);
+        let wide = HashMap::from([(1u64, vec![]), (2, vec![1])]);
+        let names = HashMap::from([("1", vec![]), ("2", vec!["1"])]);
+        assert_eq!(canonical_hash(&numbers), canonical_hash(&wide));
+        assert_eq!(canonical_hash(&numbers), canonical_hash(&names));
+
+        // Pinned, so a change of the encoding doesn't go unnoticed.
+        assert_eq!(0x4b4b_1302_b88e_3dc5, canonical_hash(&names));
+    }
+}
@@ -0,0 +1,147 @@
+//! Channel front-end, for job systems (custom pools, actor frameworks) that consume topological batches without
+//! `ThreadPoolRunner`: ready nodes are sent to a queue and completions come back on an other one.
+
+use super::common::*;
+use super::topological_batch_provider::*;
+use std::{
+    hash::Hash,
+    sync::mpsc::{Receiver, Sender},
+};
+
+/// Result of an externally executed node.
+#[derive(Debug)]
+pub enum Completion<T> {
+    Completed(T),
+    Failed(T, Error),
+}
+
+impl<T> From<T> for Completion<T> {
+    fn from(node: T) -> Self {
+        Completion::Completed(node)
+    }
+}
+
+impl<T> From<Result<T, (T, Error)>> for Completion<T> {
+    fn from(result: Result<T, (T, Error)>) -> Self {
+        match result {
+            Ok(node) => Completion::Completed(node),
+            Err((node, err)) => Completion::Failed(node, err),
+        }
+    }
+}
+
+/// Drives the provider from the calling thread until every node finished: each node becoming ready is sent on
+/// `ready`, each message of `completions` completes (or fails) a node. Completions can be plain IDs, `Completion`s or
+/// `Result<T, (T, Error)>`s. Duplicate and unknown completions are ignored.
+///
+/// Fails if either channel disconnects before the end, the nodes in flight being lost.
+pub fn pump<T, C>(
+    mut topological_batch_provider: TopologicalBatchProvider<T>,
+    ready: &Sender<T>,
+    completions: &Receiver<C>,
+) -> Result<RunReport<T>, Error>
+where
+    T: Hash + PartialEq + Eq + Clone,
+    C: Into<Completion<T>>,
+{
+    let mut report = RunReport::new();
+
+    loop {
+        while let Some(node) = topological_batch_provider.pop() {
+            ready
+                .send(node)
+                .map_err(|_| "Ready channel disconnected.")?;
+        }
+
+        if topological_batch_provider.is_empty() {
+            return Ok(report);
+        }
+
+        let completion = completions
+            .recv()
+            .map_err(|_| "Completion channel disconnected.")?
+            .into();
+        apply_completion(&mut topological_batch_provider, &mut report, completion);
+    }
+}
+
+/// Completes (or fails) the node in the provider and the report. Duplicate and unknown completions are ignored.
+pub(crate) fn apply_completion<T: Hash + PartialEq + Eq + Clone>(
+    topological_batch_provider: &mut TopologicalBatchProvider<T>,
+    report: &mut RunReport<T>,
+    completion: Completion<T>,
+) {
+    match completion {
+        Completion::Completed(node) => {
+            if topological_batch_provider.complete(node.clone()).is_ok() {
+                report.completed.push(node);
+            }
+        }
+        Completion::Failed(node, err) => {
+            if let Ok(skipped) = topological_batch_provider.fail(node.clone()) {
+                report.skipped.extend(skipped);
+                report.failed.push((node, err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::mpsc, thread};
+
+    #[test]
+    fn it_pumps_nodes_through_channels() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![]);
+        nodes.insert(4, vec![3]);
+
+        let (ready_sender, ready_receiver) = mpsc::channel::<usize>();
+        let (completion_sender, completion_receiver) = mpsc::channel();
+
+        let worker = thread::spawn(move || {
+            for node in ready_receiver {
+                let result = if node == 3 {
+                    Err((node, "boom".into()))
+                } else {
+                    Ok(node)
+                };
+                completion_sender.send(result).unwrap();
+            }
+        });
+
+        let report = pump(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            &ready_sender,
+            &completion_receiver,
+        )
+        .unwrap();
+        drop(ready_sender);
+        worker.join().unwrap();
+
+        let mut completed = report.completed.clone();
+        completed.sort_unstable();
+        assert_eq!(vec![1, 2], completed);
+        assert_eq!(3, report.failed[0].0);
+        assert_eq!(vec![4], report.skipped);
+    }
+
+    #[test]
+    fn it_fails_on_disconnect() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+        nodes.insert(1, vec![]);
+
+        let (ready_sender, _ready_receiver) = mpsc::channel();
+        let (_, completion_receiver) = mpsc::channel::<usize>();
+
+        assert!(pump(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            &ready_sender,
+            &completion_receiver
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Chrome trace event export of a run: one lane per worker thread and one slice per node execution. The file can be
+//! opened in Perfetto (or `about://tracing`) to see where the parallelism collapsed.
+
+use super::json;
+use super::observer::*;
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct Slice {
+    name: String,
+    worker: usize,
+    start: Duration,
+    duration: Duration,
+    outcome: &'static str,
+}
+
+/// Observer recording node executions, to be passed to the runner with `RunOptions::with_observer` and written out
+/// after the run.
+#[derive(Debug)]
+pub struct ChromeTraceRecorder {
+    origin: Instant,
+    slices: Mutex<Vec<Slice>>,
+}
+
+impl ChromeTraceRecorder {
+    /// Timestamps are relative to the creation of the recorder.
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            slices: Mutex::new(vec![]),
+        }
+    }
+
+    /// Writes the trace event JSON.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        let slices = self.slices.lock().unwrap();
+
+        let mut workers = slices.iter().map(|slice| slice.worker).collect::<Vec<_>>();
+        workers.sort_unstable();
+        workers.dedup();
+
+        let mut events = workers
+            .into_iter()
+            .map(|worker| {
+                format!(
+                    r#"{{"name":"thread_name","ph":"M","pid":1,"tid":{},"args":{{"name":"Worker {}"}}}}"#,
+                    worker, worker
+                )
+            })
+            .collect::<Vec<_>>();
+
+        events.extend(slices.iter().map(|slice| {
+            format!(
+                r#"{{"name":{},"ph":"X","pid":1,"tid":{},"ts":{},"dur":{},"args":{{"outcome":"{}"}}}}"#,
+                json::string(&slice.name),
+                slice.worker,
+                slice.start.as_micros(),
+                slice.duration.as_micros(),
+                slice.outcome
+            )
+        }));
+
+        writeln!(writer, r#"{{"displayTimeUnit":"ms","traceEvents":["#)?;
+        for (i, event) in events.iter().enumerate() {
+            let separator = if i + 1 < events.len() { "," } else { "" };
+            writeln!(writer, "{}{}", event, separator)?;
+        }
+        writeln!(writer, "]}}")
+    }
+
+    /// Writes the trace event JSON into the file at `path`.
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+}
+
+impl Default for ChromeTraceRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Display> RunObserver<T> for ChromeTraceRecorder {
+    fn on_node_finish(&self, worker: usize, id: &T, outcome: NodeOutcome<'_>, duration: Duration) {
+        let start = self.origin.elapsed().saturating_sub(duration);
+
+        self.slices.lock().unwrap().push(Slice {
+            name: id.to_string(),
+            worker,
+            start,
+            duration,
+            outcome: match outcome {
+                NodeOutcome::Completed => "completed",
+                NodeOutcome::Failed(_) => "failed",
+                NodeOutcome::Cached => "cached",
+            },
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_writes_trace_events() {
+        let recorder = ChromeTraceRecorder::new();
+        RunObserver::<&str>::on_node_finish(
+            &recorder,
+            1,
+            &"build \"x\"",
+            NodeOutcome::Completed,
+            Duration::ZERO,
+        );
+
+        let mut out = vec![];
+        recorder.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with(r#"{"displayTimeUnit":"ms","traceEvents":["#));
+        assert!(out.contains(r#""args":{"name":"Worker 1"}"#));
+        assert!(out.contains(r#""name":"build \"x\"","ph":"X","pid":1,"tid":1"#));
+        assert!(out.trim_end().ends_with("]}"));
+    }
+}
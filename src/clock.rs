@@ -0,0 +1,63 @@
+//! Source of time for the runner, the middleware layers and the command executor: the system clock by default, a
+//! `VirtualClock` (see `testing`) in tests and simulations, where timeouts and backoffs play out in virtual time
+//! instead of sleeping for real.
+//!
+//! ```ignore
+//! let clock = VirtualClock::new();
+//! let executor = LayerStack::new()
+//!     .with_layer(RetryLayer::new(5, Duration::from_secs(30)).with_clock(Arc::new(clock.clone())))
+//!     .wrap(executor);
+//! ```
+
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+pub trait Clock: Debug + Send + Sync {
+    /// Time elapsed since the origin of the clock, which is fixed for its lifetime.
+    fn now(&self) -> Duration;
+
+    /// Waits for `duration`. Virtual clocks move their time forward instead.
+    fn sleep(&self, duration: Duration);
+}
+
+/// A clock as held by the runner and the executors.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Wall clock time, sleeping the calling thread.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    origin: Instant,
+}
+
+impl SystemClock {
+    /// Starts at zero.
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+        }
+    }
+
+    pub(crate) fn shared() -> SharedClock {
+        Arc::new(Self::new())
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
@@ -0,0 +1,746 @@
+//! Executor running an external command per node, the command line being a template where `{id}` is replaced by the
+//! node's ID. The node succeeds when the command exits with success.
+//!
+//! With the `jobserver` feature the commands can share a GNU make jobserver, keeping the parallelism of nested build
+//! tools (`make`, `cargo`) bounded as a whole.
+//!
+//! With `CommandExecutor::with_scratch_dirs` every node runs in a directory of its own, and the artifacts it declares
+//! are copied to its dependees:
+//!
+//! ```text
+//! <root>/<id>/work/               working directory of the node, TOPO_SCRATCH
+//! <root>/<id>/inputs/<dep>/...    declared artifacts of each dependency, TOPO_INPUTS
+//! ```
+
+use super::cancel::*;
+use super::clock::*;
+use super::common::*;
+use super::topological_batch_provider::*;
+use std::{
+    collections::HashMap,
+    error, fmt,
+    fmt::Display,
+    fs,
+    hash::Hash,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::Duration,
+};
+
+/// Placeholder replaced by the node's ID in the program and arguments.
+pub const ID_PLACEHOLDER: &str = "{id}";
+
+/// Environment variable holding the node's scratch directory, see `CommandExecutor::with_scratch_dirs`.
+pub const SCRATCH_ENV: &str = "TOPO_SCRATCH";
+
+/// Environment variable holding the directory of the dependencies' artifacts, see
+/// `CommandExecutor::with_scratch_dirs`.
+pub const INPUTS_ENV: &str = "TOPO_INPUTS";
+
+/// How often a running command is checked for cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How the output of the commands reaches the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputMode {
+    /// Commands write to the inherited stdout and stderr directly, interleaving freely.
+    #[default]
+    Inherit,
+    /// Every line is prefixed with `[id] ` and written as a whole.
+    Prefixed,
+    /// The output is held back until the command exits, then written at once.
+    Buffered,
+}
+
+/// Settings of the command of a node, see `CommandExecutor::with_options` and `CommandExecutor::with_node_options`.
+/// `{id}` is replaced in the environment values and the working directory too.
+#[derive(Debug, Clone, Default)]
+pub struct CommandOptions {
+    env: Vec<(String, String)>,
+    cwd: Option<PathBuf>,
+    shell: bool,
+    allow_failure: bool,
+    timeout: Option<Duration>,
+    artifacts: Vec<PathBuf>,
+}
+
+impl CommandOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets an environment variable, on top of the inherited ones.
+    pub fn with_env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Runs the command in `cwd` instead of the current directory.
+    pub fn with_cwd(mut self, cwd: impl Into<PathBuf>) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    /// Runs the program and arguments, joined with spaces, as a script of `sh -c` (`cmd /C` on Windows). Pipes and
+    /// redirections work, but the IDs are no longer protected from being interpreted by the shell.
+    pub fn with_shell(mut self) -> Self {
+        self.shell = true;
+        self
+    }
+
+    /// Completes the node even when the command exits unsuccessfully, so its dependees still run.
+    pub fn with_allow_failure(mut self) -> Self {
+        self.allow_failure = true;
+        self
+    }
+
+    /// Declares a file or directory, relative to the scratch directory, the command produces for its dependees. A
+    /// successful command missing it fails with `MissingArtifact`. Only used with `CommandExecutor::with_scratch_dirs`.
+    pub fn with_artifact(mut self, path: impl Into<PathBuf>) -> Self {
+        self.artifacts.push(path.into());
+        self
+    }
+
+    /// Kills the command when it runs longer than `timeout`, failing the node with `CommandTimedOut`. On Unix the
+    /// command gets a process group of its own, killed as a whole, so the tools it started don't outlive it.
+    ///
+    /// Being in its own group the command no longer receives the terminal's Ctrl-C, see
+    /// `CancellationToken::cancel_on_signals` and `CommandExecutor::with_cancellation` to stop it instead.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// Error of a command exiting unsuccessfully.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandFailed {
+    pub status: ExitStatus,
+}
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Command failed: {}.", self.status)
+    }
+}
+
+impl error::Error for CommandFailed {}
+
+/// Error of a successful command not producing a declared artifact.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingArtifact {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for MissingArtifact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Missing artifact: {}.", self.path.display())
+    }
+}
+
+impl error::Error for MissingArtifact {}
+
+/// Error of a command killed after running longer than its timeout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandTimedOut {
+    pub timeout: Duration,
+}
+
+impl fmt::Display for CommandTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Command timed out after {:?}.", self.timeout)
+    }
+}
+
+impl error::Error for CommandTimedOut {}
+
+/// Runs `program` with `args` for each node. The arguments are passed as is, without a shell (unless
+/// `CommandOptions::with_shell`), so an ID can't inject anything into the command line.
+#[derive(Debug, Clone)]
+pub struct CommandExecutor {
+    program: String,
+    args: Vec<String>,
+    output: OutputMode,
+    options: CommandOptions,
+    node_options: HashMap<String, CommandOptions>,
+    scratch: Option<Scratch>,
+    cancellation: Option<CancellationToken>,
+    clock: SharedClock,
+    #[cfg(feature = "jobserver")]
+    jobserver: Option<jobserver::Client>,
+}
+
+impl CommandExecutor {
+    /// For example `CommandExecutor::new("make", ["-C", "{id}"])`.
+    pub fn new<I, S>(program: impl Into<String>, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+            output: OutputMode::Inherit,
+            options: CommandOptions::new(),
+            node_options: HashMap::new(),
+            scratch: None,
+            cancellation: None,
+            clock: SystemClock::shared(),
+            #[cfg(feature = "jobserver")]
+            jobserver: None,
+        }
+    }
+
+    /// Captures the stdout and stderr of the commands, to keep the output of parallel nodes readable. See
+    /// `OutputMode`.
+    pub fn with_output(mut self, output: OutputMode) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Options of every node without options of its own.
+    pub fn with_options(mut self, options: CommandOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Options of the node `id`, replacing (not extending) the ones of `with_options`.
+    pub fn with_node_options(mut self, id: impl Display, options: CommandOptions) -> Self {
+        self.node_options.insert(id.to_string(), options);
+        self
+    }
+
+    /// Runs every node in a fresh scratch directory under `root`, with the artifacts (see
+    /// `CommandOptions::with_artifact`) of its dependencies copied next to it - see the module docs for the layout.
+    /// The working directory of `CommandOptions::with_cwd` takes precedence over the scratch directory.
+    ///
+    /// The IDs are used as directory names, nodes whose ID isn't a plain one (empty, `.`, `..`, or containing a path
+    /// separator) fail without running. `provider` is only read for the dependencies.
+    pub fn with_scratch_dirs<T: Hash + Eq + Clone + Display>(
+        mut self,
+        root: impl Into<PathBuf>,
+        provider: &TopologicalBatchProvider<T>,
+    ) -> Self {
+        let dependencies = provider
+            .dependencies_by_id()
+            .into_iter()
+            .map(|(id, dependencies)| {
+                (
+                    id.to_string(),
+                    dependencies.iter().map(ToString::to_string).collect(),
+                )
+            })
+            .collect();
+
+        self.scratch = Some(Scratch {
+            root: root.into(),
+            dependencies,
+        });
+        self
+    }
+
+    /// Kills the running commands once the token is cancelled, failing their nodes with `Cancelled`. Share the token
+    /// with `RunOptions::with_cancellation` so the run stops as well.
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Clock the timeouts are measured with, the system clock by default.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Every command holds a token of `jobserver` while running, and is handed the jobserver (through `MAKEFLAGS`)
+    /// for its own subprocesses. Use `jobserver::Client::new` to act as the jobserver, or `jobserver::Client::from_env`
+    /// to take part in the one of a parent `make`. Available with the `jobserver` feature.
+    ///
+    /// The token implicitly owned by this process is not used for commands, so the executor runs at most one command
+    /// less than the jobserver's limit.
+    #[cfg(feature = "jobserver")]
+    pub fn with_jobserver(mut self, jobserver: jobserver::Client) -> Self {
+        self.jobserver = Some(jobserver);
+        self
+    }
+
+    /// The command of the node `id`, before being spawned.
+    pub fn command(&self, id: &str) -> Command {
+        let options = self.options_of(id);
+        let program = self.program.replace(ID_PLACEHOLDER, id);
+        let args = self.args.iter().map(|arg| arg.replace(ID_PLACEHOLDER, id));
+
+        let mut command = if options.shell {
+            let script = std::iter::once(program)
+                .chain(args)
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut command = Command::new(if cfg!(windows) { "cmd" } else { "sh" });
+            command
+                .arg(if cfg!(windows) { "/C" } else { "-c" })
+                .arg(script);
+            command
+        } else {
+            let mut command = Command::new(program);
+            command.args(args);
+            command
+        };
+
+        if let Some(scratch) = &self.scratch {
+            command
+                .current_dir(scratch.work_dir(id))
+                .env(SCRATCH_ENV, scratch.work_dir(id))
+                .env(INPUTS_ENV, scratch.inputs_dir(id));
+        }
+        for (key, value) in &options.env {
+            command.env(key, value.replace(ID_PLACEHOLDER, id));
+        }
+        if let Some(cwd) = &options.cwd {
+            command.current_dir(cwd.to_string_lossy().replace(ID_PLACEHOLDER, id));
+        }
+        #[cfg(unix)]
+        if options.timeout.is_some() {
+            std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        }
+        #[cfg(feature = "jobserver")]
+        if let Some(jobserver) = &self.jobserver {
+            jobserver.configure_make(&mut command);
+        }
+        command
+    }
+
+    fn options_of(&self, id: &str) -> &CommandOptions {
+        self.node_options.get(id).unwrap_or(&self.options)
+    }
+
+    /// Recreates the scratch directory of the node, with the artifacts of its dependencies.
+    fn prepare_scratch(&self, scratch: &Scratch, id: &str) -> io::Result<()> {
+        let dependencies = scratch.dependencies.get(id).into_iter().flatten();
+        for name in std::iter::once(id).chain(dependencies.clone().map(String::as_str)) {
+            check_dir_name(name)?;
+        }
+
+        let node_dir = scratch.root.join(id);
+        if node_dir.exists() {
+            fs::remove_dir_all(&node_dir)?;
+        }
+        fs::create_dir_all(scratch.work_dir(id))?;
+        fs::create_dir_all(scratch.inputs_dir(id))?;
+
+        for dependency in dependencies {
+            for artifact in &self.options_of(dependency).artifacts {
+                copy_recursively(
+                    &scratch.work_dir(dependency).join(artifact),
+                    &scratch.inputs_dir(id).join(dependency).join(artifact),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Scratch {
+    root: PathBuf,
+    /// Dependencies by the IDs' display form.
+    dependencies: HashMap<String, Vec<String>>,
+}
+
+impl Scratch {
+    fn work_dir(&self, id: &str) -> PathBuf {
+        self.root.join(id).join("work")
+    }
+
+    fn inputs_dir(&self, id: &str) -> PathBuf {
+        self.root.join(id).join("inputs")
+    }
+}
+
+/// Rejects IDs which as a directory name would point outside of their own directory under the scratch root, so
+/// removing it can't delete anything else.
+fn check_dir_name(id: &str) -> io::Result<()> {
+    let plain = !id.is_empty()
+        && id != "."
+        && id != ".."
+        && !id.contains(['/', '\\'])
+        && !Path::new(id).is_absolute();
+
+    match plain {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("ID {:?} can't be used as a scratch directory name.", id),
+        )),
+    }
+}
+
+fn copy_recursively(from: &Path, to: &Path) -> io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursively(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+impl<T: Display> CallableByID<T> for CommandExecutor {
+    fn call(&self, id: &T) -> Result<(), Error> {
+        // Released when the command is done.
+        #[cfg(feature = "jobserver")]
+        let _token = self
+            .jobserver
+            .as_ref()
+            .map(jobserver::Client::acquire)
+            .transpose()?;
+
+        let id = id.to_string();
+        if let Some(scratch) = &self.scratch {
+            self.prepare_scratch(scratch, &id)?;
+        }
+
+        let mut command = self.command(&id);
+        if self.output != OutputMode::Inherit {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        let mut child = command.spawn()?;
+
+        let relays = match (child.stdout.take(), child.stderr.take()) {
+            (Some(stdout), Some(stderr)) => {
+                let output = self.output;
+                let stdout_id = id.clone();
+                let stderr_id = id.clone();
+                vec![
+                    thread::spawn(move || {
+                        copy_output(output, &stdout_id, stdout, |chunk| {
+                            io::stdout().lock().write_all(chunk)
+                        })
+                    }),
+                    thread::spawn(move || {
+                        copy_output(output, &stderr_id, stderr, |chunk| {
+                            io::stderr().lock().write_all(chunk)
+                        })
+                    }),
+                ]
+            }
+            _ => vec![],
+        };
+
+        let timeout = self.options_of(&id).timeout;
+        let deadline = timeout.map(|timeout| self.clock.now() + timeout);
+
+        let status = if self.cancellation.is_none() && deadline.is_none() {
+            child.wait()?
+        } else {
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+
+                let cancelled = self
+                    .cancellation
+                    .as_ref()
+                    .is_some_and(CancellationToken::is_cancelled);
+                let timed_out = deadline.is_some_and(|deadline| self.clock.now() >= deadline);
+                if cancelled || timed_out {
+                    terminate(&mut child, timeout.is_some());
+                    child.wait()?;
+                    join_relays(relays);
+                    return Err(match timeout {
+                        Some(timeout) if !cancelled => CommandTimedOut { timeout }.into(),
+                        _ => Cancelled.into(),
+                    });
+                }
+
+                self.clock.sleep(POLL_INTERVAL);
+            }
+        };
+        join_relays(relays);
+
+        if !status.success() && !self.options_of(&id).allow_failure {
+            return Err(CommandFailed { status }.into());
+        }
+
+        // A failed command allowed to fail isn't expected to have produced anything.
+        if let (Some(scratch), true) = (&self.scratch, status.success()) {
+            for artifact in &self.options_of(&id).artifacts {
+                let path = scratch.work_dir(&id).join(artifact);
+                if !path.exists() {
+                    return Err(MissingArtifact { path }.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Kills the command, with its whole process group when it was given one (see `CommandOptions::with_timeout`). The
+/// command still has to be reaped with `wait`.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn terminate(child: &mut Child, process_group: bool) {
+    #[cfg(unix)]
+    if process_group {
+        extern "C" {
+            fn kill(pid: i32, signal: i32) -> i32;
+        }
+        const SIGKILL: i32 = 9;
+
+        // SAFETY: a plain syscall. The child is not reaped yet, so its PID (the group's ID) can't be reused.
+        unsafe {
+            kill(-(child.id() as i32), SIGKILL);
+        }
+        return;
+    }
+
+    // Fails only when the command exited in the meantime.
+    let _ = child.kill();
+}
+
+/// Writes the output of a command through `write` as `output` says, every call of `write` being a line or the whole
+/// output.
+fn copy_output(
+    output: OutputMode,
+    id: &str,
+    reader: impl Read,
+    write: impl Fn(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+
+    match output {
+        OutputMode::Inherit => Ok(()),
+        OutputMode::Prefixed => {
+            let prefix = format!("[{}] ", id);
+            let mut line = vec![];
+            loop {
+                line.clear();
+                line.extend_from_slice(prefix.as_bytes());
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(());
+                }
+                if !line.ends_with(b"\n") {
+                    line.push(b'\n');
+                }
+                write(&line)?;
+            }
+        }
+        OutputMode::Buffered => {
+            let mut buffer = vec![];
+            reader.read_to_end(&mut buffer)?;
+            if buffer.is_empty() {
+                Ok(())
+            } else {
+                write(&buffer)
+            }
+        }
+    }
+}
+
+/// Waits for the output to be written. Failing to write it (eg a closed stdout) does not fail the node.
+fn join_relays(relays: Vec<thread::JoinHandle<io::Result<()>>>) {
+    for relay in relays {
+        let _ = relay.join();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn it_runs_commands_by_exit_status() {
+        let executor = CommandExecutor::new("sh", ["-c", "test \"$0\" = ok", "{id}"]);
+
+        assert!(executor.call(&"ok").is_ok());
+        assert!(executor
+            .call(&"not ok")
+            .unwrap_err()
+            .downcast_ref::<CommandFailed>()
+            .is_some());
+        assert!(
+            CommandExecutor::new("/nonexistent/{id}", Vec::<String>::new())
+                .call(&1)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn it_applies_node_options() {
+        let executor = CommandExecutor::new(
+            "test \"$NODE\" = {id} && test -f Cargo.toml",
+            Vec::<String>::new(),
+        )
+        .with_options(
+            CommandOptions::new()
+                .with_shell()
+                .with_env("NODE", "{id}")
+                .with_cwd(env!("CARGO_MANIFEST_DIR")),
+        )
+        .with_node_options(
+            "lenient",
+            CommandOptions::new().with_shell().with_allow_failure(),
+        );
+
+        assert!(executor.call(&"build").is_ok());
+        assert!(executor.call(&"lenient").is_ok());
+
+        let executor = executor.with_options(CommandOptions::new().with_shell().with_cwd("/"));
+        assert!(executor.call(&"build").is_err());
+    }
+
+    #[test]
+    fn it_kills_timed_out_process_groups() {
+        // The background sleep keeps the output pipes open, so the call only returns if it is killed too.
+        let executor = CommandExecutor::new("sleep 30 & sleep 30", Vec::<String>::new())
+            .with_output(OutputMode::Buffered)
+            .with_options(
+                CommandOptions::new()
+                    .with_shell()
+                    .with_timeout(Duration::from_millis(50)),
+            );
+
+        let started = Instant::now();
+        let err = executor.call(&1).unwrap_err();
+
+        assert_eq!(
+            Some(&CommandTimedOut {
+                timeout: Duration::from_millis(50)
+            }),
+            err.downcast_ref::<CommandTimedOut>()
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn it_hands_artifacts_to_dependees() {
+        let parent =
+            std::env::temp_dir().join(format!("topological_batch_scratch_{}", std::process::id()));
+        let root = parent.join("root");
+
+        let mut nodes: HashMap<&str, Vec<&str>> = HashMap::new();
+        nodes.insert("produce", vec![]);
+        nodes.insert("consume", vec!["produce"]);
+        nodes.insert("forget", vec![]);
+        nodes.insert("crash", vec![]);
+        nodes.insert("..", vec![]);
+        let provider = TopologicalBatchProvider::new(nodes).unwrap();
+
+        let script = "case {id} in \
+            produce) mkdir out && echo hi > out/result.txt ;; \
+            consume) cp \"$TOPO_INPUTS/produce/out/result.txt\" copy.txt ;; \
+            crash) exit 3 ;; \
+            esac";
+        let executor = CommandExecutor::new(script, Vec::<String>::new())
+            .with_options(CommandOptions::new().with_shell())
+            .with_node_options(
+                "produce",
+                CommandOptions::new()
+                    .with_shell()
+                    .with_artifact("out/result.txt"),
+            )
+            .with_node_options(
+                "forget",
+                CommandOptions::new().with_shell().with_artifact("nothing"),
+            )
+            .with_node_options(
+                "crash",
+                CommandOptions::new()
+                    .with_shell()
+                    .with_allow_failure()
+                    .with_artifact("nothing"),
+            )
+            .with_scratch_dirs(&root, &provider);
+
+        assert!(executor.call(&"produce").is_ok());
+        assert!(executor.call(&"consume").is_ok());
+        assert_eq!(
+            "hi\n",
+            fs::read_to_string(root.join("consume/work/copy.txt")).unwrap()
+        );
+
+        assert!(executor
+            .call(&"forget")
+            .unwrap_err()
+            .downcast_ref::<MissingArtifact>()
+            .is_some());
+        assert!(executor.call(&"crash").is_ok());
+
+        // The scratch directory of ".." would be the parent of the root.
+        assert!(executor.call(&"..").is_err());
+        assert!(root.exists());
+
+        fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn it_copies_output_by_mode() {
+        let written = std::sync::Mutex::new(vec![]);
+        let write = |chunk: &[u8]| {
+            written
+                .lock()
+                .unwrap()
+                .push(String::from_utf8(chunk.to_vec()).unwrap());
+            Ok(())
+        };
+
+        copy_output(OutputMode::Prefixed, "build", &b"one\ntwo"[..], write).unwrap();
+        assert_eq!(
+            vec!["[build] one\n", "[build] two\n"],
+            *written.lock().unwrap()
+        );
+
+        written.lock().unwrap().clear();
+        copy_output(OutputMode::Buffered, "build", &b"one\ntwo\n"[..], write).unwrap();
+        assert_eq!(vec!["one\ntwo\n"], *written.lock().unwrap());
+
+        let executor =
+            CommandExecutor::new("sh", ["-c", "exit 3"]).with_output(OutputMode::Prefixed);
+        assert!(executor.call(&1).is_err());
+    }
+
+    #[cfg(feature = "jobserver")]
+    #[test]
+    fn it_hands_the_jobserver_to_commands() {
+        let jobserver = jobserver::Client::new(2).unwrap();
+        let executor = CommandExecutor::new(
+            "sh",
+            [
+                "-c",
+                "case \"$MAKEFLAGS\" in *jobserver*) ;; *) exit 1 ;; esac",
+            ],
+        )
+        .with_jobserver(jobserver.clone());
+
+        assert!(executor.call(&1).is_ok());
+        assert_eq!(2, jobserver.available().unwrap());
+    }
+
+    #[test]
+    fn it_kills_commands_on_cancellation() {
+        let cancellation = CancellationToken::new();
+        let executor =
+            CommandExecutor::new("sleep", ["{id}"]).with_cancellation(cancellation.clone());
+
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancellation.cancel();
+        });
+
+        let started = std::time::Instant::now();
+        let err = executor.call(&30).unwrap_err();
+        canceller.join().unwrap();
+
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -1,5 +1,198 @@
+use super::context::NodeCtx;
+use super::spawn::Spawner;
+use std::fmt::{self, Display};
+
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
+/// Structural problems of a dependency graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopoError {
+    /// Some nodes depend on each other.
+    Cycle,
+    /// A dependency is not declared as a node.
+    UnknownDependency,
+    /// A node is declared more than once.
+    DuplicateNode,
+}
+
+impl fmt::Display for TopoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopoError::Cycle => write!(f, "Cycle detected."),
+            TopoError::UnknownDependency => write!(f, "Unknown dependency."),
+            TopoError::DuplicateNode => write!(f, "Duplicate node."),
+        }
+    }
+}
+
+impl std::error::Error for TopoError {}
+
+/// `TopoError` naming the nodes involved, see `TopologicalBatchProvider::new_with_diagnostics`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError<T> {
+    /// Nodes depending on each other, a single one when it depends on itself.
+    Cycle(Vec<T>),
+    UnknownDependency {
+        node: T,
+        dependency: T,
+    },
+    DuplicateNode(T),
+}
+
+impl<T> GraphError<T> {
+    pub fn kind(&self) -> TopoError {
+        match self {
+            GraphError::Cycle(_) => TopoError::Cycle,
+            GraphError::UnknownDependency { .. } => TopoError::UnknownDependency,
+            GraphError::DuplicateNode(_) => TopoError::DuplicateNode,
+        }
+    }
+}
+
+impl<T: Display> Display for GraphError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::Cycle(nodes) => {
+                write!(f, "Cycle detected between:")?;
+                for node in nodes {
+                    write!(f, " {}", node)?;
+                }
+                write!(f, ".")
+            }
+            GraphError::UnknownDependency { node, dependency } => {
+                write!(f, "Unknown dependency {} of {}.", dependency, node)
+            }
+            GraphError::DuplicateNode(node) => write!(f, "Duplicate node {}.", node),
+        }
+    }
+}
+
+impl<T: fmt::Debug + Display> std::error::Error for GraphError<T> {}
+
 pub trait CallableByID<T> {
-    fn call(&self, id: T);
+    /// Computes the unit behind the ID. An error marks the node as failed: its dependees are skipped.
+    fn call(&self, id: &T) -> Result<(), Error>;
+
+    /// Same as `call`, with a handle to spawn child tasks onto the workers of the run. The node is completed once its
+    /// children finished, see `Spawner`. Only called by `ThreadPoolRunner`, the other runners call `call`.
+    fn call_with_spawner(&self, id: &T, _spawner: &Spawner) -> Result<(), Error> {
+        self.call(id)
+    }
+
+    /// Same as `call_with_spawner`, with the context of the node: its finished dependencies, their durations and
+    /// whether they were cached. The spawner is `ctx.spawner()`. Only called by `ThreadPoolRunner`, which calls this
+    /// one rather than `call_with_spawner`.
+    fn call_with_ctx(&self, id: &T, ctx: &NodeCtx<'_, T>) -> Result<(), Error> {
+        self.call_with_spawner(id, ctx.spawner())
+    }
+
+    /// Compensates a node completed earlier in the run, once one of its dependees failed. Only called with
+    /// `RunOptions::with_rollback`, does nothing by default.
+    fn on_rollback(&self, _id: &T) {}
+}
+
+/// A domain object of the graph, knowing its own ID and dependencies. Can be derived with `#[derive(TopoNode)]` (feature
+/// `derive`), see `TopologicalBatchProvider::from_nodes`.
+pub trait Node<T> {
+    fn id(&self) -> &T;
+
+    /// The dependencies can be stored in any collection, or computed lazily.
+    fn dependencies<'a>(&'a self) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a;
+}
+
+impl<T, N: Node<T> + ?Sized> Node<T> for &N {
+    fn id(&self) -> &T {
+        (**self).id()
+    }
+
+    fn dependencies<'a>(&'a self) -> impl Iterator<Item = &'a T>
+    where
+        T: 'a,
+    {
+        (**self).dependencies()
+    }
+}
+
+/// Outcome of a run.
+#[derive(Debug)]
+pub struct RunReport<T> {
+    /// Nodes executed successfully.
+    pub completed: Vec<T>,
+    /// Nodes whose execution returned an error.
+    pub failed: Vec<(T, Error)>,
+    /// Nodes never executed, as one of their dependencies failed.
+    pub skipped: Vec<T>,
+    /// Nodes not executed, as their fingerprint was found in the cache. They count as completed for their dependees.
+    pub cached: Vec<T>,
+    /// Nodes not executed, as they were completed outside of the runner before the run (see
+    /// `TopologicalBatchProvider::mark_external_complete`).
+    pub satisfied: Vec<T>,
+    /// Nodes never started, as the run was cancelled.
+    pub cancelled: Vec<T>,
+}
+
+impl<T> RunReport<T> {
+    pub fn new() -> Self {
+        Self {
+            completed: vec![],
+            failed: vec![],
+            skipped: vec![],
+            cached: vec![],
+            satisfied: vec![],
+            cancelled: vec![],
+        }
+    }
+
+    /// True when every node was executed successfully.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty() && self.skipped.is_empty() && self.cancelled.is_empty()
+    }
+
+    /// Appends the content of an other report (eg from an other worker).
+    pub fn merge(&mut self, other: RunReport<T>) {
+        self.completed.extend(other.completed);
+        self.failed.extend(other.failed);
+        self.skipped.extend(other.skipped);
+        self.cached.extend(other.cached);
+        self.satisfied.extend(other.satisfied);
+        self.cancelled.extend(other.cancelled);
+    }
+}
+
+/// Failure summary: the counts, then every failed node with its error and the nodes not executed.
+impl<T: Display> Display for RunReport<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} completed, {} failed, {} skipped, {} cached, {} satisfied, {} cancelled.",
+            self.completed.len(),
+            self.failed.len(),
+            self.skipped.len(),
+            self.cached.len(),
+            self.satisfied.len(),
+            self.cancelled.len()
+        )?;
+
+        for (node, err) in &self.failed {
+            write!(f, "\nFailed {}: {}", node, err)?;
+        }
+        for (label, nodes) in [("Skipped", &self.skipped), ("Cancelled", &self.cancelled)] {
+            if !nodes.is_empty() {
+                write!(f, "\n{}:", label)?;
+                for node in nodes {
+                    write!(f, " {}", node)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T> Default for RunReport<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
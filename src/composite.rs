@@ -0,0 +1,114 @@
+//! Composition of independently owned graph fragments. Each subgraph is mounted under a prefix, its IDs becoming
+//! `prefix::id`, so fragments can't clash; cross-subgraph edges are then added with the full names.
+
+use super::common::*;
+use super::topological_batch_provider::*;
+use std::{collections::HashMap, fmt::Display};
+
+/// Separator between the prefix and the ID of a mounted node.
+pub const SEPARATOR: &str = "::";
+
+/// Graph assembled from namespaced subgraphs, flattened into a single provider with `String` IDs.
+#[derive(Debug, Clone, Default)]
+pub struct CompositeGraph {
+    nodes: HashMap<String, Vec<String>>,
+}
+
+impl CompositeGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds every node of `subgraph` as `prefix::id`, dependencies resolved within the subgraph. Composites can be
+    /// nested by mounting the result of `into_nodes`. Fails with `TopoError::DuplicateNode` if a resulting name
+    /// already exists, leaving the graph unchanged.
+    pub fn mount<T: Display>(
+        &mut self,
+        prefix: &str,
+        subgraph: HashMap<T, Vec<T>>,
+    ) -> Result<&mut Self, TopoError> {
+        let name = |id: &T| format!("{}{}{}", prefix, SEPARATOR, id);
+
+        let mounted = subgraph
+            .iter()
+            .map(|(id, dependencies)| (name(id), dependencies.iter().map(name).collect()))
+            .collect::<Vec<(String, Vec<String>)>>();
+
+        if mounted.iter().any(|(id, _)| self.nodes.contains_key(id)) {
+            return Err(TopoError::DuplicateNode);
+        }
+
+        self.nodes.extend(mounted);
+        Ok(self)
+    }
+
+    /// Makes `dependent` depend on `dependency`, both given with their full names (eg `frontend::build` on
+    /// `api::codegen`). Fails with `TopoError::UnknownDependency` if either node is not mounted.
+    pub fn add_edge(&mut self, dependent: &str, dependency: &str) -> Result<&mut Self, TopoError> {
+        if !self.nodes.contains_key(dependency) {
+            return Err(TopoError::UnknownDependency);
+        }
+
+        let dependencies = self
+            .nodes
+            .get_mut(dependent)
+            .ok_or(TopoError::UnknownDependency)?;
+        if !dependencies.iter().any(|existing| existing == dependency) {
+            dependencies.push(dependency.to_string());
+        }
+
+        Ok(self)
+    }
+
+    /// The flattened graph, in the input format of the provider.
+    pub fn into_nodes(self) -> HashMap<String, Vec<String>> {
+        self.nodes
+    }
+
+    /// Flattens into a provider, failing if the cross-subgraph edges introduced a cycle.
+    pub fn into_provider(self) -> Result<TopologicalBatchProvider<String>, Error> {
+        TopologicalBatchProvider::new(self.nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag;
+
+    #[test]
+    fn it_flattens_namespaced_subgraphs() {
+        let mut graph = CompositeGraph::new();
+        graph
+            .mount("api", dag! { codegen => []; build => [codegen] })
+            .unwrap()
+            .mount("frontend", dag! { build => [] })
+            .unwrap()
+            .add_edge("frontend::build", "api::codegen")
+            .unwrap();
+
+        assert!(graph.mount("api", dag! { build => [] }).is_err());
+        assert!(graph.add_edge("frontend::build", "api::missing").is_err());
+
+        let mut provider = graph.into_provider().unwrap();
+
+        assert_eq!(Some("api::codegen".to_string()), provider.pop());
+        assert_eq!(None, provider.pop());
+        provider.complete("api::codegen".to_string()).unwrap();
+
+        let mut next = vec![provider.pop().unwrap(), provider.pop().unwrap()];
+        next.sort();
+        assert_eq!(vec!["api::build", "frontend::build"], next);
+    }
+
+    #[test]
+    fn it_rejects_cycles_across_subgraphs() {
+        let mut graph = CompositeGraph::new();
+        graph.mount("a", dag! { x => [] }).unwrap();
+        graph.mount("b", dag! { y => [] }).unwrap();
+        graph.add_edge("a::x", "b::y").unwrap();
+        graph.add_edge("b::y", "a::x").unwrap();
+
+        assert!(graph.into_provider().is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//! What the runner knows about a node when executing it, passed to `CallableByID::call_with_ctx`: its dependencies,
+//! how long they took and whether they were found in the cache, so nodes can adapt (eg skip verifying inputs that
+//! were cached, as they were verified when produced).
+//!
+//! ```ignore
+//! impl CallableByID<Job> for JobExecutor {
+//!     fn call_with_ctx(&self, id: &Job, ctx: &NodeCtx<'_, Job>) -> Result<(), Error> {
+//!         if !ctx.all_cached() {
+//!             self.verify_inputs(id)?;
+//!         }
+//!         self.build(id)
+//!     }
+//! }
+//! ```
+
+use super::spawn::*;
+use std::{cell::OnceCell, time::Duration};
+
+/// A dependency finished in the current run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyInfo<T> {
+    pub id: T,
+    /// Time spent in the executor, zero for cached nodes.
+    pub duration: Duration,
+    /// Not executed, as its fingerprint was found in the cache.
+    pub cached: bool,
+}
+
+/// Context of the node being executed, valid for the call it was passed to.
+pub struct NodeCtx<'a, T> {
+    /// Looked up from the run on first use, so nodes not asking don't pay for it.
+    dependencies: OnceCell<Vec<DependencyInfo<T>>>,
+    lookup: &'a dyn Fn() -> Vec<DependencyInfo<T>>,
+    spawner: &'a Spawner,
+}
+
+impl<'a, T> NodeCtx<'a, T> {
+    pub(crate) fn new(
+        lookup: &'a dyn Fn() -> Vec<DependencyInfo<T>>,
+        spawner: &'a Spawner,
+    ) -> Self {
+        Self {
+            dependencies: OnceCell::new(),
+            lookup,
+            spawner,
+        }
+    }
+
+    /// The dependencies of the node finished in this run. Dependencies unknown to the provider upfront (see
+    /// `BatchProvider::graph`), or completed outside of the run, are missing.
+    pub fn dependencies(&self) -> &[DependencyInfo<T>] {
+        self.dependencies.get_or_init(self.lookup)
+    }
+
+    pub fn any_cached(&self) -> bool {
+        self.dependencies()
+            .iter()
+            .any(|dependency| dependency.cached)
+    }
+
+    /// True for nodes without known dependencies too.
+    pub fn all_cached(&self) -> bool {
+        self.dependencies()
+            .iter()
+            .all(|dependency| dependency.cached)
+    }
+
+    /// Handle to spawn child tasks, as passed to `CallableByID::call_with_spawner`.
+    pub fn spawner(&self) -> &'a Spawner {
+        self.spawner
+    }
+}
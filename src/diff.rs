@@ -0,0 +1,127 @@
+//! Comparison of two versions of a dependency map, for incremental pipelines where the graph itself evolves between
+//! invocations.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+/// Difference between an old and a new dependency map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeSet<T> {
+    /// Nodes only present in the new map.
+    pub added: Vec<T>,
+    /// Nodes only present in the old map.
+    pub removed: Vec<T>,
+    /// Nodes present in both, with a different set of dependencies.
+    pub changed: Vec<T>,
+    /// Nodes of the new map that must run again: the added and changed nodes and all of their transitive dependees.
+    pub rerun: Vec<T>,
+}
+
+impl<T> ChangeSet<T> {
+    /// True when the two maps describe the same graph.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compares two dependency maps (same shape as `TopologicalBatchProvider::new` expects). The order of dependencies
+/// and duplicates do not count as change.
+pub fn diff<T: Hash + PartialEq + Eq + Clone>(
+    old_graph: &HashMap<T, Vec<T>>,
+    new_graph: &HashMap<T, Vec<T>>,
+) -> ChangeSet<T> {
+    let mut added = vec![];
+    let mut changed = vec![];
+
+    for (node, dependencies) in new_graph {
+        match old_graph.get(node) {
+            None => added.push(node.clone()),
+            Some(old_dependencies) => {
+                let old_dependencies = old_dependencies.iter().collect::<HashSet<_>>();
+                if old_dependencies != dependencies.iter().collect::<HashSet<_>>() {
+                    changed.push(node.clone());
+                }
+            }
+        }
+    }
+
+    let removed = old_graph
+        .keys()
+        .filter(|node| !new_graph.contains_key(*node))
+        .cloned()
+        .collect();
+
+    let mut dependents: HashMap<&T, Vec<&T>> = HashMap::new();
+    for (node, dependencies) in new_graph {
+        for dependency in dependencies {
+            dependents.entry(dependency).or_default().push(node);
+        }
+    }
+
+    let mut rerun = vec![];
+    let mut seen = HashSet::new();
+    let mut stack = added.iter().chain(changed.iter()).collect::<Vec<_>>();
+    while let Some(node) = stack.pop() {
+        if !seen.insert(node) {
+            continue;
+        }
+
+        rerun.push(node.clone());
+        if let Some(node_dependents) = dependents.get(node) {
+            stack.extend(node_dependents.iter().copied());
+        }
+    }
+
+    ChangeSet {
+        added,
+        removed,
+        changed,
+        rerun,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_the_rerun_set() {
+        let mut old_graph: HashMap<usize, Vec<usize>> = HashMap::new();
+        old_graph.insert(1, vec![]);
+        old_graph.insert(2, vec![1]);
+        old_graph.insert(3, vec![2]);
+        old_graph.insert(4, vec![]);
+        old_graph.insert(5, vec![]);
+
+        let mut new_graph = old_graph.clone();
+        new_graph.remove(&5);
+        new_graph.insert(2, vec![1, 4]);
+        new_graph.insert(6, vec![4]);
+        new_graph.insert(1, vec![]);
+
+        let change_set = diff(&old_graph, &new_graph);
+
+        let mut rerun = change_set.rerun.clone();
+        rerun.sort();
+
+        assert_eq!(vec![6], change_set.added);
+        assert_eq!(vec![5], change_set.removed);
+        assert_eq!(vec![2], change_set.changed);
+        assert_eq!(vec![2, 3, 6], rerun);
+    }
+
+    #[test]
+    fn it_ignores_dependency_order() {
+        let mut old_graph: HashMap<usize, Vec<usize>> = HashMap::new();
+        old_graph.insert(1, vec![]);
+        old_graph.insert(2, vec![]);
+        old_graph.insert(3, vec![1, 2]);
+
+        let mut new_graph = old_graph.clone();
+        new_graph.insert(3, vec![2, 1]);
+
+        assert!(diff(&old_graph, &new_graph).is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+//! Data attached to edges, such as the name of the artifact flowing along them or whether the dependency is hard or
+//! soft. Dataflow style executors can look up which input corresponds to which dependency. Build with
+//! `TopologicalBatchProvider::from_edge_map`.
+
+use super::common::*;
+use super::topological_batch_provider::*;
+use std::{collections::HashMap, hash::Hash};
+
+/// Metadata of every edge. Share it with the executor (eg in an `Arc`) to read the inputs of the node being executed.
+#[derive(Debug, Clone)]
+pub struct EdgeData<T, E> {
+    inputs: HashMap<T, Vec<(T, E)>>,
+    outputs: HashMap<T, Vec<T>>,
+}
+
+impl<T: Hash + Eq + Clone, E> EdgeData<T, E> {
+    /// Metadata of the edge from `dependency` to `dependent`.
+    pub fn get(&self, dependent: &T, dependency: &T) -> Option<&E> {
+        self.inputs
+            .get(dependent)?
+            .iter()
+            .find(|(id, _)| id == dependency)
+            .map(|(_, data)| data)
+    }
+
+    /// Dependencies of `dependent`, with the metadata of their edges.
+    pub fn inputs<'a>(&'a self, dependent: &T) -> impl Iterator<Item = (&'a T, &'a E)> {
+        self.inputs
+            .get(dependent)
+            .into_iter()
+            .flatten()
+            .map(|(id, data)| (id, data))
+    }
+
+    /// Dependees of `dependency`, with the metadata of their edges.
+    pub fn outputs<'a>(&'a self, dependency: &'a T) -> impl Iterator<Item = (&'a T, &'a E)> {
+        self.outputs
+            .get(dependency)
+            .into_iter()
+            .flatten()
+            .filter_map(move |dependent| Some((dependent, self.get(dependent, dependency)?)))
+    }
+
+    /// Every edge as `(dependent, dependency, metadata)`, in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (&T, &T, &E)> {
+        self.inputs.iter().flat_map(|(dependent, inputs)| {
+            inputs
+                .iter()
+                .map(move |(dependency, data)| (dependent, dependency, data))
+        })
+    }
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> TopologicalBatchProvider<T> {
+    /// Same as `new`, for nodes given as `id => [(dependency, edge metadata)]`. The metadata is returned separately.
+    pub fn from_edge_map<E>(
+        nodes: HashMap<T, Vec<(T, E)>>,
+    ) -> Result<(Self, EdgeData<T, E>), Error> {
+        let provider = Self::try_from_iter(nodes.iter().map(|(id, dependencies)| {
+            (
+                id.clone(),
+                dependencies
+                    .iter()
+                    .map(|(dependency, _)| dependency.clone())
+                    .collect::<Vec<_>>(),
+            )
+        }))?;
+
+        let mut outputs: HashMap<T, Vec<T>> = HashMap::new();
+        for (dependent, dependencies) in &nodes {
+            for (dependency, _) in dependencies {
+                outputs
+                    .entry(dependency.clone())
+                    .or_default()
+                    .push(dependent.clone());
+            }
+        }
+
+        Ok((
+            provider,
+            EdgeData {
+                inputs: nodes,
+                outputs,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Edge {
+        Hard(&'static str),
+        Soft,
+    }
+
+    #[test]
+    fn it_keeps_edge_metadata() {
+        let mut nodes = HashMap::new();
+        nodes.insert("compile", vec![]);
+        nodes.insert("docs", vec![]);
+        nodes.insert(
+            "link",
+            vec![("compile", Edge::Hard("main.o")), ("docs", Edge::Soft)],
+        );
+
+        let (mut provider, edges) = TopologicalBatchProvider::from_edge_map(nodes).unwrap();
+
+        assert_eq!(Some(&Edge::Hard("main.o")), edges.get(&"link", &"compile"));
+        assert_eq!(None, edges.get(&"compile", &"link"));
+        assert_eq!(
+            vec![(&"link", &Edge::Soft)],
+            edges.outputs(&"docs").collect::<Vec<_>>()
+        );
+        assert_eq!(2, edges.inputs(&"link").count());
+        assert_eq!(2, edges.iter().count());
+
+        let mut roots = vec![provider.pop().unwrap(), provider.pop().unwrap()];
+        roots.sort();
+        assert_eq!(vec!["compile", "docs"], roots);
+        assert_eq!(None, provider.pop());
+    }
+}
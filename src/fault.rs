@@ -0,0 +1,202 @@
+//! Fault injection for chaos testing of failure handling. Enabled for a run with `RunOptions::with_fault_injector`,
+//! the injector wraps the executor and fails, delays or panics chosen (or randomly picked) nodes.
+
+use super::clock::*;
+use super::common::*;
+use super::context::*;
+use super::rng::*;
+use super::spawn::*;
+use std::{
+    collections::{HashMap, HashSet},
+    error, fmt,
+    hash::Hash,
+    sync::Arc,
+    time::Duration,
+};
+
+/// Error returned for injected failures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InjectedFault;
+
+impl fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Injected fault.")
+    }
+}
+
+impl error::Error for InjectedFault {}
+
+/// Faults to inject. Random decisions are derived from the seed and the node, so they don't depend on the order the
+/// workers take the nodes in: the same seed fails the same nodes on every run.
+///
+/// Chosen nodes are matched by hash, so they must be given with the ID type of the graph (`&1usize`, not `&1`).
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    seed: u64,
+    failure_rate: f64,
+    max_random_delay: Duration,
+    failing: HashSet<u64>,
+    panicking: HashSet<u64>,
+    delays: HashMap<u64, Duration>,
+}
+
+impl FaultInjector {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            ..Self::default()
+        }
+    }
+
+    /// Probability in `[0, 1]` of failing any node with `InjectedFault` instead of executing it.
+    pub fn with_failure_rate(mut self, failure_rate: f64) -> Self {
+        self.failure_rate = failure_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sleeps a random duration up to `max_delay` before executing each node.
+    pub fn with_random_delay(mut self, max_delay: Duration) -> Self {
+        self.max_random_delay = max_delay;
+        self
+    }
+
+    /// Always fails `node` with `InjectedFault`.
+    pub fn with_failing_node<T: Hash>(mut self, node: &T) -> Self {
+        self.failing.insert(stable_hash(node));
+        self
+    }
+
+    /// Panics instead of executing `node`, like a crashing executor would.
+    pub fn with_panicking_node<T: Hash>(mut self, node: &T) -> Self {
+        self.panicking.insert(stable_hash(node));
+        self
+    }
+
+    /// Sleeps `delay` on the run's clock (see `RunOptions::with_clock`) before executing `node`, in addition to any
+    /// random delay.
+    pub fn with_node_delay<T: Hash>(mut self, node: &T, delay: Duration) -> Self {
+        self.delays.insert(stable_hash(node), delay);
+        self
+    }
+
+    /// Applies the faults for `node`, delays being slept on `clock`: `Err` if it should fail without being executed.
+    pub(crate) fn inject<T: Hash>(&self, node: &T, clock: &dyn Clock) -> Result<(), Error> {
+        let hash = stable_hash(node);
+        let mut rng = Rng::new(self.seed ^ hash);
+
+        let delay = self.delays.get(&hash).copied().unwrap_or_default()
+            + self.max_random_delay.mul_f64(rng.next_f64());
+        if !delay.is_zero() {
+            clock.sleep(delay);
+        }
+
+        if self.panicking.contains(&hash) {
+            panic!("Injected panic.");
+        }
+
+        if self.failing.contains(&hash) || rng.next_f64() < self.failure_rate {
+            return Err(InjectedFault.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Executor applying the injector's faults before delegating.
+pub(crate) struct FaultyExecutor<T> {
+    pub(crate) inner: Arc<dyn CallableByID<T> + Send + Sync>,
+    pub(crate) injector: FaultInjector,
+    pub(crate) clock: SharedClock,
+}
+
+impl<T: Hash> CallableByID<T> for FaultyExecutor<T> {
+    fn call(&self, id: &T) -> Result<(), Error> {
+        self.injector.inject(id, &*self.clock)?;
+        self.inner.call(id)
+    }
+
+    fn call_with_spawner(&self, id: &T, spawner: &Spawner) -> Result<(), Error> {
+        self.injector.inject(id, &*self.clock)?;
+        self.inner.call_with_spawner(id, spawner)
+    }
+
+    fn call_with_ctx(&self, id: &T, ctx: &NodeCtx<'_, T>) -> Result<(), Error> {
+        self.injector.inject(id, &*self.clock)?;
+        self.inner.call_with_ctx(id, ctx)
+    }
+
+    fn on_rollback(&self, id: &T) {
+        self.inner.on_rollback(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_is_reproducible() {
+        let injector = FaultInjector::new(42).with_failure_rate(0.5);
+        let failing = (0..100)
+            .filter(|node| injector.inject(node, &SystemClock::new()).is_err())
+            .collect::<Vec<_>>();
+
+        assert!(!failing.is_empty() && failing.len() < 100);
+        assert_eq!(
+            failing,
+            (0..100)
+                .filter(|node| FaultInjector::new(42)
+                    .with_failure_rate(0.5)
+                    .inject(node, &SystemClock::new())
+                    .is_err())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn it_fails_chosen_nodes() {
+        let injector = FaultInjector::new(0).with_failing_node(&"b");
+
+        assert!(injector.inject(&"a", &SystemClock::new()).is_ok());
+        assert!(injector
+            .inject(&"b", &SystemClock::new())
+            .unwrap_err()
+            .downcast_ref::<InjectedFault>()
+            .is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "Injected panic.")]
+    fn it_panics_on_chosen_nodes() {
+        FaultInjector::new(0)
+            .with_panicking_node(&1)
+            .inject(&1, &SystemClock::new())
+            .unwrap();
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingClock {
+        slept: std::sync::Mutex<Vec<Duration>>,
+    }
+
+    impl Clock for RecordingClock {
+        fn now(&self) -> Duration {
+            Duration::ZERO
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.slept.lock().unwrap().push(duration);
+        }
+    }
+
+    #[test]
+    fn it_delays_on_the_clock() {
+        let clock = RecordingClock::default();
+        FaultInjector::new(0)
+            .with_node_delay(&1, Duration::from_secs(60))
+            .inject(&1, &clock)
+            .unwrap();
+
+        assert_eq!(vec![Duration::from_secs(60)], *clock.slept.lock().unwrap());
+    }
+}
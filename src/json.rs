@@ -0,0 +1,207 @@
+//! Minimal JSON helpers, to keep the crate free of dependencies. Reading is limited to flat objects, which is all the
+//! crate writes.
+
+use std::collections::HashMap;
+
+/// Scalar JSON value.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl Value {
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(value) if *value >= 0.0 && value.fract() == 0.0 => Some(*value as u64),
+            _ => None,
+        }
+    }
+}
+
+/// JSON string literal (with the quotes) of `value`.
+pub(crate) fn string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+/// Parses an object of scalar values, such as `{"a":1,"b":"x"}`.
+pub(crate) fn parse_object(input: &str) -> Result<HashMap<String, Value>, String> {
+    let mut parser = Parser {
+        chars: input.chars().peekable(),
+    };
+    let mut object = HashMap::new();
+
+    parser.expect('{')?;
+    if parser.peek() == Some('}') {
+        parser.chars.next();
+    } else {
+        loop {
+            let key = parser.string()?;
+            parser.expect(':')?;
+            let value = parser.value()?;
+            object.insert(key, value);
+
+            match parser.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                other => return Err(format!("Expected ',' or '}}', found {:?}.", other)),
+            }
+        }
+    }
+
+    match parser.next() {
+        None => Ok(object),
+        Some(c) => Err(format!("Unexpected trailing {:?}.", c)),
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.next()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            other => Err(format!("Expected {:?}, found {:?}.", expected, other)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !(c.is_ascii_digit() || "+-.eE".contains(c)) {
+                        break;
+                    }
+                    number.push(c);
+                    self.chars.next();
+                }
+                number
+                    .parse()
+                    .map(Value::Number)
+                    .map_err(|_| format!("Invalid number {:?}.", number))
+            }
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !c.is_ascii_alphabetic() {
+                        break;
+                    }
+                    word.push(c);
+                    self.chars.next();
+                }
+                match word.as_str() {
+                    "null" => Ok(Value::Null),
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => Err(format!("Unexpected value {:?}.", word)),
+                }
+            }
+            None => Err("Unexpected end of input.".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(out),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let code = (0..4).filter_map(|_| self.chars.next()).collect::<String>();
+                        let c = u32::from_str_radix(&code, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("Invalid escape \\u{}.", code))?;
+                        out.push(c);
+                    }
+                    Some(c) => out.push(c),
+                    None => return Err("Unterminated string.".to_string()),
+                },
+                Some(c) => out.push(c),
+                None => return Err("Unterminated string.".to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_escapes_strings() {
+        assert_eq!(r#""a\"b\\c\nd\u0001""#, string("a\"b\\c\nd\u{1}"));
+    }
+
+    #[test]
+    fn it_parses_what_it_writes() {
+        let raw = "a\"b\\c\nd\u{1}é";
+        let object = parse_object(&format!(
+            r#"{{"s":{}, "n":12, "f":-1.5e1, "t":true, "z":null}}"#,
+            string(raw)
+        ))
+        .unwrap();
+
+        assert_eq!(Some(raw), object["s"].as_str());
+        assert_eq!(Some(12), object["n"].as_u64());
+        assert_eq!(Value::Number(-15.0), object["f"]);
+        assert_eq!(Value::Bool(true), object["t"]);
+        assert_eq!(Value::Null, object["z"]);
+
+        assert!(parse_object("{}").unwrap().is_empty());
+        assert!(parse_object(r#"{"a":1"#).is_err());
+        assert!(parse_object(r#"{"a":1} x"#).is_err());
+    }
+}
@@ -16,8 +16,10 @@
 //!
 //! /// The implementation of the execution. ID represents the link to the topological structure.
 //! impl CallableByID<usize> for ExecutorExample {
-//!     fn call(&self, id: usize) {
+//!     fn call(&self, id: &usize) -> Result<(), Error> {
 //!         /// Code to execute parallel - for an ID that came after all of its dependencies.
+//!         /// An error marks the node as failed, its dependees are then skipped.
+//!         Ok(())
 //!     }
 //! }
 //!
@@ -30,16 +32,154 @@
 //! let topological_batch_provider = TopologicalBatchProvider::new(dependency_graph.clone())?;
 //! let runner = ThreadPoolRunner::new(8);
 //! let executor = Arc::new(ExecutorExample {});
-//! runner.run(topological_batch_provider, executor);
+//! let report = runner.run(topological_batch_provider, executor);
 //! ```
 //!
 //! The topological ordering is defined with IDs, that act as a pointer to computation units. An ID should be
 //! as light as possible (eg `usize`) to be efficiently worked with.
 
+extern crate self as topological_batch;
+
 mod common;
+mod json;
+mod macros;
+mod rng;
+mod sync;
+#[cfg(test)]
+mod test_support;
+
+pub use common::*;
+
+/// Derives `Node` from the fields marked `#[topo(id)]` and `#[topo(deps)]`.
+#[cfg(feature = "derive")]
+pub use topological_batch_derive::TopoNode;
 
 /// Thread runner for the topological graph.
 pub mod thread_pool_runner;
 
+/// Child tasks spawned by executors onto the runner.
+pub mod spawn;
+
+/// Pluggable source of time, real or virtual.
+pub mod clock;
+
+/// Context of a node passed to executors.
+pub mod context;
+
+/// Run errors naming their node, with the executor error as source.
+pub mod run_error;
+
+/// Runner as a long-lived service fed over a channel.
+pub mod service;
+
+/// Pluggable policies for the provider's `pop`.
+pub mod scheduler;
+
+/// Named countable resources claimed by nodes.
+pub mod slots;
+
+/// Ahead-of-time scheduling from cost estimates.
+pub mod planning;
+
+/// Runtime agnostic async runner for the topological graph.
+pub mod async_runner;
+
+/// Cooperative cancellation of runs.
+pub mod cancel;
+
+/// Hooks into the progress of a run.
+pub mod observer;
+
+/// Node durations of previous runs, as a cost model for scheduling.
+pub mod profile;
+
+/// Chrome trace event export of runs.
+pub mod chrome_trace;
+
+/// OpenTelemetry spans of runs.
+#[cfg(feature = "otel")]
+pub mod otel;
+
+/// Run metrics through the `metrics` facade.
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+/// JSON lines run log and its replay.
+pub mod run_log;
+
+/// Test support: deterministic runner and random graphs.
+pub mod testing;
+
+/// Fault injection for chaos testing.
+pub mod fault;
+
+/// Namespaced composition of subgraphs.
+pub mod composite;
+
+/// Nodes carrying a payload for the executor.
+pub mod payload;
+
+/// Metadata attached to edges.
+pub mod edges;
+
+/// Speculative execution over soft dependencies, with rollback.
+pub mod speculation;
+
+/// Channel front-end for external job systems.
+pub mod channel;
+
+/// Node dispatch through message queues, for remote workers.
+pub mod queue;
+
+/// Middleware layers composed around executors.
+pub mod middleware;
+
+/// Executor running external commands.
+pub mod command;
+
+/// Executor POSTing nodes to a webhook.
+#[cfg(feature = "webhook")]
+pub mod webhook;
+
+/// Provider state persisted to SQLite.
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// Multi-process coordination through Redis.
+#[cfg(feature = "redis")]
+pub mod redis;
+
+/// Live terminal dashboard of runs.
+#[cfg(feature = "tui")]
+pub mod tui;
+
+/// HTTP status page of runs.
+#[cfg(feature = "status_page")]
+pub mod status_page;
+
+/// Fingerprint based skipping of already computed nodes.
+pub mod cache;
+
 /// Topological batch provider.
 pub mod topological_batch_provider;
+
+/// Validated graph reusable across runs.
+pub mod plan;
+
+/// Flat topological ordering and batches of dependency maps.
+pub mod ordering;
+
+/// Validation of dependency maps, reporting all problems at once.
+pub mod validation;
+
+/// Comparison of dependency maps, computing what must run again.
+pub mod diff;
+
+/// Canonical, order-independent hash of dependency graphs.
+pub mod canonical;
+
+/// Renderings of the dependency graph (Mermaid, ASCII execution plan).
+pub mod render;
+
+/// Shape statistics of the dependency graph.
+pub mod stats;
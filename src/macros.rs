@@ -0,0 +1,64 @@
+//! Declarative graph construction.
+
+/// Builds the provider's input map, `HashMap<&'static str, Vec<&'static str>>`, from `node => [dependencies]` entries.
+/// Node names are identifiers, used as strings. Duplicate nodes and unknown dependencies fail to compile.
+///
+/// ```
+/// use topological_batch::{dag, topological_batch_provider::TopologicalBatchProvider};
+///
+/// let nodes = dag! {
+///     a => [];
+///     b => [a];
+///     c => [a, b];
+/// };
+///
+/// assert_eq!(vec!["a", "b"], nodes["c"]);
+/// assert!(TopologicalBatchProvider::new(nodes).is_ok());
+/// ```
+///
+/// ```compile_fail
+/// use topological_batch::{dag, topological_batch_provider::TopologicalBatchProvider};
+///
+/// let nodes = dag! { a => []; a => [] };
+/// ```
+///
+/// ```compile_fail
+/// use topological_batch::{dag, topological_batch_provider::TopologicalBatchProvider};
+///
+/// let nodes = dag! { a => [b] };
+/// ```
+#[macro_export]
+macro_rules! dag {
+    ($($node:ident => [$($dependency:ident),* $(,)?]);* $(;)?) => {{
+        #[allow(non_camel_case_types, dead_code)]
+        enum DagNodes {
+            $($node,)*
+        }
+        $($(let _ = DagNodes::$dependency;)*)*
+
+        #[allow(unused_mut)]
+        let mut nodes: ::std::collections::HashMap<&'static str, ::std::vec::Vec<&'static str>> =
+            ::std::collections::HashMap::new();
+        $(nodes.insert(::std::stringify!($node), ::std::vec![$(::std::stringify!($dependency)),*]);)*
+        nodes
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn it_builds_graphs() {
+        let nodes = dag! {
+            a => [];
+            b => [a,];
+            c => [a, b]
+        };
+
+        assert_eq!(3, nodes.len());
+        assert!(nodes["a"].is_empty());
+        assert_eq!(vec!["a"], nodes["b"]);
+        assert_eq!(vec!["a", "b"], nodes["c"]);
+
+        assert!(dag! {}.is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+//! Run metrics reported through the `metrics` facade, so any of its exporters (Prometheus included) can expose them.
+//! Available with the `metrics` feature.
+//!
+//! Counters: `topological_batch_nodes_completed_total`, `topological_batch_nodes_failed_total`,
+//! `topological_batch_nodes_skipped_total`, `topological_batch_nodes_cached_total`.
+//!
+//! Histograms, in seconds: `topological_batch_node_queue_wait_seconds` (from the node becoming ready to a worker taking
+//! it) and `topological_batch_node_duration_seconds` (execution).
+
+use super::clock::*;
+use super::observer::*;
+use super::topological_batch_provider::*;
+use std::{collections::HashMap, hash::Hash, sync::Mutex, time::Duration};
+
+#[derive(Debug)]
+struct ReadyTracker<T> {
+    dependents: HashMap<T, Vec<T>>,
+    remaining_dependencies: HashMap<T, usize>,
+    /// In the time of the observer's clock.
+    ready_at: HashMap<T, Duration>,
+}
+
+/// Observer reporting the run into the globally installed `metrics` recorder. Nodes without dependencies are ready
+/// from the creation of the observer.
+#[derive(Debug)]
+pub struct MetricsObserver<T> {
+    tracker: Mutex<ReadyTracker<T>>,
+    clock: SharedClock,
+}
+
+impl<T: Hash + Eq + Clone> MetricsObserver<T> {
+    /// `provider` is only read for the dependencies, create the observer right before running it.
+    pub fn new(provider: &TopologicalBatchProvider<T>) -> Self {
+        Self::new_with_clock(provider, SystemClock::shared())
+    }
+
+    /// Same as `new`, measuring the queue wait with `clock`.
+    pub fn new_with_clock(provider: &TopologicalBatchProvider<T>, clock: SharedClock) -> Self {
+        let now = clock.now();
+        let dependencies = provider.dependencies_by_id();
+
+        let mut dependents: HashMap<T, Vec<T>> = HashMap::with_capacity(dependencies.len());
+        for (id, node_dependencies) in &dependencies {
+            for dependency in node_dependencies {
+                dependents
+                    .entry(dependency.clone())
+                    .or_default()
+                    .push(id.clone());
+            }
+        }
+
+        let ready_at = dependencies
+            .iter()
+            .filter(|(_, node_dependencies)| node_dependencies.is_empty())
+            .map(|(id, _)| (id.clone(), now))
+            .collect();
+
+        let remaining_dependencies = dependencies
+            .into_iter()
+            .map(|(id, node_dependencies)| (id, node_dependencies.len()))
+            .collect();
+
+        Self {
+            tracker: Mutex::new(ReadyTracker {
+                dependents,
+                remaining_dependencies,
+                ready_at,
+            }),
+            clock,
+        }
+    }
+
+    fn release_dependents(&self, id: &T) {
+        let now = self.clock.now();
+        let mut tracker = self.tracker.lock().unwrap();
+        let ReadyTracker {
+            dependents,
+            remaining_dependencies,
+            ready_at,
+        } = &mut *tracker;
+
+        for dependent in dependents.get(id).into_iter().flatten() {
+            if let Some(remaining) = remaining_dependencies.get_mut(dependent) {
+                *remaining -= 1;
+                if *remaining == 0 {
+                    ready_at.insert(dependent.clone(), now);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone> RunObserver<T> for MetricsObserver<T> {
+    fn on_node_start(&self, _worker: usize, id: &T) {
+        if let Some(ready_at) = self.tracker.lock().unwrap().ready_at.remove(id) {
+            ::metrics::histogram!("topological_batch_node_queue_wait_seconds")
+                .record(self.clock.now().saturating_sub(ready_at).as_secs_f64());
+        }
+    }
+
+    fn on_node_finish(&self, _worker: usize, id: &T, outcome: NodeOutcome<'_>, duration: Duration) {
+        match outcome {
+            NodeOutcome::Completed => {
+                ::metrics::counter!("topological_batch_nodes_completed_total").increment(1);
+                ::metrics::histogram!("topological_batch_node_duration_seconds")
+                    .record(duration.as_secs_f64());
+                self.release_dependents(id);
+            }
+            NodeOutcome::Failed(_) => {
+                ::metrics::counter!("topological_batch_nodes_failed_total").increment(1);
+                ::metrics::histogram!("topological_batch_node_duration_seconds")
+                    .record(duration.as_secs_f64());
+            }
+            NodeOutcome::Cached => {
+                ::metrics::counter!("topological_batch_nodes_cached_total").increment(1);
+                self.release_dependents(id);
+            }
+        }
+    }
+
+    fn on_node_skipped(&self, _id: &T) {
+        ::metrics::counter!("topological_batch_nodes_skipped_total").increment(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_tracks_ready_nodes() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1, 2]);
+
+        let observer = MetricsObserver::new(&TopologicalBatchProvider::new(nodes).unwrap());
+        let ready = |observer: &MetricsObserver<usize>| {
+            let mut ready = observer
+                .tracker
+                .lock()
+                .unwrap()
+                .ready_at
+                .keys()
+                .copied()
+                .collect::<Vec<_>>();
+            ready.sort_unstable();
+            ready
+        };
+
+        assert_eq!(vec![1], ready(&observer));
+
+        observer.on_node_start(0, &1);
+        assert!(ready(&observer).is_empty());
+
+        observer.on_node_finish(0, &1, NodeOutcome::Completed, Duration::ZERO);
+        assert_eq!(vec![2], ready(&observer));
+
+        observer.on_node_start(0, &2);
+        observer.on_node_finish(0, &2, NodeOutcome::Cached, Duration::ZERO);
+        assert_eq!(vec![3], ready(&observer));
+    }
+}
@@ -0,0 +1,344 @@
+//! Layers wrapping executors, so cross-cutting concerns (logging, timing, retries, rate limiting, ...) are composed
+//! around any `CallableByID` instead of being baked into each of them, as tower does for services.
+//!
+//! ```ignore
+//! let executor = LayerStack::new()
+//!     .with_layer(InspectLayer::new(Arc::new(|node: &Job, result: &Result<(), Error>, duration| {
+//!         eprintln!("{} finished in {:?}: {:?}", node, duration, result);
+//!     })))
+//!     .with_layer(RetryLayer::new(3, Duration::from_millis(100)))
+//!     .with_layer(RateLimitLayer::new(Duration::from_millis(10)))
+//!     .wrap(Arc::new(JobExecutor::new()));
+//! ```
+
+use super::clock::*;
+use super::common::*;
+use super::context::*;
+use super::spawn::*;
+use super::sync::*;
+use std::{sync::Arc, time::Duration};
+
+/// An executor as passed to the runners.
+pub type SharedExecutor<T> = Arc<dyn CallableByID<T> + Send + Sync>;
+
+/// Wraps an executor into an other one. Closures taking and returning a `SharedExecutor` are layers too.
+pub trait Layer<T> {
+    fn layer(&self, inner: SharedExecutor<T>) -> SharedExecutor<T>;
+}
+
+impl<T, F: Fn(SharedExecutor<T>) -> SharedExecutor<T>> Layer<T> for F {
+    fn layer(&self, inner: SharedExecutor<T>) -> SharedExecutor<T> {
+        self(inner)
+    }
+}
+
+/// Layers applied in the order they are added: the first one is the outermost, seeing every call first.
+pub struct LayerStack<T> {
+    layers: Vec<Box<dyn Layer<T>>>,
+}
+
+impl<T> LayerStack<T> {
+    pub fn new() -> Self {
+        Self { layers: vec![] }
+    }
+
+    pub fn with_layer(mut self, layer: impl Layer<T> + 'static) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    pub fn wrap(&self, executor: SharedExecutor<T>) -> SharedExecutor<T> {
+        self.layers
+            .iter()
+            .rev()
+            .fold(executor, |inner, layer| layer.layer(inner))
+    }
+}
+
+impl<T> Default for LayerStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Called with the node, the outcome of the call and its duration.
+pub type OnFinish<T> = Arc<dyn Fn(&T, &Result<(), Error>, Duration) + Send + Sync>;
+
+/// Reports every call once it returned, eg to log or time the nodes.
+pub struct InspectLayer<T> {
+    on_finish: OnFinish<T>,
+    clock: SharedClock,
+}
+
+impl<T> InspectLayer<T> {
+    pub fn new(on_finish: OnFinish<T>) -> Self {
+        Self {
+            on_finish,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    /// Clock the calls are timed with, the system clock by default.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl<T: 'static> Layer<T> for InspectLayer<T> {
+    fn layer(&self, inner: SharedExecutor<T>) -> SharedExecutor<T> {
+        Arc::new(Inspect {
+            inner,
+            on_finish: self.on_finish.clone(),
+            clock: self.clock.clone(),
+        })
+    }
+}
+
+struct Inspect<T> {
+    inner: SharedExecutor<T>,
+    on_finish: OnFinish<T>,
+    clock: SharedClock,
+}
+
+impl<T> Inspect<T> {
+    fn inspect(&self, id: &T, call: impl FnOnce() -> Result<(), Error>) -> Result<(), Error> {
+        let started = self.clock.now();
+        let result = call();
+        (self.on_finish)(id, &result, self.clock.now().saturating_sub(started));
+        result
+    }
+}
+
+impl<T> CallableByID<T> for Inspect<T> {
+    fn call(&self, id: &T) -> Result<(), Error> {
+        self.inspect(id, || self.inner.call(id))
+    }
+
+    fn call_with_spawner(&self, id: &T, spawner: &Spawner) -> Result<(), Error> {
+        self.inspect(id, || self.inner.call_with_spawner(id, spawner))
+    }
+
+    fn call_with_ctx(&self, id: &T, ctx: &NodeCtx<'_, T>) -> Result<(), Error> {
+        self.inspect(id, || self.inner.call_with_ctx(id, ctx))
+    }
+
+    fn on_rollback(&self, id: &T) {
+        self.inner.on_rollback(id);
+    }
+}
+
+/// Calls the executor again when it fails, up to `attempts` calls in total, sleeping `backoff` (doubled after every
+/// attempt) in between. The error of the last attempt is returned.
+#[derive(Debug, Clone)]
+pub struct RetryLayer {
+    attempts: u32,
+    backoff: Duration,
+    clock: SharedClock,
+}
+
+impl RetryLayer {
+    pub fn new(attempts: u32, backoff: Duration) -> Self {
+        Self {
+            attempts: attempts.max(1),
+            backoff,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    /// Clock sleeping the backoffs, the system clock by default.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl<T: 'static> Layer<T> for RetryLayer {
+    fn layer(&self, inner: SharedExecutor<T>) -> SharedExecutor<T> {
+        Arc::new(Retry {
+            inner,
+            policy: self.clone(),
+        })
+    }
+}
+
+struct Retry<T> {
+    inner: SharedExecutor<T>,
+    policy: RetryLayer,
+}
+
+impl<T> Retry<T> {
+    fn retry(&self, mut call: impl FnMut() -> Result<(), Error>) -> Result<(), Error> {
+        let mut backoff = self.policy.backoff;
+        let mut attempt = 1;
+        loop {
+            match call() {
+                Err(_) if attempt < self.policy.attempts => {
+                    self.policy.clock.sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<T> CallableByID<T> for Retry<T> {
+    fn call(&self, id: &T) -> Result<(), Error> {
+        self.retry(|| self.inner.call(id))
+    }
+
+    fn call_with_spawner(&self, id: &T, spawner: &Spawner) -> Result<(), Error> {
+        self.retry(|| self.inner.call_with_spawner(id, spawner))
+    }
+
+    fn call_with_ctx(&self, id: &T, ctx: &NodeCtx<'_, T>) -> Result<(), Error> {
+        self.retry(|| self.inner.call_with_ctx(id, ctx))
+    }
+
+    fn on_rollback(&self, id: &T) {
+        self.inner.on_rollback(id);
+    }
+}
+
+/// Starts at most one call per `interval` across all workers, the others wait for their turn.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    interval: Duration,
+    /// Shared by the executors wrapped by clones of the layer, in the time of `clock`.
+    next_start: Arc<Mutex<Option<Duration>>>,
+    clock: SharedClock,
+}
+
+impl RateLimitLayer {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_start: Arc::new(Mutex::new(None)),
+            clock: SystemClock::shared(),
+        }
+    }
+
+    /// Clock the calls are spaced with, the system clock by default.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Waits for the turn of the caller.
+    fn acquire(&self) {
+        let start = {
+            let mut next_start = self.next_start.lock();
+            let now = self.clock.now();
+            let start = next_start.map_or(now, |next_start| next_start.max(now));
+            *next_start = Some(start + self.interval);
+            start
+        };
+
+        self.clock.sleep(start.saturating_sub(self.clock.now()));
+    }
+}
+
+impl<T: 'static> Layer<T> for RateLimitLayer {
+    fn layer(&self, inner: SharedExecutor<T>) -> SharedExecutor<T> {
+        Arc::new(RateLimit {
+            inner,
+            limit: self.clone(),
+        })
+    }
+}
+
+struct RateLimit<T> {
+    inner: SharedExecutor<T>,
+    limit: RateLimitLayer,
+}
+
+impl<T> CallableByID<T> for RateLimit<T> {
+    fn call(&self, id: &T) -> Result<(), Error> {
+        self.limit.acquire();
+        self.inner.call(id)
+    }
+
+    fn call_with_spawner(&self, id: &T, spawner: &Spawner) -> Result<(), Error> {
+        self.limit.acquire();
+        self.inner.call_with_spawner(id, spawner)
+    }
+
+    fn call_with_ctx(&self, id: &T, ctx: &NodeCtx<'_, T>) -> Result<(), Error> {
+        self.limit.acquire();
+        self.inner.call_with_ctx(id, ctx)
+    }
+
+    fn on_rollback(&self, id: &T) {
+        self.inner.on_rollback(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::VirtualClock;
+    use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+    /// Fails the first two calls of every node.
+    #[derive(Default)]
+    struct FlakyExecutor {
+        calls: Mutex<HashMap<usize, u32>>,
+    }
+
+    impl CallableByID<usize> for FlakyExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            let mut calls = self.calls.lock().unwrap();
+            let count = calls.entry(*id).or_insert(0);
+            *count += 1;
+            if *count <= 2 {
+                return Err("Flaky.".into());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_composes_layers() {
+        let finished = Arc::new(Mutex::new(vec![]));
+        let recorder = finished.clone();
+        let outermost = |inner: SharedExecutor<usize>| inner;
+
+        let executor = LayerStack::new()
+            .with_layer(outermost)
+            .with_layer(InspectLayer::new(Arc::new(
+                move |id: &usize, result: &Result<(), Error>, _| {
+                    recorder.lock().unwrap().push((*id, result.is_ok()));
+                },
+            )))
+            .with_layer(RetryLayer::new(3, Duration::ZERO))
+            .with_layer(RateLimitLayer::new(Duration::from_millis(5)))
+            .wrap(Arc::new(FlakyExecutor::default()));
+
+        let started = Instant::now();
+        assert!(executor.call(&1).is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(10));
+        assert_eq!(vec![(1, true)], *finished.lock().unwrap());
+
+        let executor = LayerStack::new()
+            .with_layer(RetryLayer::new(2, Duration::ZERO))
+            .wrap(Arc::new(FlakyExecutor::default()));
+        assert!(executor.call(&1).is_err());
+    }
+
+    #[test]
+    fn it_waits_in_virtual_time() {
+        let clock = VirtualClock::new();
+        let shared: SharedClock = Arc::new(clock.clone());
+        let executor = LayerStack::new()
+            .with_layer(RetryLayer::new(3, Duration::from_secs(30)).with_clock(shared.clone()))
+            .with_layer(RateLimitLayer::new(Duration::from_secs(60)).with_clock(shared))
+            .wrap(Arc::new(FlakyExecutor::default()));
+
+        let started = Instant::now();
+        assert!(executor.call(&1).is_ok());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(Duration::from_secs(120), clock.now());
+    }
+}
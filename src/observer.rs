@@ -0,0 +1,116 @@
+//! Hooks into the progress of a run, for tracing, metrics and logs. Observers are passed to the runner with
+//! `RunOptions::with_observer`; every callback has a no-op default.
+
+use super::common::*;
+use super::sync::*;
+use std::{collections::HashMap, hash::Hash, sync::Arc, time::Duration};
+
+/// How a node's execution ended.
+#[derive(Debug)]
+pub enum NodeOutcome<'a> {
+    Completed,
+    Failed(&'a Error),
+    /// Not executed, its fingerprint was found in the cache.
+    Cached,
+}
+
+/// Callbacks are made from the worker threads, so they should be quick. `worker` is the index of the worker thread.
+pub trait RunObserver<T> {
+    /// A worker took the node.
+    fn on_node_start(&self, _worker: usize, _id: &T) {}
+
+    /// The node is done, `duration` being the time spent executing it.
+    fn on_node_finish(
+        &self,
+        _worker: usize,
+        _id: &T,
+        _outcome: NodeOutcome<'_>,
+        _duration: Duration,
+    ) {
+    }
+
+    /// The node will never be executed, as one of its dependencies failed.
+    fn on_node_skipped(&self, _id: &T) {}
+
+    /// The node has been executing on `worker` for longer than the watchdog's limit, see
+    /// `RunOptions::with_watchdog`. Called once per node, from the watchdog thread, while the node keeps executing.
+    fn on_node_slow(&self, _worker: usize, _id: &T, _elapsed: Duration) {}
+
+    /// All workers stopped.
+    fn on_run_finish(&self, _report: &RunReport<T>) {}
+}
+
+/// Called with the index of a level and its nodes, see `LevelObserver`.
+pub type LevelHook<T> = Arc<dyn Fn(usize, &[T]) + Send + Sync>;
+
+/// Calls the hook whenever a whole topological level is done (completed, failed, cached or skipped), eg to flush
+/// buffers or commit a transaction per stage. Levels are reported in order, each once the previous ones are done too.
+/// Nodes of later levels may already be executing meanwhile: their own dependencies are done, not their whole level.
+///
+/// The hook is called on the worker finishing the last node of the level, holding up the other workers reporting a
+/// finished node meanwhile.
+pub struct LevelObserver<T> {
+    levels: Vec<Vec<T>>,
+    on_level: LevelHook<T>,
+    state: Mutex<LevelState<T>>,
+}
+
+struct LevelState<T> {
+    /// Level of every node not done yet.
+    pending: HashMap<T, usize>,
+    /// Nodes not done yet in every level.
+    remaining: Vec<usize>,
+    /// The first level not reported yet.
+    next: usize,
+}
+
+impl<T: Hash + Eq + Clone> LevelObserver<T> {
+    /// `levels` as given by `TopologicalBatchProvider::levels`.
+    pub fn new(levels: Vec<Vec<T>>, on_level: LevelHook<T>) -> Self {
+        let pending = levels
+            .iter()
+            .enumerate()
+            .flat_map(|(level, nodes)| nodes.iter().map(move |node| (node.clone(), level)))
+            .collect();
+        let remaining = levels.iter().map(Vec::len).collect();
+
+        Self {
+            levels,
+            on_level,
+            state: Mutex::new(LevelState {
+                pending,
+                remaining,
+                next: 0,
+            }),
+        }
+    }
+
+    fn done(&self, id: &T) {
+        let mut state = self.state.lock();
+        let Some(level) = state.pending.remove(id) else {
+            return;
+        };
+        state.remaining[level] -= 1;
+
+        while state.next < self.levels.len() && state.remaining[state.next] == 0 {
+            (self.on_level)(state.next, &self.levels[state.next]);
+            state.next += 1;
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone> RunObserver<T> for LevelObserver<T> {
+    fn on_node_finish(
+        &self,
+        _worker: usize,
+        id: &T,
+        _outcome: NodeOutcome<'_>,
+        _duration: Duration,
+    ) {
+        self.done(id);
+    }
+
+    fn on_node_skipped(&self, id: &T) {
+        self.done(id);
+    }
+}
@@ -0,0 +1,89 @@
+//! Plain orderings of a dependency map, for when only the order is needed and not the interactive provider.
+
+use super::common::*;
+use super::topological_batch_provider::*;
+use std::{collections::HashMap, hash::Hash};
+
+/// A flat, valid execution order of the dependency map (same shape as `TopologicalBatchProvider::new` expects):
+/// every node comes after all of its dependencies.
+pub fn topological_sort<T: Hash + PartialEq + Eq + Clone>(
+    nodes: &HashMap<T, Vec<T>>,
+) -> Result<Vec<T>, TopoError> {
+    let mut provider = TopologicalBatchProvider::build(
+        nodes
+            .iter()
+            .map(|(node, dependencies)| (node.clone(), dependencies.iter().cloned())),
+        nodes.len(),
+    )?;
+    let mut order = Vec::with_capacity(nodes.len());
+
+    while let Some(node) = provider.pop() {
+        let _ = provider.complete(node.clone());
+        order.push(node);
+    }
+
+    Ok(order)
+}
+
+/// The batches of the dependency map: nodes of a batch only depend on nodes of earlier batches, so each batch can be
+/// executed in parallel.
+pub fn levels<T: Hash + PartialEq + Eq + Clone>(
+    nodes: &HashMap<T, Vec<T>>,
+) -> Result<Vec<Vec<T>>, TopoError> {
+    let provider = TopologicalBatchProvider::build(
+        nodes
+            .iter()
+            .map(|(node, dependencies)| (node.clone(), dependencies.iter().cloned())),
+        nodes.len(),
+    )?;
+
+    Ok(provider.levels())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_sorts_topologically() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1]);
+        nodes.insert(4, vec![2, 3]);
+
+        let order = topological_sort(&nodes).unwrap();
+
+        assert_eq!(4, order.len());
+        for (node, dependencies) in &nodes {
+            let position = order.iter().position(|e| e == node).unwrap();
+            for dependency in dependencies {
+                assert!(order.iter().position(|e| e == dependency).unwrap() < position);
+            }
+        }
+    }
+
+    #[test]
+    fn it_computes_levels() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+
+        assert_eq!(vec![vec![1], vec![2], vec![3]], levels(&nodes).unwrap());
+    }
+
+    #[test]
+    fn it_reports_typed_errors() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![2]);
+        nodes.insert(2, vec![1]);
+        assert_eq!(Err(TopoError::Cycle), topological_sort(&nodes));
+
+        nodes.insert(2, vec![3]);
+        assert_eq!(Err(TopoError::UnknownDependency), topological_sort(&nodes));
+    }
+}
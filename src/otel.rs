@@ -0,0 +1,138 @@
+//! OpenTelemetry spans of a run: a span for the whole run and a child span per executed node, linked to the spans of
+//! its dependencies. Available with the `otel` feature.
+
+use super::common::*;
+use super::observer::*;
+use super::topological_batch_provider::*;
+use opentelemetry::{
+    trace::{Link, Span, SpanBuilder, SpanContext, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// Observer exporting the run through `tracer`. The run span starts when the observer is created and ends with the
+/// run.
+pub struct OtelObserver<T, Tr> {
+    tracer: Tr,
+    run_context: Context,
+    dependencies: HashMap<T, Vec<T>>,
+    span_contexts: Mutex<HashMap<T, SpanContext>>,
+}
+
+impl<T, Tr> OtelObserver<T, Tr>
+where
+    T: Hash + Eq + Clone,
+    Tr: Tracer,
+    Tr::Span: Send + Sync + 'static,
+{
+    /// `provider` is only read for the dependencies, create the observer before running it.
+    pub fn new(tracer: Tr, provider: &TopologicalBatchProvider<T>) -> Self {
+        let run_span = tracer.build(SpanBuilder::from_name("topological_batch.run"));
+
+        Self {
+            tracer,
+            run_context: Context::new().with_span(run_span),
+            dependencies: provider.dependencies_by_id(),
+            span_contexts: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T, Tr> RunObserver<T> for OtelObserver<T, Tr>
+where
+    T: Hash + Eq + Clone + Display,
+    Tr: Tracer,
+    Tr::Span: Send + Sync + 'static,
+{
+    fn on_node_finish(&self, worker: usize, id: &T, outcome: NodeOutcome<'_>, duration: Duration) {
+        let end = SystemTime::now();
+        let mut span_contexts = self.span_contexts.lock().unwrap();
+
+        let links = self
+            .dependencies
+            .get(id)
+            .into_iter()
+            .flatten()
+            .filter_map(|dependency| span_contexts.get(dependency))
+            .map(|span_context| Link::with_context(span_context.clone()))
+            .collect::<Vec<_>>();
+
+        let outcome_name = match outcome {
+            NodeOutcome::Completed => "completed",
+            NodeOutcome::Failed(_) => "failed",
+            NodeOutcome::Cached => "cached",
+        };
+
+        let builder = SpanBuilder::from_name(id.to_string())
+            .with_start_time(end - duration)
+            .with_links(links)
+            .with_attributes([
+                KeyValue::new("worker", worker as i64),
+                KeyValue::new("outcome", outcome_name),
+            ]);
+
+        let mut span = self.tracer.build_with_context(builder, &self.run_context);
+        if let NodeOutcome::Failed(err) = outcome {
+            span.set_status(Status::error(err.to_string()));
+        }
+        span_contexts.insert(id.clone(), span.span_context().clone());
+        span.end_with_timestamp(end);
+    }
+
+    fn on_run_finish(&self, report: &RunReport<T>) {
+        let run_span = self.run_context.span();
+        run_span.set_attribute(KeyValue::new("completed", report.completed.len() as i64));
+        run_span.set_attribute(KeyValue::new("failed", report.failed.len() as i64));
+        run_span.set_attribute(KeyValue::new("skipped", report.skipped.len() as i64));
+        if !report.is_success() {
+            run_span.set_status(Status::error("Run failed."));
+        }
+        run_span.end();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_pool_runner::*;
+    use opentelemetry::trace::noop::NoopTracer;
+    use std::sync::Arc;
+
+    struct Noop;
+
+    impl CallableByID<usize> for Noop {
+        fn call(&self, _id: &usize) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_collects_dependencies() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1, 2]);
+
+        let provider = TopologicalBatchProvider::new(nodes).unwrap();
+        let observer = Arc::new(OtelObserver::new(NoopTracer::new(), &provider));
+
+        let mut dependencies = observer.dependencies[&3].clone();
+        dependencies.sort_unstable();
+        assert_eq!(vec![1, 2], dependencies);
+        assert!(observer.dependencies[&1].is_empty());
+
+        let report = ThreadPoolRunner::new(2).run_with_options(
+            provider,
+            Arc::new(Noop),
+            RunOptions::new().with_observer(observer.clone()),
+        );
+        assert_eq!(3, report.completed.len());
+        assert_eq!(3, observer.span_contexts.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,96 @@
+//! Nodes carrying a payload, handed to the executor along with the ID, so executors don't need a side table keyed by
+//! ID. Build with `TopologicalBatchProvider::from_payload_map` and run with a `PayloadExecutor`.
+
+use super::common::*;
+use super::topological_batch_provider::*;
+use std::{collections::HashMap, hash::Hash};
+
+pub trait CallableWithPayload<T, P> {
+    /// Same as `CallableByID::call`, with the payload of the node.
+    fn call(&self, id: &T, payload: &P) -> Result<(), Error>;
+}
+
+/// Adapts a `CallableWithPayload` to the runners, looking up the payload of each node. Executing a node without a
+/// payload is an error.
+#[derive(Debug)]
+pub struct PayloadExecutor<T, P, E> {
+    payloads: HashMap<T, P>,
+    executor: E,
+}
+
+impl<T, P, E> PayloadExecutor<T, P, E> {
+    pub fn new(payloads: HashMap<T, P>, executor: E) -> Self {
+        Self { payloads, executor }
+    }
+}
+
+impl<T, P, E> CallableByID<T> for PayloadExecutor<T, P, E>
+where
+    T: Hash + Eq,
+    E: CallableWithPayload<T, P>,
+{
+    fn call(&self, id: &T) -> Result<(), Error> {
+        let payload = self.payloads.get(id).ok_or("Missing payload.")?;
+        self.executor.call(id, payload)
+    }
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> TopologicalBatchProvider<T> {
+    /// Same as `new`, for nodes given as `id => (payload, dependencies)`. The payloads are returned separately, to be
+    /// passed to a `PayloadExecutor`.
+    pub fn from_payload_map<P>(
+        nodes: HashMap<T, (P, Vec<T>)>,
+    ) -> Result<(Self, HashMap<T, P>), Error> {
+        let mut payloads = HashMap::with_capacity(nodes.len());
+        let mut dependencies = Vec::with_capacity(nodes.len());
+
+        for (id, (payload, node_dependencies)) in nodes {
+            payloads.insert(id.clone(), payload);
+            dependencies.push((id, node_dependencies));
+        }
+
+        Ok((Self::try_from_iter(dependencies)?, payloads))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_pool_runner::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Echo {
+        seen: Mutex<Vec<(usize, String)>>,
+    }
+
+    impl CallableWithPayload<usize, String> for Echo {
+        fn call(&self, id: &usize, payload: &String) -> Result<(), Error> {
+            self.seen.lock().unwrap().push((*id, payload.clone()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_passes_payloads_to_the_executor() {
+        let mut nodes = HashMap::new();
+        nodes.insert(1, ("fetch".to_string(), vec![]));
+        nodes.insert(2, ("build".to_string(), vec![1]));
+
+        let (provider, payloads) = TopologicalBatchProvider::from_payload_map(nodes).unwrap();
+        let executor = Arc::new(PayloadExecutor::new(
+            payloads,
+            Echo {
+                seen: Mutex::new(vec![]),
+            },
+        ));
+
+        let report = ThreadPoolRunner::new(2).run(provider, executor.clone());
+
+        assert!(report.is_success());
+        assert_eq!(
+            vec![(1, "fetch".to_string()), (2, "build".to_string())],
+            *executor.executor.seen.lock().unwrap()
+        );
+        assert!(CallableByID::call(&*executor, &3).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! The validated and indexed form of a graph, built once and instantiated into fresh providers any number of times.
+//! Recurring pipelines running the same graph skip the validation and indexing on every run:
+//!
+//! ```ignore
+//! let plan = Plan::new(dependency_graph)?;
+//! loop {
+//!     let report = runner.run(plan.provider(), executor.clone());
+//!     // ...
+//! }
+//! ```
+
+use super::common::*;
+use super::topological_batch_provider::*;
+use std::{collections::HashMap, hash::Hash};
+
+/// Immutable, so it can be shared between threads and runs.
+#[derive(Debug, Clone)]
+pub struct Plan<T> {
+    pub(crate) ids: Vec<T>,
+    pub(crate) indices: HashMap<T, usize>,
+    pub(crate) dependents: Vec<Vec<usize>>,
+    /// Initial number of dependencies of each node.
+    pub(crate) pending_dependencies: Vec<usize>,
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> Plan<T> {
+    /// Validates the graph with the errors of `TopologicalBatchProvider::new`.
+    pub fn new(nodes: HashMap<T, Vec<T>>) -> Result<Self, Error> {
+        let capacity = nodes.len();
+        Self::with_capacity(nodes, capacity)
+    }
+
+    /// Same as `new`, with the errors of `TopologicalBatchProvider::try_from_iter`.
+    pub fn try_from_iter<I, D>(nodes: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (T, D)>,
+        D: IntoIterator<Item = T>,
+    {
+        let nodes = nodes.into_iter();
+        let capacity = nodes.size_hint().0;
+        Self::with_capacity(nodes, capacity)
+    }
+
+    fn with_capacity<I, D>(nodes: I, capacity: usize) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (T, D)>,
+        D: IntoIterator<Item = T>,
+    {
+        Ok(TopologicalBatchProvider::build(nodes, capacity)?.into_plan())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// A provider where none of the nodes are popped yet, copying the plan without validating it again.
+    pub fn provider(&self) -> TopologicalBatchProvider<T> {
+        TopologicalBatchProvider::from_parts(
+            self.ids.clone(),
+            self.indices.clone(),
+            self.dependents.clone(),
+            self.pending_dependencies.clone(),
+        )
+    }
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> From<&Plan<T>> for TopologicalBatchProvider<T> {
+    fn from(plan: &Plan<T>) -> Self {
+        plan.provider()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_pool_runner::*;
+    use std::sync::{Arc, Mutex};
+
+    struct RecordingExecutor {
+        calls: Mutex<Vec<usize>>,
+    }
+
+    impl CallableByID<usize> for RecordingExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            self.calls.lock().unwrap().push(*id);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_instantiates_fresh_providers() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+
+        let plan = Plan::new(nodes).unwrap();
+        let runner = ThreadPoolRunner::new(2);
+        let executor = Arc::new(RecordingExecutor {
+            calls: Mutex::new(vec![]),
+        });
+
+        for _ in 0..2 {
+            let report = runner.run(plan.provider(), executor.clone());
+            assert_eq!(3, report.completed.len());
+        }
+        assert_eq!(vec![1, 2, 3, 1, 2, 3], *executor.calls.lock().unwrap());
+
+        let provider = TopologicalBatchProvider::from(&plan);
+        assert_eq!(vec![vec![1], vec![2], vec![3]], provider.levels());
+        assert_eq!(3, plan.len());
+        assert!(Plan::new(HashMap::from([(1, vec![1])])).is_err());
+    }
+}
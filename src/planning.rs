@@ -0,0 +1,235 @@
+//! Ahead-of-time scheduling from per-node cost estimates. For stable pipelines a precomputed assignment of nodes to
+//! workers (list scheduling by upward rank, as in HEFT) beats the greedy dispatch of the runner.
+
+use super::topological_batch_provider::*;
+use std::{error, fmt, hash::Hash, time::Duration};
+
+/// A node placed on a worker's timeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledNode<T> {
+    pub id: T,
+    pub start: Duration,
+    pub finish: Duration,
+}
+
+/// Assignment of every node to a worker, in execution order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule<T> {
+    /// For each worker its nodes in execution order.
+    pub workers: Vec<Vec<ScheduledNode<T>>>,
+    /// Projected wall time of the whole graph.
+    pub makespan: Duration,
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> Schedule<T> {
+    /// Plans the provider's graph on `worker_count` workers with the estimated `cost` of each node. Nodes are taken
+    /// by decreasing upward rank (own cost plus the most expensive path of dependees) and each goes to the worker
+    /// becoming free the earliest.
+    ///
+    /// The schedule is only valid for the graph it was planned for, see `ThreadPoolRunner::run_planned`.
+    pub fn plan<F>(
+        topological_batch_provider: &TopologicalBatchProvider<T>,
+        worker_count: usize,
+        cost: F,
+    ) -> Self
+    where
+        F: Fn(&T) -> Duration,
+    {
+        let ids = topological_batch_provider.ids();
+        let costs = ids.iter().map(&cost).collect::<Vec<_>>();
+        let dependents = |index| topological_batch_provider.dependents_of_index(index);
+
+        let mut ranks = vec![Duration::ZERO; ids.len()];
+        for level in topological_batch_provider.level_indices().iter().rev() {
+            for &index in level {
+                let dependee_rank = dependents(index)
+                    .iter()
+                    .map(|&dependent| ranks[dependent])
+                    .max()
+                    .unwrap_or_default();
+                ranks[index] = costs[index] + dependee_rank;
+            }
+        }
+
+        let mut pending_dependencies = vec![0; ids.len()];
+        for index in 0..ids.len() {
+            for &dependent in dependents(index) {
+                pending_dependencies[dependent] += 1;
+            }
+        }
+
+        let mut ready = (0..ids.len())
+            .filter(|&index| pending_dependencies[index] == 0)
+            .collect::<Vec<_>>();
+        let mut ready_at = vec![Duration::ZERO; ids.len()];
+        let mut worker_free_at = vec![Duration::ZERO; worker_count.max(1)];
+        let mut workers = vec![vec![]; worker_count.max(1)];
+        let mut makespan = Duration::ZERO;
+
+        while !ready.is_empty() {
+            let position = (0..ready.len())
+                .max_by_key(|&position| (ranks[ready[position]], usize::MAX - ready[position]))
+                .unwrap();
+            let index = ready.swap_remove(position);
+
+            let worker = (0..worker_free_at.len())
+                .min_by_key(|&worker| worker_free_at[worker].max(ready_at[index]))
+                .unwrap();
+            let start = worker_free_at[worker].max(ready_at[index]);
+            let finish = start + costs[index];
+
+            worker_free_at[worker] = finish;
+            makespan = makespan.max(finish);
+            workers[worker].push(ScheduledNode {
+                id: ids[index].clone(),
+                start,
+                finish,
+            });
+
+            for &dependent in dependents(index) {
+                ready_at[dependent] = ready_at[dependent].max(finish);
+                pending_dependencies[dependent] -= 1;
+                if pending_dependencies[dependent] == 0 {
+                    ready.push(dependent);
+                }
+            }
+        }
+
+        Self { workers, makespan }
+    }
+}
+
+/// Projection of a greedy run, see `ThreadPoolRunner::simulate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimReport {
+    /// Projected wall time of the whole graph.
+    pub makespan: Duration,
+    /// For each thread the time spent executing nodes.
+    pub busy: Vec<Duration>,
+}
+
+impl SimReport {
+    /// For each thread the fraction of the makespan spent executing nodes.
+    pub fn utilization(&self) -> Vec<f64> {
+        self.busy
+            .iter()
+            .map(|busy| {
+                if self.makespan.is_zero() {
+                    0.0
+                } else {
+                    busy.as_secs_f64() / self.makespan.as_secs_f64()
+                }
+            })
+            .collect()
+    }
+}
+
+/// `ThreadPoolRunner::simulate` of a runner without threads, which executes on the thread waiting for the run instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoThreads;
+
+impl fmt::Display for NoThreads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cannot simulate a runner without threads.")
+    }
+}
+
+impl error::Error for NoThreads {}
+
+/// Replays the greedy dispatch of the thread pool runner in virtual time: whenever a thread is free (and the in-flight
+/// cap allows), it pops the next node from the provider. The waiting of idle workers is not modelled.
+pub(crate) fn simulate<T, F>(
+    mut topological_batch_provider: TopologicalBatchProvider<T>,
+    thread_count: usize,
+    max_in_flight: usize,
+    cost: F,
+) -> SimReport
+where
+    T: Hash + PartialEq + Eq + Clone,
+    F: Fn(&T) -> Duration,
+{
+    let mut now = Duration::ZERO;
+    let mut busy = vec![Duration::ZERO; thread_count];
+    let mut free_threads = (0..thread_count).rev().collect::<Vec<_>>();
+    let mut running: Vec<(Duration, usize, T)> = vec![];
+
+    loop {
+        while running.len() < max_in_flight {
+            let Some(&thread) = free_threads.last() else {
+                break;
+            };
+            let Some(node) = topological_batch_provider.pop() else {
+                break;
+            };
+
+            free_threads.pop();
+            let node_cost = cost(&node);
+            busy[thread] += node_cost;
+            running.push((now + node_cost, thread, node));
+        }
+
+        let Some(position) =
+            (0..running.len()).min_by_key(|&position| (running[position].0, running[position].1))
+        else {
+            break;
+        };
+
+        let (finish, thread, node) = running.swap_remove(position);
+        now = finish;
+        let _ = topological_batch_provider.complete(node);
+        free_threads.push(thread);
+        free_threads.sort_by(|a, b| b.cmp(a));
+    }
+
+    SimReport {
+        makespan: now,
+        busy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_simulates_greedy_runs() {
+        let nodes = vec![(1, vec![]), (2, vec![]), (3, vec![]), (4, vec![1, 2, 3])];
+        let topological_batch_provider = TopologicalBatchProvider::with_capacity(nodes, 4).unwrap();
+
+        let report = simulate(topological_batch_provider, 2, 2, |_| Duration::from_secs(1));
+
+        assert_eq!(Duration::from_secs(3), report.makespan);
+        assert_eq!(Duration::from_secs(4), report.busy.iter().sum::<Duration>());
+        assert_eq!(2, report.utilization().len());
+    }
+
+    #[test]
+    fn it_plans_the_critical_path_first() {
+        // 1 -> 2 -> 3 is the long chain, 4 and 5 are short independent nodes.
+        let nodes = vec![
+            (1, vec![]),
+            (2, vec![1]),
+            (3, vec![2]),
+            (4, vec![]),
+            (5, vec![]),
+        ];
+        let topological_batch_provider = TopologicalBatchProvider::with_capacity(nodes, 5).unwrap();
+
+        let schedule = Schedule::plan(&topological_batch_provider, 2, |&id| {
+            Duration::from_secs(if id <= 3 { 10 } else { 5 })
+        });
+
+        assert_eq!(Duration::from_secs(30), schedule.makespan);
+
+        let chain_worker = schedule
+            .workers
+            .iter()
+            .find(|worker| worker[0].id == 1)
+            .unwrap();
+        assert_eq!(
+            vec![1, 2, 3],
+            chain_worker.iter().map(|node| node.id).collect::<Vec<_>>()
+        );
+        assert_eq!(5, schedule.workers.iter().map(Vec::len).sum::<usize>());
+    }
+}
@@ -0,0 +1,177 @@
+//! Node durations recorded from previous runs, fed back as the cost model of the next run's scheduling: see
+//! `TopologicalBatchProvider::with_profile`. Recurring pipelines get a better makespan without hand-tuned costs.
+//!
+//! Profiles are stored as newline delimited JSON, nodes being identified by their display form:
+//!
+//! ```text
+//! {"node":"build","duration_us":1520000}
+//! ```
+
+use super::common::*;
+use super::json;
+use super::observer::*;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// Last known duration of every node.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    durations: HashMap<String, Duration>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads a profile written by `save`, an empty profile when the file does not exist yet.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        match File::open(path) {
+            Ok(file) => Self::read(BufReader::new(file)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn read(reader: impl BufRead) -> Result<Self, Error> {
+        let mut profile = Self::new();
+
+        for (line_index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let invalid =
+                |reason: &str| format!("Invalid profile line {}: {}", line_index + 1, reason);
+            let entry = json::parse_object(&line).map_err(|reason| invalid(&reason))?;
+            let node = entry
+                .get("node")
+                .and_then(json::Value::as_str)
+                .ok_or_else(|| invalid("missing node."))?;
+            let duration = entry
+                .get("duration_us")
+                .and_then(json::Value::as_u64)
+                .ok_or_else(|| invalid("missing duration_us."))?;
+
+            profile
+                .durations
+                .insert(node.to_string(), Duration::from_micros(duration));
+        }
+
+        Ok(profile)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Entries are written sorted by node, so profiles diff well.
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut entries = self.durations.iter().collect::<Vec<_>>();
+        entries.sort();
+
+        for (node, duration) in entries {
+            writeln!(
+                writer,
+                "{{\"node\":{},\"duration_us\":{}}}",
+                json::string(node),
+                duration.as_micros()
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn duration(&self, node: &impl Display) -> Option<Duration> {
+        self.durations.get(&node.to_string()).copied()
+    }
+
+    /// Overwrites the previous duration of the node. Durations are kept with microsecond precision, as stored.
+    pub fn record(&mut self, node: &impl Display, duration: Duration) {
+        self.durations.insert(
+            node.to_string(),
+            Duration::from_micros(duration.as_micros() as u64),
+        );
+    }
+}
+
+/// Observer recording the duration of the executed nodes (cached ones are left as they were) into a profile.
+pub struct ProfileRecorder {
+    profile: Mutex<Profile>,
+}
+
+impl ProfileRecorder {
+    /// `profile` is updated by the run, nodes not executed keep their earlier duration.
+    pub fn new(profile: Profile) -> Self {
+        Self {
+            profile: Mutex::new(profile),
+        }
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.profile.lock().unwrap().clone()
+    }
+}
+
+impl<T: Display> RunObserver<T> for ProfileRecorder {
+    fn on_node_finish(&self, _worker: usize, id: &T, outcome: NodeOutcome<'_>, duration: Duration) {
+        if !matches!(outcome, NodeOutcome::Cached) {
+            self.profile.lock().unwrap().record(id, duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_pool_runner::*;
+    use crate::topological_batch_provider::*;
+    use std::sync::Arc;
+
+    struct SleepingExecutor;
+
+    impl CallableByID<usize> for SleepingExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            std::thread::sleep(Duration::from_millis(*id as u64));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_records_and_reads_back_profiles() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(5, vec![1]);
+
+        let mut earlier = Profile::new();
+        earlier.record(&"gone", Duration::from_millis(3));
+        let recorder = Arc::new(ProfileRecorder::new(earlier));
+
+        ThreadPoolRunner::new(2).run_with_options(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            Arc::new(SleepingExecutor),
+            RunOptions::new().with_observer(recorder.clone()),
+        );
+
+        let profile = recorder.profile();
+        assert!(profile.duration(&5).unwrap() >= Duration::from_millis(5));
+        assert!(profile.duration(&1).unwrap() < profile.duration(&5).unwrap());
+        assert_eq!(Some(Duration::from_millis(3)), profile.duration(&"gone"));
+
+        let mut written = vec![];
+        profile.write(&mut written).unwrap();
+        assert_eq!(profile, Profile::read(written.as_slice()).unwrap());
+        assert!(Profile::read("{\"node\":\"a\"}".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! Node dispatch through a message queue, for distributing the execution over machines (SQS, Pub/Sub, ...) while the
+//! topology stays with a single coordinator: `dispatch` enqueues the ready nodes and applies the completions coming
+//! back, remote workers receive the nodes and report them done.
+//!
+//! Received nodes are invisible to the other workers for a visibility timeout, after which they are delivered again,
+//! so a crashed worker doesn't lose its node. Every delivery comes with a receipt (the receipt handle of SQS, the ack
+//! ID of Pub/Sub), which workers busy for longer pass to `extend_visibility` to keep their node, and to `complete` to
+//! report it done. The receipt of a delivery that timed out is rejected, so only the delivery running last counts.
+//! `InMemoryQueue` implements the semantics within a process, as a reference for backends and for tests.
+//!
+//! ```ignore
+//! let queue = Arc::new(InMemoryQueue::new(Duration::from_secs(30)));
+//! for _ in 0..4 {
+//!     let queue = queue.clone();
+//!     thread::spawn(move || {
+//!         while let Ok(Some(delivery)) = queue.receive(Duration::from_secs(1)) {
+//!             let completion = match execute(&delivery.node) {
+//!                 Ok(()) => Completion::Completed(delivery.node),
+//!                 Err(err) => Completion::Failed(delivery.node, err),
+//!             };
+//!             let _ = queue.complete(&delivery.receipt, completion);
+//!         }
+//!     });
+//! }
+//! let report = dispatch(provider, &*queue, Duration::from_millis(100))?;
+//! ```
+
+use super::channel::*;
+use super::clock::*;
+use super::common::*;
+use super::sync::*;
+use super::topological_batch_provider::*;
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::Duration,
+};
+
+/// A node received by a worker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery<T> {
+    pub node: T,
+    /// Identifies this delivery of the node to the queue, valid until its visibility timeout runs out.
+    pub receipt: String,
+}
+
+/// Transport between the coordinator and the workers. Both sides share it, a backend implements the work queue and
+/// the completion queue behind it.
+pub trait Queue<T>: Send + Sync {
+    /// Coordinator side: publishes a ready node.
+    fn enqueue(&self, node: T) -> Result<(), Error>;
+
+    /// Coordinator side: waits up to `timeout` for a node to be reported done, `None` if none was.
+    fn receive_completion(&self, timeout: Duration) -> Result<Option<Completion<T>>, Error>;
+
+    /// Worker side: waits up to `timeout` for a visible node, which then becomes invisible for the visibility timeout.
+    fn receive(&self, timeout: Duration) -> Result<Option<Delivery<T>>, Error>;
+
+    /// Worker side: keeps the node of the delivery with `receipt` invisible for `by` from now on. Fails if the
+    /// delivery timed out.
+    fn extend_visibility(&self, receipt: &str, by: Duration) -> Result<(), Error>;
+
+    /// Worker side: reports the node of the delivery with `receipt` done, removing it from the queue. Fails if the
+    /// delivery timed out, the node being delivered again.
+    fn complete(&self, receipt: &str, completion: Completion<T>) -> Result<(), Error>;
+}
+
+/// Drives the provider from the calling thread until every node finished, as `pump` does for channels. Completions
+/// are polled every `poll`, duplicated ones (of nodes delivered more than once) are ignored.
+pub fn dispatch<T: Hash + PartialEq + Eq + Clone>(
+    mut topological_batch_provider: TopologicalBatchProvider<T>,
+    queue: &dyn Queue<T>,
+    poll: Duration,
+) -> Result<RunReport<T>, Error> {
+    let mut report = RunReport::new();
+
+    loop {
+        while let Some(node) = topological_batch_provider.pop() {
+            queue.enqueue(node)?;
+        }
+
+        if topological_batch_provider.is_empty() {
+            return Ok(report);
+        }
+
+        if let Some(completion) = queue.receive_completion(poll)? {
+            apply_completion(&mut topological_batch_provider, &mut report, completion);
+        }
+    }
+}
+
+struct InFlight<T> {
+    node: T,
+    /// In the time of the queue's clock.
+    visible_at: Duration,
+}
+
+struct QueueState<T> {
+    visible: VecDeque<T>,
+    /// Received nodes by receipt.
+    in_flight: HashMap<String, InFlight<T>>,
+    completions: VecDeque<Completion<T>>,
+    next_receipt: u64,
+}
+
+impl<T> QueueState<T> {
+    /// Makes the nodes whose visibility timeout ran out visible again, dropping their receipts.
+    fn expire(&mut self, now: Duration) {
+        let expired = self
+            .in_flight
+            .iter()
+            .filter(|(_, in_flight)| in_flight.visible_at <= now)
+            .map(|(receipt, _)| receipt.clone())
+            .collect::<Vec<_>>();
+        for receipt in expired {
+            if let Some(in_flight) = self.in_flight.remove(&receipt) {
+                self.visible.push_back(in_flight.node);
+            }
+        }
+    }
+}
+
+/// `Queue` within the process. Waits block the calling thread, timeouts being measured on the queue's clock.
+pub struct InMemoryQueue<T> {
+    state: Mutex<QueueState<T>>,
+    /// Notified on every enqueue and completion.
+    changed: Condvar,
+    visibility_timeout: Duration,
+    clock: SharedClock,
+}
+
+impl<T: Hash + Eq + Clone> InMemoryQueue<T> {
+    pub fn new(visibility_timeout: Duration) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                visible: VecDeque::new(),
+                in_flight: HashMap::new(),
+                completions: VecDeque::new(),
+                next_receipt: 0,
+            }),
+            changed: Condvar::new(),
+            visibility_timeout,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    /// Clock the visibility timeouts run on, the system clock by default.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+fn not_in_flight() -> Error {
+    "Delivery is no longer in flight.".into()
+}
+
+impl<T: Hash + Eq + Clone + Send> Queue<T> for InMemoryQueue<T> {
+    fn enqueue(&self, node: T) -> Result<(), Error> {
+        self.state.lock().visible.push_back(node);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    fn receive_completion(&self, timeout: Duration) -> Result<Option<Completion<T>>, Error> {
+        let deadline = self.clock.now() + timeout;
+        let mut state = self.state.lock();
+
+        loop {
+            if let Some(completion) = state.completions.pop_front() {
+                return Ok(Some(completion));
+            }
+
+            let now = self.clock.now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            state = self.changed.wait_timeout(state, deadline - now);
+        }
+    }
+
+    fn receive(&self, timeout: Duration) -> Result<Option<Delivery<T>>, Error> {
+        let deadline = self.clock.now() + timeout;
+        let mut state = self.state.lock();
+
+        loop {
+            let now = self.clock.now();
+            state.expire(now);
+
+            if let Some(node) = state.visible.pop_front() {
+                let receipt = state.next_receipt.to_string();
+                state.next_receipt += 1;
+                state.in_flight.insert(
+                    receipt.clone(),
+                    InFlight {
+                        node: node.clone(),
+                        visible_at: now + self.visibility_timeout,
+                    },
+                );
+                return Ok(Some(Delivery { node, receipt }));
+            }
+
+            if now >= deadline {
+                return Ok(None);
+            }
+            // Wakes up for the next node becoming visible again too.
+            let wake_at = state
+                .in_flight
+                .values()
+                .map(|in_flight| in_flight.visible_at)
+                .fold(deadline, Duration::min);
+            state = self.changed.wait_timeout(state, wake_at - now);
+        }
+    }
+
+    fn extend_visibility(&self, receipt: &str, by: Duration) -> Result<(), Error> {
+        let now = self.clock.now();
+        let mut state = self.state.lock();
+        state.expire(now);
+
+        let in_flight = state.in_flight.get_mut(receipt).ok_or_else(not_in_flight)?;
+        in_flight.visible_at = now + by;
+        Ok(())
+    }
+
+    fn complete(&self, receipt: &str, completion: Completion<T>) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        state.expire(self.clock.now());
+
+        state.in_flight.remove(receipt).ok_or_else(not_in_flight)?;
+        state.completions.push_back(completion);
+        self.changed.notify_all();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::VirtualClock;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    #[test]
+    fn it_redelivers_nodes_of_crashed_workers() {
+        let nodes = HashMap::from([(1, vec![]), (2, vec![1]), (3, vec![])]);
+        let queue = Arc::new(InMemoryQueue::new(Duration::from_millis(30)));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let worker = {
+            let queue = queue.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut deliveries: HashMap<usize, usize> = HashMap::new();
+                let mut receipts = HashMap::new();
+                while !done.load(Ordering::SeqCst) {
+                    let Some(delivery) = queue.receive(Duration::from_millis(5)).unwrap() else {
+                        continue;
+                    };
+                    let node = delivery.node;
+                    receipts.insert(node, delivery.receipt.clone());
+                    let count = deliveries.entry(node).or_insert(0);
+                    *count += 1;
+
+                    match (node, *count) {
+                        // Crashes on the first delivery.
+                        (1, 1) => continue,
+                        (3, _) => {
+                            queue
+                                .extend_visibility(&delivery.receipt, Duration::from_secs(10))
+                                .unwrap();
+                            thread::sleep(Duration::from_millis(60));
+                        }
+                        _ => {}
+                    }
+                    queue
+                        .complete(&delivery.receipt, Completion::Completed(node))
+                        .unwrap();
+                }
+                (deliveries, receipts)
+            })
+        };
+
+        let report = dispatch(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            &*queue,
+            Duration::from_millis(5),
+        )
+        .unwrap();
+        done.store(true, Ordering::SeqCst);
+        let (deliveries, receipts) = worker.join().unwrap();
+
+        let mut completed = report.completed;
+        completed.sort_unstable();
+        assert_eq!(vec![1, 2, 3], completed);
+        assert_eq!(HashMap::from([(1, 2), (2, 1), (3, 1)]), deliveries);
+        assert!(queue
+            .extend_visibility(&receipts[&3], Duration::ZERO)
+            .is_err());
+    }
+
+    #[test]
+    fn it_rejects_receipts_of_timed_out_deliveries() {
+        let clock = VirtualClock::new();
+        let queue = InMemoryQueue::new(Duration::from_secs(30)).with_clock(Arc::new(clock.clone()));
+        queue.enqueue(1).unwrap();
+
+        let late = queue.receive(Duration::ZERO).unwrap().unwrap();
+        clock.advance(Duration::from_secs(20));
+        queue
+            .extend_visibility(&late.receipt, Duration::from_secs(30))
+            .unwrap();
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(None, queue.receive(Duration::ZERO).unwrap());
+
+        clock.advance(Duration::from_secs(20));
+        assert!(queue
+            .extend_visibility(&late.receipt, Duration::from_secs(30))
+            .is_err());
+        assert!(queue
+            .complete(&late.receipt, Completion::Completed(1))
+            .is_err());
+
+        let redelivered = queue.receive(Duration::ZERO).unwrap().unwrap();
+        assert_eq!(1, redelivered.node);
+        assert_ne!(late.receipt, redelivered.receipt);
+        queue
+            .complete(&redelivered.receipt, Completion::Completed(1))
+            .unwrap();
+        assert!(matches!(
+            queue.receive_completion(Duration::ZERO).unwrap(),
+            Some(Completion::Completed(1))
+        ));
+        assert_eq!(None, queue.receive(Duration::ZERO).unwrap());
+    }
+}
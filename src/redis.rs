@@ -0,0 +1,621 @@
+//! Coordination of several processes (or hosts) pulling from the same graph, with the ready set and the progress kept
+//! in Redis. Available with the `redis` feature.
+//!
+//! Every process builds the same graph and a `RedisCoordinator` over it with the same key prefix; the first one to
+//! `init` seeds the state. Claims, completions and failures are Lua scripts, so they are atomic across processes.
+//!
+//! With `with_lease`, claims are leases a worker keeps alive with `heartbeat`. Nodes whose lease expired (the worker
+//! crashed or hung) are requeued by the next `claim` of any process, so a dead worker doesn't wedge the graph. Lease
+//! times come from the Redis server clock.
+//!
+//! Every claim carries the token of its lease, required to heartbeat, complete or fail the node. The worker holding the
+//! token can be an other process than the claiming one, and a worker whose lease expired gets `LeaseLost` instead of
+//! finishing a node that was handed to an other worker meanwhile.
+//!
+//! The coordinator is also a `BatchProvider`, so each process can run its share of the graph with `ThreadPoolRunner`
+//! once the state is seeded. The runner doesn't renew leases: with `with_lease`, the lease has to outlast the longest
+//! node.
+
+use super::common::*;
+use super::topological_batch_provider::*;
+use redis::{Commands, Connection, FromRedisValue, RedisResult, Script};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error,
+    fmt::{self, Display},
+    hash::Hash,
+    time::Duration,
+};
+
+/// Prepended to scripts reading the server time.
+const NOW_MS: &str = r"
+if redis.replicate_commands then
+    redis.replicate_commands()
+end
+local time = redis.call('TIME')
+local now_ms = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+";
+
+const INIT: &str = r"
+if redis.call('SETNX', KEYS[1], 1) == 0 then
+    return 0
+end
+local incomplete = 0
+for i = 1, #ARGV, 2 do
+    local name, count = ARGV[i], tonumber(ARGV[i + 1])
+    incomplete = incomplete + 1
+    if count == 0 then
+        redis.call('HSET', KEYS[2], name, 'available')
+        redis.call('LPUSH', KEYS[4], name)
+    else
+        redis.call('HSET', KEYS[2], name, 'pending')
+        redis.call('HSET', KEYS[3], name, count)
+    end
+end
+redis.call('SET', KEYS[5], incomplete)
+return 1
+";
+
+// Entries of nodes finished or claimed again since they were queued are dropped. Tokens are the worker name and a
+// counter shared by every process, so each lease gets its own.
+const CLAIM: &str = r"
+while true do
+    local name = redis.call('RPOP', KEYS[4])
+    if not name then
+        return false
+    end
+    if redis.call('HGET', KEYS[2], name) == 'available' then
+        redis.call('HSET', KEYS[2], name, 'running')
+        local token = ''
+        if ARGV[1] ~= '' then
+            token = ARGV[1] .. ':' .. redis.call('INCR', KEYS[8])
+            redis.call('HSET', KEYS[7], name, token)
+            redis.call('ZADD', KEYS[6], now_ms + tonumber(ARGV[2]), name)
+        end
+        return {name, token}
+    end
+end
+";
+
+const HEARTBEAT: &str = r"
+if redis.call('HGET', KEYS[7], ARGV[3]) ~= ARGV[1] then
+    return 0
+end
+redis.call('ZADD', KEYS[6], 'XX', now_ms + tonumber(ARGV[2]), ARGV[3])
+return 1
+";
+
+const REQUEUE_EXPIRED: &str = r"
+local requeued = {}
+for _, name in ipairs(redis.call('ZRANGEBYSCORE', KEYS[6], '-inf', now_ms)) do
+    redis.call('ZREM', KEYS[6], name)
+    redis.call('HDEL', KEYS[7], name)
+    if redis.call('HGET', KEYS[2], name) == 'running' then
+        redis.call('HSET', KEYS[2], name, 'available')
+        redis.call('RPUSH', KEYS[4], name)
+        table.insert(requeued, name)
+    end
+end
+return requeued
+";
+
+// Nodes claimed without a lease have no owner, which matches their empty token.
+const COMPLETE: &str = r"
+if (redis.call('HGET', KEYS[7], ARGV[1]) or '') ~= ARGV[2] then
+    return -1
+end
+redis.call('ZREM', KEYS[6], ARGV[1])
+redis.call('HDEL', KEYS[7], ARGV[1])
+local status = redis.call('HGET', KEYS[2], ARGV[1])
+if status == 'completed' or status == 'failed' or status == 'skipped' then
+    return 0
+end
+redis.call('HSET', KEYS[2], ARGV[1], 'completed')
+redis.call('DECR', KEYS[5])
+for i = 3, #ARGV do
+    local left = redis.call('HINCRBY', KEYS[3], ARGV[i], -1)
+    if left == 0 and redis.call('HGET', KEYS[2], ARGV[i]) == 'pending' then
+        redis.call('HSET', KEYS[2], ARGV[i], 'available')
+        redis.call('LPUSH', KEYS[4], ARGV[i])
+    end
+end
+return 1
+";
+
+const FAIL: &str = r"
+if (redis.call('HGET', KEYS[7], ARGV[1]) or '') ~= ARGV[2] then
+    return false
+end
+redis.call('ZREM', KEYS[6], ARGV[1])
+redis.call('HDEL', KEYS[7], ARGV[1])
+local status = redis.call('HGET', KEYS[2], ARGV[1])
+if status == 'completed' or status == 'failed' or status == 'skipped' then
+    return {}
+end
+redis.call('HSET', KEYS[2], ARGV[1], 'failed')
+redis.call('DECR', KEYS[5])
+local skipped = {}
+for i = 3, #ARGV do
+    local dependent = redis.call('HGET', KEYS[2], ARGV[i])
+    if dependent ~= 'completed' and dependent ~= 'failed' and dependent ~= 'skipped' then
+        redis.call('HSET', KEYS[2], ARGV[i], 'skipped')
+        redis.call('DECR', KEYS[5])
+        table.insert(skipped, ARGV[i])
+    end
+end
+return skipped
+";
+
+struct Scripts {
+    init: Script,
+    claim: Script,
+    heartbeat: Script,
+    requeue_expired: Script,
+    complete: Script,
+    fail: Script,
+}
+
+#[derive(Debug, Clone)]
+struct Lease {
+    worker: String,
+    duration: Duration,
+}
+
+/// A claimed node with the token of its lease, empty when claimed without a lease. Workers of other processes can
+/// rebuild it from the node and the token to finish the node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Claim<T> {
+    pub node: T,
+    pub token: String,
+}
+
+/// The lease of a node expired and it was handed to an other worker (or finished by it).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaseLost {
+    /// `Display` form of the node.
+    pub node: String,
+}
+
+impl fmt::Display for LeaseLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Lease of node {} lost.", self.node)
+    }
+}
+
+impl error::Error for LeaseLost {}
+
+fn invoke<R: FromRedisValue>(
+    connection: &mut Connection,
+    script: &Script,
+    keys: &[String],
+    args: &[String],
+) -> RedisResult<R> {
+    let mut invocation = script.prepare_invoke();
+    for key in keys {
+        invocation.key(key);
+    }
+    for arg in args {
+        invocation.arg(arg);
+    }
+    invocation.invoke(connection)
+}
+
+/// A process' handle on the shared state. IDs are stored by their `Display` form, which must be unique.
+pub struct RedisCoordinator<T> {
+    /// Shared by the `&self` queries of `BatchProvider`.
+    connection: RefCell<Connection>,
+    scripts: Scripts,
+    /// Init flag, status hash, pending dependency count hash, ready list, incomplete count, lease expiry sorted set,
+    /// lease token hash and lease counter.
+    keys: [String; 8],
+    lease: Option<Lease>,
+    ids: HashMap<String, T>,
+    names: Vec<String>,
+    dependents: Vec<Vec<usize>>,
+    indices: HashMap<String, usize>,
+    /// Tokens of the nodes popped through `BatchProvider`, by name.
+    tokens: HashMap<String, String>,
+}
+
+impl<T: Hash + PartialEq + Eq + Clone + Display> RedisCoordinator<T> {
+    /// Only the graph of `provider` is used, its progress is ignored. Keys are namespaced by `prefix`, one per run.
+    pub fn new(
+        connection: Connection,
+        prefix: impl Into<String>,
+        provider: &TopologicalBatchProvider<T>,
+    ) -> Self {
+        let prefix = prefix.into();
+        let names = provider
+            .ids()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        Self {
+            connection: RefCell::new(connection),
+            scripts: Scripts {
+                init: Script::new(INIT),
+                claim: Script::new(&format!("{}{}", NOW_MS, CLAIM)),
+                heartbeat: Script::new(&format!("{}{}", NOW_MS, HEARTBEAT)),
+                requeue_expired: Script::new(&format!("{}{}", NOW_MS, REQUEUE_EXPIRED)),
+                complete: Script::new(COMPLETE),
+                fail: Script::new(FAIL),
+            },
+            keys: [
+                "init",
+                "status",
+                "pending",
+                "ready",
+                "incomplete",
+                "leases",
+                "owners",
+                "tokens",
+            ]
+            .map(|name| format!("{}:{}", prefix, name)),
+            lease: None,
+            ids: names
+                .iter()
+                .cloned()
+                .zip(provider.ids().iter().cloned())
+                .collect(),
+            indices: names
+                .iter()
+                .enumerate()
+                .map(|(index, name)| (name.clone(), index))
+                .collect(),
+            dependents: (0..names.len())
+                .map(|index| provider.dependents_of_index(index).to_vec())
+                .collect(),
+            names,
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Seeds the shared state, unless an other process did already. Returns true if this call seeded it.
+    pub fn init(&mut self) -> Result<bool, Error> {
+        let mut dependency_counts = vec![0; self.names.len()];
+        for dependents in &self.dependents {
+            for &dependent in dependents {
+                dependency_counts[dependent] += 1;
+            }
+        }
+
+        let args = self
+            .names
+            .iter()
+            .zip(dependency_counts)
+            .flat_map(|(name, count)| [name.clone(), count.to_string()])
+            .collect::<Vec<_>>();
+
+        let seeded: i64 = invoke(
+            self.connection.get_mut(),
+            &self.scripts.init,
+            &self.keys,
+            &args,
+        )?;
+        Ok(seeded == 1)
+    }
+
+    /// Claims nodes as `worker` for `lease` at a time: the lease has to be renewed with `heartbeat` before it
+    /// expires, otherwise the node is handed to an other worker.
+    pub fn with_lease(mut self, worker: impl Into<String>, lease: Duration) -> Self {
+        self.lease = Some(Lease {
+            worker: worker.into(),
+            duration: lease,
+        });
+        self
+    }
+
+    /// Atomically takes a ready node, if any. Nodes of an other graph are an error. With leases, expired ones are
+    /// requeued first.
+    pub fn claim(&mut self) -> Result<Option<Claim<T>>, Error> {
+        let args = match &self.lease {
+            Some(lease) => vec![lease.worker.clone(), lease.duration.as_millis().to_string()],
+            None => vec![String::new(), String::new()],
+        };
+
+        if self.lease.is_some() {
+            self.requeue_expired()?;
+        }
+
+        let claimed: Option<(String, String)> = invoke(
+            self.connection.get_mut(),
+            &self.scripts.claim,
+            &self.keys,
+            &args,
+        )?;
+        let Some((name, token)) = claimed else {
+            return Ok(None);
+        };
+
+        Ok(Some(Claim {
+            node: self.id(&name)?,
+            token,
+        }))
+    }
+
+    /// Renews the lease of a claimed node for the lease duration of `with_lease`, from any process. Call it well
+    /// within the lease duration, eg from a timer thread with its own connection. Fails with `LeaseLost` when the
+    /// lease already expired, and when leases are not enabled.
+    pub fn heartbeat(&mut self, claim: &Claim<T>) -> Result<(), Error> {
+        let Some(lease) = &self.lease else {
+            return Err("Leases are not enabled.".into());
+        };
+
+        let args = [
+            claim.token.clone(),
+            lease.duration.as_millis().to_string(),
+            claim.node.to_string(),
+        ];
+        let extended: i64 = invoke(
+            self.connection.get_mut(),
+            &self.scripts.heartbeat,
+            &self.keys,
+            &args,
+        )?;
+        if extended == 0 {
+            return Err(LeaseLost {
+                node: claim.node.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Hands the running nodes with an expired lease back to the ready set, returning them.
+    pub fn requeue_expired(&mut self) -> Result<Vec<T>, Error> {
+        let requeued: Vec<String> = invoke(
+            self.connection.get_mut(),
+            &self.scripts.requeue_expired,
+            &self.keys,
+            &[],
+        )?;
+        requeued.iter().map(|name| self.id(name)).collect()
+    }
+
+    /// Completes a claimed node, releasing its dependees to every process. Fails with `LeaseLost` if the lease of the
+    /// claim expired.
+    pub fn complete(&mut self, claim: &Claim<T>) -> Result<(), Error> {
+        let name = claim.node.to_string();
+        let index = self.index(&name)?;
+
+        let args = [name.clone(), claim.token.clone()]
+            .into_iter()
+            .chain(
+                self.dependents[index]
+                    .iter()
+                    .map(|&dependent| self.names[dependent].clone()),
+            )
+            .collect::<Vec<_>>();
+
+        let completed: i64 = invoke(
+            self.connection.get_mut(),
+            &self.scripts.complete,
+            &self.keys,
+            &args,
+        )?;
+        if completed == -1 {
+            return Err(LeaseLost { node: name }.into());
+        }
+        Ok(())
+    }
+
+    /// Fails a claimed node and skips its dependees transitively, returning the skipped ones. Fails with `LeaseLost`
+    /// if the lease of the claim expired.
+    pub fn fail(&mut self, claim: &Claim<T>) -> Result<Vec<T>, Error> {
+        let name = claim.node.to_string();
+        let index = self.index(&name)?;
+
+        let mut transitive = vec![];
+        let mut seen = vec![false; self.names.len()];
+        let mut stack = self.dependents[index].clone();
+        while let Some(dependent) = stack.pop() {
+            if !std::mem::replace(&mut seen[dependent], true) {
+                transitive.push(dependent);
+                stack.extend_from_slice(&self.dependents[dependent]);
+            }
+        }
+
+        let args = [name.clone(), claim.token.clone()]
+            .into_iter()
+            .chain(
+                transitive
+                    .into_iter()
+                    .map(|dependent| self.names[dependent].clone()),
+            )
+            .collect::<Vec<_>>();
+
+        let skipped: Option<Vec<String>> = invoke(
+            self.connection.get_mut(),
+            &self.scripts.fail,
+            &self.keys,
+            &args,
+        )?;
+        let Some(skipped) = skipped else {
+            return Err(LeaseLost { node: name }.into());
+        };
+        skipped.iter().map(|name| self.id(name)).collect()
+    }
+
+    /// True when every node finished, in any process.
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        let incomplete: Option<i64> = self.connection.borrow_mut().get(&self.keys[4])?;
+        Ok(incomplete == Some(0))
+    }
+
+    /// The nodes not finished by any process.
+    pub fn unfinished(&self) -> Result<Vec<T>, Error> {
+        let statuses: HashMap<String, String> =
+            self.connection.borrow_mut().hgetall(&self.keys[1])?;
+        statuses
+            .iter()
+            .filter(|(_, status)| !matches!(status.as_str(), "completed" | "failed" | "skipped"))
+            .map(|(name, _)| self.id(name))
+            .collect()
+    }
+
+    /// Deletes the shared state, for reusing the prefix.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        let _: () = self.connection.get_mut().del(&self.keys[..])?;
+        Ok(())
+    }
+
+    /// The claim of a node popped through `BatchProvider`.
+    fn popped(&mut self, node: T) -> Claim<T> {
+        let token = self.tokens.remove(&node.to_string()).unwrap_or_default();
+        Claim { node, token }
+    }
+
+    fn id(&self, name: &str) -> Result<T, Error> {
+        self.ids
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("Unknown node {:?} in Redis.", name).into())
+    }
+
+    fn index(&self, name: &str) -> Result<usize, Error> {
+        self.indices
+            .get(name)
+            .copied()
+            .ok_or_else(|| TopoError::UnknownDependency.into())
+    }
+}
+
+/// Errors reaching the server make `is_empty` false and `unfinished` empty, the next `pop` then ends the run with the
+/// error.
+impl<T: Hash + PartialEq + Eq + Clone + Display> BatchProvider<T> for RedisCoordinator<T> {
+    fn pop(&mut self) -> Result<Option<T>, Error> {
+        let Some(claim) = self.claim()? else {
+            return Ok(None);
+        };
+
+        self.tokens.insert(claim.node.to_string(), claim.token);
+        Ok(Some(claim.node))
+    }
+
+    fn complete(&mut self, node: T) -> Result<(), Error> {
+        let claim = self.popped(node);
+        RedisCoordinator::complete(self, &claim)
+    }
+
+    fn fail(&mut self, node: T) -> Result<Vec<T>, Error> {
+        let claim = self.popped(node);
+        RedisCoordinator::fail(self, &claim)
+    }
+
+    fn is_empty(&self) -> bool {
+        RedisCoordinator::is_empty(self).unwrap_or(false)
+    }
+
+    fn unfinished(&self) -> Vec<T> {
+        RedisCoordinator::unfinished(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::thread_pool_runner::*;
+    use std::{env, process, sync::Arc};
+
+    fn coordinator() -> RedisCoordinator<usize> {
+        let url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        let connection = redis::Client::open(url).unwrap().get_connection().unwrap();
+        let provider = TopologicalBatchProvider::try_from_iter([
+            (1, vec![]),
+            (2, vec![1]),
+            (3, vec![2]),
+            (4, vec![]),
+        ])
+        .unwrap();
+
+        RedisCoordinator::new(
+            connection,
+            format!("topological_batch_test_{}", process::id()),
+            &provider,
+        )
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_URL"]
+    fn it_coordinates_through_redis() {
+        let mut first = coordinator();
+        let mut second = coordinator();
+        first.reset().unwrap();
+
+        assert!(first.init().unwrap());
+        assert!(!second.init().unwrap());
+
+        let mut claimed = [
+            first.claim().unwrap().unwrap(),
+            second.claim().unwrap().unwrap(),
+        ];
+        claimed.sort_unstable_by_key(|claim| claim.node);
+        assert_eq!(
+            vec![1, 4],
+            claimed.iter().map(|claim| claim.node).collect::<Vec<_>>()
+        );
+        assert_eq!(None, first.claim().unwrap());
+
+        second.complete(&claimed[0]).unwrap();
+        let claim = first.claim().unwrap().unwrap();
+        assert_eq!(2, claim.node);
+        assert_eq!(vec![3], first.fail(&claim).unwrap());
+        assert!(!second.is_empty().unwrap());
+        second.complete(&claimed[1]).unwrap();
+        assert!(first.is_empty().unwrap());
+
+        first.reset().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_URL"]
+    fn it_requeues_nodes_of_dead_workers() {
+        let mut crashing = coordinator().with_lease("crashing", Duration::from_millis(50));
+        let mut alive = coordinator().with_lease("alive", Duration::from_secs(60));
+        crashing.reset().unwrap();
+        crashing.init().unwrap();
+
+        let first = crashing.claim().unwrap().unwrap();
+        let second = alive.claim().unwrap().unwrap();
+        alive.heartbeat(&second).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(vec![first.node], alive.requeue_expired().unwrap());
+        let reclaimed = alive.claim().unwrap().unwrap();
+        assert_eq!(first.node, reclaimed.node);
+        assert_ne!(first.token, reclaimed.token);
+        assert_eq!(None, alive.claim().unwrap());
+
+        // The stale worker can neither renew nor finish the node handed to an other one.
+        for err in [crashing.heartbeat(&first), crashing.complete(&first)] {
+            assert!(err.unwrap_err().downcast_ref::<LeaseLost>().is_some());
+        }
+        assert!(crashing.fail(&first).is_err());
+
+        // An other process can finish the node with the claim's token.
+        let mut helper = coordinator().with_lease("helper", Duration::from_secs(60));
+        helper.heartbeat(&reclaimed).unwrap();
+        helper.complete(&reclaimed).unwrap();
+        alive.complete(&second).unwrap();
+
+        alive.reset().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_URL"]
+    fn it_runs_with_the_thread_pool_runner() {
+        let mut worker = coordinator();
+        worker.reset().unwrap();
+        worker.init().unwrap();
+
+        let report = ThreadPoolRunner::new(2).run(worker, Arc::new(FailingExecutor(2)));
+        assert_eq!(vec![3], report.skipped);
+
+        let mut observer = coordinator();
+        assert!(observer.is_empty().unwrap());
+        observer.reset().unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+//! Textual renderings of a provider's dependency graph, to be embedded in documentation, issues or terminal output.
+
+use super::topological_batch_provider::*;
+use std::{fmt::Display, hash::Hash};
+
+impl<T: Hash + PartialEq + Eq + Clone + Display> TopologicalBatchProvider<T> {
+    /// Mermaid flowchart (`graph TD`) of the dependency graph. Edges point from a dependency to its dependee, ie in
+    /// the order of execution.
+    ///
+    /// Edges of pruned nodes (see `prune_completed`) are no longer known, so they are not rendered.
+    pub fn to_mermaid(&self) -> String {
+        self.mermaid(false)
+    }
+
+    /// Same as `to_mermaid`, with every node styled by its current `NodeStatus`.
+    pub fn to_mermaid_with_status(&self) -> String {
+        self.mermaid(true)
+    }
+
+    /// Execution plan for terminal output: one block per batch, every node listed with its dependencies. Nodes in a
+    /// batch are sorted by their label, so the output is stable across runs. For example:
+    ///
+    /// ```text
+    /// Batch 1
+    /// ├── fetch
+    /// └── lint
+    /// Batch 2
+    /// └── build <- fetch, lint
+    /// ```
+    pub fn render_plan(&self) -> String {
+        let mut dependencies = vec![vec![]; self.ids().len()];
+        for index in 0..self.ids().len() {
+            for &dependent in self.dependents_of_index(index) {
+                dependencies[dependent].push(self.ids()[index].to_string());
+            }
+        }
+
+        let mut out = String::new();
+
+        for (batch, level) in self.level_indices().into_iter().enumerate() {
+            out.push_str(&format!("Batch {}\n", batch + 1));
+
+            let mut rows = level
+                .into_iter()
+                .map(|index| {
+                    let mut row = self.ids()[index].to_string();
+                    if !dependencies[index].is_empty() {
+                        dependencies[index].sort();
+                        row.push_str(&format!(" <- {}", dependencies[index].join(", ")));
+                    }
+                    row
+                })
+                .collect::<Vec<_>>();
+            rows.sort();
+
+            for (i, row) in rows.iter().enumerate() {
+                let branch = if i + 1 == rows.len() {
+                    "└──"
+                } else {
+                    "├──"
+                };
+                out.push_str(&format!("{} {}\n", branch, row));
+            }
+        }
+
+        out
+    }
+
+    fn mermaid(&self, with_status: bool) -> String {
+        let mut out = String::from("graph TD\n");
+
+        for (index, id) in self.ids().iter().enumerate() {
+            out.push_str(&format!(
+                "    n{}[\"{}\"]\n",
+                index,
+                escape_mermaid(&id.to_string())
+            ));
+        }
+
+        for index in 0..self.ids().len() {
+            for dependent in self.dependents_of_index(index) {
+                out.push_str(&format!("    n{} --> n{}\n", index, dependent));
+            }
+        }
+
+        if with_status {
+            out.push_str("    classDef pending fill:#eeeeee,stroke:#999999\n");
+            out.push_str("    classDef available fill:#fff3b0,stroke:#c9a800\n");
+            out.push_str("    classDef running fill:#b0d4ff,stroke:#2f6fbf\n");
+            out.push_str("    classDef completed fill:#b8f0b8,stroke:#2f8f2f\n");
+            out.push_str("    classDef satisfied fill:#d8f5e0,stroke:#2f8f2f,stroke-dasharray:4\n");
+            out.push_str("    classDef failed fill:#ffb8b8,stroke:#bf2f2f\n");
+            out.push_str("    classDef skipped fill:#f5f5f5,stroke:#999999,stroke-dasharray:4\n");
+
+            for index in 0..self.ids().len() {
+                out.push_str(&format!(
+                    "    class n{} {}\n",
+                    index,
+                    status_class(self.status_of_index(index))
+                ));
+            }
+        }
+
+        out
+    }
+}
+
+fn status_class(status: NodeStatus) -> &'static str {
+    match status {
+        NodeStatus::Pending => "pending",
+        NodeStatus::Available => "available",
+        NodeStatus::Running => "running",
+        NodeStatus::Completed => "completed",
+        NodeStatus::Satisfied => "satisfied",
+        NodeStatus::Failed => "failed",
+        NodeStatus::Skipped => "skipped",
+    }
+}
+
+/// Mermaid labels are quoted, quotes inside need to be entity encoded.
+fn escape_mermaid(label: &str) -> String {
+    label.replace('"', "#quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_mermaid() {
+        let topological_batch_provider =
+            TopologicalBatchProvider::try_from_iter([("a", vec![]), ("b", vec!["a"])]).unwrap();
+
+        assert_eq!(
+            "graph TD\n    n0[\"a\"]\n    n1[\"b\"]\n    n0 --> n1\n",
+            topological_batch_provider.to_mermaid()
+        );
+    }
+
+    #[test]
+    fn it_renders_plan() {
+        let topological_batch_provider = TopologicalBatchProvider::try_from_iter([
+            ("fetch", vec![]),
+            ("lint", vec![]),
+            ("build", vec!["lint", "fetch"]),
+            ("test", vec!["build"]),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            "Batch 1\n├── fetch\n└── lint\nBatch 2\n└── build <- fetch, lint\nBatch 3\n└── test <- build\n",
+            topological_batch_provider.render_plan()
+        );
+    }
+
+    #[test]
+    fn it_renders_mermaid_with_status() {
+        let mut topological_batch_provider = TopologicalBatchProvider::try_from_iter([
+            ("a", vec![]),
+            ("\"b\"", vec!["a"]),
+            ("c", vec![]),
+        ])
+        .unwrap();
+        topological_batch_provider
+            .mark_external_complete(&"c")
+            .unwrap();
+        topological_batch_provider.pop();
+
+        let mermaid = topological_batch_provider.to_mermaid_with_status();
+
+        assert!(mermaid.contains("    n1[\"#quot;b#quot;\"]\n"));
+        assert!(mermaid.contains("    class n0 running\n"));
+        assert!(mermaid.contains("    class n1 pending\n"));
+        assert!(mermaid.contains("    class n2 satisfied\n"));
+        // Every class used is defined.
+        for class in mermaid
+            .lines()
+            .filter_map(|line| line.strip_prefix("    class "))
+        {
+            let class = class.split(' ').nth(1).unwrap();
+            assert!(mermaid.contains(&format!("    classDef {} ", class)));
+        }
+    }
+}
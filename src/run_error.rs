@@ -0,0 +1,210 @@
+//! Errors of a run carrying the node they originate from, for callers propagating them with `?` (eg into an
+//! `anyhow::Error`). The executor error is kept as the `source` of the run error instead of being formatted into it, so
+//! error reporters print the whole chain.
+
+use super::cancel::*;
+use super::command::*;
+use super::common::*;
+use std::{
+    any::Any,
+    error,
+    fmt::{self, Debug, Display},
+    time::Duration,
+};
+
+/// Why a node did not complete. `E` is the executor error: `Error` as returned by the executor, or an other owner of a
+/// `dyn Error` through `map_source` (eg an `Arc` to share it).
+#[derive(Debug)]
+pub enum RunError<T, E = Error> {
+    /// The executor returned an error.
+    Failed { node: T, source: E },
+    /// The executor panicked, `message` is the panic payload when it was a string. A panic ends the whole run, see
+    /// `ThreadPoolRunner::try_run`; `node` is `None` when it came from a callback of the run (an observer, the
+    /// scheduler, ...) instead of an executor.
+    Panicked {
+        node: Option<T>,
+        message: Option<String>,
+    },
+    /// The node was stopped after running longer than `timeout`, see `CommandOptions::with_timeout`.
+    TimedOut { node: T, timeout: Duration },
+    /// The node was interrupted or never started, as the run was cancelled.
+    Cancelled { node: T },
+    /// The run got stuck with nodes left but none available nor executing, eg as a popped node was never completed by
+    /// a custom provider. `stuck` are the unfinished nodes. See `RunOptions::with_deadlock_detection`.
+    Deadlock { stuck: Vec<T> },
+    /// The provider failed to pop, complete or fail a node, eg on I/O, see `BatchProvider`.
+    Provider { source: E },
+}
+
+impl<T> RunError<T> {
+    /// Classifies an error returned for `node`: timeouts and cancellations reported by the bundled executors get their
+    /// own variant, anything else is `Failed`.
+    pub fn from_failure(node: T, err: Error) -> Self {
+        if let Some(timed_out) = err.downcast_ref::<CommandTimedOut>() {
+            return RunError::TimedOut {
+                node,
+                timeout: timed_out.timeout,
+            };
+        }
+        if err.is::<Cancelled>() {
+            return RunError::Cancelled { node };
+        }
+
+        RunError::Failed { node, source: err }
+    }
+}
+
+impl<T, E> RunError<T, E> {
+    /// Keeps the message of the payload caught by `std::panic::catch_unwind`.
+    pub fn panicked(node: Option<T>, payload: &(dyn Any + Send)) -> Self {
+        RunError::Panicked {
+            node,
+            message: panic_message(payload),
+        }
+    }
+
+    /// `None` for panics out of the run's callbacks, deadlocks and provider errors.
+    pub fn node(&self) -> Option<&T> {
+        match self {
+            RunError::Failed { node, .. }
+            | RunError::TimedOut { node, .. }
+            | RunError::Cancelled { node } => Some(node),
+            RunError::Panicked { node, .. } => node.as_ref(),
+            RunError::Deadlock { .. } | RunError::Provider { .. } => None,
+        }
+    }
+
+    pub fn map_source<F>(self, f: impl FnOnce(E) -> F) -> RunError<T, F> {
+        match self {
+            RunError::Failed { node, source } => RunError::Failed {
+                node,
+                source: f(source),
+            },
+            RunError::Panicked { node, message } => RunError::Panicked { node, message },
+            RunError::TimedOut { node, timeout } => RunError::TimedOut { node, timeout },
+            RunError::Cancelled { node } => RunError::Cancelled { node },
+            RunError::Deadlock { stuck } => RunError::Deadlock { stuck },
+            RunError::Provider { source } => RunError::Provider { source: f(source) },
+        }
+    }
+}
+
+/// Names the node only, the executor error being the `source`.
+impl<T: Display, E> Display for RunError<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Failed { node, .. } => write!(f, "Node {} failed.", node),
+            RunError::Panicked { node, message } => {
+                match node {
+                    Some(node) => write!(f, "Node {} panicked", node)?,
+                    None => write!(f, "Run panicked")?,
+                }
+                match message {
+                    Some(message) => write!(f, ": {}", message),
+                    None => write!(f, "."),
+                }
+            }
+            RunError::TimedOut { node, timeout } => {
+                write!(f, "Node {} timed out after {:?}.", node, timeout)
+            }
+            RunError::Cancelled { node } => write!(f, "Node {} was cancelled.", node),
+            RunError::Deadlock { stuck } => {
+                write!(f, "Deadlock detected, stuck nodes:")?;
+                for node in stuck {
+                    write!(f, " {}", node)?;
+                }
+                write!(f, ".")
+            }
+            RunError::Provider { .. } => write!(f, "Provider failed."),
+        }
+    }
+}
+
+impl<T, E> error::Error for RunError<T, E>
+where
+    T: Debug + Display,
+    E: Debug + AsRef<dyn error::Error + Send + Sync>,
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RunError::Failed { source, .. } | RunError::Provider { source } => {
+                Some(source.as_ref())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The message of a panic payload, when it is a string.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> Option<String> {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+}
+
+impl<T> RunReport<T> {
+    /// The failed and cancelled nodes as errors, failures first. Skipped nodes are left out: they are a consequence
+    /// of the failures.
+    pub fn into_errors(self) -> Vec<RunError<T>> {
+        self.failed
+            .into_iter()
+            .map(|(node, err)| RunError::from_failure(node, err))
+            .chain(
+                self.cancelled
+                    .into_iter()
+                    .map(|node| RunError::Cancelled { node }),
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{error::Error as _, panic, sync::Arc};
+
+    #[test]
+    fn it_chains_executor_errors() {
+        let mut report = RunReport::new();
+        report.failed.push(("build", "compiler crashed".into()));
+        report.failed.push((
+            "test",
+            CommandTimedOut {
+                timeout: Duration::from_secs(2),
+            }
+            .into(),
+        ));
+        report.failed.push(("lint", Cancelled.into()));
+        report.cancelled.push("deploy");
+
+        let errors = report.into_errors();
+        let messages = errors.iter().map(|err| err.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "Node build failed.",
+                "Node test timed out after 2s.",
+                "Node lint was cancelled.",
+                "Node deploy was cancelled."
+            ],
+            messages
+        );
+        assert_eq!("compiler crashed", errors[0].source().unwrap().to_string());
+        assert!(errors[1].source().is_none());
+        assert_eq!(Some(&"deploy"), errors[3].node());
+
+        let shared = RunError::from_failure(1, Error::from(MissingArtifact { path: "out".into() }))
+            .map_source(Arc::<dyn error::Error + Send + Sync>::from);
+        assert!(shared.source().unwrap().is::<MissingArtifact>());
+
+        let payload = panic::catch_unwind(|| panic!("Panicking on purpose.")).unwrap_err();
+        assert_eq!(
+            "Node 1 panicked: Panicking on purpose.",
+            RunError::<_, Error>::panicked(Some(1), payload.as_ref()).to_string()
+        );
+        assert_eq!(
+            "Run panicked: Panicking on purpose.",
+            RunError::<usize, Error>::panicked(None, payload.as_ref()).to_string()
+        );
+    }
+}
@@ -0,0 +1,592 @@
+//! Newline delimited JSON log of a run, one event per line, and its replay into a timeline for post-mortem analysis.
+//! Timestamps are microseconds since the creation of the logger.
+//!
+//! ```text
+//! {"ts_us":12,"event":"node_start","node":"a","worker":0}
+//! {"ts_us":500,"event":"node_slow","node":"a","worker":0,"elapsed_us":488}
+//! {"ts_us":950,"event":"node_finish","node":"a","worker":0,"outcome":"failed","duration_us":938,"error":"boom"}
+//! {"ts_us":951,"event":"node_skipped","node":"b"}
+//! {"ts_us":1003,"event":"run_finish","completed":0,"failed":1,"skipped":1,"cached":0,"satisfied":0,"cancelled":0}
+//! ```
+//!
+//! With the `zstd` feature, logs of large runs can be written compressed with `RunLogger::to_zstd_file` and read back
+//! with `replay_zstd_file`.
+
+use super::common::*;
+use super::json;
+use super::observer::*;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io::{self, BufRead, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Events written between two flushes of the writer, unless set with `RunLogger::with_flush_every`.
+pub const DEFAULT_FLUSH_EVERY: usize = 1000;
+
+struct LogWriter {
+    writer: Box<dyn Write + Send>,
+    error: Option<io::Error>,
+    /// Events written since the last flush.
+    unflushed: usize,
+}
+
+impl LogWriter {
+    fn flush(&mut self) {
+        self.unflushed = 0;
+        if self.error.is_none() {
+            if let Err(err) = self.writer.flush() {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+/// Observer writing the run log. The writer is flushed every `DEFAULT_FLUSH_EVERY` events and when the run finishes.
+/// Write errors don't interrupt the run, the first one is returned by `flush`.
+pub struct RunLogger {
+    origin: Instant,
+    writer: Mutex<LogWriter>,
+    flush_every: usize,
+}
+
+impl RunLogger {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            origin: Instant::now(),
+            writer: Mutex::new(LogWriter {
+                writer: Box::new(writer),
+                error: None,
+                unflushed: 0,
+            }),
+            flush_every: DEFAULT_FLUSH_EVERY,
+        }
+    }
+
+    /// Flushes the writer after every `events` events (at least 1), bounding what a crash loses.
+    pub fn with_flush_every(mut self, events: usize) -> Self {
+        self.flush_every = events.max(1);
+        self
+    }
+
+    /// Logs into a newly created file at `path`.
+    pub fn to_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Logs into a newly created file at `path`, compressed with Zstandard at `level` (0 for the default). Every
+    /// `flush` ends a compressed frame, so a log cut short by a crash is readable up to the last flush. Available with
+    /// the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    pub fn to_zstd_file(path: impl AsRef<Path>, level: i32) -> io::Result<Self> {
+        Ok(Self::new(ZstdFrames::new(File::create(path)?, level)?))
+    }
+
+    /// Flushes the writer, returning the first error that happened while logging.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(err) = writer.error.take() {
+            return Err(err);
+        }
+        writer.writer.flush()
+    }
+
+    fn log(&self, event: &str, fields: &[(&str, String)]) {
+        let mut line = format!(
+            r#"{{"ts_us":{},"event":{}"#,
+            self.origin.elapsed().as_micros(),
+            json::string(event)
+        );
+        for (key, value) in fields {
+            line.push_str(&format!(",{}:{}", json::string(key), value));
+        }
+        line.push('}');
+
+        let mut writer = self.writer.lock().unwrap();
+        if writer.error.is_none() {
+            if let Err(err) = writeln!(writer.writer, "{}", line) {
+                writer.error = Some(err);
+            }
+        }
+        writer.unflushed += 1;
+        if writer.unflushed >= self.flush_every {
+            writer.flush();
+        }
+    }
+}
+
+impl<T: Display> RunObserver<T> for RunLogger {
+    fn on_node_start(&self, worker: usize, id: &T) {
+        self.log(
+            "node_start",
+            &[
+                ("node", json::string(&id.to_string())),
+                ("worker", worker.to_string()),
+            ],
+        );
+    }
+
+    fn on_node_finish(&self, worker: usize, id: &T, outcome: NodeOutcome<'_>, duration: Duration) {
+        let mut fields = vec![
+            ("node", json::string(&id.to_string())),
+            ("worker", worker.to_string()),
+            (
+                "outcome",
+                json::string(RecordedOutcome::from(&outcome).name()),
+            ),
+            ("duration_us", duration.as_micros().to_string()),
+        ];
+        if let NodeOutcome::Failed(err) = outcome {
+            fields.push(("error", json::string(&err.to_string())));
+        }
+
+        self.log("node_finish", &fields);
+    }
+
+    fn on_node_skipped(&self, id: &T) {
+        self.log("node_skipped", &[("node", json::string(&id.to_string()))]);
+    }
+
+    fn on_node_slow(&self, worker: usize, id: &T, elapsed: Duration) {
+        self.log(
+            "node_slow",
+            &[
+                ("node", json::string(&id.to_string())),
+                ("worker", worker.to_string()),
+                ("elapsed_us", elapsed.as_micros().to_string()),
+            ],
+        );
+    }
+
+    fn on_run_finish(&self, report: &RunReport<T>) {
+        self.log(
+            "run_finish",
+            &[
+                ("completed", report.completed.len().to_string()),
+                ("failed", report.failed.len().to_string()),
+                ("skipped", report.skipped.len().to_string()),
+                ("cached", report.cached.len().to_string()),
+                ("satisfied", report.satisfied.len().to_string()),
+                ("cancelled", report.cancelled.len().to_string()),
+            ],
+        );
+
+        self.writer.lock().unwrap().flush();
+    }
+}
+
+/// How a logged node execution ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordedOutcome {
+    Completed,
+    Failed,
+    Cached,
+}
+
+impl RecordedOutcome {
+    fn name(self) -> &'static str {
+        match self {
+            RecordedOutcome::Completed => "completed",
+            RecordedOutcome::Failed => "failed",
+            RecordedOutcome::Cached => "cached",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "completed" => Some(RecordedOutcome::Completed),
+            "failed" => Some(RecordedOutcome::Failed),
+            "cached" => Some(RecordedOutcome::Cached),
+            _ => None,
+        }
+    }
+}
+
+impl From<&NodeOutcome<'_>> for RecordedOutcome {
+    fn from(outcome: &NodeOutcome<'_>) -> Self {
+        match outcome {
+            NodeOutcome::Completed => RecordedOutcome::Completed,
+            NodeOutcome::Failed(_) => RecordedOutcome::Failed,
+            NodeOutcome::Cached => RecordedOutcome::Cached,
+        }
+    }
+}
+
+/// A node execution reconstructed from the log. Times are relative to the creation of the logger.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeRecord {
+    pub id: String,
+    pub worker: usize,
+    pub start: Duration,
+    /// `None` if the log ended while the node was running.
+    pub finish: Option<Duration>,
+    pub outcome: Option<RecordedOutcome>,
+    pub error: Option<String>,
+}
+
+/// A run reconstructed from its log.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunTimeline {
+    /// Executions in the order they were started.
+    pub nodes: Vec<NodeRecord>,
+    pub skipped: Vec<String>,
+    /// `None` if the run didn't finish, such as when the process crashed.
+    pub finished_at: Option<Duration>,
+}
+
+impl RunTimeline {
+    /// Nodes which were running when the log ended.
+    pub fn unfinished(&self) -> impl Iterator<Item = &NodeRecord> {
+        self.nodes.iter().filter(|node| node.finish.is_none())
+    }
+
+    /// Executions of a worker, in order.
+    pub fn worker(&self, worker: usize) -> impl Iterator<Item = &NodeRecord> {
+        self.nodes.iter().filter(move |node| node.worker == worker)
+    }
+}
+
+/// A node started on a worker, see `RunTimeline::schedule`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dispatch<T> {
+    pub id: T,
+    pub worker: usize,
+}
+
+impl RunTimeline {
+    /// The dispatches of the run in order, to replay it with `DeterministicRunner::replay`. Nodes are parsed back
+    /// from their logged display form.
+    pub fn schedule<T: FromStr>(&self) -> Result<Vec<Dispatch<T>>, Error> {
+        self.nodes
+            .iter()
+            .map(|node| {
+                let id = node
+                    .id
+                    .parse()
+                    .map_err(|_| format!("Invalid node in schedule: {}.", node.id))?;
+                Ok(Dispatch {
+                    id,
+                    worker: node.worker,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Writer compressing into a new Zstandard frame after every flush. Decoders read concatenated frames as one stream.
+#[cfg(feature = "zstd")]
+struct ZstdFrames {
+    /// Missing only when starting a frame failed.
+    encoder: Option<zstd::Encoder<'static, File>>,
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdFrames {
+    fn new(file: File, level: i32) -> io::Result<Self> {
+        Ok(Self {
+            encoder: Some(zstd::Encoder::new(file, level)?),
+            level,
+        })
+    }
+
+    fn closed() -> io::Error {
+        io::Error::other("Compressed log is closed.")
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Write for ZstdFrames {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.as_mut().ok_or_else(Self::closed)?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let encoder = self.encoder.take().ok_or_else(Self::closed)?;
+
+        let mut file = encoder.finish()?;
+        file.flush()?;
+        self.encoder = Some(zstd::Encoder::new(file, self.level)?);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Drop for ZstdFrames {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.encoder.take() {
+            let _ = encoder.finish();
+        }
+    }
+}
+
+/// Ends the stream at a frame cut short, instead of failing, dropping the line the frame was cut in.
+#[cfg(feature = "zstd")]
+struct UntilTruncated<R> {
+    inner: R,
+    /// Decoded bytes not passed on yet, as the line they end with may be cut short.
+    pending: Vec<u8>,
+    ended: bool,
+}
+
+#[cfg(feature = "zstd")]
+impl<R: io::Read> UntilTruncated<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            pending: vec![],
+            ended: false,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<R: io::Read> io::Read for UntilTruncated<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let complete = if self.ended {
+                self.pending.len()
+            } else {
+                self.pending
+                    .iter()
+                    .rposition(|byte| *byte == b'\n')
+                    .map_or(0, |index| index + 1)
+            };
+            if complete > 0 || self.ended {
+                let len = complete.min(buf.len());
+                buf[..len].copy_from_slice(&self.pending[..len]);
+                self.pending.drain(..len);
+                return Ok(len);
+            }
+
+            let mut chunk = [0; 8192];
+            match self.inner.read(&mut chunk) {
+                Ok(0) => self.ended = true,
+                Ok(len) => self.pending.extend_from_slice(&chunk[..len]),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.pending.clear();
+                    self.ended = true;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Same as `replay`, from a file written by `RunLogger::to_zstd_file`. A frame cut short by a crash ends the log, the
+/// line it was cut in being dropped.
+/// Available with the `zstd` feature.
+#[cfg(feature = "zstd")]
+pub fn replay_zstd_file(path: impl AsRef<Path>) -> Result<RunTimeline, Error> {
+    let decoder = zstd::Decoder::new(File::open(path)?)?;
+    replay(io::BufReader::new(UntilTruncated::new(decoder)))
+}
+
+/// Reconstructs the timeline from a log written by `RunLogger`. Unknown events are ignored.
+pub fn replay(reader: impl BufRead) -> Result<RunTimeline, Error> {
+    let mut timeline = RunTimeline::default();
+    let mut running: HashMap<usize, usize> = HashMap::new();
+
+    for (line_index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let invalid = |reason: &str| format!("Invalid run log line {}: {}", line_index + 1, reason);
+        let event = json::parse_object(&line).map_err(|reason| invalid(&reason))?;
+        let str_field = |key: &str| {
+            event
+                .get(key)
+                .and_then(json::Value::as_str)
+                .ok_or_else(|| invalid(&format!("missing {}.", key)))
+        };
+        let u64_field = |key: &str| {
+            event
+                .get(key)
+                .and_then(json::Value::as_u64)
+                .ok_or_else(|| invalid(&format!("missing {}.", key)))
+        };
+
+        let ts = Duration::from_micros(u64_field("ts_us")?);
+
+        match str_field("event")? {
+            "node_start" => {
+                let worker = u64_field("worker")? as usize;
+                running.insert(worker, timeline.nodes.len());
+                timeline.nodes.push(NodeRecord {
+                    id: str_field("node")?.to_string(),
+                    worker,
+                    start: ts,
+                    finish: None,
+                    outcome: None,
+                    error: None,
+                });
+            }
+            "node_finish" => {
+                let worker = u64_field("worker")? as usize;
+                let index = running
+                    .remove(&worker)
+                    .ok_or_else(|| invalid("finish without start."))?;
+                let node = &mut timeline.nodes[index];
+                node.finish = Some(ts);
+                node.outcome = Some(
+                    RecordedOutcome::from_name(str_field("outcome")?)
+                        .ok_or_else(|| invalid("unknown outcome."))?,
+                );
+                node.error = event
+                    .get("error")
+                    .and_then(json::Value::as_str)
+                    .map(str::to_string);
+            }
+            "node_skipped" => timeline.skipped.push(str_field("node")?.to_string()),
+            "run_finish" => timeline.finished_at = Some(ts),
+            _ => {}
+        }
+    }
+
+    Ok(timeline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::thread_pool_runner::*;
+    use crate::topological_batch_provider::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_replays_the_logged_run() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+
+        let buffer = SharedBuffer::default();
+        let logger = Arc::new(RunLogger::new(buffer.clone()));
+
+        ThreadPoolRunner::new(2).run_with_options(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            Arc::new(FailingExecutor(2)),
+            RunOptions::new().with_observer(logger.clone()),
+        );
+        logger.flush().unwrap();
+
+        let log = buffer.0.lock().unwrap().clone();
+        let timeline = replay(&log[..]).unwrap();
+
+        let ids = timeline
+            .nodes
+            .iter()
+            .map(|node| node.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["1", "2"], ids);
+        assert_eq!(Some(RecordedOutcome::Completed), timeline.nodes[0].outcome);
+        assert_eq!(Some(RecordedOutcome::Failed), timeline.nodes[1].outcome);
+        assert_eq!(Some("broken"), timeline.nodes[1].error.as_deref());
+        assert_eq!(vec!["3".to_string()], timeline.skipped);
+        assert!(timeline.finished_at.is_some());
+        assert_eq!(0, timeline.unfinished().count());
+    }
+
+    #[test]
+    fn it_replays_interrupted_logs() {
+        let log = concat!(
+            r#"{"ts_us":1,"event":"node_start","node":"a","worker":0}"#,
+            "\n",
+            r#"{"ts_us":2,"event":"node_start","node":"b","worker":1}"#,
+            "\n",
+            r#"{"ts_us":5,"event":"node_finish","node":"a","worker":0,"outcome":"completed","duration_us":4}"#,
+            "\n",
+        );
+
+        let timeline = replay(log.as_bytes()).unwrap();
+
+        assert_eq!(None, timeline.finished_at);
+        assert_eq!(
+            vec!["b"],
+            timeline
+                .unfinished()
+                .map(|node| node.id.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(Duration::from_micros(5)), timeline.nodes[0].finish);
+
+        assert!(replay(&b"{\"event\":\"node_start\"}\n"[..]).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn it_replays_compressed_logs() {
+        let path = std::env::temp_dir().join(format!(
+            "topological_batch_log_{}.jsonl.zst",
+            std::process::id()
+        ));
+
+        let logger = RunLogger::to_zstd_file(&path, 0)
+            .unwrap()
+            .with_flush_every(2);
+        RunObserver::<&str>::on_node_start(&logger, 0, &"a");
+        RunObserver::<&str>::on_node_start(&logger, 1, &"b");
+        // Crash before the next flush.
+        RunObserver::<&str>::on_node_skipped(&logger, &"c");
+        std::mem::forget(logger);
+
+        let timeline = replay_zstd_file(&path).unwrap();
+        assert_eq!(2, timeline.unfinished().count());
+        assert!(timeline.skipped.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn it_drops_the_line_a_compressed_log_is_cut_in() {
+        let path = std::env::temp_dir().join(format!(
+            "topological_batch_log_cut_{}.jsonl.zst",
+            std::process::id()
+        ));
+
+        let logger = RunLogger::to_zstd_file(&path, 0)
+            .unwrap()
+            .with_flush_every(usize::MAX);
+        RunObserver::<&str>::on_node_start(&logger, 0, &"a");
+        logger.flush().unwrap();
+        let flushed = std::fs::metadata(&path).unwrap().len();
+        // Spans several compressed blocks, so part of it decodes before the cut.
+        for worker in 1..20_000 {
+            RunObserver::<String>::on_node_start(&logger, worker, &format!("{:x}", worker * 7919));
+        }
+        logger.flush().unwrap();
+        drop(logger);
+
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(flushed + (len - flushed) * 3 / 4).unwrap();
+
+        let started = replay_zstd_file(&path).unwrap().unfinished().count();
+        assert!(started > 1 && started < 20_000);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
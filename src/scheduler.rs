@@ -0,0 +1,261 @@
+//! Pluggable policies for picking the next node among the available ones. The provider delegates its `pop` to the
+//! scheduler set with `TopologicalBatchProvider::with_scheduler`.
+//!
+//! Schedulers work on the provider's dense node indices, IDs are only there for domain specific decisions.
+
+use super::rng::*;
+use super::topological_batch_provider::*;
+use std::{collections::HashMap, hash::Hash};
+
+/// An available node offered to the scheduler.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadyNode<'a, T> {
+    pub index: usize,
+    pub id: &'a T,
+}
+
+/// Read-only view of the provider's graph for schedulers.
+#[derive(Debug)]
+pub struct GraphView<'a, T> {
+    pub(crate) ids: &'a [T],
+    pub(crate) indices: &'a HashMap<T, usize>,
+    pub(crate) dependents: &'a [Vec<usize>],
+    pub(crate) statuses: &'a [NodeStatus],
+}
+
+impl<'a, T: Hash + PartialEq + Eq> GraphView<'a, T> {
+    pub fn node_count(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn id(&self, index: usize) -> &'a T {
+        &self.ids[index]
+    }
+
+    /// `None` for unknown (or pruned) IDs.
+    pub fn index(&self, id: &T) -> Option<usize> {
+        self.indices.get(id).copied()
+    }
+
+    /// Indices of the nodes directly depending on the node.
+    pub fn dependents(&self, index: usize) -> &'a [usize] {
+        &self.dependents[index]
+    }
+
+    pub fn status(&self, index: usize) -> NodeStatus {
+        self.statuses[index]
+    }
+
+    /// Number of distinct nodes transitively depending on each node, by index. Takes `O(nodes * edges)` in the worst
+    /// case, so it is meant to be computed once.
+    pub fn transitive_dependent_counts(&self) -> Vec<usize> {
+        let mut visited_by = vec![usize::MAX; self.ids.len()];
+        let mut stack = vec![];
+
+        (0..self.ids.len())
+            .map(|index| {
+                let mut count = 0;
+                stack.extend_from_slice(&self.dependents[index]);
+
+                while let Some(dependent) = stack.pop() {
+                    if visited_by[dependent] != index {
+                        visited_by[dependent] = index;
+                        count += 1;
+                        stack.extend_from_slice(&self.dependents[dependent]);
+                    }
+                }
+
+                count
+            })
+            .collect()
+    }
+}
+
+pub trait Scheduler<T> {
+    /// Picks the next node to pop. `ready` is never empty, the returned value is a position in it.
+    fn select(&mut self, ready: &[ReadyNode<'_, T>], graph: &GraphView<'_, T>) -> usize;
+
+    /// A copy for a cloned provider or a snapshot, carrying on from the same state.
+    fn clone_box(&self) -> Box<dyn Scheduler<T> + Send>;
+
+    /// The provider dropped the nodes flagged in `removed` (by their old index, see
+    /// `TopologicalBatchProvider::remove_node`), the indices of the others shifting down in the same order. State kept
+    /// by index has to follow, see `compacted`. Does nothing by default.
+    fn compact(&mut self, _removed: &[bool]) {}
+}
+
+/// Drops the values of the removed indices from per-index `values`, the others following their node's new index.
+/// `values` may be shorter than `removed`, for nodes added after it was computed.
+pub fn compacted<V>(values: Vec<V>, removed: &[bool]) -> Vec<V> {
+    values
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !**removed)
+        .map(|(value, _)| value)
+        .collect()
+}
+
+/// Scheduler picking the node with the highest static priority (by index), ties broken by the lower position.
+#[derive(Debug, Clone)]
+pub struct PriorityScheduler {
+    priorities: Vec<usize>,
+}
+
+impl PriorityScheduler {
+    pub fn new(priorities: Vec<usize>) -> Self {
+        Self { priorities }
+    }
+
+    /// Priority by the number of transitive dependees, see `SchedulingPolicy::FanOutFirst`.
+    pub fn fan_out_first<T: Hash + PartialEq + Eq>(graph: &GraphView<'_, T>) -> Self {
+        Self::new(graph.transitive_dependent_counts())
+    }
+}
+
+impl<T> Scheduler<T> for PriorityScheduler {
+    fn clone_box(&self) -> Box<dyn Scheduler<T> + Send> {
+        Box::new(self.clone())
+    }
+
+    fn compact(&mut self, removed: &[bool]) {
+        self.priorities = compacted(std::mem::take(&mut self.priorities), removed);
+    }
+
+    fn select(&mut self, ready: &[ReadyNode<'_, T>], _graph: &GraphView<'_, T>) -> usize {
+        // Nodes added after the priorities were computed have the lowest priority.
+        let priority =
+            |node: &ReadyNode<'_, T>| self.priorities.get(node.index).copied().unwrap_or(0);
+
+        let mut best = 0;
+        for (position, node) in ready.iter().enumerate() {
+            if priority(node) > priority(&ready[best]) {
+                best = position;
+            }
+        }
+
+        best
+    }
+}
+
+/// Scheduler picking by the order nodes became available: the newest first goes depth-first, descending into freshly
+/// unblocked dependents (locality of caches and temporary files), the oldest first goes breadth-first, draining a
+/// level before the next one (wider fan-out). See `SchedulingPolicy::DepthFirst` and `SchedulingPolicy::BreadthFirst`.
+#[derive(Debug, Clone)]
+pub struct TraversalScheduler {
+    newest_first: bool,
+    /// When each node was first offered, by index, 0 for never.
+    arrivals: Vec<u64>,
+    next_arrival: u64,
+}
+
+impl TraversalScheduler {
+    pub fn depth_first() -> Self {
+        Self::new(true)
+    }
+
+    pub fn breadth_first() -> Self {
+        Self::new(false)
+    }
+
+    fn new(newest_first: bool) -> Self {
+        Self {
+            newest_first,
+            arrivals: vec![],
+            next_arrival: 1,
+        }
+    }
+}
+
+impl<T> Scheduler<T> for TraversalScheduler {
+    fn clone_box(&self) -> Box<dyn Scheduler<T> + Send> {
+        Box::new(self.clone())
+    }
+
+    fn compact(&mut self, removed: &[bool]) {
+        self.arrivals = compacted(std::mem::take(&mut self.arrivals), removed);
+    }
+
+    fn select(&mut self, ready: &[ReadyNode<'_, T>], _graph: &GraphView<'_, T>) -> usize {
+        for node in ready {
+            if node.index >= self.arrivals.len() {
+                self.arrivals.resize(node.index + 1, 0);
+            }
+            if self.arrivals[node.index] == 0 {
+                self.arrivals[node.index] = self.next_arrival;
+                self.next_arrival += 1;
+            }
+        }
+
+        let arrival = |position: &usize| self.arrivals[ready[*position].index];
+        let best = if self.newest_first {
+            (0..ready.len()).max_by_key(arrival)
+        } else {
+            (0..ready.len()).min_by_key(arrival)
+        }
+        .unwrap_or(0);
+
+        // A requeued node arrives again.
+        self.arrivals[ready[best].index] = 0;
+        best
+    }
+}
+
+/// Scheduler picking at random, with probabilities proportional to the weights of the ready nodes (by index). Smooths
+/// resource usage when nodes of one kind become available in bursts: they are interleaved with the others instead of
+/// being drained together. Nodes without a positive weight are only picked when no other one is ready, nodes added
+/// after the weights were set weigh 1. The same seed picks the same nodes given the same ready sets.
+#[derive(Debug, Clone)]
+pub struct WeightedRandomScheduler {
+    weights: Vec<f64>,
+    rng: Rng,
+}
+
+impl WeightedRandomScheduler {
+    pub fn new(weights: Vec<f64>, seed: u64) -> Self {
+        Self {
+            weights,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl<T> Scheduler<T> for WeightedRandomScheduler {
+    fn clone_box(&self) -> Box<dyn Scheduler<T> + Send> {
+        Box::new(self.clone())
+    }
+
+    fn compact(&mut self, removed: &[bool]) {
+        self.weights = compacted(std::mem::take(&mut self.weights), removed);
+    }
+
+    fn select(&mut self, ready: &[ReadyNode<'_, T>], _graph: &GraphView<'_, T>) -> usize {
+        let weight = |node: &ReadyNode<'_, T>| {
+            let weight = self.weights.get(node.index).copied().unwrap_or(1.0);
+            if weight.is_finite() && weight > 0.0 {
+                weight
+            } else {
+                0.0
+            }
+        };
+
+        let total: f64 = ready.iter().map(weight).sum();
+        if total == 0.0 {
+            return (self.rng.next_u64() % ready.len() as u64) as usize;
+        }
+
+        let mut target = self.rng.next_f64() * total;
+        for (position, node) in ready.iter().enumerate() {
+            let weight = weight(node);
+            if target < weight {
+                return position;
+            }
+            target -= weight;
+        }
+
+        // Rounding left the target past the last weight.
+        ready
+            .iter()
+            .rposition(|node| weight(node) > 0.0)
+            .unwrap_or(0)
+    }
+}
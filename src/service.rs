@@ -0,0 +1,189 @@
+//! The runner as a long-lived component: graphs are received over a channel, executed side by side, and their reports
+//! sent back over an other channel. Meant for daemons orchestrating recurring pipelines.
+
+use super::common::*;
+use super::run_error::*;
+use super::thread_pool_runner::*;
+use super::topological_batch_provider::*;
+use std::{
+    hash::Hash,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// How often the started jobs are checked while no new one arrives.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A graph to execute, see `ThreadPoolRunner::serve`.
+pub struct ServiceJob<T> {
+    /// Returned with the report, to tell the jobs apart.
+    pub name: String,
+    pub provider: Box<dyn BatchProvider<T> + Send>,
+    pub executor: Arc<dyn CallableByID<T> + Send + Sync>,
+    pub options: RunOptions<T>,
+}
+
+impl<T> ServiceJob<T> {
+    pub fn new(
+        name: impl Into<String>,
+        provider: impl BatchProvider<T> + Send + 'static,
+        executor: Arc<dyn CallableByID<T> + Send + Sync>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            provider: Box::new(provider),
+            executor,
+            options: RunOptions::new(),
+        }
+    }
+
+    pub fn with_options(mut self, options: RunOptions<T>) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// Outcome of a `ServiceJob`.
+#[derive(Debug)]
+pub struct ServiceReport<T> {
+    pub name: String,
+    /// A panic ending the run is `RunError::Panicked`, see `RunHandle::wait`.
+    pub report: Result<RunReport<T>, RunError<T>>,
+}
+
+impl ThreadPoolRunner {
+    /// Executes every job received from `jobs`, sending its report to `reports` once done. Jobs are started as they
+    /// arrive, without waiting for the previous ones: use `with_persistent_workers` to keep the number of threads
+    /// bounded, the jobs then sharing the workers fairly.
+    ///
+    /// The service stops when `jobs` is disconnected and the started jobs are done. Reports of jobs finishing after
+    /// `reports` is disconnected are dropped. Besides the workers, the service takes two threads: one receiving the
+    /// jobs and one waiting for them.
+    pub fn serve<T: Hash + PartialEq + Eq + Clone + Send + 'static>(
+        self,
+        jobs: Receiver<ServiceJob<T>>,
+        reports: Sender<ServiceReport<T>>,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let (started, started_receiver) = mpsc::channel();
+            let waiter = thread::spawn(move || wait_for_jobs(started_receiver, reports));
+
+            for job in jobs {
+                let handle = self.submit_with_options(job.provider, job.executor, job.options);
+                let _ = started.send((job.name, handle));
+            }
+
+            drop(started);
+            let _ = waiter.join();
+        })
+    }
+}
+
+/// Reports the started jobs as they finish, until `started` is disconnected and every job is reported.
+fn wait_for_jobs<T: Hash + PartialEq + Eq + Clone>(
+    started: Receiver<(String, RunHandle<T>)>,
+    reports: Sender<ServiceReport<T>>,
+) {
+    let mut running = vec![];
+    let report = |(name, handle): (String, RunHandle<T>)| {
+        let _ = reports.send(ServiceReport {
+            name,
+            report: handle.wait(),
+        });
+    };
+
+    loop {
+        match started.recv_timeout(POLL_INTERVAL) {
+            Ok(job) => running.push(job),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let (finished, unfinished): (Vec<_>, Vec<_>) = running
+            .into_iter()
+            .partition(|(_, handle)| handle.is_finished());
+        running = unfinished;
+        finished.into_iter().for_each(report);
+    }
+
+    // No more jobs are coming, so they can be waited for in any order.
+    running.into_iter().for_each(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::mpsc};
+
+    struct Noop;
+
+    impl CallableByID<usize> for Noop {
+        fn call(&self, _id: &usize) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    struct Panicking;
+
+    impl CallableByID<usize> for Panicking {
+        fn call(&self, _id: &usize) -> Result<(), Error> {
+            panic!("Panicking on purpose.");
+        }
+    }
+
+    #[test]
+    fn it_serves_jobs_from_a_channel() {
+        let (jobs, job_receiver) = mpsc::channel();
+        let (report_sender, reports) = mpsc::channel();
+
+        let service = ThreadPoolRunner::new(2)
+            .with_persistent_workers()
+            .serve(job_receiver, report_sender);
+
+        for size in 1..=3 {
+            let nodes = (0..size)
+                .map(|i| (i, vec![]))
+                .collect::<HashMap<usize, Vec<usize>>>();
+            jobs.send(ServiceJob::new(
+                format!("job-{}", size),
+                TopologicalBatchProvider::new(nodes).unwrap(),
+                Arc::new(Noop),
+            ))
+            .unwrap();
+        }
+        jobs.send(ServiceJob::new(
+            "job-panicking",
+            TopologicalBatchProvider::new(HashMap::from([(0, vec![])])).unwrap(),
+            Arc::new(Panicking),
+        ))
+        .unwrap();
+        drop(jobs);
+
+        let mut reports = reports
+            .iter()
+            .map(|report| match report.report {
+                Ok(run) => (report.name, Ok(run.completed.len())),
+                Err(err) => (report.name, Err(err.to_string())),
+            })
+            .collect::<Vec<_>>();
+        reports.sort();
+
+        assert_eq!(
+            vec![
+                ("job-1".to_string(), Ok(1)),
+                ("job-2".to_string(), Ok(2)),
+                ("job-3".to_string(), Ok(3)),
+                (
+                    "job-panicking".to_string(),
+                    Err("Node 0 panicked: Panicking on purpose.".to_string())
+                ),
+            ],
+            reports
+        );
+        service.join().unwrap();
+    }
+}
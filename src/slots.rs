@@ -0,0 +1,145 @@
+//! Named countable resources nodes claim while executing (GPUs, licenses, memory, ...). A node only starts once all of
+//! its claims fit in the remaining capacity of their slots, it waits in the ready state meanwhile while other nodes
+//! go ahead. Used by `RunOptions::with_slots` of the threaded runner and `RunFuture::with_slots` of the async runner.
+//!
+//! ```ignore
+//! let slots = Slots::new(Arc::new(|node: &Job| match node.kind {
+//!     Kind::Training => vec![("gpu".to_string(), 1)],
+//!     Kind::Preprocessing => vec![],
+//! }))
+//! .with_capacity("gpu", 2);
+//! ```
+
+use std::{collections::HashMap, sync::Arc};
+
+/// The slots a node claims, with the amount of each.
+pub type SlotClaims<T> = Arc<dyn Fn(&T) -> Vec<(String, u64)> + Send + Sync>;
+
+/// Capacities of the slots and the claims of the nodes.
+pub struct Slots<T> {
+    capacities: HashMap<String, u64>,
+    claims: SlotClaims<T>,
+}
+
+impl<T> Slots<T> {
+    pub fn new(claims: SlotClaims<T>) -> Self {
+        Self {
+            capacities: HashMap::new(),
+            claims,
+        }
+    }
+
+    /// Claims of slots without a capacity are not limited. A claim above the capacity of its slot is let through
+    /// once nothing else holds the slot, so the node still runs, alone.
+    pub fn with_capacity(mut self, slot: impl Into<String>, capacity: u64) -> Self {
+        self.capacities.insert(slot.into(), capacity);
+        self
+    }
+}
+
+impl<T> Clone for Slots<T> {
+    fn clone(&self) -> Self {
+        Self {
+            capacities: self.capacities.clone(),
+            claims: self.claims.clone(),
+        }
+    }
+}
+
+/// What an admitted node holds, one list per `Slots` of the run. Handed back to `SlotLedger::release`.
+pub(crate) type Claims = Vec<Vec<(String, u64)>>;
+
+/// Slot usage of a run.
+pub(crate) struct SlotLedger<T> {
+    slots: Vec<Slots<T>>,
+    /// Held amount of every slot, in the order of `slots`.
+    in_use: Vec<HashMap<String, u64>>,
+}
+
+impl<T> SlotLedger<T> {
+    pub(crate) fn new(slots: Vec<Slots<T>>) -> Self {
+        Self {
+            in_use: vec![HashMap::new(); slots.len()],
+            slots,
+        }
+    }
+
+    pub(crate) fn push(&mut self, slots: Slots<T>) {
+        self.slots.push(slots);
+        self.in_use.push(HashMap::new());
+    }
+
+    /// Takes the claims of the node if they all fit, otherwise nothing is taken.
+    pub(crate) fn try_acquire(&mut self, node: &T) -> Option<Claims> {
+        let claims = self
+            .slots
+            .iter()
+            .map(|slots| (slots.claims)(node))
+            .collect::<Claims>();
+
+        let fits =
+            self.slots
+                .iter()
+                .zip(&self.in_use)
+                .zip(&claims)
+                .all(|((slots, in_use), claims)| {
+                    claims.iter().all(|(slot, amount)| {
+                        let held = in_use.get(slot).copied().unwrap_or(0);
+                        match slots.capacities.get(slot) {
+                            Some(&capacity) => {
+                                *amount == 0
+                                    || held == 0
+                                    || held.saturating_add(*amount) <= capacity
+                            }
+                            None => true,
+                        }
+                    })
+                });
+        if !fits {
+            return None;
+        }
+
+        for (in_use, claims) in self.in_use.iter_mut().zip(&claims) {
+            for (slot, amount) in claims {
+                *in_use.entry(slot.clone()).or_insert(0) += amount;
+            }
+        }
+
+        Some(claims)
+    }
+
+    pub(crate) fn release(&mut self, claims: &Claims) {
+        for (in_use, claims) in self.in_use.iter_mut().zip(claims) {
+            for (slot, amount) in claims {
+                if let Some(held) = in_use.get_mut(slot) {
+                    *held -= amount;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_admits_claims_within_capacity() {
+        let slots = Slots::new(Arc::new(|node: &u64| vec![("gpu".to_string(), *node)]))
+            .with_capacity("gpu", 2);
+        let mut ledger = SlotLedger::new(vec![slots]);
+
+        let first = ledger.try_acquire(&1).unwrap();
+        let second = ledger.try_acquire(&1).unwrap();
+        assert!(ledger.try_acquire(&1).is_none());
+
+        ledger.release(&first);
+        ledger.release(&second);
+        let oversized = ledger.try_acquire(&5).unwrap();
+        assert!(ledger.try_acquire(&0).is_some());
+        assert!(ledger.try_acquire(&1).is_none());
+
+        ledger.release(&oversized);
+        assert!(ledger.try_acquire(&2).is_some());
+    }
+}
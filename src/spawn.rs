@@ -0,0 +1,149 @@
+//! Follow-up work spawned by an executor onto the workers of its run, for nodes internally fanning out. The spawned
+//! tasks are children of the node: it is only completed (or failed) once all of them finished, and fails with the first
+//! error of a child.
+
+use super::common::*;
+use super::sync::*;
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::Duration,
+};
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// Handle passed to `CallableByID::call_with_spawner`, valid for the node it was passed for.
+pub struct Spawner {
+    queue: Arc<TaskQueue>,
+    group: Arc<TaskGroup>,
+}
+
+impl Spawner {
+    pub(crate) fn new(queue: Arc<TaskQueue>) -> Self {
+        Self {
+            queue,
+            group: Arc::new(TaskGroup {
+                state: Mutex::new(GroupState {
+                    pending: 0,
+                    error: None,
+                }),
+                finished: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Queues `task` to be executed by a worker of the run, possibly by the one of the node while it waits for its
+    /// children. Does not count against the in-flight cap of the runner.
+    pub fn spawn(&self, task: impl FnOnce() -> Result<(), Error> + Send + 'static) {
+        self.group.state.lock().pending += 1;
+
+        let group = self.group.clone();
+        self.queue.push(Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(task))
+                .unwrap_or_else(|_| Err("Spawned task panicked.".into()));
+            group.finish(result);
+        }));
+    }
+
+    /// Blocks until every spawned task finished, executing queued tasks of the run meanwhile. Returns the first error
+    /// of the children.
+    pub(crate) fn join(self) -> Result<(), Error> {
+        loop {
+            {
+                let mut state = self.group.state.lock();
+                if state.pending == 0 {
+                    return match state.error.take() {
+                        Some(err) => Err(err),
+                        None => Ok(()),
+                    };
+                }
+            }
+
+            if !self.queue.run_one() {
+                let state = self.group.state.lock();
+                if state.pending > 0 {
+                    drop(self.group.finished.wait_timeout(state, JOIN_WAIT));
+                }
+            }
+        }
+    }
+}
+
+/// How long a node waiting for its children sleeps when there is nothing to help with, unless woken by a child.
+const JOIN_WAIT: Duration = Duration::from_millis(10);
+
+struct TaskGroup {
+    state: Mutex<GroupState>,
+    /// Notified when a child finishes.
+    finished: Condvar,
+}
+
+struct GroupState {
+    pending: usize,
+    error: Option<Error>,
+}
+
+impl TaskGroup {
+    fn finish(&self, result: Result<(), Error>) {
+        let mut state = self.state.lock();
+        state.pending -= 1;
+        if let (Err(err), None) = (result, &state.error) {
+            state.error = Some(err);
+        }
+        self.finished.notify_all();
+    }
+}
+
+/// Spawned tasks of a run, not yet picked up by a worker.
+#[derive(Default)]
+pub(crate) struct TaskQueue {
+    tasks: Mutex<VecDeque<Task>>,
+}
+
+impl TaskQueue {
+    fn push(&self, task: Task) {
+        self.tasks.lock().push_back(task);
+    }
+
+    /// Executes the oldest queued task, returns whether there was any.
+    pub(crate) fn run_one(&self) -> bool {
+        let task = self.tasks.lock().pop_front();
+        match task {
+            Some(task) => {
+                task();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn it_joins_children_with_their_first_error() {
+        let queue = Arc::new(TaskQueue::default());
+        let spawner = Spawner::new(queue.clone());
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        for i in 0..4 {
+            let counter = counter.clone();
+            spawner.spawn(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                match i {
+                    2 => Err("broken".into()),
+                    3 => panic!("Panicking on purpose."),
+                    _ => Ok(()),
+                }
+            });
+        }
+
+        assert_eq!("broken", spawner.join().unwrap_err().to_string());
+        assert_eq!(4, counter.load(Ordering::SeqCst));
+        assert!(!queue.run_one());
+    }
+}
@@ -0,0 +1,237 @@
+//! Optimistic pipelines: nodes whose only unmet dependencies are soft ones start right away, speculating on their
+//! success. When a soft dependency fails after all, the node is rolled back with the undo hook, together with whatever
+//! ran on top of it, and reported as skipped. Nodes still running are rolled back once they return. Meant for cheap
+//! compensations (deleting an output, reverting a row, ...), the undo hook being called while the run is locked.
+//!
+//! ```ignore
+//! let provider = SpeculativeProvider::new(
+//!     HashMap::from([("fetch", vec![]), ("render", vec![]), ("publish", vec!["render"])]),
+//!     HashMap::from([("render", vec!["fetch"])]),
+//!     Arc::new(|node: &&str| remove_output(node)),
+//! )?;
+//! let report = ThreadPoolRunner::new(4).run(provider, executor);
+//! ```
+
+use super::common::*;
+use super::topological_batch_provider::*;
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::Arc,
+};
+
+/// Compensates a node rolled back after it completed.
+pub type UndoHook<T> = Arc<dyn Fn(&T) + Send + Sync>;
+
+/// Provider starting nodes before their soft dependencies are done. Runs with `ThreadPoolRunner`, the other runners
+/// don't report rollbacks.
+pub struct SpeculativeProvider<T> {
+    /// Only knows the hard edges, so soft dependencies don't hold nodes back.
+    inner: TopologicalBatchProvider<T>,
+    soft_dependencies: HashMap<T, Vec<T>>,
+    soft_dependents: HashMap<T, Vec<T>>,
+    /// Running nodes to roll back once they return.
+    doomed: HashSet<T>,
+    /// Completed nodes stay completed for `inner`, so they are only rolled back once.
+    undone: HashSet<T>,
+    rolled_back: Vec<T>,
+    undo: UndoHook<T>,
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> SpeculativeProvider<T> {
+    /// `nodes` are the hard dependencies as for `TopologicalBatchProvider::new`, `soft` the soft dependencies of some
+    /// of them. The graph of both has to be acyclic.
+    pub fn new(
+        nodes: HashMap<T, Vec<T>>,
+        soft: HashMap<T, Vec<T>>,
+        undo: UndoHook<T>,
+    ) -> Result<Self, Error> {
+        let mut hard = nodes;
+        for dependent in soft.keys() {
+            hard.entry(dependent.clone()).or_default();
+        }
+
+        let mut all = hard.clone();
+        let mut soft_dependents: HashMap<T, Vec<T>> = HashMap::new();
+        for (dependent, dependencies) in &soft {
+            all.entry(dependent.clone())
+                .or_default()
+                .extend(dependencies.iter().cloned());
+            for dependency in dependencies {
+                soft_dependents
+                    .entry(dependency.clone())
+                    .or_default()
+                    .push(dependent.clone());
+            }
+        }
+        TopologicalBatchProvider::new(all)?;
+
+        Ok(Self {
+            inner: TopologicalBatchProvider::new(hard)?,
+            soft_dependencies: soft,
+            soft_dependents,
+            doomed: HashSet::new(),
+            undone: HashSet::new(),
+            rolled_back: vec![],
+            undo,
+        })
+    }
+
+    /// Nodes popped while some of their soft dependencies are not completed yet.
+    pub fn is_speculative(&self, node: &T) -> bool {
+        self.soft_dependencies
+            .get(node)
+            .into_iter()
+            .flatten()
+            .any(|dependency| {
+                !self
+                    .inner
+                    .status(dependency)
+                    .is_some_and(|status| status.is_completed())
+            })
+    }
+
+    /// Goes through the dependees of the nodes not completing: the ones not started are skipped (and returned), the
+    /// running ones doomed and the completed ones rolled back.
+    fn invalidate_dependents(&mut self, mut stack: Vec<T>) -> Vec<T> {
+        let mut skipped = vec![];
+
+        while let Some(node) = stack.pop() {
+            let dependents = self
+                .inner
+                .dependents_of(&node)
+                .unwrap_or_default()
+                .into_iter()
+                .chain(self.soft_dependents.get(&node).cloned().unwrap_or_default());
+
+            for dependent in dependents {
+                match self.inner.status(&dependent) {
+                    Some(NodeStatus::Pending | NodeStatus::Available) => {
+                        let newly_skipped = self.inner.skip(&dependent);
+                        stack.extend(newly_skipped.iter().cloned());
+                        skipped.extend(newly_skipped);
+                    }
+                    Some(NodeStatus::Running) => {
+                        self.doomed.insert(dependent);
+                    }
+                    Some(NodeStatus::Completed) if self.undone.insert(dependent.clone()) => {
+                        (self.undo)(&dependent);
+                        self.rolled_back.push(dependent.clone());
+                        stack.push(dependent);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        skipped
+    }
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> BatchProvider<T> for SpeculativeProvider<T> {
+    fn pop(&mut self) -> Result<Option<T>, Error> {
+        Ok(self.inner.pop())
+    }
+
+    fn complete(&mut self, node: T) -> Result<(), Error> {
+        if !self.doomed.remove(&node) {
+            return Ok(self.inner.complete(node)?);
+        }
+
+        let skipped = self.inner.fail(node.clone())?;
+        (self.undo)(&node);
+        self.rolled_back.push(node.clone());
+        self.rolled_back.extend(skipped.iter().cloned());
+
+        let mut stack = skipped;
+        stack.push(node);
+        let skipped = self.invalidate_dependents(stack);
+        self.rolled_back.extend(skipped);
+        Ok(())
+    }
+
+    fn fail(&mut self, node: T) -> Result<Vec<T>, Error> {
+        self.doomed.remove(&node);
+        let mut skipped = self.inner.fail(node.clone())?;
+
+        let mut stack = skipped.clone();
+        stack.push(node);
+        skipped.extend(self.invalidate_dependents(stack));
+        Ok(skipped)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn unfinished(&self) -> Vec<T> {
+        BatchProvider::unfinished(&self.inner)
+    }
+
+    fn graph(&self) -> Option<Vec<(T, Vec<T>)>> {
+        let mut graph = self.inner.graph()?;
+        for (node, dependencies) in &mut graph {
+            if let Some(soft) = self.soft_dependencies.get(node) {
+                dependencies.extend(soft.iter().cloned());
+            }
+        }
+
+        Some(graph)
+    }
+
+    fn satisfied(&self) -> Vec<T> {
+        self.inner.satisfied_externally()
+    }
+
+    fn take_rolled_back(&mut self) -> Vec<T> {
+        std::mem::take(&mut self.rolled_back)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn it_rolls_back_speculative_nodes() {
+        let undone = Arc::new(Mutex::new(vec![]));
+        let recorder = undone.clone();
+        let mut provider = SpeculativeProvider::new(
+            HashMap::from([
+                ("a", vec![]),
+                ("b", vec![]),
+                ("c", vec!["b"]),
+                ("x", vec![]),
+            ]),
+            HashMap::from([("b", vec!["a"]), ("x", vec!["a"])]),
+            Arc::new(move |node: &&str| recorder.lock().unwrap().push(*node)),
+        )
+        .unwrap();
+
+        let mut popped = vec![];
+        while let Some(node) = provider.pop().unwrap() {
+            popped.push(node);
+        }
+        popped.sort();
+        assert_eq!(vec!["a", "b", "x"], popped);
+        assert!(provider.is_speculative(&"b"));
+
+        provider.complete("b").unwrap();
+        assert_eq!(Some("c"), provider.pop().unwrap());
+        provider.complete("c").unwrap();
+
+        assert!(provider.fail("a").unwrap().is_empty());
+        let mut rolled_back = provider.take_rolled_back();
+        rolled_back.sort();
+        assert_eq!(vec!["b", "c"], rolled_back);
+
+        provider.complete("x").unwrap();
+        assert_eq!(vec!["x"], provider.take_rolled_back());
+        assert!(provider.is_empty());
+
+        let mut undone = undone.lock().unwrap().clone();
+        undone.sort();
+        assert_eq!(vec!["b", "c", "x"], undone);
+    }
+}
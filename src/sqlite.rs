@@ -0,0 +1,454 @@
+//! Provider whose progress is persisted to SQLite, so a crashed coordinator can restart where it left off. Available
+//! with the `sqlite` feature.
+//!
+//! Every `pop`, `complete` and `fail` is written in a transaction before returning. Nodes recorded as running when
+//! the state is reopened were interrupted by the crash, they are handed out again. The canonical hash of the graph is
+//! recorded too, so progress is never restored into a graph that changed meanwhile.
+//!
+//! The schema is versioned (SQLite's `user_version`): databases written by older versions of the crate are migrated
+//! when opened, so a deployment can upgrade without losing its progress. Databases of newer versions are refused.
+
+use super::common::*;
+use super::topological_batch_provider::*;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::HashMap,
+    error,
+    fmt::{self, Display},
+    hash::Hash,
+    path::Path,
+};
+
+/// Schema migrations, the one at position `n` taking the database from version `n` to `n + 1`. Databases written
+/// before the schema was versioned are at version 0, having the status table already, hence `IF NOT EXISTS`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS topological_batch_status (
+        id TEXT PRIMARY KEY NOT NULL,
+        status TEXT NOT NULL
+    );",
+    // Progress recorded without the graph hash is assumed to be of the graph it is next opened with.
+    "CREATE TABLE IF NOT EXISTS topological_batch_meta (
+        key TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    );",
+];
+
+/// The database was written by a newer version of the crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedSchema {
+    pub found: i64,
+    pub supported: i64,
+}
+
+impl fmt::Display for UnsupportedSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unsupported schema version {}, at most {} is supported.",
+            self.found, self.supported
+        )
+    }
+}
+
+impl error::Error for UnsupportedSchema {}
+
+/// The recorded progress belongs to an other graph than the one opened, see `SqliteProvider::reset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GraphChanged {
+    pub recorded: u64,
+    pub current: u64,
+}
+
+impl fmt::Display for GraphChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Recorded progress is of graph {:016x}, not {:016x}.",
+            self.recorded, self.current
+        )
+    }
+}
+
+impl error::Error for GraphChanged {}
+
+/// Wraps a provider, persisting the status of every node. IDs are stored by their `Display` form, which must be
+/// unique.
+#[derive(Debug)]
+pub struct SqliteProvider<T: Hash + PartialEq + Eq + Clone> {
+    provider: TopologicalBatchProvider<T>,
+    connection: Connection,
+}
+
+impl<T: Hash + PartialEq + Eq + Clone + Display> SqliteProvider<T> {
+    /// Opens (or creates) the database at `path` and restores the recorded progress into the fresh `provider`. Fails
+    /// with `GraphChanged` if the progress was recorded for an other graph, and with `UnsupportedSchema` if the database
+    /// was written by a newer version of the crate.
+    pub fn open(
+        path: impl AsRef<Path>,
+        provider: TopologicalBatchProvider<T>,
+    ) -> Result<Self, Error> {
+        Self::with_connection(Connection::open(path)?, provider)
+    }
+
+    /// Same as `open`, but progress recorded for an other graph is forgotten instead of failing with `GraphChanged`,
+    /// starting the changed graph from scratch.
+    pub fn open_discarding_changed(
+        path: impl AsRef<Path>,
+        provider: TopologicalBatchProvider<T>,
+    ) -> Result<Self, Error> {
+        Self::connect(Connection::open(path)?, provider, true)
+    }
+
+    /// Same as `open`, on an already open connection (eg in-memory for tests).
+    pub fn with_connection(
+        connection: Connection,
+        provider: TopologicalBatchProvider<T>,
+    ) -> Result<Self, Error> {
+        Self::connect(connection, provider, false)
+    }
+
+    fn connect(
+        mut connection: Connection,
+        mut provider: TopologicalBatchProvider<T>,
+        discard_changed: bool,
+    ) -> Result<Self, Error> {
+        migrate(&mut connection)?;
+
+        let current = provider.canonical_hash();
+        let recorded = connection
+            .query_row(
+                "SELECT value FROM topological_batch_meta WHERE key = 'graph_hash'",
+                [],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        match recorded {
+            Some(recorded) => {
+                let recorded = u64::from_str_radix(&recorded, 16)?;
+                if recorded != current {
+                    if !discard_changed {
+                        return Err(GraphChanged { recorded, current }.into());
+                    }
+                    connection.execute("DELETE FROM topological_batch_status", [])?;
+                    record_graph_hash(&connection, current)?;
+                }
+            }
+            None => record_graph_hash(&connection, current)?,
+        }
+
+        let ids = provider
+            .ids()
+            .iter()
+            .map(|id| (id.to_string(), id.clone()))
+            .collect::<HashMap<_, _>>();
+
+        let recorded = {
+            let mut statement =
+                connection.prepare("SELECT id, status FROM topological_batch_status")?;
+            let rows = statement.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        // Skipped nodes follow from the failed ones, running ones are to be redone.
+        for (id, status) in recorded {
+            let Some(node) = ids.get(&id) else {
+                continue;
+            };
+            match status.as_str() {
+                "completed" => provider.force_complete(node),
+                "failed" => {
+                    provider.force_fail(node);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            provider,
+            connection,
+        })
+    }
+
+    fn record<'a>(
+        &mut self,
+        statuses: impl IntoIterator<Item = (&'a T, &'static str)>,
+    ) -> Result<(), Error>
+    where
+        T: 'a,
+    {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO topological_batch_status (id, status) VALUES (?1, ?2)
+                ON CONFLICT(id) DO UPDATE SET status = excluded.status",
+            )?;
+            for (id, status) in statuses {
+                statement.execute(params![id.to_string(), status])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// See `TopologicalBatchProvider::pop`. If the state can't be written the node is requeued.
+    pub fn pop(&mut self) -> Result<Option<T>, Error> {
+        let Some(node) = self.provider.pop() else {
+            return Ok(None);
+        };
+
+        if let Err(err) = self.record([(&node, "running")]) {
+            self.provider.requeue(&node);
+            return Err(err);
+        }
+
+        Ok(Some(node))
+    }
+
+    /// See `TopologicalBatchProvider::complete`, its `CompletionError`s are returned as is. On a write error the node
+    /// is completed in memory only, reopening the state runs it again.
+    pub fn complete(&mut self, node: T) -> Result<(), Error> {
+        self.provider.complete(node.clone())?;
+        self.record([(&node, "completed")])
+    }
+
+    /// See `TopologicalBatchProvider::pop_up_to`, the nodes being recorded in a single transaction. If the state can't
+    /// be written the nodes are requeued.
+    pub fn pop_up_to(&mut self, n: usize) -> Result<Vec<T>, Error> {
+        let nodes = self.provider.pop_up_to(n);
+
+        if let Err(err) = self.record(nodes.iter().map(|node| (node, "running"))) {
+            for node in &nodes {
+                self.provider.requeue(node);
+            }
+            return Err(err);
+        }
+
+        Ok(nodes)
+    }
+
+    /// See `TopologicalBatchProvider::complete_many`, the completed nodes being recorded in a single transaction. On a
+    /// write error they are completed in memory only.
+    pub fn complete_many(
+        &mut self,
+        nodes: impl IntoIterator<Item = T>,
+    ) -> Result<Vec<(T, CompletionError)>, Error> {
+        let mut completed = vec![];
+        let mut rejected = vec![];
+        for node in nodes {
+            match self.provider.complete(node.clone()) {
+                Ok(()) => completed.push(node),
+                Err(err) => rejected.push((node, err)),
+            }
+        }
+
+        self.record(completed.iter().map(|node| (node, "completed")))?;
+        Ok(rejected)
+    }
+
+    /// See `TopologicalBatchProvider::fail`, returning the skipped nodes.
+    pub fn fail(&mut self, node: T) -> Result<Vec<T>, Error> {
+        let skipped = self.provider.fail(node.clone())?;
+        self.record(
+            std::iter::once((&node, "failed")).chain(skipped.iter().map(|id| (id, "skipped"))),
+        )?;
+        Ok(skipped)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.provider.is_empty()
+    }
+
+    pub fn status(&self, node: &T) -> Option<NodeStatus> {
+        self.provider.status(node)
+    }
+
+    /// Forgets the recorded progress, for starting the graph over. To start a changed graph from scratch, open the
+    /// database with `open_discarding_changed`.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.connection
+            .execute("DELETE FROM topological_batch_status", [])?;
+        record_graph_hash(&self.connection, self.provider.canonical_hash())
+    }
+}
+
+/// Runs with `ThreadPoolRunner` like the wrapped provider, recording the progress as it goes.
+impl<T: Hash + PartialEq + Eq + Clone + Display> BatchProvider<T> for SqliteProvider<T> {
+    fn pop(&mut self) -> Result<Option<T>, Error> {
+        SqliteProvider::pop(self)
+    }
+
+    fn complete(&mut self, node: T) -> Result<(), Error> {
+        SqliteProvider::complete(self, node)
+    }
+
+    fn fail(&mut self, node: T) -> Result<Vec<T>, Error> {
+        SqliteProvider::fail(self, node)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.provider.is_empty()
+    }
+
+    fn unfinished(&self) -> Vec<T> {
+        BatchProvider::unfinished(&self.provider)
+    }
+
+    fn graph(&self) -> Option<Vec<(T, Vec<T>)>> {
+        BatchProvider::graph(&self.provider)
+    }
+
+    fn satisfied(&self) -> Vec<T> {
+        self.provider.satisfied_externally()
+    }
+}
+
+/// Brings the schema to the latest version, each migration in its own transaction.
+fn migrate(connection: &mut Connection) -> Result<(), Error> {
+    let supported = MIGRATIONS.len() as i64;
+    let found: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if found > supported {
+        return Err(UnsupportedSchema { found, supported }.into());
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", version as i64 + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+fn record_graph_hash(connection: &Connection, hash: u64) -> Result<(), Error> {
+    connection.execute(
+        "INSERT INTO topological_batch_meta (key, value) VALUES ('graph_hash', ?1)
+        ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![format!("{:016x}", hash)],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::thread_pool_runner::*;
+    use std::{env, fs, process, sync::Arc};
+
+    fn provider() -> TopologicalBatchProvider<usize> {
+        TopologicalBatchProvider::try_from_iter([
+            (1, vec![]),
+            (2, vec![1]),
+            (3, vec![2]),
+            (4, vec![]),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn it_restarts_where_it_left_off() {
+        let path = env::temp_dir().join(format!("topological_batch_sqlite_{}.db", process::id()));
+        let _ = fs::remove_file(&path);
+
+        {
+            let mut durable = SqliteProvider::open(&path, provider()).unwrap();
+            let mut popped = vec![
+                durable.pop().unwrap().unwrap(),
+                durable.pop().unwrap().unwrap(),
+            ];
+            popped.sort_unstable();
+            assert_eq!(vec![1, 4], popped);
+
+            durable.complete(1).unwrap();
+            assert!(durable.fail(4).unwrap().is_empty());
+            assert_eq!(Some(2), durable.pop().unwrap());
+            // Crash while 2 is running.
+        }
+
+        let mut durable = SqliteProvider::open(&path, provider()).unwrap();
+        assert_eq!(Some(NodeStatus::Completed), durable.status(&1));
+        assert_eq!(Some(NodeStatus::Failed), durable.status(&4));
+        assert_eq!(vec![2], durable.pop_up_to(5).unwrap());
+        assert_eq!(None, durable.pop().unwrap());
+        assert!(durable.complete_many([2]).unwrap().is_empty());
+        assert_eq!(Some(3), durable.pop().unwrap());
+        durable.complete(3).unwrap();
+        assert!(durable.is_empty());
+
+        durable.reset().unwrap();
+        drop(durable);
+        assert_eq!(
+            Some(NodeStatus::Available),
+            SqliteProvider::open(&path, provider()).unwrap().status(&1)
+        );
+
+        let changed = TopologicalBatchProvider::try_from_iter([(1, vec![]), (2, vec![])]).unwrap();
+        let err = SqliteProvider::open(&path, changed).unwrap_err();
+        assert!(err.downcast_ref::<GraphChanged>().is_some());
+
+        let changed = TopologicalBatchProvider::try_from_iter([(1, vec![]), (2, vec![])]).unwrap();
+        let mut durable = SqliteProvider::open_discarding_changed(&path, changed).unwrap();
+        assert_eq!(Some(NodeStatus::Available), durable.status(&2));
+        let node = durable.pop().unwrap().unwrap();
+        durable.complete(node).unwrap();
+        drop(durable);
+        let changed = TopologicalBatchProvider::try_from_iter([(1, vec![]), (2, vec![])]).unwrap();
+        let durable = SqliteProvider::open(&path, changed).unwrap();
+        assert_eq!(Some(NodeStatus::Completed), durable.status(&node));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_records_runs_of_the_runner() {
+        let path = env::temp_dir().join(format!(
+            "topological_batch_sqlite_runner_{}.db",
+            process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let report = ThreadPoolRunner::new(2).run(
+            SqliteProvider::open(&path, provider()).unwrap(),
+            Arc::new(FailingExecutor(2)),
+        );
+        assert_eq!(vec![3], report.skipped);
+
+        let durable = SqliteProvider::open(&path, provider()).unwrap();
+        assert_eq!(Some(NodeStatus::Completed), durable.status(&4));
+        assert_eq!(Some(NodeStatus::Failed), durable.status(&2));
+        assert_eq!(Some(NodeStatus::Skipped), durable.status(&3));
+        assert!(durable.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_migrates_older_schemas() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE topological_batch_status (
+                    id TEXT PRIMARY KEY NOT NULL,
+                    status TEXT NOT NULL
+                );
+                INSERT INTO topological_batch_status (id, status) VALUES ('1', 'completed');",
+            )
+            .unwrap();
+
+        let durable = SqliteProvider::with_connection(connection, provider()).unwrap();
+        assert_eq!(Some(NodeStatus::Completed), durable.status(&1));
+        let version: i64 = durable
+            .connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(MIGRATIONS.len() as i64, version);
+
+        let connection = Connection::open_in_memory().unwrap();
+        connection.pragma_update(None, "user_version", 99).unwrap();
+        let err = SqliteProvider::with_connection(connection, provider()).unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedSchema>().is_some());
+    }
+}
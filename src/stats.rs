@@ -0,0 +1,98 @@
+//! Shape of a provider's dependency graph, to reason about how much parallelism it offers.
+
+use super::topological_batch_provider::*;
+use std::hash::Hash;
+
+/// See `TopologicalBatchProvider::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GraphStats {
+    pub node_count: usize,
+    pub edge_count: usize,
+    /// Nodes without dependencies.
+    pub roots: usize,
+    /// Nodes nothing depends on.
+    pub leaves: usize,
+    /// Most dependencies of a single node.
+    pub max_fan_in: usize,
+    /// Most dependees of a single node.
+    pub max_fan_out: usize,
+    /// Number of batches, ie the length of the longest dependency chain.
+    pub level_count: usize,
+    /// Size of the largest batch: more threads than this never help.
+    pub width: usize,
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> TopologicalBatchProvider<T> {
+    /// Computed over the full graph, regardless of the progress. Edges of pruned nodes (see `prune_completed`) are no
+    /// longer known, so they are not counted.
+    pub fn stats(&self) -> GraphStats {
+        let node_count = self.ids().len();
+        let mut fan_in = vec![0; node_count];
+        let mut stats = GraphStats {
+            node_count,
+            ..GraphStats::default()
+        };
+
+        for index in 0..node_count {
+            let dependents = self.dependents_of_index(index);
+
+            stats.edge_count += dependents.len();
+            stats.max_fan_out = stats.max_fan_out.max(dependents.len());
+            if dependents.is_empty() {
+                stats.leaves += 1;
+            }
+            for &dependent in dependents {
+                fan_in[dependent] += 1;
+            }
+        }
+
+        stats.roots = fan_in.iter().filter(|&&count| count == 0).count();
+        stats.max_fan_in = fan_in.into_iter().max().unwrap_or(0);
+
+        let levels = self.level_indices();
+        stats.level_count = levels.len();
+        stats.width = levels.iter().map(Vec::len).max().unwrap_or(0);
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn it_summarizes_the_graph() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1]);
+        nodes.insert(4, vec![1]);
+        nodes.insert(5, vec![2, 3, 4]);
+        nodes.insert(6, vec![]);
+
+        let stats = TopologicalBatchProvider::new(nodes).unwrap().stats();
+
+        assert_eq!(
+            GraphStats {
+                node_count: 6,
+                edge_count: 6,
+                roots: 2,
+                leaves: 2,
+                max_fan_in: 3,
+                max_fan_out: 3,
+                level_count: 3,
+                width: 3,
+            },
+            stats
+        );
+        assert_eq!(
+            GraphStats::default(),
+            TopologicalBatchProvider::<usize>::new(HashMap::new())
+                .unwrap()
+                .stats()
+        );
+    }
+}
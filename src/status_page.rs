@@ -0,0 +1,333 @@
+//! Tiny HTTP status page of a run, to check long-running pipelines from a browser. Available with the `status_page`
+//! feature.
+//!
+//! Register a clone of `StatusPage` with `RunOptions::with_observer` and keep it served with `serve` while the run
+//! lasts. `/` is an HTML table of the nodes, `/status.json` the same as JSON:
+//!
+//! ```text
+//! {"finished":false,"nodes":[{"id":"build","status":"running","worker":0,"elapsed_ms":1520}, ...]}
+//! ```
+
+use super::common::*;
+use super::json;
+use super::observer::*;
+use super::topological_batch_provider::*;
+use std::{
+    collections::HashMap,
+    fmt::{Display, Write},
+    hash::Hash,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tiny_http::{Header, Response, Server};
+
+#[derive(Debug, Clone, Copy)]
+enum NodeState {
+    Pending,
+    Running {
+        worker: usize,
+        started: Instant,
+    },
+    Finished {
+        outcome: &'static str,
+        duration: Duration,
+    },
+    Skipped,
+}
+
+struct PageState<T> {
+    /// Nodes in the order of the provider's batches.
+    order: Vec<T>,
+    nodes: HashMap<T, NodeState>,
+    run_finished: bool,
+}
+
+/// Shared state of the run, cloning gives another handle to the same page.
+pub struct StatusPage<T> {
+    state: Arc<Mutex<PageState<T>>>,
+}
+
+impl<T> Clone for StatusPage<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone + Display + Send + 'static> StatusPage<T> {
+    /// `provider` is only read for the nodes, create the page before running it.
+    pub fn new(provider: &TopologicalBatchProvider<T>) -> Self {
+        let order = provider.levels().into_iter().flatten().collect::<Vec<_>>();
+        let nodes = order
+            .iter()
+            .map(|node| (node.clone(), NodeState::Pending))
+            .collect();
+
+        Self {
+            state: Arc::new(Mutex::new(PageState {
+                order,
+                nodes,
+                run_finished: false,
+            })),
+        }
+    }
+
+    /// Starts serving the page on `addr` from a background thread, until the returned server is dropped.
+    pub fn serve(&self, addr: impl ToSocketAddrs) -> Result<StatusServer, Error> {
+        let server = Arc::new(Server::http(addr)?);
+        let addr = server
+            .server_addr()
+            .to_ip()
+            .ok_or("Status page is not served over IP.")?;
+
+        let page = self.clone();
+        let thread_server = server.clone();
+        let handle = thread::spawn(move || {
+            for request in thread_server.incoming_requests() {
+                // Query strings, eg cache busters, don't change the page.
+                let path = request.url().split('?').next().unwrap_or_default();
+                let response = match path {
+                    "/" => {
+                        Response::from_string(page.html()).with_header(content_type("text/html"))
+                    }
+                    "/status.json" => Response::from_string(page.json())
+                        .with_header(content_type("application/json")),
+                    _ => Response::from_string("Not found.").with_status_code(404),
+                };
+                // The client went away, nothing to do about it.
+                let _ = request.respond(response);
+            }
+        });
+
+        Ok(StatusServer {
+            addr,
+            server,
+            handle: Some(handle),
+        })
+    }
+
+    /// Statuses and timings of the nodes as JSON.
+    pub fn json(&self) -> String {
+        let state = self.state.lock().unwrap();
+
+        let nodes = state
+            .rows()
+            .map(|(node, status, worker, timing)| {
+                let mut out = format!(
+                    "{{\"id\":{},\"status\":\"{}\"",
+                    json::string(&node.to_string()),
+                    status
+                );
+                if let Some(worker) = worker {
+                    write!(out, ",\"worker\":{}", worker).unwrap();
+                }
+                match timing {
+                    Some(Timing::Elapsed(elapsed)) => {
+                        write!(out, ",\"elapsed_ms\":{}", elapsed.as_millis()).unwrap()
+                    }
+                    Some(Timing::Duration(duration)) => {
+                        write!(out, ",\"duration_ms\":{}", duration.as_millis()).unwrap()
+                    }
+                    None => {}
+                }
+                out.push('}');
+                out
+            })
+            .collect::<Vec<_>>();
+
+        format!(
+            "{{\"finished\":{},\"nodes\":[{}]}}",
+            state.run_finished,
+            nodes.join(",")
+        )
+    }
+
+    /// Statuses and timings of the nodes as an HTML page, refreshing itself while the run is active.
+    pub fn html(&self) -> String {
+        let state = self.state.lock().unwrap();
+
+        let mut out = String::from("<!DOCTYPE html><html><head><meta charset=\"utf-8\">");
+        if !state.run_finished {
+            out.push_str("<meta http-equiv=\"refresh\" content=\"2\">");
+        }
+        out.push_str("<title>Run status</title></head><body><table>");
+        out.push_str("<tr><th>Node</th><th>Status</th><th>Worker</th><th>Time</th></tr>");
+
+        for (node, status, worker, timing) in state.rows() {
+            let worker = worker.map(|worker| worker.to_string()).unwrap_or_default();
+            let timing = match timing {
+                Some(Timing::Elapsed(elapsed)) | Some(Timing::Duration(elapsed)) => {
+                    format!("{:.1?}", elapsed)
+                }
+                None => String::new(),
+            };
+            write!(
+                out,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                html_escape(&node.to_string()),
+                status,
+                worker,
+                timing
+            )
+            .unwrap();
+        }
+
+        out.push_str("</table></body></html>");
+        out
+    }
+}
+
+enum Timing {
+    /// Running for this long.
+    Elapsed(Duration),
+    /// Took this long.
+    Duration(Duration),
+}
+
+impl<T: Hash + Eq> PageState<T> {
+    /// Node, status name, worker and timing of every node.
+    fn rows(&self) -> impl Iterator<Item = (&T, &'static str, Option<usize>, Option<Timing>)> {
+        self.order.iter().map(|node| match self.nodes[node] {
+            NodeState::Pending => (node, "pending", None, None),
+            NodeState::Running { worker, started } => (
+                node,
+                "running",
+                Some(worker),
+                Some(Timing::Elapsed(started.elapsed())),
+            ),
+            NodeState::Finished { outcome, duration } => {
+                (node, outcome, None, Some(Timing::Duration(duration)))
+            }
+            NodeState::Skipped => (node, "skipped", None, None),
+        })
+    }
+}
+
+impl<T: Hash + Eq> RunObserver<T> for StatusPage<T> {
+    fn on_node_start(&self, worker: usize, id: &T) {
+        if let Some(node) = self.state.lock().unwrap().nodes.get_mut(id) {
+            *node = NodeState::Running {
+                worker,
+                started: Instant::now(),
+            };
+        }
+    }
+
+    fn on_node_finish(&self, _worker: usize, id: &T, outcome: NodeOutcome<'_>, duration: Duration) {
+        let outcome = match outcome {
+            NodeOutcome::Completed => "completed",
+            NodeOutcome::Failed(_) => "failed",
+            NodeOutcome::Cached => "cached",
+        };
+
+        if let Some(node) = self.state.lock().unwrap().nodes.get_mut(id) {
+            *node = NodeState::Finished { outcome, duration };
+        }
+    }
+
+    fn on_node_skipped(&self, id: &T) {
+        if let Some(node) = self.state.lock().unwrap().nodes.get_mut(id) {
+            *node = NodeState::Skipped;
+        }
+    }
+
+    fn on_run_finish(&self, _report: &RunReport<T>) {
+        self.state.lock().unwrap().run_finished = true;
+    }
+}
+
+/// A status page being served, stopped when dropped.
+pub struct StatusServer {
+    addr: SocketAddr,
+    server: Arc<Server>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StatusServer {
+    /// Address the page is served on, useful when binding to port 0.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for StatusServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes("Content-Type", value).unwrap()
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::thread_pool_runner::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn it_serves_node_statuses() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+
+        let provider = TopologicalBatchProvider::new(nodes).unwrap();
+        let page = StatusPage::new(&provider);
+        let server = page.serve("127.0.0.1:0").unwrap();
+
+        assert!(get(server.addr(), "/status.json").ends_with(
+            r#"{"finished":false,"nodes":[{"id":"1","status":"pending"},{"id":"2","status":"pending"},{"id":"3","status":"pending"}]}"#
+        ));
+
+        let runner = ThreadPoolRunner::new(2);
+        runner.run_with_options(
+            provider,
+            Arc::new(FailingExecutor(2)),
+            RunOptions::new().with_observer(Arc::new(page.clone())),
+        );
+
+        let json = get(server.addr(), "/status.json");
+        assert!(json.contains(
+            r#"{"finished":true,"nodes":[{"id":"1","status":"completed","duration_ms":"#
+        ));
+        assert!(json.contains(r#"{"id":"2","status":"failed","duration_ms":"#));
+        assert!(json.contains(r#"{"id":"3","status":"skipped"}"#));
+
+        let html = get(server.addr(), "/");
+        assert!(html.contains("Content-Type: text/html"));
+        assert!(html.contains("<td>3</td><td>skipped</td>"));
+        assert!(get(server.addr(), "/status.json?t=1").contains(r#""finished":true"#));
+
+        assert!(get(server.addr(), "/missing").starts_with("HTTP/1.0 404"));
+    }
+}
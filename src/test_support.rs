@@ -0,0 +1,15 @@
+//! Fixtures shared by the unit tests of the crate.
+
+use super::common::*;
+
+/// Fails the node with the given ID with "broken", completes every other one.
+pub(crate) struct FailingExecutor(pub usize);
+
+impl CallableByID<usize> for FailingExecutor {
+    fn call(&self, id: &usize) -> Result<(), Error> {
+        match *id == self.0 {
+            true => Err("broken".into()),
+            false => Ok(()),
+        }
+    }
+}
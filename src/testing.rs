@@ -0,0 +1,425 @@
+//! Test support for code built on the crate: a single threaded deterministic runner with a virtual clock, to unit test
+//! executors and failure handling without threads, sleeps or flaky timing, and random graph generation for property
+//! tests and benchmarks. With the `testing` feature, proptest strategies generate shrinkable graphs and providers.
+//!
+//! The deterministic runner also replays the dispatch order of a run recorded by `RunLogger`, so an interleaving
+//! observed once in production can be reproduced locally.
+
+use super::clock::*;
+use super::common::*;
+use super::rng::*;
+use super::run_log::*;
+use super::scheduler::*;
+use super::topological_batch_provider::*;
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(feature = "testing")]
+use proptest::{sample, strategy::Strategy};
+
+/// Manually advanced clock. Clones share the time, so the executor under test can hold one and "sleep" on it while the
+/// runner reads the same time.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl VirtualClock {
+    /// Starts at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time elapsed since the start.
+    pub fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    /// Moves the time forward, returning immediately.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+/// Sleeping advances the time, so code waiting on a shared clock returns at once.
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        VirtualClock::now(self)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// An execution in virtual time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry<T> {
+    pub id: T,
+    /// The recorded worker of the node when replaying, 0 otherwise.
+    pub worker: usize,
+    pub start: Duration,
+    pub finish: Duration,
+}
+
+/// Result of a deterministic run.
+#[derive(Debug)]
+pub struct DeterministicReport<T> {
+    pub report: RunReport<T>,
+    /// Executions in order.
+    pub trace: Vec<TraceEntry<T>>,
+    /// Position in the replayed schedule from which the run no longer followed it, eg as a node recorded next was not
+    /// ready. `None` for runs not replaying a schedule.
+    pub diverged_at: Option<usize>,
+}
+
+/// Runs nodes one at a time on the calling thread. Among the ready nodes the smallest ID goes first, so the same
+/// graph and executor always produce the same order.
+#[derive(Debug, Clone, Default)]
+pub struct DeterministicRunner {
+    clock: VirtualClock,
+}
+
+impl DeterministicRunner {
+    pub fn new(clock: VirtualClock) -> Self {
+        Self { clock }
+    }
+
+    /// Runs the provider to completion. Any scheduler of the provider is replaced.
+    pub fn run<T>(
+        &self,
+        topological_batch_provider: TopologicalBatchProvider<T>,
+        node_executor: &dyn CallableByID<T>,
+    ) -> DeterministicReport<T>
+    where
+        T: Hash + PartialEq + Eq + Clone + Ord + Send + 'static,
+    {
+        self.replay(topological_batch_provider, node_executor, &[])
+    }
+
+    /// Runs the nodes in the order of `schedule`, as recorded from an earlier run by `RunTimeline::schedule`, to
+    /// reproduce an interleaving observed once. Should the run stop following the schedule (see
+    /// `DeterministicReport::diverged_at`), or the schedule end before the run, the rest of the nodes goes smallest ID
+    /// first as with `run`.
+    pub fn replay<T>(
+        &self,
+        topological_batch_provider: TopologicalBatchProvider<T>,
+        node_executor: &dyn CallableByID<T>,
+        schedule: &[Dispatch<T>],
+    ) -> DeterministicReport<T>
+    where
+        T: Hash + PartialEq + Eq + Clone + Ord + Send + 'static,
+    {
+        let mut provider = topological_batch_provider.with_scheduler(ReplayScheduler {
+            next: schedule
+                .iter()
+                .map(|dispatch| dispatch.id.clone())
+                .collect(),
+        });
+        let mut report = RunReport::new();
+        let mut trace = vec![];
+        let mut position = 0;
+        let mut diverged_at = None;
+
+        while let Some(node) = provider.pop() {
+            let mut worker = 0;
+            if diverged_at.is_none() && position < schedule.len() {
+                if schedule[position].id == node {
+                    worker = schedule[position].worker;
+                    position += 1;
+                } else {
+                    diverged_at = Some(position);
+                }
+            }
+
+            let start = self.clock.now();
+            let result = node_executor.call(&node);
+            trace.push(TraceEntry {
+                id: node.clone(),
+                worker,
+                start,
+                finish: self.clock.now(),
+            });
+
+            match result {
+                Ok(()) => {
+                    let _ = provider.complete(node.clone());
+                    report.completed.push(node);
+                }
+                Err(err) => {
+                    report
+                        .skipped
+                        .extend(provider.fail(node.clone()).unwrap_or_default());
+                    report.failed.push((node, err));
+                }
+            }
+        }
+
+        if diverged_at.is_none() && position < schedule.len() {
+            diverged_at = Some(position);
+        }
+
+        DeterministicReport {
+            report,
+            trace,
+            diverged_at,
+        }
+    }
+}
+
+struct SmallestIdFirst;
+
+impl<T: Ord> Scheduler<T> for SmallestIdFirst {
+    fn select(&mut self, ready: &[ReadyNode<'_, T>], _graph: &GraphView<'_, T>) -> usize {
+        let mut best = 0;
+        for (position, node) in ready.iter().enumerate() {
+            if node.id < ready[best].id {
+                best = position;
+            }
+        }
+
+        best
+    }
+
+    fn clone_box(&self) -> Box<dyn Scheduler<T> + Send> {
+        Box::new(SmallestIdFirst)
+    }
+}
+
+/// Follows the recorded order while the next recorded node is ready, then falls back to `SmallestIdFirst`.
+#[derive(Clone)]
+struct ReplayScheduler<T> {
+    next: VecDeque<T>,
+}
+
+impl<T: PartialEq + Ord + Clone + Send + 'static> Scheduler<T> for ReplayScheduler<T> {
+    fn select(&mut self, ready: &[ReadyNode<'_, T>], graph: &GraphView<'_, T>) -> usize {
+        let recorded = self
+            .next
+            .front()
+            .and_then(|next| ready.iter().position(|node| node.id == next));
+
+        match recorded {
+            Some(position) => {
+                self.next.pop_front();
+                position
+            }
+            None => {
+                self.next.clear();
+                SmallestIdFirst.select(ready, graph)
+            }
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Scheduler<T> + Send> {
+        Box::new(self.clone())
+    }
+}
+
+/// Random acyclic graph of `node_count` nodes (IDs `0..node_count`, of any integer type they fit in), in the input
+/// format of the provider. Each pair of nodes is connected with probability `edge_density` (clamped to `[0, 1]`),
+/// always from a smaller to a larger ID, which keeps the graph acyclic. The same seed always produces the same graph.
+///
+/// Panics when `node_count` doesn't fit in `T`, eg above 256 nodes for `u8` IDs.
+pub fn random_dag<T: TryFrom<usize> + Hash + Eq + Clone>(
+    node_count: usize,
+    edge_density: f64,
+    seed: u64,
+) -> HashMap<T, Vec<T>> {
+    let edge_density = edge_density.clamp(0.0, 1.0);
+    let mut rng = Rng::new(seed);
+    let ids = (0..node_count)
+        .map(|node| {
+            T::try_from(node)
+                .unwrap_or_else(|_| panic!("Node ID {} does not fit the ID type.", node))
+        })
+        .collect::<Vec<_>>();
+
+    (0..node_count)
+        .map(|node| {
+            let dependencies = (0..node)
+                .filter(|_| rng.next_f64() < edge_density)
+                .map(|dependency| ids[dependency].clone())
+                .collect();
+            (ids[node].clone(), dependencies)
+        })
+        .collect()
+}
+
+/// Proptest strategy of acyclic graphs of at most `max_nodes` nodes (IDs `0..n`), in the input format of the provider.
+/// Nodes only depend on smaller IDs, which keeps the graph acyclic. Failing cases shrink towards fewer nodes and edges.
+#[cfg(feature = "testing")]
+pub fn dag_strategy(max_nodes: usize) -> impl Strategy<Value = HashMap<usize, Vec<usize>>> {
+    (0..=max_nodes).prop_flat_map(|node_count| {
+        (0..node_count)
+            .map(|node| sample::subsequence((0..node).collect::<Vec<_>>(), 0..=node))
+            .collect::<Vec<_>>()
+            .prop_map(|dependencies| dependencies.into_iter().enumerate().collect())
+    })
+}
+
+/// Proptest strategy of providers over the graphs of `dag_strategy`.
+#[cfg(feature = "testing")]
+pub fn provider_strategy(
+    max_nodes: usize,
+) -> impl Strategy<Value = TopologicalBatchProvider<usize>> {
+    dag_strategy(max_nodes).prop_map(|nodes| TopologicalBatchProvider::new(nodes).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ordering::*;
+    #[cfg(feature = "testing")]
+    use crate::test_support::*;
+    #[cfg(feature = "testing")]
+    use proptest::prelude::*;
+
+    struct SleepyExecutor {
+        clock: VirtualClock,
+    }
+
+    impl CallableByID<usize> for SleepyExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            self.clock.advance(Duration::from_secs(*id as u64));
+            match id {
+                4 => Err("boom".into()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn it_runs_deterministically_in_virtual_time() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![]);
+        nodes.insert(3, vec![1]);
+        nodes.insert(4, vec![2]);
+        nodes.insert(5, vec![4]);
+
+        let clock = VirtualClock::new();
+        let runner = DeterministicRunner::new(clock.clone());
+        let result = runner.run(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            &SleepyExecutor {
+                clock: clock.clone(),
+            },
+        );
+
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            result
+                .trace
+                .iter()
+                .map(|entry| entry.id)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            TraceEntry {
+                id: 3,
+                worker: 0,
+                start: Duration::from_secs(3),
+                finish: Duration::from_secs(6),
+            },
+            result.trace[2]
+        );
+        assert_eq!(Duration::from_secs(10), clock.now());
+        assert_eq!(vec![5], result.report.skipped);
+        assert_eq!(4, result.report.failed[0].0);
+        assert_eq!(None, result.diverged_at);
+    }
+
+    struct Noop;
+
+    impl CallableByID<usize> for Noop {
+        fn call(&self, _id: &usize) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_replays_recorded_schedules() {
+        let nodes = || {
+            TopologicalBatchProvider::new(HashMap::from([
+                (1, vec![]),
+                (2, vec![]),
+                (3, vec![1]),
+                (4, vec![]),
+            ]))
+            .unwrap()
+        };
+        let log = concat!(
+            "{\"ts_us\":1,\"event\":\"node_start\",\"node\":\"4\",\"worker\":1}\n",
+            "{\"ts_us\":2,\"event\":\"node_start\",\"node\":\"1\",\"worker\":0}\n",
+            "{\"ts_us\":3,\"event\":\"node_finish\",\"node\":\"1\",\"worker\":0,\"outcome\":\"completed\",\"duration_us\":1}\n",
+            "{\"ts_us\":4,\"event\":\"node_start\",\"node\":\"3\",\"worker\":0}\n",
+        );
+        let schedule = replay(log.as_bytes()).unwrap().schedule::<usize>().unwrap();
+
+        let result = DeterministicRunner::default().replay(nodes(), &Noop, &schedule);
+        assert_eq!(
+            vec![(4, 1), (1, 0), (3, 0), (2, 0)],
+            result
+                .trace
+                .iter()
+                .map(|entry| (entry.id, entry.worker))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(None, result.diverged_at);
+
+        let out_of_order = [Dispatch { id: 3, worker: 0 }, Dispatch { id: 1, worker: 0 }];
+        let result = DeterministicRunner::default().replay(nodes(), &Noop, &out_of_order);
+        assert_eq!(Some(0), result.diverged_at);
+        assert_eq!(4, result.report.completed.len());
+    }
+
+    #[test]
+    fn it_generates_random_dags() {
+        let nodes: HashMap<usize, Vec<usize>> = random_dag(50, 0.2, 3);
+
+        assert_eq!(50, nodes.len());
+        assert!(nodes.values().any(|dependencies| !dependencies.is_empty()));
+        assert_eq!(50, topological_sort(&nodes).unwrap().len());
+        assert_eq!(nodes, random_dag(50, 0.2, 3));
+        assert_ne!(nodes, random_dag(50, 0.2, 4));
+
+        let narrow: HashMap<u8, Vec<u8>> = random_dag(50, 0.2, 3);
+        assert_eq!(nodes.len(), narrow.len());
+        assert_eq!(nodes[&49].len(), narrow[&49].len());
+
+        let empty: HashMap<usize, Vec<usize>> = random_dag(10, 0.0, 0);
+        assert!(empty.values().all(Vec::is_empty));
+    }
+
+    #[cfg(feature = "testing")]
+    proptest! {
+        #[test]
+        fn it_runs_generated_graphs_in_dependency_order(
+            nodes in dag_strategy(20),
+            failing in any::<usize>(),
+        ) {
+            let executor = FailingExecutor(failing % 21);
+            let result = DeterministicRunner::default().run(
+                TopologicalBatchProvider::new(nodes.clone()).unwrap(),
+                &executor,
+            );
+
+            let report = result.report;
+            prop_assert_eq!(
+                nodes.len(),
+                report.completed.len() + report.failed.len() + report.skipped.len()
+            );
+            for (position, entry) in result.trace.iter().enumerate() {
+                for dependency in &nodes[&entry.id] {
+                    prop_assert!(report.completed[..].contains(dependency));
+                    prop_assert!(result.trace[..position].iter().any(|earlier| earlier.id == *dependency));
+                }
+            }
+        }
+    }
+}
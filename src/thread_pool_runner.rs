@@ -1,137 +1,3491 @@
 use std::{
+    any::Any,
+    cell::Cell,
+    collections::{HashMap, HashSet, VecDeque},
     hash::Hash,
-    sync::{Arc, Mutex},
+    hint,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, OnceLock},
     thread,
     time::Duration,
 };
 
+use super::cache::*;
+use super::cancel::*;
+use super::clock::*;
 use super::common::*;
+use super::context::*;
+use super::fault::*;
+use super::observer::*;
+use super::planning::*;
+use super::run_error::*;
+use super::slots::*;
+use super::spawn::*;
+use super::sync::*;
 use super::topological_batch_provider::*;
 
+/// Per-run extensions of `ThreadPoolRunner::run_with_options`.
+pub struct RunOptions<T> {
+    cache: Option<NodeCache<T>>,
+    observers: Vec<Arc<dyn RunObserver<T> + Send + Sync>>,
+    fault_injector: Option<FaultInjector>,
+    cancellation: Option<CancellationToken>,
+    affinity: Option<Arc<dyn Affinity<T> + Send + Sync>>,
+    weights: Option<Weights<T>>,
+    progress: bool,
+    slots: Vec<Slots<T>>,
+    locality: Option<LocalityKeys<T>>,
+    rollback: bool,
+    tenant: Option<String>,
+    deadlock_detection: bool,
+    watchdog: Option<Duration>,
+    clock: SharedClock,
+}
+
+/// Relative cost of every node, see `RunOptions::with_weights`.
+pub type Weights<T> = Arc<dyn Fn(&T) -> f64 + Send + Sync>;
+
+/// Estimated memory usage of every node, in the unit of the budget, see `RunOptions::with_memory_budget`.
+pub type MemoryEstimates<T> = Arc<dyn Fn(&T) -> u64 + Send + Sync>;
+
+/// Locality key of every node, `None` for nodes without one, see `RunOptions::with_locality`.
+pub type LocalityKeys<T> = Arc<dyn Fn(&T) -> Option<String> + Send + Sync>;
+
+/// Worker indices nodes are restricted to, see `RunOptions::with_affinity`.
+pub trait Affinity<T> {
+    /// `None` when the node can be executed by any worker.
+    fn workers(&self, id: &T) -> Option<&[usize]>;
+}
+
+impl<T: Hash + Eq> Affinity<T> for HashMap<T, Vec<usize>> {
+    fn workers(&self, id: &T) -> Option<&[usize]> {
+        self.get(id).map(Vec::as_slice)
+    }
+}
+
+impl<T> RunOptions<T> {
+    pub fn new() -> Self {
+        Self {
+            cache: None,
+            observers: vec![],
+            fault_injector: None,
+            cancellation: None,
+            affinity: None,
+            weights: None,
+            progress: false,
+            slots: vec![],
+            locality: None,
+            rollback: false,
+            tenant: None,
+            deadlock_detection: false,
+            clock: SystemClock::shared(),
+            watchdog: None,
+        }
+    }
+
+    /// See `ThreadPoolRunner::run_cached`.
+    pub fn with_cache(mut self, cache: NodeCache<T>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Adds an observer notified about the progress of the run.
+    pub fn with_observer(mut self, observer: Arc<dyn RunObserver<T> + Send + Sync>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Wraps the executor with the injector, for testing failure handling under controlled chaos.
+    pub fn with_fault_injector(mut self, fault_injector: FaultInjector) -> Self {
+        self.fault_injector = Some(fault_injector);
+        self
+    }
+
+    /// Once the token is cancelled no more nodes are started. The executing ones are let finish, the rest is reported
+    /// as cancelled.
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Restricts nodes to some of the workers, eg when the executor holds a resource usable from a single thread (a
+    /// GPU context, a COM apartment, ...). The other workers leave these nodes to them, taking other available nodes
+    /// meanwhile. Indices missing from the runner are ignored, a node without any existing worker runs on any of them.
+    pub fn with_affinity(mut self, affinity: Arc<dyn Affinity<T> + Send + Sync>) -> Self {
+        self.affinity = Some(affinity);
+        self
+    }
+
+    /// Tracks the progress of the run for `RunHandle::progress`, at the cost of a copy of the graph and of walking it
+    /// on every call.
+    pub fn with_progress(mut self) -> Self {
+        self.progress = true;
+        self
+    }
+
+    /// Relative cost of the nodes, for `RunHandle::progress`. Every node weighs 1 by default. Implies
+    /// `with_progress`.
+    pub fn with_weights(mut self, weights: Weights<T>) -> Self {
+        self.weights = Some(weights);
+        self.progress = true;
+        self
+    }
+
+    /// Only starts a node when the estimates of the executing nodes and its own stay within `budget`, eg to keep a few
+    /// memory hungry nodes of a wide graph from running together. Nodes not fitting wait while the workers take other
+    /// available nodes. A node estimated above the whole budget is started once no other node holds memory. Same as a
+    /// `memory` slot, see `with_slots`.
+    pub fn with_memory_budget(self, budget: u64, estimates: MemoryEstimates<T>) -> Self
+    where
+        T: 'static,
+    {
+        self.with_slots(
+            Slots::new(Arc::new(move |node| {
+                vec![("memory".to_string(), estimates(node))]
+            }))
+            .with_capacity("memory", budget),
+        )
+    }
+
+    /// Only starts a node once its claims fit in the free capacity of their slots, see `Slots`. Can be called more than
+    /// once, the slots of every call are independent from each other.
+    pub fn with_slots(mut self, slots: Slots<T>) -> Self {
+        self.slots.push(slots);
+        self
+    }
+
+    /// Keeps nodes sharing a locality key on the same worker, one after the other, eg nodes reading the same large file
+    /// from a warm page cache. A worker goes on with the key of its last node while there are nodes of it, and leaves
+    /// the keys other workers are on to them. It only takes over such nodes when nothing else is available.
+    pub fn with_locality(mut self, locality: LocalityKeys<T>) -> Self {
+        self.locality = Some(locality);
+        self
+    }
+
+    /// Unwinds failed runs saga-style: once the run is over, `CallableByID::on_rollback` is called for every completed
+    /// transitive dependency of the failed nodes, dependees first. Needs a provider exposing its graph (see
+    /// `BatchProvider::graph`), nothing is rolled back otherwise.
+    pub fn with_rollback(mut self) -> Self {
+        self.rollback = true;
+        self
+    }
+
+    /// The tenant the run is accounted to when sharing persistent workers with other runs, see
+    /// `ThreadPoolRunner::with_fairness`. Runs without a tenant are each accounted on their own.
+    pub fn with_tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    /// Ends the run instead of hanging forever when nothing is executing nor available while the provider is not
+    /// empty, eg as a popped node was never completed. `RunHandle::wait` returns a `RunError::Deadlock` listing the
+    /// unfinished nodes, `ThreadPoolRunner::run` panics. Not for providers fed from outside the run (channels, other
+    /// processes, ...), which are legitimately waiting in that state.
+    pub fn with_deadlock_detection(mut self) -> Self {
+        self.deadlock_detection = true;
+        self
+    }
+
+    /// Starts a watchdog thread reporting the nodes executing for longer than `warn_after` to the observers (see
+    /// `RunObserver::on_node_slow`), to spot silently hung nodes. The nodes are not interrupted. Runners without threads
+    /// execute the run before it could start, so they go without.
+    pub fn with_watchdog(mut self, warn_after: Duration) -> Self {
+        self.watchdog = Some(warn_after);
+        self
+    }
+
+    /// Clock the node durations (reported to the observers and the progress estimates) and the watchdog are measured
+    /// with, and the delays of the fault injector slept on, the system clock by default. The watchdog still polls in
+    /// real time.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl<T> Default for RunOptions<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for RunOptions<T> {
+    fn clone(&self) -> Self {
+        Self {
+            cache: self.cache.clone(),
+            observers: self.observers.clone(),
+            fault_injector: self.fault_injector.clone(),
+            cancellation: self.cancellation.clone(),
+            affinity: self.affinity.clone(),
+            weights: self.weights.clone(),
+            progress: self.progress,
+            slots: self.slots.clone(),
+            locality: self.locality.clone(),
+            rollback: self.rollback,
+            tenant: self.tenant.clone(),
+            deadlock_detection: self.deadlock_detection,
+            watchdog: self.watchdog,
+            clock: self.clock.clone(),
+        }
+    }
+}
+
 pub struct ThreadPoolRunner {
     thread_count: usize,
+    max_in_flight: Option<usize>,
+    persistent_workers: bool,
+    /// Started on the first submitted run, so the hooks set after `with_persistent_workers` apply.
+    pool: OnceLock<WorkerPool>,
+    hooks: WorkerHooks,
+    idle_strategy: IdleStrategy,
+    fairness: Fairness,
+}
+
+/// How persistent workers are shared between the submitted runs, see `ThreadPoolRunner::with_fairness`. Workers pick
+/// from the least served tenant first, the runs of a tenant taking turns.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Fairness {
+    /// Every tenant gets the same share of the dispatched nodes.
+    #[default]
+    RoundRobin,
+    /// Tenants get dispatched nodes in proportion to their share. Tenants missing from the map (and runs without a
+    /// tenant) have a share of 1.
+    WeightedShares(HashMap<String, u32>),
+}
+
+impl Fairness {
+    fn share(&self, tenant: Option<&str>) -> u32 {
+        match self {
+            Fairness::RoundRobin => 1,
+            Fairness::WeightedShares(shares) => tenant
+                .and_then(|tenant| shares.get(tenant))
+                .copied()
+                .unwrap_or(1)
+                .max(1),
+        }
+    }
+}
+
+/// How a worker without available node waits for one, see `ThreadPoolRunner::with_idle_strategy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleStrategy {
+    /// Busy-loops up to the given number of consecutive idle checks, then falls back to waiting on the condition
+    /// variable. The lowest latency, at the cost of a core per idle worker.
+    Spin(u32),
+    /// Yields to the OS scheduler between checks.
+    Yield,
+    /// Parks the thread for the given time between checks. Not woken early.
+    Park(Duration),
+    /// Waits on the run's condition variable, woken as soon as a node finishes, checking again after the given time at
+    /// the latest. The default, with 100ms.
+    Condvar(Duration),
+}
+
+impl IdleStrategy {
+    /// Waits after `streak` consecutive idle checks. `wait` blocks on the condition variable for at most the given time.
+    fn idle(&self, streak: u32, wait: impl FnOnce(Duration)) {
+        match *self {
+            IdleStrategy::Spin(spins) if streak < spins => hint::spin_loop(),
+            IdleStrategy::Spin(_) => wait(IDLE_WAIT),
+            IdleStrategy::Yield => thread::yield_now(),
+            IdleStrategy::Park(timeout) => thread::park_timeout(timeout),
+            IdleStrategy::Condvar(timeout) => wait(timeout),
+        }
+    }
+}
+
+impl Default for IdleStrategy {
+    fn default() -> Self {
+        IdleStrategy::Condvar(IDLE_WAIT)
+    }
+}
+
+/// Called with the index of the worker, on its own thread.
+pub type WorkerHook = Arc<dyn Fn(usize) + Send + Sync>;
+
+#[derive(Clone, Default)]
+struct WorkerHooks {
+    on_start: Option<WorkerHook>,
+    on_stop: Option<WorkerHook>,
+}
+
+impl WorkerHooks {
+    /// Runs `work` on the worker's thread between the hooks.
+    fn around(&self, worker: usize, work: impl FnOnce()) {
+        if let Some(on_start) = &self.on_start {
+            on_start(worker);
+        }
+        as_worker(worker, work);
+        if let Some(on_stop) = &self.on_stop {
+            on_stop(worker);
+        }
+    }
+}
+
+thread_local! {
+    /// Index of the worker running on this thread, for `PerThreadExecutor`.
+    static WORKER: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Runs `f` as `worker`, for `PerThreadExecutor` to pick the worker's instance.
+fn as_worker<R>(worker: usize, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<usize>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            WORKER.set(self.0);
+        }
+    }
+
+    let _restore = Restore(WORKER.replace(Some(worker)));
+    f()
 }
 
 impl ThreadPoolRunner {
+    /// A thread count of 0 executes the nodes inline, on the calling thread of `run` (or `submit`, which then returns
+    /// once the run is over), without spawning any thread. Useful in tests and where creating threads is restricted.
     pub fn new(thread_count: usize) -> Self {
-        Self { thread_count }
+        Self {
+            thread_count,
+            max_in_flight: None,
+            persistent_workers: false,
+            pool: OnceLock::new(),
+            hooks: WorkerHooks::default(),
+            idle_strategy: IdleStrategy::default(),
+            fairness: Fairness::default(),
+        }
+    }
+
+    /// Keeps the worker threads alive between runs, instead of spawning and joining them for each run. Meant for
+    /// applications executing many small graphs. The threads stop when the runner is dropped, after the submitted
+    /// runs finished.
+    ///
+    /// A node executor waiting for an other run of the same runner occupies a worker meanwhile, with all workers
+    /// waiting that run never progresses.
+    pub fn with_persistent_workers(mut self) -> Self {
+        self.persistent_workers = true;
+        self
+    }
+
+    /// Called on every worker thread before it executes any node, eg to set up thread-local resources (runtime
+    /// handles, allocators, profiler guards, ...). Without persistent workers this happens for every run.
+    pub fn with_on_worker_start(mut self, hook: WorkerHook) -> Self {
+        self.hooks.on_start = Some(hook);
+        self
+    }
+
+    /// Called on every worker thread once it stopped executing nodes, to tear down what `with_on_worker_start` set up.
+    /// Persistent workers stop when the runner is dropped.
+    pub fn with_on_worker_stop(mut self, hook: WorkerHook) -> Self {
+        self.hooks.on_stop = Some(hook);
+        self
+    }
+
+    /// Sets how idle workers wait for nodes to become available. Latency sensitive workloads of tiny nodes can spin,
+    /// batch jobs rather save the CPU.
+    pub fn with_idle_strategy(mut self, idle_strategy: IdleStrategy) -> Self {
+        self.idle_strategy = idle_strategy;
+        self
+    }
+
+    /// Sets how persistent workers are shared between the runs of the tenants (see `RunOptions::with_tenant`), so one
+    /// giant graph can't monopolize all of them. Tenants can't take more than the workers their runs keep busy: the
+    /// share of a tenant without available nodes goes to the others. Only applies with `with_persistent_workers`,
+    /// otherwise every run has its own threads.
+    pub fn with_fairness(mut self, fairness: Fairness) -> Self {
+        self.fairness = fairness;
+        self
+    }
+
+    /// Caps the number of nodes executed at the same time, independently from the thread count. Useful when the
+    /// executor spawns processes, or when the throttle should be the DAG's width rather than the pool size.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight.max(1));
+        self
     }
 
+    /// Executes all nodes of the provider, each after all of its dependencies completed. When a node fails, its
+    /// dependees are skipped, the rest of the graph is still executed.
     pub fn run<T: Hash + PartialEq + Eq + Clone + Send + 'static>(
+        &self,
+        topological_batch_provider: impl BatchProvider<T> + Send + 'static,
+        node_executor: Arc<dyn CallableByID<T> + Send + Sync>,
+    ) -> RunReport<T> {
+        self.run_with_options(topological_batch_provider, node_executor, RunOptions::new())
+    }
+
+    /// Same as `run`, but a panic of the executor (or of a callback such as an observer or a scheduler) is returned as
+    /// an error instead of being resumed, see `RunHandle::wait`. Meant for long-lived services embedding the runner.
+    pub fn try_run<T: Hash + PartialEq + Eq + Clone + Send + 'static>(
+        &self,
+        topological_batch_provider: impl BatchProvider<T> + Send + 'static,
+        node_executor: Arc<dyn CallableByID<T> + Send + Sync>,
+    ) -> Result<RunReport<T>, RunError<T>> {
+        self.submit(topological_batch_provider, node_executor)
+            .wait()
+    }
+
+    /// Same as `run`, but before executing a node its fingerprint is looked up in the cache store: on a hit the node
+    /// is completed without calling the executor (and reported as cached). Successful executions are recorded.
+    pub fn run_cached<T: Hash + PartialEq + Eq + Clone + Send + 'static>(
+        &self,
+        topological_batch_provider: impl BatchProvider<T> + Send + 'static,
+        node_executor: Arc<dyn CallableByID<T> + Send + Sync>,
+        cache: NodeCache<T>,
+    ) -> RunReport<T> {
+        self.run_with_options(
+            topological_batch_provider,
+            node_executor,
+            RunOptions::new().with_cache(cache),
+        )
+    }
+
+    /// Same as `run`, extended with the cache, observers, fault injection and cancellation of `options`.
+    pub fn run_with_options<T: Hash + PartialEq + Eq + Clone + Send + 'static>(
+        &self,
+        topological_batch_provider: impl BatchProvider<T> + Send + 'static,
+        node_executor: Arc<dyn CallableByID<T> + Send + Sync>,
+        options: RunOptions<T>,
+    ) -> RunReport<T> {
+        self.submit_with_options(topological_batch_provider, node_executor, options)
+            .join()
+            .unwrap_or_else(|abort| abort.resume())
+    }
+
+    /// Same as `run`, without waiting for the run to finish. With persistent workers (see `with_persistent_workers`)
+    /// the submitted runs share the workers, taking turns in getting their nodes executed, so a large graph doesn't
+    /// starve the others.
+    pub fn submit<T: Hash + PartialEq + Eq + Clone + Send + 'static>(
+        &self,
+        topological_batch_provider: impl BatchProvider<T> + Send + 'static,
+        node_executor: Arc<dyn CallableByID<T> + Send + Sync>,
+    ) -> RunHandle<T> {
+        self.submit_with_options(topological_batch_provider, node_executor, RunOptions::new())
+    }
+
+    /// Same as `run`, with an executor instance per worker created by `factory` on the worker's first node. The
+    /// executor is then not required to be `Sync`, and can hold thread-local caches or connections. `on_rollback` is
+    /// called on the instance that executed the node.
+    pub fn run_with_factory<T, E, F>(
+        &self,
+        topological_batch_provider: impl BatchProvider<T> + Send + 'static,
+        factory: F,
+    ) -> RunReport<T>
+    where
+        T: Hash + PartialEq + Eq + Clone + Send + 'static,
+        E: CallableByID<T> + Send + 'static,
+        F: Fn() -> E + Send + Sync + 'static,
+    {
+        self.run(
+            topological_batch_provider,
+            Arc::new(PerThreadExecutor::new(factory)),
+        )
+    }
+
+    /// Same as `run_with_options`, without waiting for the run to finish.
+    pub fn submit_with_options<T: Hash + PartialEq + Eq + Clone + Send + 'static>(
+        &self,
+        topological_batch_provider: impl BatchProvider<T> + Send + 'static,
+        node_executor: Arc<dyn CallableByID<T> + Send + Sync>,
+        options: RunOptions<T>,
+    ) -> RunHandle<T> {
+        let node_executor: Arc<dyn CallableByID<T> + Send + Sync> = match &options.fault_injector {
+            Some(injector) => Arc::new(FaultyExecutor {
+                inner: node_executor,
+                injector: injector.clone(),
+                clock: options.clock.clone(),
+            }),
+            None => node_executor,
+        };
+        let report = RunReport {
+            satisfied: topological_batch_provider.satisfied(),
+            ..RunReport::new()
+        };
+        // Otherwise only built once a node asks for its dependencies.
+        let graph = options
+            .progress
+            .then(|| RunGraph::new(topological_batch_provider.graph()));
+        let progress = graph
+            .as_ref()
+            .filter(|graph| !graph.nodes.is_empty())
+            .map(|graph| ProgressModel::new(&graph.nodes, options.weights.as_deref()));
+        let tenant = options.tenant.clone();
+        let run = Arc::new(SharedRun {
+            state: Mutex::new(RunState {
+                provider: Box::new(topological_batch_provider),
+                progress,
+                deferred: Deferred::new(),
+                slots: SlotLedger::new(options.slots.clone()),
+                localities: HashMap::new(),
+                in_flight: 0,
+                outcome: None,
+                graph,
+                finished: HashMap::new(),
+                completed_by: HashMap::new(),
+            }),
+            changed: Condvar::new(),
+            node_executor,
+            options,
+            max_in_flight: self.max_in_flight.unwrap_or(usize::MAX),
+            thread_count: self.thread_count,
+            report: Mutex::new(report),
+            tasks: Arc::new(TaskQueue::default()),
+            executing: Mutex::new(HashMap::new()),
+        });
+
+        if self.thread_count == 0 {
+            self.hooks.around(0, || work(&run, 0, self.idle_strategy));
+        } else if self.persistent_workers {
+            self.pool
+                .get_or_init(|| {
+                    WorkerPool::new(
+                        self.thread_count,
+                        self.hooks.clone(),
+                        self.idle_strategy,
+                        self.fairness.clone(),
+                    )
+                })
+                .add(run.clone(), tenant);
+        } else {
+            for worker in 0..self.thread_count {
+                let run = run.clone();
+                let hooks = self.hooks.clone();
+                let idle_strategy = self.idle_strategy;
+                thread::spawn(move || hooks.around(worker, || work(&run, worker, idle_strategy)));
+            }
+        }
+
+        if let Some(warn_after) = run.options.watchdog {
+            if self.thread_count > 0 && !run.state.lock().is_over() {
+                let run = run.clone();
+                thread::spawn(move || watch(&run, warn_after));
+            }
+        }
+
+        RunHandle { run }
+    }
+
+    /// Projects the wall time and per-thread utilization of `run` with this runner's thread count and in-flight cap,
+    /// given an estimated `cost` for every node. Nothing is executed, the provider is only used to replay the
+    /// scheduling decisions in virtual time. A runner without threads is rejected.
+    pub fn simulate<T, F>(
+        &self,
+        topological_batch_provider: TopologicalBatchProvider<T>,
+        cost: F,
+    ) -> Result<SimReport, NoThreads>
+    where
+        T: Hash + PartialEq + Eq + Clone,
+        F: Fn(&T) -> Duration,
+    {
+        if self.thread_count == 0 {
+            return Err(NoThreads);
+        }
+
+        Ok(simulate(
+            topological_batch_provider,
+            self.thread_count,
+            self.max_in_flight.unwrap_or(usize::MAX),
+            cost,
+        ))
+    }
+
+    /// Executes the graph following a precomputed schedule: one thread per scheduled worker, each running its nodes
+    /// in the planned order, waiting for their dependencies when needed. The schedule must be planned on the same
+    /// graph, nodes missing from it are never executed. The thread count and in-flight cap of the runner are ignored.
+    ///
+    /// A schedule leaving out an unfinished dependency of a planned node is rejected upfront with
+    /// `RunError::Deadlock`, naming the planned nodes that would wait for it forever. A panicking executor fails its
+    /// node, a panic of a worker otherwise is returned as `RunError::Panicked`.
+    pub fn run_planned<T: Hash + PartialEq + Eq + Clone + Send + Sync + 'static>(
         &self,
         topological_batch_provider: TopologicalBatchProvider<T>,
+        schedule: &Schedule<T>,
         node_executor: Arc<dyn CallableByID<T> + Send + Sync>,
-    ) {
-        let provider = Arc::new(Mutex::new(topological_batch_provider));
+    ) -> Result<RunReport<T>, RunError<T>> {
+        let stuck = blocked_by_schedule(&topological_batch_provider, schedule);
+        if !stuck.is_empty() {
+            return Err(RunError::Deadlock { stuck });
+        }
+
+        let state = Arc::new((Mutex::new(topological_batch_provider), Condvar::new()));
         let mut handles = vec![];
 
-        for _ in 0..self.thread_count {
+        for (worker, planned) in schedule.workers.iter().enumerate() {
+            let assigned = planned
+                .iter()
+                .map(|node| node.id.clone())
+                .collect::<Vec<_>>();
+
             let handle = thread::spawn({
-                let provider = provider.clone();
+                let state = state.clone();
                 let node_executor = node_executor.clone();
 
-                move || loop {
-                    let node;
-                    {
-                        let mut provider_lock = provider.lock().unwrap();
-                        if provider_lock.is_empty() {
-                            break;
-                        }
+                move || {
+                    let (provider, changed) = &*state;
+                    let mut report = RunReport::new();
 
-                        node = provider_lock.pop();
-                    }
+                    for node in assigned {
+                        {
+                            let mut provider_lock = provider.lock();
+                            loop {
+                                if provider_lock.take(&node) {
+                                    break;
+                                }
+
+                                match provider_lock.status(&node) {
+                                    Some(NodeStatus::Pending) | Some(NodeStatus::Running) => {
+                                        provider_lock = changed.wait(provider_lock);
+                                    }
+                                    // Skipped (reported by the failing worker), or not part of the graph.
+                                    _ => break,
+                                }
+                            }
+
+                            if provider_lock.status(&node) != Some(NodeStatus::Running) {
+                                continue;
+                            }
+                        }
 
-                    if let Some(node) = node {
-                        node_executor.call(node.clone());
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            as_worker(worker, || node_executor.call(&node))
+                        }))
+                        .unwrap_or_else(|payload| {
+                            Err(match panic_message(&*payload) {
+                                Some(message) => format!("Executor panicked: {}", message).into(),
+                                None => "Executor panicked.".into(),
+                            })
+                        });
 
                         {
-                            let mut provider_lock = provider.lock().unwrap();
-                            provider_lock.complete(node);
+                            let mut provider_lock = provider.lock();
+                            match result {
+                                Ok(()) => {
+                                    let _ = provider_lock.complete(node.clone());
+                                    report.completed.push(node);
+                                }
+                                Err(err) => {
+                                    report.skipped.extend(
+                                        provider_lock.fail(node.clone()).unwrap_or_default(),
+                                    );
+                                    report.failed.push((node, err));
+                                }
+                            }
                         }
-                    } else {
-                        thread::sleep(Duration::from_millis(100));
+                        changed.notify_all();
                     }
+
+                    report
                 }
             });
             handles.push(handle);
         }
 
+        let mut report = RunReport::new();
+        let mut panicked = None;
         for handle in handles {
-            handle.join().unwrap();
+            match handle.join() {
+                Ok(worker_report) => report.merge(worker_report),
+                Err(payload) => {
+                    panicked.get_or_insert_with(|| RunError::panicked(None, &*payload));
+                }
+            }
+        }
+
+        match panicked {
+            Some(err) => Err(err),
+            None => Ok(report),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::collections::{HashMap, HashSet};
+    /// Brings up the graph with `setup` in dependency order, then tears it down with `teardown` in reverse order
+    /// (dependees before their dependencies). A failed teardown does not stop the teardown of the node's dependencies,
+    /// whose resources would leak otherwise. With `only_succeeded` the teardown is only called for nodes whose setup
+    /// succeeded, the others are passed over (still respecting the reverse order) and reported as skipped.
+    pub fn run_setup_teardown<T: Hash + PartialEq + Eq + Clone + Send + Sync + 'static>(
+        &self,
+        topological_batch_provider: TopologicalBatchProvider<T>,
+        setup: Arc<dyn CallableByID<T> + Send + Sync>,
+        teardown: Arc<dyn CallableByID<T> + Send + Sync>,
+        only_succeeded: bool,
+    ) -> SetupTeardownReport<T> {
+        let teardown_provider = ContinueOnFailure(topological_batch_provider.reversed());
+        let setup_report = self.run(topological_batch_provider, setup);
 
-    use super::*;
+        let succeeded = Arc::new(
+            setup_report
+                .completed
+                .iter()
+                .cloned()
+                .collect::<HashSet<_>>(),
+        );
+        let teardown = if only_succeeded {
+            Arc::new(SucceededOnly {
+                succeeded: succeeded.clone(),
+                inner: teardown,
+            })
+        } else {
+            teardown
+        };
+        let mut teardown_report = self.run(teardown_provider, teardown);
 
-    struct ExecutorExample {
-        dependency_graph: HashMap<usize, Vec<usize>>,
-        seen: Arc<Mutex<HashSet<usize>>>,
+        if only_succeeded {
+            let (completed, not_set_up) = teardown_report
+                .completed
+                .into_iter()
+                .partition(|node| succeeded.contains(node));
+            teardown_report.completed = completed;
+            teardown_report.skipped.extend(not_set_up);
+        }
+
+        SetupTeardownReport {
+            setup: setup_report,
+            teardown: teardown_report,
+        }
     }
+}
 
-    impl ExecutorExample {
-        fn new(dependency_graph: HashMap<usize, Vec<usize>>) -> Self {
-            Self {
-                dependency_graph,
-                seen: Arc::new(Mutex::new(HashSet::new())),
+/// How long an idle worker waits before looking for available nodes again, unless woken by a node finishing. See
+/// `IdleStrategy::Condvar`.
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
+/// Result of a worker's attempt to make progress on a run.
+enum Step {
+    /// A node was executed.
+    Executed,
+    /// No node is available right now.
+    Idle,
+    /// No node will ever be available, the run is over (or being finished by the other workers).
+    Done,
+}
+
+/// A run as seen by the workers, independently of its ID type.
+trait Steppable: Send + Sync {
+    /// Executes one available node, if there is any.
+    fn step(&self, worker: usize) -> Step;
+
+    /// Ends the run after a panic out of `step`, without waiting for the nodes in flight.
+    fn abort(&self, payload: Box<dyn Any + Send>);
+}
+
+/// Reports the nodes executing for longer than `warn_after`, once each, until the run is over.
+fn watch<T: Hash + Eq + Clone>(run: &SharedRun<T>, warn_after: Duration) {
+    let interval = (warn_after / 4).max(Duration::from_millis(1));
+
+    while !run.state.lock().is_over() {
+        let slow = run
+            .executing
+            .lock()
+            .iter_mut()
+            .filter_map(|(node, executing)| {
+                let elapsed = run.options.clock.now().saturating_sub(executing.started);
+                if executing.reported || elapsed < warn_after {
+                    return None;
+                }
+
+                executing.reported = true;
+                Some((node.clone(), executing.worker, elapsed))
+            })
+            .collect::<Vec<_>>();
+
+        for (node, worker, elapsed) in slow {
+            for observer in &run.options.observers {
+                observer.on_node_slow(worker, &node, elapsed);
             }
         }
+
+        run.wait_for_change(interval);
     }
+}
 
-    impl CallableByID<usize> for ExecutorExample {
-        fn call(&self, id: usize) {
-            thread::sleep(Duration::from_micros(100));
+/// The planned nodes depending, directly or through other planned nodes, on an unfinished node left out of `schedule`.
+fn blocked_by_schedule<T: Hash + PartialEq + Eq + Clone>(
+    topological_batch_provider: &TopologicalBatchProvider<T>,
+    schedule: &Schedule<T>,
+) -> Vec<T> {
+    let planned = schedule
+        .workers
+        .iter()
+        .flatten()
+        .map(|node| &node.id)
+        .collect::<HashSet<_>>();
+    let never_runs = |node: &T| {
+        !planned.contains(node)
+            && matches!(
+                topological_batch_provider.status(node),
+                Some(NodeStatus::Pending | NodeStatus::Available)
+            )
+    };
 
-            let mut seen = self.seen.lock().unwrap();
-            seen.insert(id);
+    // Dependencies come first in the graph.
+    let mut blocked = HashSet::new();
+    let mut stuck = vec![];
+    for (node, dependencies) in topological_batch_provider.graph().unwrap_or_default() {
+        if planned.contains(&node)
+            && dependencies
+                .iter()
+                .any(|dependency| blocked.contains(dependency) || never_runs(dependency))
+        {
+            blocked.insert(node.clone());
+            stuck.push(node);
+        }
+    }
 
-            for dep in &self.dependency_graph[&id] {
-                assert!(seen.contains(&dep));
+    stuck
+}
+
+/// Steps the run as `worker` until it is over.
+fn work<T: Hash + PartialEq + Eq + Clone + Send + 'static>(
+    run: &SharedRun<T>,
+    worker: usize,
+    idle_strategy: IdleStrategy,
+) {
+    let mut streak = 0;
+    loop {
+        match step_guarded(run, worker) {
+            Step::Executed => streak = 0,
+            Step::Idle => {
+                idle_strategy.idle(streak, |timeout| run.wait_for_change(timeout));
+                streak = streak.saturating_add(1);
             }
+            Step::Done => break,
         }
     }
+}
 
-    #[test]
-    fn it_works_with_single_thread() {
-        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+/// Steps the run, a panic aborting the run instead of unwinding the worker.
+fn step_guarded(run: &dyn Steppable, worker: usize) -> Step {
+    match panic::catch_unwind(AssertUnwindSafe(|| run.step(worker))) {
+        Ok(step) => step,
+        Err(payload) => {
+            run.abort(payload);
+            Step::Done
+        }
+    }
+}
 
-        nodes.insert(1, vec![]);
-        nodes.insert(2, vec![1]);
-        nodes.insert(3, vec![1]);
-        nodes.insert(4, vec![]);
-        nodes.insert(5, vec![]);
-        nodes.insert(6, vec![2, 3]);
-        nodes.insert(7, vec![3, 4]);
-        nodes.insert(8, vec![6]);
+/// Why a run ended before its graph was done, other than a cancellation.
+enum Abort<T> {
+    /// `node` is the node whose executor panicked, `None` for a panic out of the runner's callbacks.
+    Panic {
+        node: Option<T>,
+        payload: Box<dyn Any + Send>,
+    },
+    /// The unfinished nodes of a deadlocked run, see `RunOptions::with_deadlock_detection`.
+    Deadlock(Vec<T>),
+    /// The provider failed to pop, complete or fail a node.
+    Provider(Error),
+}
 
-        let topological_batch_provider = TopologicalBatchProvider::new(nodes.clone());
-        let runner = ThreadPoolRunner::new(1);
-        let executor = Arc::new(ExecutorExample::new(nodes));
+impl<T> Abort<T> {
+    fn into_error(self) -> RunError<T> {
+        match self {
+            Abort::Panic { node, payload } => RunError::panicked(node, &*payload),
+            Abort::Deadlock(stuck) => RunError::Deadlock { stuck },
+            Abort::Provider(source) => RunError::Provider { source },
+        }
+    }
 
-        runner.run(topological_batch_provider.unwrap(), executor);
+    /// Resumes the panic for the callers of `ThreadPoolRunner::run`, a deadlock becoming one.
+    fn resume(self) -> ! {
+        match self {
+            Abort::Panic { payload, .. } => panic::resume_unwind(payload),
+            Abort::Deadlock(stuck) => panic!(
+                "Deadlock detected, {} nodes can't become available.",
+                stuck.len()
+            ),
+            Abort::Provider(err) => panic!("Provider failed: {}", err),
+        }
     }
+}
 
-    #[test]
-    fn it_works_with_multiple_threads() {
-        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+struct RunState<T> {
+    provider: Box<dyn BatchProvider<T> + Send>,
+    /// Popped nodes left for the workers they have an affinity to or the locality key of, or waiting for their slots.
+    deferred: Deferred<T>,
+    slots: SlotLedger<T>,
+    /// Locality key of the last node of every worker, see `RunOptions::with_locality`.
+    localities: HashMap<usize, String>,
+    /// Missing when the provider does not know its graph upfront.
+    progress: Option<ProgressModel<T>>,
+    in_flight: usize,
+    /// Set once no more node is dispatched: when the graph is done, the run is cancelled or the executor panicked.
+    outcome: Option<Result<(), Abort<T>>>,
+    /// For the `NodeCtx` of the nodes and the progress estimates, built on first use.
+    graph: Option<RunGraph<T>>,
+    /// Executed and cached nodes, for the `NodeCtx` of their dependents.
+    finished: HashMap<T, DependencyInfo<T>>,
+    /// Worker of every completed node, only kept with `RunOptions::with_rollback` to roll it back as that worker.
+    completed_by: HashMap<T, usize>,
+}
 
-        nodes.insert(1, vec![]);
-        nodes.insert(2, vec![1]);
-        nodes.insert(3, vec![1]);
-        nodes.insert(4, vec![]);
-        nodes.insert(5, vec![]);
-        nodes.insert(6, vec![2, 3]);
-        nodes.insert(7, vec![3, 4]);
-        nodes.insert(8, vec![6]);
+/// The graph of the provider, empty when not known upfront.
+struct RunGraph<T> {
+    /// Dependencies first.
+    nodes: Vec<(T, Vec<T>)>,
+    /// Position of every node in `nodes`.
+    positions: HashMap<T, usize>,
+}
 
-        let topological_batch_provider = TopologicalBatchProvider::new(nodes.clone());
-        let runner = ThreadPoolRunner::new(4);
-        let executor = Arc::new(ExecutorExample::new(nodes));
+impl<T: Hash + Eq + Clone> RunGraph<T> {
+    fn new(nodes: Option<Vec<(T, Vec<T>)>>) -> Self {
+        let nodes = nodes.unwrap_or_default();
+        let positions = nodes
+            .iter()
+            .enumerate()
+            .map(|(position, (node, _))| (node.clone(), position))
+            .collect();
 
-        runner.run(topological_batch_provider.unwrap(), executor);
+        Self { nodes, positions }
+    }
+}
+
+/// Popped nodes waiting for a worker, grouped by locality key (`None` for nodes without one) so picking by key only
+/// looks at the nodes of the keys asked for.
+struct Deferred<T> {
+    /// Nodes of every key in popping order, with their position in it.
+    groups: HashMap<Option<String>, VecDeque<(u64, T)>>,
+    popped: u64,
+}
+
+impl<T> Deferred<T> {
+    fn new() -> Self {
+        Self {
+            groups: HashMap::new(),
+            popped: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    fn push(&mut self, key: Option<String>, node: T) {
+        self.groups
+            .entry(key)
+            .or_default()
+            .push_back((self.popped, node));
+        self.popped += 1;
+    }
+
+    /// Keys passing `filter`, the one of the earliest popped node first.
+    fn keys(&self, filter: impl Fn(Option<&String>) -> bool) -> Vec<Option<String>> {
+        let mut keys = self
+            .groups
+            .iter()
+            .filter(|(key, _)| filter(key.as_ref()))
+            .map(|(key, nodes)| (nodes[0].0, key.clone()))
+            .collect::<Vec<_>>();
+        keys.sort_unstable_by_key(|(popped, _)| *popped);
+        keys.into_iter().map(|(_, key)| key).collect()
+    }
+
+    /// The earliest popped node of `key` that `claim` accepts, with its claims.
+    fn take<C>(
+        &mut self,
+        key: &Option<String>,
+        claim: &mut impl FnMut(&T) -> Option<C>,
+    ) -> Option<(T, C)> {
+        let nodes = self.groups.get_mut(key)?;
+        let (position, claims) = nodes
+            .iter()
+            .enumerate()
+            .find_map(|(position, (_, node))| claim(node).map(|claims| (position, claims)))?;
+        let (_, node) = nodes.remove(position)?;
+        if nodes.is_empty() {
+            self.groups.remove(key);
+        }
+
+        Some((node, claims))
+    }
+}
+
+impl<T> RunState<T> {
+    /// Nothing executes anymore, except nodes left behind by an aborted run.
+    fn is_over(&self) -> bool {
+        match &self.outcome {
+            None => false,
+            Some(Err(Abort::Panic { node: None, .. })) => true,
+            Some(_) => self.in_flight == 0,
+        }
+    }
+}
+
+impl<T> RunState<T> {
+    /// Ends the run on an error of the provider, the waiters being notified once the executing nodes are done.
+    fn abort_on(&mut self, err: Error) {
+        if self.outcome.is_none() {
+            self.outcome = Some(Err(Abort::Provider(err)));
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone> RunState<T> {
+    /// Keeps what the dependents of the node get in their `NodeCtx`.
+    fn record_finished(&mut self, node: &T, duration: Duration, cached: bool) {
+        if self
+            .graph
+            .as_ref()
+            .is_some_and(|graph| graph.nodes.is_empty())
+        {
+            return;
+        }
+
+        self.finished.insert(
+            node.clone(),
+            DependencyInfo {
+                id: node.clone(),
+                duration,
+                cached,
+            },
+        );
+    }
+
+    /// The finished dependencies of `node`, building the graph if no node asked before.
+    fn finished_dependencies(&mut self, node: &T) -> Vec<DependencyInfo<T>> {
+        let RunState {
+            provider,
+            graph,
+            finished,
+            ..
+        } = self;
+        let graph = graph.get_or_insert_with(|| RunGraph::new(provider.graph()));
+
+        graph
+            .positions
+            .get(node)
+            .into_iter()
+            .flat_map(|&position| &graph.nodes[position].1)
+            .filter_map(|dependency| finished.get(dependency).cloned())
+            .collect()
+    }
+}
+
+/// A run shared by its workers and its handle.
+struct SharedRun<T> {
+    state: Mutex<RunState<T>>,
+    /// Notified when a node finishes or the run ends.
+    changed: Condvar,
+    node_executor: Arc<dyn CallableByID<T> + Send + Sync>,
+    options: RunOptions<T>,
+    max_in_flight: usize,
+    thread_count: usize,
+    report: Mutex<RunReport<T>>,
+    /// Children spawned by the executing nodes, run by the workers before popping new nodes.
+    tasks: Arc<TaskQueue>,
+    /// Nodes in the executor, only tracked for the watchdog.
+    executing: Mutex<HashMap<T, Executing>>,
+}
+
+struct Executing {
+    worker: usize,
+    /// In the time of the run's clock.
+    started: Duration,
+    /// Reported by the watchdog already.
+    reported: bool,
+}
+
+impl<T> SharedRun<T> {
+    fn finish(&self, outcome: Result<(), Abort<T>>) {
+        let mut state = self.state.lock();
+        if state.outcome.is_none() {
+            state.outcome = Some(outcome);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Ends the execution of a node, once it is fully accounted for in the report. `claims` are its slots.
+    fn release(&self, claims: &Claims) {
+        let mut state = self.state.lock();
+        state.in_flight = state.in_flight.saturating_sub(1);
+        state.slots.release(claims);
+        self.changed.notify_all();
+    }
+
+    /// How many nodes are executed at the same time at most.
+    fn parallelism(&self) -> usize {
+        self.thread_count.min(self.max_in_flight).max(1)
+    }
+
+    fn wait_for_change(&self, timeout: Duration) {
+        drop(self.changed.wait_timeout(self.state.lock(), timeout));
+    }
+}
+
+impl<T: Hash + Eq> SharedRun<T> {
+    /// The first available node `worker` is allowed to execute and whose slots are free, with its claims. The others
+    /// popped meanwhile are deferred.
+    fn next_node(
+        &self,
+        state: &mut RunState<T>,
+        worker: usize,
+    ) -> Result<Option<(T, Claims)>, Error> {
+        let Some(next) = self.pick_node(state, worker)? else {
+            return Ok(None);
+        };
+        if let Some(key) = self.locality_key(&next.0) {
+            state.localities.insert(worker, key);
+        }
+
+        Ok(Some(next))
+    }
+
+    fn pick_node(
+        &self,
+        state: &mut RunState<T>,
+        worker: usize,
+    ) -> Result<Option<(T, Claims)>, Error> {
+        let RunState {
+            provider,
+            deferred,
+            slots,
+            localities,
+            ..
+        } = state;
+        let allowed = |node: &T| match self
+            .options
+            .affinity
+            .as_ref()
+            .and_then(|affinity| affinity.workers(node))
+        {
+            Some(workers) if workers.iter().any(|&other| other < self.thread_count) => {
+                workers.contains(&worker)
+            }
+            _ => true,
+        };
+        let mut claim = |node: &T| match allowed(node) {
+            true => slots.try_acquire(node),
+            false => None,
+        };
+        // 0 for the key of the worker, 2 for the keys of the other workers.
+        let own = localities.get(&worker);
+        let held = localities.values().collect::<HashSet<_>>();
+        let rank = |key: Option<&String>| match key {
+            Some(key) if own == Some(key) => 0,
+            Some(key) if held.contains(key) => 2,
+            _ => 1,
+        };
+
+        if let Some(own) = own {
+            if let Some(next) = deferred.take(&Some(own.clone()), &mut claim) {
+                return Ok(Some(next));
+            }
+        }
+        for key in deferred.keys(|key| rank(key) == 1) {
+            if let Some(next) = deferred.take(&key, &mut claim) {
+                return Ok(Some(next));
+            }
+        }
+
+        while let Some(node) = provider.pop()? {
+            let key = self.locality_key(&node);
+            if rank(key.as_ref()) < 2 {
+                if let Some(claims) = claim(&node) {
+                    return Ok(Some((node, claims)));
+                }
+            }
+            deferred.push(key, node);
+            self.changed.notify_all();
+        }
+
+        if self.options.locality.is_some() {
+            for key in deferred.keys(|_| true) {
+                if let Some(next) = deferred.take(&key, &mut claim) {
+                    return Ok(Some(next));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn locality_key(&self, node: &T) -> Option<String> {
+        self.options
+            .locality
+            .as_ref()
+            .and_then(|locality| locality(node))
+    }
+}
+
+impl<T: Hash + PartialEq + Eq + Clone + Send + 'static> Steppable for SharedRun<T> {
+    fn abort(&self, payload: Box<dyn Any + Send>) {
+        self.finish(Err(Abort::Panic {
+            node: None,
+            payload,
+        }));
+    }
+
+    fn step(&self, worker: usize) -> Step {
+        let RunOptions {
+            cache,
+            observers,
+            cancellation,
+            ..
+        } = &self.options;
+
+        if self.tasks.run_one() {
+            return Step::Executed;
+        }
+
+        let (node, claims) = {
+            let mut state = self.state.lock();
+
+            if state.outcome.is_none()
+                && (state.provider.is_empty()
+                    || cancellation
+                        .as_ref()
+                        .is_some_and(CancellationToken::is_cancelled))
+            {
+                state.outcome = Some(Ok(()));
+                self.changed.notify_all();
+            }
+            if state.outcome.is_some() {
+                return Step::Done;
+            }
+            if state.in_flight >= self.max_in_flight {
+                return Step::Idle;
+            }
+
+            match self.next_node(&mut state, worker) {
+                Ok(Some((node, claims))) => {
+                    state.in_flight += 1;
+                    (node, claims)
+                }
+                // Executing nodes complete theirs before leaving the count, and deferred ones fit some worker once
+                // nothing executes, so nothing can change anymore.
+                Ok(None)
+                    if self.options.deadlock_detection
+                        && state.in_flight == 0
+                        && state.deferred.is_empty() =>
+                {
+                    let stuck = state.provider.unfinished();
+                    state.outcome = Some(Err(Abort::Deadlock(stuck)));
+                    self.changed.notify_all();
+                    return Step::Done;
+                }
+                Ok(None) => return Step::Idle,
+                Err(err) => {
+                    state.outcome = Some(Err(Abort::Provider(err)));
+                    self.changed.notify_all();
+                    return Step::Done;
+                }
+            }
+        };
+
+        for observer in observers {
+            observer.on_node_start(worker, &node);
+        }
+
+        let fingerprint = cache
+            .as_ref()
+            .and_then(|cache| Some((cache, cache.fingerprint.fingerprint(&node)?)));
+
+        let result = match fingerprint {
+            Some((cache, fingerprint)) if cache.store.contains(fingerprint) => None,
+            _ => {
+                let started = self.options.clock.now();
+                if self.options.watchdog.is_some() {
+                    self.executing.lock().insert(
+                        node.clone(),
+                        Executing {
+                            worker,
+                            started,
+                            reported: false,
+                        },
+                    );
+                }
+                let spawner = Spawner::new(self.tasks.clone());
+                let dependencies = || self.state.lock().finished_dependencies(&node);
+                let ctx = NodeCtx::new(&dependencies, &spawner);
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    self.node_executor.call_with_ctx(&node, &ctx)
+                }));
+                // The children are waited for even when the node itself failed.
+                let children = spawner.join();
+                if self.options.watchdog.is_some() {
+                    self.executing.lock().remove(&node);
+                }
+                let duration = self.options.clock.now().saturating_sub(started);
+                Some((result.map(|result| result.and(children)), duration))
+            }
+        };
+
+        let (result, duration) = match result {
+            None => {
+                {
+                    let mut state = self.state.lock();
+                    if let Err(err) = state.provider.complete(node.clone()) {
+                        state.abort_on(err);
+                    }
+                    state.record_finished(&node, Duration::ZERO, true);
+                }
+                for observer in observers {
+                    observer.on_node_finish(worker, &node, NodeOutcome::Cached, Duration::ZERO);
+                }
+                self.report.lock().cached.push(node);
+
+                self.release(&claims);
+                return Step::Executed;
+            }
+            Some((Err(payload), _)) => {
+                self.finish(Err(Abort::Panic {
+                    node: Some(node),
+                    payload,
+                }));
+                self.release(&claims);
+                return Step::Done;
+            }
+            Some((Ok(result), duration)) => (result, duration),
+        };
+
+        if let (Ok(()), Some((cache, fingerprint))) = (&result, fingerprint) {
+            cache.store.record(fingerprint);
+        }
+
+        let (skipped, rolled_back) = {
+            let mut state = self.state.lock();
+            if let Some(progress) = &mut state.progress {
+                progress.record(&node, duration);
+            }
+            let finished = match &result {
+                Ok(()) => state.provider.complete(node.clone()).map(|()| vec![]),
+                Err(_) => state.provider.fail(node.clone()),
+            };
+            if result.is_ok() {
+                state.record_finished(&node, duration, false);
+                if self.options.rollback {
+                    state.completed_by.insert(node.clone(), worker);
+                }
+            }
+            let skipped = finished.unwrap_or_else(|err| {
+                state.abort_on(err);
+                vec![]
+            });
+            (skipped, state.provider.take_rolled_back())
+        };
+
+        for observer in observers {
+            let outcome = match &result {
+                Ok(()) => NodeOutcome::Completed,
+                Err(err) => NodeOutcome::Failed(err),
+            };
+            observer.on_node_finish(worker, &node, outcome, duration);
+            for skipped_node in skipped.iter().chain(&rolled_back) {
+                observer.on_node_skipped(skipped_node);
+            }
+        }
+
+        {
+            let mut report = self.report.lock();
+            match result {
+                Ok(()) => report.completed.push(node),
+                Err(err) => report.failed.push((node, err)),
+            }
+            report.skipped.extend(skipped);
+            if !rolled_back.is_empty() {
+                report
+                    .completed
+                    .retain(|completed| !rolled_back.contains(completed));
+                report.skipped.extend(rolled_back);
+            }
+        }
+
+        self.release(&claims);
+        Step::Executed
+    }
+}
+
+/// Calls `on_rollback` for the completed ancestors of the failed nodes, in reverse topological order, each as the
+/// worker that completed it.
+fn roll_back<T: Hash + Eq>(
+    node_executor: &dyn CallableByID<T>,
+    graph: &[(T, Vec<T>)],
+    report: &RunReport<T>,
+    completed_by: &HashMap<T, usize>,
+) {
+    let dependencies = graph
+        .iter()
+        .map(|(node, dependencies)| (node, dependencies))
+        .collect::<HashMap<_, _>>();
+    let mut ancestors = HashSet::new();
+    let mut stack = report
+        .failed
+        .iter()
+        .map(|(node, _)| node)
+        .collect::<Vec<_>>();
+    while let Some(node) = stack.pop() {
+        for dependency in dependencies.get(node).into_iter().copied().flatten() {
+            if ancestors.insert(dependency) {
+                stack.push(dependency);
+            }
+        }
+    }
+
+    let completed = report.completed.iter().collect::<HashSet<_>>();
+    for (node, _) in graph.iter().rev() {
+        if ancestors.contains(node) && completed.contains(node) {
+            match completed_by.get(node) {
+                Some(&worker) => as_worker(worker, || node_executor.on_rollback(node)),
+                None => node_executor.on_rollback(node),
+            }
+        }
+    }
+}
+
+/// A run started with `ThreadPoolRunner::submit`.
+pub struct RunHandle<T> {
+    run: Arc<SharedRun<T>>,
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> RunHandle<T> {
+    /// Blocks until the run is over, then notifies the observers about its end. A panic ending the run, including one
+    /// of `CallableByID::on_rollback` or `RunObserver::on_run_finish`, is returned as `RunError::Panicked`, a deadlock
+    /// detected by the run (see `RunOptions::with_deadlock_detection`) as `RunError::Deadlock`.
+    pub fn wait(self) -> Result<RunReport<T>, RunError<T>> {
+        self.join().map_err(Abort::into_error)
+    }
+
+    /// True once the run is over, `wait` then only notifies the observers and assembles the report.
+    pub fn is_finished(&self) -> bool {
+        self.run.state.lock().is_over()
+    }
+
+    fn join(self) -> Result<RunReport<T>, Abort<T>> {
+        let mut state = self.run.state.lock();
+        while !state.is_over() {
+            state = self.run.changed.wait(state);
+        }
+        if matches!(state.outcome, Some(Err(_))) {
+            // Hands the abort out, leaving an outcome so the workers still around don't pick up nodes again.
+            if let Some(Err(abort)) = state.outcome.replace(Ok(())) {
+                return Err(abort);
+            }
+        }
+
+        let mut report = std::mem::take(&mut *self.run.report.lock());
+        report.cancelled = state.provider.unfinished();
+        let graph = (self.run.options.rollback && !report.failed.is_empty())
+            .then(|| state.provider.graph())
+            .flatten();
+        let completed_by = std::mem::take(&mut state.completed_by);
+        drop(state);
+
+        let finished = panic::catch_unwind(AssertUnwindSafe(|| {
+            if let Some(graph) = graph {
+                roll_back(&*self.run.node_executor, &graph, &report, &completed_by);
+            }
+
+            for observer in &self.run.options.observers {
+                observer.on_run_finish(&report);
+            }
+        }));
+
+        match finished {
+            Ok(()) => Ok(report),
+            Err(payload) => Err(Abort::Panic {
+                node: None,
+                payload,
+            }),
+        }
+    }
+
+    /// Share of the work done and estimated time left, `None` unless enabled with `RunOptions::with_progress` and when
+    /// the provider does not expose its graph (see `BatchProvider::graph`). The weights of `RunOptions::with_weights`
+    /// are converted to time by the pace of the nodes executed so far, so there is no ETA before the first node
+    /// finished.
+    ///
+    /// The time left is the longest of the remaining critical path and the remaining work spread over the workers.
+    pub fn progress(&self) -> Option<Progress> {
+        let state = self.run.state.lock();
+        let model = state.progress.as_ref()?;
+
+        let report = self.run.report.lock();
+        let finished = report
+            .completed
+            .iter()
+            .chain(report.failed.iter().map(|(node, _)| node))
+            .chain(&report.skipped)
+            .chain(&report.cached)
+            .chain(&report.satisfied)
+            .collect::<HashSet<_>>();
+
+        let graph = state.graph.as_ref()?;
+        Some(model.progress(&graph.nodes, &finished, self.run.parallelism()))
+    }
+}
+
+/// See `RunHandle::progress`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Finished weight over the total weight, between 0 and 1. Skipped nodes count as finished.
+    pub fraction: f64,
+    pub eta: Option<Duration>,
+}
+
+struct ProgressModel<T> {
+    weights: HashMap<T, f64>,
+    total: f64,
+    /// Weight of the executed (not cached) nodes, and the time they took.
+    executed: f64,
+    busy: Duration,
+}
+
+impl<T: Hash + Eq + Clone> ProgressModel<T> {
+    fn new(graph: &[(T, Vec<T>)], weights: Option<&(dyn Fn(&T) -> f64 + Send + Sync)>) -> Self {
+        let weights = graph
+            .iter()
+            .map(|(node, _)| (node.clone(), weights.map_or(1.0, |weights| weights(node))))
+            .collect::<HashMap<_, _>>();
+
+        Self {
+            total: weights.values().sum(),
+            weights,
+            executed: 0.0,
+            busy: Duration::ZERO,
+        }
+    }
+
+    fn record(&mut self, node: &T, duration: Duration) {
+        self.executed += self.weights.get(node).copied().unwrap_or(0.0);
+        self.busy += duration;
+    }
+
+    /// `graph` being the one the model was built from, dependencies first.
+    fn progress(
+        &self,
+        graph: &[(T, Vec<T>)],
+        finished: &HashSet<&T>,
+        parallelism: usize,
+    ) -> Progress {
+        let done = finished
+            .iter()
+            .filter_map(|node| self.weights.get(*node))
+            .sum::<f64>();
+
+        // Heaviest chain of unfinished nodes ending at each node.
+        let mut paths: HashMap<&T, f64> = HashMap::new();
+        for (node, dependencies) in graph {
+            let path = if finished.contains(node) {
+                0.0
+            } else {
+                self.weights[node]
+                    + dependencies
+                        .iter()
+                        .filter_map(|dependency| paths.get(dependency))
+                        .fold(0.0, |longest: f64, &path| longest.max(path))
+            };
+            paths.insert(node, path);
+        }
+        let critical_path = paths
+            .values()
+            .fold(0.0, |longest: f64, &path| longest.max(path));
+
+        let remaining = (self.total - done).max(0.0);
+        let eta = (self.executed > 0.0).then(|| {
+            let pace = self.busy.as_secs_f64() / self.executed;
+            Duration::from_secs_f64(pace * critical_path.max(remaining / parallelism as f64))
+        });
+
+        Progress {
+            fraction: if self.total > 0.0 {
+                done / self.total
+            } else {
+                1.0
+            },
+            eta,
+        }
+    }
+}
+
+/// Long-lived threads executing the nodes of the submitted runs, see `ThreadPoolRunner::with_persistent_workers`.
+/// Every time a worker looks for work it starts with the least served run, so the runs progress side by side.
+struct WorkerPool {
+    shared: Arc<PoolShared>,
+    handles: Vec<thread::JoinHandle<()>>,
+}
+
+struct PoolShared {
+    state: Mutex<PoolState>,
+    /// Notified when a run is added, a node finishes or the pool shuts down.
+    changed: Condvar,
+    idle_strategy: IdleStrategy,
+    fairness: Fairness,
+}
+
+struct PoolRun {
+    run: Arc<dyn Steppable>,
+    tenant: Option<String>,
+    /// Dispatched nodes, divided by the share.
+    usage: f64,
+}
+
+struct PoolState {
+    runs: VecDeque<PoolRun>,
+    /// Dispatched nodes of every tenant, divided by their share.
+    tenants: HashMap<String, f64>,
+    shutdown: bool,
+}
+
+impl PoolState {
+    fn add(&mut self, run: Arc<dyn Steppable>, tenant: Option<String>) {
+        // Newcomers start level with the least served runs, instead of taking over the pool to catch up.
+        let floor = self
+            .runs
+            .iter()
+            .map(|other| self.usage(other))
+            .reduce(f64::min)
+            .unwrap_or(0.0);
+        if let Some(tenant) = &tenant {
+            if !self
+                .runs
+                .iter()
+                .any(|other| other.tenant.as_ref() == Some(tenant))
+            {
+                let usage = self.tenants.entry(tenant.clone()).or_insert(0.0);
+                *usage = usage.max(floor);
+            }
+        }
+
+        self.runs.push_back(PoolRun {
+            run,
+            tenant,
+            usage: floor,
+        });
+    }
+
+    fn usage(&self, run: &PoolRun) -> f64 {
+        match &run.tenant {
+            Some(tenant) => self.tenants.get(tenant).copied().unwrap_or(0.0),
+            None => run.usage,
+        }
+    }
+
+    /// The runs to try, the least served first.
+    fn order(&mut self) -> Vec<Arc<dyn Steppable>> {
+        // Equally served runs take turns.
+        self.runs.rotate_left(1);
+        let mut runs = self
+            .runs
+            .iter()
+            .map(|run| ((self.usage(run), run.usage), run.run.clone()))
+            .collect::<Vec<_>>();
+        runs.sort_by(|((usage, own), _), ((other, other_own), _)| {
+            usage.total_cmp(other).then(own.total_cmp(other_own))
+        });
+
+        runs.into_iter().map(|(_, run)| run).collect()
+    }
+
+    /// Accounts a node dispatched by `run`.
+    fn charge(&mut self, run: &Arc<dyn Steppable>, fairness: &Fairness) {
+        self.account(run, fairness, 1.0);
+    }
+
+    /// Takes back a `charge` for a step that did not execute anything.
+    fn refund(&mut self, run: &Arc<dyn Steppable>, fairness: &Fairness) {
+        self.account(run, fairness, -1.0);
+    }
+
+    fn account(&mut self, run: &Arc<dyn Steppable>, fairness: &Fairness, nodes: f64) {
+        let Some(pool_run) = self
+            .runs
+            .iter_mut()
+            .find(|other| Arc::ptr_eq(&other.run, run))
+        else {
+            return;
+        };
+
+        let cost = nodes / fairness.share(pool_run.tenant.as_deref()) as f64;
+        pool_run.usage += cost;
+        if let Some(tenant) = &pool_run.tenant {
+            *self.tenants.entry(tenant.clone()).or_insert(0.0) += cost;
+        }
+    }
+
+    /// Drops a finished run, and the usage of its tenant with its last run.
+    fn remove(&mut self, run: &Arc<dyn Steppable>) {
+        let Some(position) = self
+            .runs
+            .iter()
+            .position(|other| Arc::ptr_eq(&other.run, run))
+        else {
+            return;
+        };
+
+        let removed = self.runs.remove(position);
+        if let Some(tenant) = removed.and_then(|removed| removed.tenant) {
+            if !self
+                .runs
+                .iter()
+                .any(|other| other.tenant.as_ref() == Some(&tenant))
+            {
+                self.tenants.remove(&tenant);
+            }
+        }
+    }
+}
+
+impl WorkerPool {
+    fn new(
+        thread_count: usize,
+        hooks: WorkerHooks,
+        idle_strategy: IdleStrategy,
+        fairness: Fairness,
+    ) -> Self {
+        let shared = Arc::new(PoolShared {
+            state: Mutex::new(PoolState {
+                runs: VecDeque::new(),
+                tenants: HashMap::new(),
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+            idle_strategy,
+            fairness,
+        });
+
+        let handles = (0..thread_count)
+            .map(|worker| {
+                let shared = shared.clone();
+                let hooks = hooks.clone();
+                thread::spawn(move || hooks.around(worker, || shared.work(worker)))
+            })
+            .collect();
+
+        Self { shared, handles }
+    }
+
+    fn add(&self, run: Arc<dyn Steppable>, tenant: Option<String>) {
+        self.shared.state.lock().add(run, tenant);
+        self.shared.changed.notify_all();
+    }
+}
+
+impl PoolShared {
+    fn work(&self, worker: usize) {
+        let mut streak = 0;
+        loop {
+            let runs = {
+                let mut state = self.state.lock();
+                while state.runs.is_empty() {
+                    if state.shutdown {
+                        return;
+                    }
+                    state = self.changed.wait(state);
+                }
+                state.order()
+            };
+
+            let mut executed = false;
+            for run in runs {
+                // Charged upfront, so the other workers see the run as served while its node executes.
+                self.state.lock().charge(&run, &self.fairness);
+                match step_guarded(&*run, worker) {
+                    Step::Executed => {
+                        executed = true;
+                        break;
+                    }
+                    Step::Idle => self.state.lock().refund(&run, &self.fairness),
+                    Step::Done => self.state.lock().remove(&run),
+                }
+            }
+
+            if executed {
+                streak = 0;
+                self.changed.notify_all();
+            } else {
+                self.idle_strategy.idle(streak, |timeout| {
+                    drop(self.changed.wait_timeout(self.state.lock(), timeout));
+                });
+                streak = streak.saturating_add(1);
+            }
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    /// Lets the submitted runs finish, then stops the threads.
+    fn drop(&mut self) {
+        self.shared.state.lock().shutdown = true;
+        self.shared.changed.notify_all();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+type BoxedExecutor<T> = Box<dyn CallableByID<T> + Send>;
+type SharedInstance<T> = Arc<Mutex<BoxedExecutor<T>>>;
+
+/// Executor with an instance per worker index of the runner, created on demand by a factory and kept from run to run.
+/// Calls from outside the workers of a `ThreadPoolRunner` share one instance. See `ThreadPoolRunner::run_with_factory`,
+/// or pass it as any other executor to combine it with options.
+pub struct PerThreadExecutor<T> {
+    factory: Box<dyn Fn() -> BoxedExecutor<T> + Send + Sync>,
+    /// By worker index. Every instance is only used by its worker (and by rollbacks once the run is over), their lock
+    /// is only contended when runs share the executor.
+    instances: Mutex<HashMap<Option<usize>, SharedInstance<T>>>,
+}
+
+impl<T> PerThreadExecutor<T> {
+    pub fn new<E, F>(factory: F) -> Self
+    where
+        E: CallableByID<T> + Send + 'static,
+        F: Fn() -> E + Send + Sync + 'static,
+    {
+        Self {
+            factory: Box::new(move || Box::new(factory())),
+            instances: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The instance of the calling worker.
+    fn instance(&self) -> SharedInstance<T> {
+        self.instances
+            .lock()
+            .entry(WORKER.get())
+            .or_insert_with(|| Arc::new(Mutex::new((self.factory)())))
+            .clone()
+    }
+}
+
+/// Rollbacks are called as the worker that completed the node, so on its instance.
+impl<T> CallableByID<T> for PerThreadExecutor<T> {
+    fn call(&self, id: &T) -> Result<(), Error> {
+        self.instance().lock().call(id)
+    }
+
+    fn call_with_spawner(&self, id: &T, spawner: &Spawner) -> Result<(), Error> {
+        self.instance().lock().call_with_spawner(id, spawner)
+    }
+
+    fn call_with_ctx(&self, id: &T, ctx: &NodeCtx<'_, T>) -> Result<(), Error> {
+        self.instance().lock().call_with_ctx(id, ctx)
+    }
+
+    fn on_rollback(&self, id: &T) {
+        self.instance().lock().on_rollback(id);
+    }
+}
+
+/// Outcome of `ThreadPoolRunner::run_setup_teardown`.
+#[derive(Debug)]
+pub struct SetupTeardownReport<T> {
+    pub setup: RunReport<T>,
+    pub teardown: RunReport<T>,
+}
+
+/// Teardown executor ignoring the nodes that were not set up.
+struct SucceededOnly<T> {
+    succeeded: Arc<HashSet<T>>,
+    inner: Arc<dyn CallableByID<T> + Send + Sync>,
+}
+
+impl<T: Hash + Eq> CallableByID<T> for SucceededOnly<T> {
+    fn call(&self, id: &T) -> Result<(), Error> {
+        if self.succeeded.contains(id) {
+            self.inner.call(id)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Teardown provider completing failed nodes, so their dependencies are still torn down.
+struct ContinueOnFailure<T>(TopologicalBatchProvider<T>);
+
+impl<T: Hash + PartialEq + Eq + Clone> BatchProvider<T> for ContinueOnFailure<T> {
+    fn pop(&mut self) -> Result<Option<T>, Error> {
+        Ok(self.0.pop())
+    }
+
+    fn complete(&mut self, node: T) -> Result<(), Error> {
+        Ok(self.0.complete(node)?)
+    }
+
+    fn fail(&mut self, node: T) -> Result<Vec<T>, Error> {
+        self.complete(node).map(|()| vec![])
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn unfinished(&self) -> Vec<T> {
+        BatchProvider::unfinished(&self.0)
+    }
+
+    fn graph(&self) -> Option<Vec<(T, Vec<T>)>> {
+        BatchProvider::graph(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::chrome_trace::*;
+    use crate::scheduler::*;
+    use std::sync::{Condvar, Mutex};
+
+    struct ExecutorExample {
+        dependency_graph: HashMap<usize, Vec<usize>>,
+        seen: Arc<Mutex<HashSet<usize>>>,
+    }
+
+    impl ExecutorExample {
+        fn new(dependency_graph: HashMap<usize, Vec<usize>>) -> Self {
+            Self {
+                dependency_graph,
+                seen: Arc::new(Mutex::new(HashSet::new())),
+            }
+        }
+    }
+
+    impl CallableByID<usize> for ExecutorExample {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            thread::sleep(Duration::from_micros(100));
+
+            let mut seen = self.seen.lock().unwrap();
+            seen.insert(*id);
+
+            for dep in &self.dependency_graph[id] {
+                assert!(seen.contains(dep));
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_works_with_single_thread() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1]);
+        nodes.insert(4, vec![]);
+        nodes.insert(5, vec![]);
+        nodes.insert(6, vec![2, 3]);
+        nodes.insert(7, vec![3, 4]);
+        nodes.insert(8, vec![6]);
+
+        let topological_batch_provider = TopologicalBatchProvider::new(nodes.clone());
+        let runner = ThreadPoolRunner::new(1);
+        let executor = Arc::new(ExecutorExample::new(nodes));
+
+        runner.run(topological_batch_provider.unwrap(), executor);
+    }
+
+    #[test]
+    fn it_works_with_multiple_threads() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1]);
+        nodes.insert(4, vec![]);
+        nodes.insert(5, vec![]);
+        nodes.insert(6, vec![2, 3]);
+        nodes.insert(7, vec![3, 4]);
+        nodes.insert(8, vec![6]);
+
+        let topological_batch_provider = TopologicalBatchProvider::new(nodes.clone());
+        let runner = ThreadPoolRunner::new(4);
+        let executor = Arc::new(ExecutorExample::new(nodes));
+
+        runner.run(topological_batch_provider.unwrap(), executor);
+    }
+
+    struct RecordingExecutor {
+        failing: Option<usize>,
+        calls: Mutex<Vec<usize>>,
+    }
+
+    impl CallableByID<usize> for RecordingExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            self.calls.lock().unwrap().push(*id);
+
+            if Some(*id) == self.failing {
+                Err("Failing on purpose.".into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn it_reports_failures() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+        nodes.insert(4, vec![]);
+
+        let topological_batch_provider = TopologicalBatchProvider::new(nodes).unwrap();
+        let runner = ThreadPoolRunner::new(2);
+        let executor = Arc::new(RecordingExecutor {
+            failing: Some(2),
+            calls: Mutex::new(vec![]),
+        });
+
+        let report = runner.run(topological_batch_provider, executor);
+
+        assert!(!report.is_success());
+        assert_eq!(
+            HashSet::from([1, 4]),
+            HashSet::from_iter(report.completed.iter().cloned())
+        );
+        assert_eq!(
+            vec![2],
+            report.failed.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        assert_eq!(vec![3], report.skipped);
+    }
+
+    struct ConcurrencyTracker {
+        current: Mutex<usize>,
+        max: Mutex<usize>,
+    }
+
+    impl CallableByID<usize> for ConcurrencyTracker {
+        fn call(&self, _id: &usize) -> Result<(), Error> {
+            {
+                let mut current = self.current.lock().unwrap();
+                *current += 1;
+                let mut max = self.max.lock().unwrap();
+                *max = (*max).max(*current);
+            }
+
+            thread::sleep(Duration::from_millis(5));
+            *self.current.lock().unwrap() -= 1;
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_caps_nodes_in_flight() {
+        let nodes = (0..8)
+            .map(|i| (i, vec![]))
+            .collect::<HashMap<usize, Vec<usize>>>();
+
+        let runner = ThreadPoolRunner::new(4).with_max_in_flight(2);
+        let executor = Arc::new(ConcurrencyTracker {
+            current: Mutex::new(0),
+            max: Mutex::new(0),
+        });
+
+        let report = runner.run(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            executor.clone(),
+        );
+
+        assert_eq!(8, report.completed.len());
+        assert!(*executor.max.lock().unwrap() <= 2);
+    }
+
+    struct IdFingerprint;
+
+    impl Fingerprint<usize> for IdFingerprint {
+        fn fingerprint(&self, id: &usize) -> Option<u64> {
+            if *id == 3 {
+                None
+            } else {
+                Some(*id as u64)
+            }
+        }
+    }
+
+    #[test]
+    fn it_reports_nodes_completed_outside_of_the_run() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+
+        let mut provider = TopologicalBatchProvider::new(nodes).unwrap();
+        provider.mark_external_complete(&1).unwrap();
+
+        let executor = Arc::new(RecordingExecutor {
+            failing: None,
+            calls: Mutex::new(vec![]),
+        });
+        let report = ThreadPoolRunner::new(2).run(provider, executor.clone());
+        assert_eq!(vec![2], *executor.calls.lock().unwrap());
+        assert_eq!(vec![2], report.completed);
+        assert_eq!(vec![1], report.satisfied);
+    }
+
+    #[test]
+    fn it_skips_cached_nodes() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+
+        let runner = ThreadPoolRunner::new(2);
+        let cache = NodeCache::new(Arc::new(IdFingerprint), Arc::new(MemoryCacheStore::new()));
+        cache.store.record(1);
+
+        let executor = Arc::new(RecordingExecutor {
+            failing: None,
+            calls: Mutex::new(vec![]),
+        });
+        let report = runner.run_cached(
+            TopologicalBatchProvider::new(nodes.clone()).unwrap(),
+            executor.clone(),
+            cache.clone(),
+        );
+        assert_eq!(vec![2, 3], *executor.calls.lock().unwrap());
+        assert_eq!(vec![1], report.cached);
+
+        let executor = Arc::new(RecordingExecutor {
+            failing: None,
+            calls: Mutex::new(vec![]),
+        });
+        runner.run_cached(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            executor.clone(),
+            cache,
+        );
+        assert_eq!(vec![3], *executor.calls.lock().unwrap());
+    }
+
+    /// Records the dependencies seen by every node.
+    #[derive(Default)]
+    struct CtxRecorder {
+        seen: Mutex<HashMap<usize, Vec<DependencyInfo<usize>>>>,
+    }
+
+    impl CallableByID<usize> for CtxRecorder {
+        fn call(&self, _id: &usize) -> Result<(), Error> {
+            unreachable!()
+        }
+
+        fn call_with_ctx(&self, id: &usize, ctx: &NodeCtx<'_, usize>) -> Result<(), Error> {
+            thread::sleep(Duration::from_millis(2));
+            let mut dependencies = ctx.dependencies().to_vec();
+            dependencies.sort_by_key(|dependency| dependency.id);
+            self.seen.lock().unwrap().insert(*id, dependencies);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_passes_the_dependencies_to_the_executor() {
+        let nodes = HashMap::from([(1, vec![]), (2, vec![]), (3, vec![1, 2])]);
+        let cache = NodeCache::new(Arc::new(IdFingerprint), Arc::new(MemoryCacheStore::new()));
+        cache.store.record(1);
+
+        let executor = Arc::new(CtxRecorder::default());
+        let report = ThreadPoolRunner::new(2).run_cached(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            executor.clone(),
+            cache,
+        );
+        assert!(report.is_success());
+
+        let seen = executor.seen.lock().unwrap();
+        assert_eq!(Some(&vec![]), seen.get(&2));
+        let dependencies = &seen[&3];
+        assert_eq!(
+            vec![(1, true), (2, false)],
+            dependencies
+                .iter()
+                .map(|dependency| (dependency.id, dependency.cached))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Duration::ZERO, dependencies[0].duration);
+        assert!(dependencies[1].duration >= Duration::from_millis(2));
+    }
+
+    /// Counts the calls of `graph`.
+    struct GraphCounter {
+        inner: TopologicalBatchProvider<usize>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl BatchProvider<usize> for GraphCounter {
+        fn pop(&mut self) -> Result<Option<usize>, Error> {
+            Ok(self.inner.pop())
+        }
+
+        fn complete(&mut self, node: usize) -> Result<(), Error> {
+            Ok(self.inner.complete(node)?)
+        }
+
+        fn fail(&mut self, node: usize) -> Result<Vec<usize>, Error> {
+            Ok(self.inner.fail(node)?)
+        }
+
+        fn is_empty(&self) -> bool {
+            self.inner.is_empty()
+        }
+
+        fn unfinished(&self) -> Vec<usize> {
+            BatchProvider::unfinished(&self.inner)
+        }
+
+        fn graph(&self) -> Option<Vec<(usize, Vec<usize>)>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.graph()
+        }
+    }
+
+    #[test]
+    fn it_only_builds_the_graph_when_asked_for() {
+        let nodes = HashMap::from([(1, vec![]), (2, vec![1]), (3, vec![1, 2])]);
+        let runner = ThreadPoolRunner::new(2);
+        let counted = |calls: &Arc<AtomicUsize>| GraphCounter {
+            inner: TopologicalBatchProvider::new(nodes.clone()).unwrap(),
+            calls: calls.clone(),
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let report = runner.run(
+            counted(&calls),
+            Arc::new(ExecutorExample::new(nodes.clone())),
+        );
+        assert!(report.is_success());
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+
+        let executor = Arc::new(CtxRecorder::default());
+        assert!(runner.run(counted(&calls), executor.clone()).is_success());
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        assert_eq!(2, executor.seen.lock().unwrap()[&3].len());
+    }
+
+    #[test]
+    fn it_simulates_with_the_runner_settings() {
+        let nodes = (0..4)
+            .map(|i| (i, vec![]))
+            .collect::<HashMap<usize, Vec<usize>>>();
+
+        let runner = ThreadPoolRunner::new(4).with_max_in_flight(2);
+        let report = runner
+            .simulate(
+                TopologicalBatchProvider::new(nodes.clone()).unwrap(),
+                |_| Duration::from_secs(1),
+            )
+            .unwrap();
+
+        assert_eq!(Duration::from_secs(2), report.makespan);
+        assert_eq!(4, report.busy.len());
+
+        assert_eq!(
+            Err(NoThreads),
+            ThreadPoolRunner::new(0)
+                .simulate(TopologicalBatchProvider::new(nodes).unwrap(), |_| {
+                    Duration::from_secs(1)
+                })
+        );
+    }
+
+    #[test]
+    fn it_follows_a_planned_schedule() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1]);
+        nodes.insert(4, vec![]);
+        nodes.insert(5, vec![]);
+        nodes.insert(6, vec![2, 3]);
+        nodes.insert(7, vec![3, 4]);
+        nodes.insert(8, vec![6]);
+
+        let topological_batch_provider = TopologicalBatchProvider::new(nodes.clone()).unwrap();
+        let schedule = Schedule::plan(&topological_batch_provider, 3, |_| Duration::from_millis(1));
+        let runner = ThreadPoolRunner::new(1);
+        let executor = Arc::new(ExecutorExample::new(nodes.clone()));
+
+        let report = runner
+            .run_planned(topological_batch_provider, &schedule, executor.clone())
+            .unwrap();
+
+        assert!(report.is_success());
+        assert_eq!(8, executor.seen.lock().unwrap().len());
+
+        let topological_batch_provider = TopologicalBatchProvider::new(nodes).unwrap();
+        let mut incomplete = schedule.clone();
+        for worker in &mut incomplete.workers {
+            worker.retain(|node| node.id != 3);
+        }
+        match runner.run_planned(topological_batch_provider, &incomplete, executor) {
+            Err(RunError::Deadlock { mut stuck }) => {
+                stuck.sort_unstable();
+                assert_eq!(vec![6, 7, 8], stuck);
+            }
+            other => panic!(
+                "Unexpected result: {:?}",
+                other.map(|report| report.completed)
+            ),
+        }
+    }
+
+    #[test]
+    fn it_fails_panicking_nodes_of_a_planned_schedule() {
+        let nodes = HashMap::from([(1, vec![]), (2, vec![1]), (3, vec![1]), (4, vec![3])]);
+        let topological_batch_provider = TopologicalBatchProvider::new(nodes).unwrap();
+        let schedule = Schedule::plan(&topological_batch_provider, 2, |_| Duration::from_millis(1));
+
+        let report = ThreadPoolRunner::new(1)
+            .run_planned(
+                topological_batch_provider,
+                &schedule,
+                Arc::new(PanickingExecutor),
+            )
+            .unwrap();
+
+        let mut completed = report.completed;
+        completed.sort_unstable();
+        assert_eq!(vec![1, 2], completed);
+        assert_eq!(3, report.failed[0].0);
+        assert_eq!(
+            "Executor panicked: Panicking on purpose.",
+            report.failed[0].1.to_string()
+        );
+        assert_eq!(vec![4], report.skipped);
+    }
+
+    #[test]
+    fn it_records_chrome_traces() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+
+        let recorder = Arc::new(ChromeTraceRecorder::new());
+        let runner = ThreadPoolRunner::new(2);
+        let executor = Arc::new(ExecutorExample::new(nodes.clone()));
+
+        runner.run_with_options(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            executor,
+            RunOptions::new().with_observer(recorder.clone()),
+        );
+
+        let mut out = vec![];
+        recorder.write_to(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(2, out.matches(r#""ph":"X""#).count());
+    }
+
+    #[test]
+    fn it_injects_faults() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![]);
+
+        let runner = ThreadPoolRunner::new(2);
+        let executor = Arc::new(ExecutorExample::new(nodes.clone()));
+
+        let report = runner.run_with_options(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            executor,
+            RunOptions::new().with_fault_injector(FaultInjector::new(0).with_failing_node(&1usize)),
+        );
+
+        assert_eq!(vec![3], report.completed);
+        assert_eq!(vec![2], report.skipped);
+        assert!(report.failed[0].1.downcast_ref::<InjectedFault>().is_some());
+    }
+
+    struct CancellingExecutor {
+        cancel_at: usize,
+        cancellation: CancellationToken,
+    }
+
+    impl CallableByID<usize> for CancellingExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            if *id == self.cancel_at {
+                self.cancellation.cancel();
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_stops_dispatching_on_cancellation() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+        nodes.insert(4, vec![3]);
+
+        let cancellation = CancellationToken::new();
+        let runner = ThreadPoolRunner::new(2);
+        let executor = Arc::new(CancellingExecutor {
+            cancel_at: 2,
+            cancellation: cancellation.clone(),
+        });
+
+        let mut report = runner.run_with_options(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            executor,
+            RunOptions::new().with_cancellation(cancellation),
+        );
+        report.cancelled.sort();
+
+        assert_eq!(vec![1, 2], report.completed);
+        assert_eq!(vec![3, 4], report.cancelled);
+        assert!(!report.is_success());
+    }
+
+    struct ThreadRecorder {
+        threads: Mutex<HashSet<thread::ThreadId>>,
+    }
+
+    impl CallableByID<usize> for ThreadRecorder {
+        fn call(&self, _id: &usize) -> Result<(), Error> {
+            self.threads.lock().unwrap().insert(thread::current().id());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_reuses_persistent_workers_across_runs() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![]);
+
+        let runner = ThreadPoolRunner::new(2).with_persistent_workers();
+        let executor = Arc::new(ThreadRecorder {
+            threads: Mutex::new(HashSet::new()),
+        });
+
+        let handles = (0..5)
+            .map(|_| {
+                runner.submit(
+                    TopologicalBatchProvider::new(nodes.clone()).unwrap(),
+                    executor.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            let mut report = handle.wait().unwrap();
+            report.completed.sort();
+            assert_eq!(vec![1, 2, 3], report.completed);
+        }
+        assert!(executor.threads.lock().unwrap().len() <= 2);
+    }
+
+    #[test]
+    fn it_interleaves_runs_on_persistent_workers() {
+        let large = (0..20)
+            .map(|i| (i, vec![]))
+            .collect::<HashMap<usize, Vec<usize>>>();
+        let small = (100..102)
+            .map(|i| (i, vec![]))
+            .collect::<HashMap<usize, Vec<usize>>>();
+
+        let runner = ThreadPoolRunner::new(1).with_persistent_workers();
+        let executor = Arc::new(SleepingRecorder {
+            calls: Mutex::new(vec![]),
+        });
+
+        let large = runner.submit(
+            TopologicalBatchProvider::new(large).unwrap(),
+            executor.clone(),
+        );
+        let small = runner.submit(
+            TopologicalBatchProvider::new(small).unwrap(),
+            executor.clone(),
+        );
+        assert!(small.wait().unwrap().is_success());
+        assert!(large.wait().unwrap().is_success());
+
+        let calls = executor.calls.lock().unwrap();
+        let last_small = calls.iter().rposition(|&id| id >= 100).unwrap();
+        assert!(last_small < 8, "{:?}", calls);
+    }
+
+    struct FanOutExecutor {
+        done: Arc<AtomicUsize>,
+    }
+
+    impl CallableByID<usize> for FanOutExecutor {
+        fn call(&self, _id: &usize) -> Result<(), Error> {
+            unreachable!()
+        }
+
+        fn call_with_spawner(&self, id: &usize, spawner: &Spawner) -> Result<(), Error> {
+            match id {
+                1 => {
+                    for _ in 0..10 {
+                        let done = self.done.clone();
+                        spawner.spawn(move || {
+                            thread::sleep(Duration::from_millis(1));
+                            done.fetch_add(1, Ordering::SeqCst);
+                            Ok(())
+                        });
+                    }
+                    Ok(())
+                }
+                2 => {
+                    assert_eq!(10, self.done.load(Ordering::SeqCst));
+                    spawner.spawn(|| Err("Child failing on purpose.".into()));
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    struct PanickingExecutor;
+
+    impl CallableByID<usize> for PanickingExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            if *id == 3 {
+                panic!("Panicking on purpose.");
+            }
+            Ok(())
+        }
+    }
+
+    struct PanickingScheduler;
+
+    impl Scheduler<usize> for PanickingScheduler {
+        fn select(
+            &mut self,
+            _ready: &[ReadyNode<'_, usize>],
+            _graph: &GraphView<'_, usize>,
+        ) -> usize {
+            panic!("Scheduler broken.");
+        }
+
+        fn clone_box(&self) -> Box<dyn Scheduler<usize> + Send> {
+            Box::new(PanickingScheduler)
+        }
+    }
+
+    struct PanickingObserver;
+
+    impl RunObserver<usize> for PanickingObserver {
+        fn on_run_finish(&self, _report: &RunReport<usize>) {
+            panic!("Observer broken.");
+        }
+    }
+
+    #[test]
+    fn it_runs_with_every_idle_strategy() {
+        let strategies = [
+            IdleStrategy::Spin(1000),
+            IdleStrategy::Yield,
+            IdleStrategy::Park(Duration::from_millis(1)),
+            IdleStrategy::Condvar(Duration::from_millis(1)),
+        ];
+        let nodes = (0..20)
+            .map(|i| (i, if i < 10 { vec![] } else { vec![i - 10] }))
+            .collect::<HashMap<usize, Vec<usize>>>();
+
+        for strategy in strategies {
+            for runner in [
+                ThreadPoolRunner::new(4).with_idle_strategy(strategy),
+                ThreadPoolRunner::new(4)
+                    .with_idle_strategy(strategy)
+                    .with_persistent_workers(),
+            ] {
+                let report = runner.run(
+                    TopologicalBatchProvider::new(nodes.clone()).unwrap(),
+                    Arc::new(RecordingExecutor {
+                        failing: None,
+                        calls: Mutex::new(vec![]),
+                    }),
+                );
+                assert_eq!(20, report.completed.len());
+            }
+        }
+    }
+
+    #[test]
+    fn it_returns_panics_as_errors() {
+        let nodes = || {
+            TopologicalBatchProvider::new(HashMap::from([(1, vec![]), (2, vec![1]), (3, vec![2])]))
+                .unwrap()
+        };
+        let runner = ThreadPoolRunner::new(3);
+
+        let panicked = runner
+            .try_run(nodes(), Arc::new(PanickingExecutor))
+            .unwrap_err();
+        assert_eq!(Some(&3), panicked.node());
+        assert_eq!(
+            "Node 3 panicked: Panicking on purpose.",
+            panicked.to_string()
+        );
+
+        // Panics while holding the run's lock, poisoning it.
+        let panicked = runner
+            .try_run(
+                nodes().with_scheduler(PanickingScheduler),
+                Arc::new(PanickingExecutor),
+            )
+            .unwrap_err();
+        assert_eq!(None, panicked.node());
+        assert_eq!("Run panicked: Scheduler broken.", panicked.to_string());
+
+        let panicked = runner
+            .submit_with_options(
+                TopologicalBatchProvider::new(HashMap::from([(1, vec![])])).unwrap(),
+                Arc::new(PanickingExecutor),
+                RunOptions::new().with_observer(Arc::new(PanickingObserver)),
+            )
+            .wait()
+            .unwrap_err();
+        assert_eq!("Run panicked: Observer broken.", panicked.to_string());
+
+        let report = runner.try_run(
+            TopologicalBatchProvider::new(HashMap::from([(1, vec![])])).unwrap(),
+            Arc::new(PanickingExecutor),
+        );
+        assert_eq!(vec![1], report.unwrap().completed);
+    }
+
+    struct LateCallRecorder {
+        returned: Arc<AtomicBool>,
+        late_calls: Arc<AtomicUsize>,
+    }
+
+    impl CallableByID<usize> for LateCallRecorder {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            if self.returned.load(Ordering::SeqCst) {
+                self.late_calls.fetch_add(1, Ordering::SeqCst);
+            }
+            match id {
+                0 => {
+                    thread::sleep(Duration::from_millis(10));
+                    panic!("Panicking on purpose.");
+                }
+                1 => thread::sleep(Duration::from_millis(20)),
+                _ => thread::sleep(Duration::from_millis(1)),
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_executes_nothing_after_returning_an_error() {
+        // The dependents of 1 become available while the run ends.
+        let mut nodes = (2..50)
+            .map(|node| (node, vec![1]))
+            .collect::<HashMap<_, _>>();
+        nodes.insert(0, vec![]);
+        nodes.insert(1, vec![]);
+
+        // Parked workers only look at the run again after `wait` returned.
+        for idle_strategy in [
+            IdleStrategy::default(),
+            IdleStrategy::Park(Duration::from_millis(40)),
+        ] {
+            let returned = Arc::new(AtomicBool::new(false));
+            let late_calls = Arc::new(AtomicUsize::new(0));
+
+            let err = ThreadPoolRunner::new(4)
+                .with_idle_strategy(idle_strategy)
+                .submit(
+                    TopologicalBatchProvider::new(nodes.clone()).unwrap(),
+                    Arc::new(LateCallRecorder {
+                        returned: returned.clone(),
+                        late_calls: late_calls.clone(),
+                    }),
+                )
+                .wait()
+                .unwrap_err();
+            returned.store(true, Ordering::SeqCst);
+            assert_eq!(Some(&0), err.node());
+
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(0, late_calls.load(Ordering::SeqCst));
+        }
+    }
+
+    #[test]
+    fn it_waits_for_spawned_children() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+
+        for runner in [
+            ThreadPoolRunner::new(1),
+            ThreadPoolRunner::new(3).with_persistent_workers(),
+        ] {
+            let report = runner.run(
+                TopologicalBatchProvider::new(nodes.clone()).unwrap(),
+                Arc::new(FanOutExecutor {
+                    done: Arc::new(AtomicUsize::new(0)),
+                }),
+            );
+
+            assert_eq!(vec![1], report.completed);
+            assert_eq!(2, report.failed[0].0);
+            assert_eq!(vec![3], report.skipped);
+        }
+    }
+
+    struct WorkerRecorder {
+        workers: Mutex<HashMap<usize, usize>>,
+    }
+
+    impl RunObserver<usize> for WorkerRecorder {
+        fn on_node_start(&self, worker: usize, id: &usize) {
+            self.workers.lock().unwrap().insert(*id, worker);
+        }
+    }
+
+    #[test]
+    fn it_respects_node_affinity() {
+        let nodes = (0..20)
+            .map(|i| (i, if i < 10 { vec![] } else { vec![i - 10] }))
+            .collect::<HashMap<usize, Vec<usize>>>();
+
+        let recorder = Arc::new(WorkerRecorder {
+            workers: Mutex::new(HashMap::new()),
+        });
+        let affinity = (0..20)
+            .step_by(3)
+            .map(|node| (node, vec![node % 2 + 1]))
+            .chain([(1, vec![7])])
+            .collect::<HashMap<usize, Vec<usize>>>();
+        let options = RunOptions::new()
+            .with_affinity(Arc::new(affinity))
+            .with_observer(recorder.clone());
+
+        let report = ThreadPoolRunner::new(4).run_with_options(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            Arc::new(RecordingExecutor {
+                failing: None,
+                calls: Mutex::new(vec![]),
+            }),
+            options,
+        );
+
+        assert_eq!(20, report.completed.len());
+        let workers = recorder.workers.lock().unwrap();
+        for node in (0..20).step_by(3) {
+            assert_eq!(node % 2 + 1, workers[&node]);
+        }
+    }
+
+    #[test]
+    fn it_keeps_locality_groups_on_one_worker() {
+        let nodes = (0..20)
+            .map(|i| (i, vec![]))
+            .collect::<HashMap<usize, Vec<usize>>>();
+
+        let recorder = Arc::new(WorkerRecorder {
+            workers: Mutex::new(HashMap::new()),
+        });
+        let options = RunOptions::new()
+            .with_locality(Arc::new(|node: &usize| Some(format!("file-{}", node % 2))))
+            .with_observer(recorder.clone());
+
+        let report = ThreadPoolRunner::new(2).run_with_options(
+            TopologicalBatchProvider::new(nodes.clone()).unwrap(),
+            Arc::new(ExecutorExample::new(nodes)),
+            options,
+        );
+
+        assert_eq!(20, report.completed.len());
+        let workers = recorder.workers.lock().unwrap();
+        for key in 0..2 {
+            let mut per_worker = HashMap::new();
+            for node in (key..20).step_by(2) {
+                *per_worker.entry(workers[&node]).or_insert(0) += 1;
+            }
+            assert!(per_worker.values().any(|&count| count >= 8));
+        }
+    }
+
+    /// Fails node 3, recording the rollbacks.
+    #[derive(Default)]
+    struct SagaExecutor {
+        rolled_back: Mutex<Vec<usize>>,
+    }
+
+    impl CallableByID<usize> for SagaExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            if *id == 3 {
+                return Err("Deployment failed.".into());
+            }
+            Ok(())
+        }
+
+        fn on_rollback(&self, id: &usize) {
+            self.rolled_back.lock().unwrap().push(*id);
+        }
+    }
+
+    #[test]
+    fn it_rolls_back_the_ancestors_of_failed_nodes() {
+        let nodes = vec![
+            (1, vec![]),
+            (2, vec![1]),
+            (3, vec![2]),
+            (4, vec![]),
+            (5, vec![1]),
+        ];
+        let executor = Arc::new(SagaExecutor::default());
+
+        let report = ThreadPoolRunner::new(2).run_with_options(
+            TopologicalBatchProvider::with_capacity(nodes, 5).unwrap(),
+            executor.clone(),
+            RunOptions::new().with_rollback(),
+        );
+
+        assert_eq!(3, report.failed[0].0);
+        assert_eq!(vec![2, 1], *executor.rolled_back.lock().unwrap());
+    }
+
+    struct IdleRun;
+
+    impl Steppable for IdleRun {
+        fn step(&self, _worker: usize) -> Step {
+            Step::Idle
+        }
+
+        fn abort(&self, _payload: Box<dyn Any + Send>) {}
+    }
+
+    #[test]
+    fn it_shares_workers_between_tenants() {
+        let fairness = Fairness::WeightedShares(HashMap::from([("reports".to_string(), 2)]));
+        let big: Arc<dyn Steppable> = Arc::new(IdleRun);
+        let other_big: Arc<dyn Steppable> = Arc::new(IdleRun);
+        let small: Arc<dyn Steppable> = Arc::new(IdleRun);
+        let mut state = PoolState {
+            runs: VecDeque::new(),
+            tenants: HashMap::new(),
+            shutdown: false,
+        };
+        state.add(big.clone(), Some("batch".to_string()));
+        state.add(other_big.clone(), Some("batch".to_string()));
+        state.add(small.clone(), Some("reports".to_string()));
+
+        let mut dispatched = HashMap::new();
+        for _ in 0..30 {
+            let run = state.order().remove(0);
+            state.charge(&run, &fairness);
+            *dispatched
+                .entry(Arc::as_ptr(&run) as *const ())
+                .or_insert(0) += 1;
+        }
+
+        let count = |run: &Arc<dyn Steppable>| dispatched[&(Arc::as_ptr(run) as *const ())];
+        assert_eq!(20, count(&small));
+        assert_eq!(10, count(&big) + count(&other_big));
+        assert_eq!(5, count(&big));
+
+        let late: Arc<dyn Steppable> = Arc::new(IdleRun);
+        state.add(late.clone(), None);
+        assert_eq!(10.0, state.usage(state.runs.back().unwrap()));
+
+        state.remove(&small);
+        assert!(!state.tenants.contains_key("reports"));
+        state.remove(&big);
+        assert!(state.tenants.contains_key("batch"));
+        state.remove(&other_big);
+        assert!(state.tenants.is_empty());
+    }
+
+    #[test]
+    fn it_reports_finished_levels_in_order() {
+        let nodes = vec![
+            (1, vec![]),
+            (2, vec![]),
+            (3, vec![1]),
+            (4, vec![2]),
+            (5, vec![3, 4]),
+            (6, vec![3]),
+        ];
+        let provider = TopologicalBatchProvider::with_capacity(nodes, 6).unwrap();
+        let levels = Arc::new(Mutex::new(vec![]));
+        let recorder = levels.clone();
+        let observer = LevelObserver::new(
+            provider.levels(),
+            Arc::new(move |level, nodes: &[usize]| {
+                let mut nodes = nodes.to_vec();
+                nodes.sort();
+                recorder.lock().unwrap().push((level, nodes));
+            }),
+        );
+
+        let report = ThreadPoolRunner::new(3).run_with_options(
+            provider,
+            Arc::new(RecordingExecutor {
+                failing: Some(4),
+                calls: Mutex::new(vec![]),
+            }),
+            RunOptions::new().with_observer(Arc::new(observer)),
+        );
+
+        assert_eq!(vec![5], report.skipped);
+        assert_eq!(
+            vec![(0, vec![1, 2]), (1, vec![3, 4]), (2, vec![5, 6])],
+            *levels.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn it_executes_inline_without_threads() {
+        let nodes = vec![(1, vec![]), (2, vec![1]), (3, vec![2]), (4, vec![1])];
+        let executor = Arc::new(ThreadRecorder {
+            threads: Mutex::new(HashSet::new()),
+        });
+        let recorder = Arc::new(WorkerRecorder {
+            workers: Mutex::new(HashMap::new()),
+        });
+
+        let report = ThreadPoolRunner::new(0).run_with_options(
+            TopologicalBatchProvider::with_capacity(nodes, 4).unwrap(),
+            executor.clone(),
+            RunOptions::new().with_observer(recorder.clone()),
+        );
+
+        assert_eq!(4, report.completed.len());
+        assert_eq!(
+            HashSet::from([thread::current().id()]),
+            *executor.threads.lock().unwrap()
+        );
+        assert!(recorder
+            .workers
+            .lock()
+            .unwrap()
+            .values()
+            .all(|&worker| worker == 0));
+    }
+
+    /// Pops node 1, while node 2 never becomes available.
+    struct StuckProvider {
+        popped: bool,
+    }
+
+    impl BatchProvider<usize> for StuckProvider {
+        fn pop(&mut self) -> Result<Option<usize>, Error> {
+            Ok((!std::mem::replace(&mut self.popped, true)).then_some(1))
+        }
+
+        fn complete(&mut self, _node: usize) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn fail(&mut self, _node: usize) -> Result<Vec<usize>, Error> {
+            Ok(vec![])
+        }
+
+        fn is_empty(&self) -> bool {
+            false
+        }
+
+        fn unfinished(&self) -> Vec<usize> {
+            vec![2]
+        }
+    }
+
+    /// Loses its connection on the first pop.
+    struct DisconnectedProvider;
+
+    impl BatchProvider<usize> for DisconnectedProvider {
+        fn pop(&mut self) -> Result<Option<usize>, Error> {
+            Err("Connection lost.".into())
+        }
+
+        fn complete(&mut self, _node: usize) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn fail(&mut self, _node: usize) -> Result<Vec<usize>, Error> {
+            Ok(vec![])
+        }
+
+        fn is_empty(&self) -> bool {
+            false
+        }
+
+        fn unfinished(&self) -> Vec<usize> {
+            vec![1]
+        }
+    }
+
+    #[test]
+    fn it_ends_runs_on_provider_errors() {
+        let err = ThreadPoolRunner::new(2)
+            .try_run(DisconnectedProvider, Arc::new(PanickingExecutor))
+            .unwrap_err();
+
+        assert_eq!("Provider failed.", err.to_string());
+        assert_eq!(
+            "Connection lost.",
+            std::error::Error::source(&err).unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn it_detects_deadlocks() {
+        let result = ThreadPoolRunner::new(2)
+            .submit_with_options(
+                StuckProvider { popped: false },
+                Arc::new(RecordingExecutor {
+                    failing: None,
+                    calls: Mutex::new(vec![]),
+                }),
+                RunOptions::new().with_deadlock_detection(),
+            )
+            .wait();
+
+        let err = result.unwrap_err();
+        assert!(matches!(&err, RunError::Deadlock { stuck } if stuck == &vec![2]));
+        assert_eq!("Deadlock detected, stuck nodes: 2.", err.to_string());
+    }
+
+    /// Executes node 2 for 100ms, the others right away.
+    struct SlowNodeExecutor;
+
+    impl CallableByID<usize> for SlowNodeExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            if *id == 2 {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct SlowRecorder {
+        slow: Mutex<Vec<usize>>,
+    }
+
+    impl RunObserver<usize> for SlowRecorder {
+        fn on_node_slow(&self, _worker: usize, id: &usize, elapsed: Duration) {
+            assert!(elapsed >= Duration::from_millis(20));
+            self.slow.lock().unwrap().push(*id);
+        }
+    }
+
+    #[test]
+    fn it_reports_slow_nodes() {
+        let nodes = (1..=5)
+            .map(|i| (i, vec![]))
+            .collect::<HashMap<usize, Vec<usize>>>();
+        let recorder = Arc::new(SlowRecorder::default());
+
+        let report = ThreadPoolRunner::new(2).run_with_options(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            Arc::new(SlowNodeExecutor),
+            RunOptions::new()
+                .with_watchdog(Duration::from_millis(20))
+                .with_observer(recorder.clone()),
+        );
+
+        assert_eq!(5, report.completed.len());
+        assert_eq!(vec![2], *recorder.slow.lock().unwrap());
+    }
+
+    fn memory_estimate(id: &usize) -> u64 {
+        match id {
+            0..=2 => 5,
+            8 => 10,
+            _ => 1,
+        }
+    }
+
+    /// Records the memory in use when each node starts.
+    #[derive(Default)]
+    struct MemoryTracker {
+        in_use: AtomicUsize,
+        usage: Mutex<Vec<(usize, usize)>>,
+    }
+
+    impl CallableByID<usize> for MemoryTracker {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            let estimate = memory_estimate(id) as usize;
+            let in_use = self.in_use.fetch_add(estimate, Ordering::SeqCst) + estimate;
+            self.usage.lock().unwrap().push((*id, in_use));
+            thread::sleep(Duration::from_millis(10));
+            self.in_use.fetch_sub(estimate, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_keeps_within_the_memory_budget() {
+        let nodes = (0..10)
+            .map(|i| (i, vec![]))
+            .collect::<HashMap<usize, Vec<usize>>>();
+        let tracker = Arc::new(MemoryTracker::default());
+
+        let report = ThreadPoolRunner::new(4).run_with_options(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            tracker.clone(),
+            RunOptions::new().with_memory_budget(6, Arc::new(memory_estimate)),
+        );
+
+        assert_eq!(10, report.completed.len());
+        let usage = tracker.usage.lock().unwrap();
+        assert_eq!(10, usage.len());
+        for &(node, in_use) in usage.iter() {
+            match node {
+                8 => assert_eq!(10, in_use),
+                _ => assert!(in_use <= 6),
+            }
+        }
+    }
+
+    /// Not `Sync`, as it counts its calls in a `Cell`.
+    struct CountingExecutor {
+        calls: std::cell::Cell<usize>,
+        total: Arc<AtomicUsize>,
+    }
+
+    impl CallableByID<usize> for CountingExecutor {
+        fn call(&self, _id: &usize) -> Result<(), Error> {
+            self.calls.set(self.calls.get() + 1);
+            self.total.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_creates_an_executor_per_thread() {
+        let nodes = (0..50)
+            .map(|i| (i, vec![]))
+            .collect::<HashMap<usize, Vec<usize>>>();
+        let instances = Arc::new(AtomicUsize::new(0));
+        let total = Arc::new(AtomicUsize::new(0));
+
+        let report = ThreadPoolRunner::new(3).run_with_factory(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            {
+                let instances = instances.clone();
+                let total = total.clone();
+                move || {
+                    instances.fetch_add(1, Ordering::SeqCst);
+                    CountingExecutor {
+                        calls: std::cell::Cell::new(0),
+                        total: total.clone(),
+                    }
+                }
+            },
+        );
+
+        assert_eq!(50, report.completed.len());
+        assert_eq!(50, total.load(Ordering::SeqCst));
+        assert!((1..=3).contains(&instances.load(Ordering::SeqCst)));
+    }
+
+    /// Not `Sync` either, counting the rollbacks of nodes it did not execute itself.
+    struct OwnRollbackExecutor {
+        executed: std::cell::RefCell<HashSet<usize>>,
+        rollbacks: Arc<AtomicUsize>,
+        foreign_rollbacks: Arc<AtomicUsize>,
+    }
+
+    impl CallableByID<usize> for OwnRollbackExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            self.executed.borrow_mut().insert(*id);
+            match id {
+                3 => Err("Deployment failed.".into()),
+                _ => Ok(()),
+            }
+        }
+
+        fn on_rollback(&self, id: &usize) {
+            self.rollbacks.fetch_add(1, Ordering::SeqCst);
+            if !self.executed.borrow().contains(id) {
+                self.foreign_rollbacks.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[test]
+    fn it_rolls_back_on_the_instance_of_the_node() {
+        let nodes = vec![(1, vec![]), (2, vec![1]), (3, vec![2]), (4, vec![])];
+        let rollbacks = Arc::new(AtomicUsize::new(0));
+        let foreign_rollbacks = Arc::new(AtomicUsize::new(0));
+        let executor = PerThreadExecutor::new({
+            let rollbacks = rollbacks.clone();
+            let foreign_rollbacks = foreign_rollbacks.clone();
+            move || OwnRollbackExecutor {
+                executed: std::cell::RefCell::new(HashSet::new()),
+                rollbacks: rollbacks.clone(),
+                foreign_rollbacks: foreign_rollbacks.clone(),
+            }
+        });
+
+        let report = ThreadPoolRunner::new(2).run_with_options(
+            TopologicalBatchProvider::with_capacity(nodes, 4).unwrap(),
+            Arc::new(executor),
+            RunOptions::new().with_rollback(),
+        );
+
+        assert_eq!(3, report.failed[0].0);
+        assert_eq!(2, rollbacks.load(Ordering::SeqCst));
+        assert_eq!(0, foreign_rollbacks.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn it_keeps_one_instance_per_worker_across_runs() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let executor = Arc::new(PerThreadExecutor::new({
+            let created = created.clone();
+            move || {
+                created.fetch_add(1, Ordering::SeqCst);
+                RecordingExecutor {
+                    failing: None,
+                    calls: Mutex::new(vec![]),
+                }
+            }
+        }));
+
+        let runner = ThreadPoolRunner::new(2);
+        for _ in 0..3 {
+            let nodes = (0..20)
+                .map(|node| (node, vec![]))
+                .collect::<HashMap<_, _>>();
+            let report = runner.run(
+                TopologicalBatchProvider::new(nodes).unwrap(),
+                executor.clone(),
+            );
+            assert_eq!(20, report.completed.len());
+        }
+
+        assert!(created.load(Ordering::SeqCst) <= 2);
+        assert!(executor.instances.lock().len() <= 2);
+    }
+
+    #[test]
+    fn it_calls_worker_hooks() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let hook = |event: &'static str| -> WorkerHook {
+            let events = events.clone();
+            Arc::new(move |worker| events.lock().unwrap().push((event, worker)))
+        };
+
+        let runner = ThreadPoolRunner::new(2)
+            .with_persistent_workers()
+            .with_on_worker_start(hook("start"))
+            .with_on_worker_stop(hook("stop"));
+        for _ in 0..2 {
+            runner.run(
+                TopologicalBatchProvider::new(HashMap::from([(1, vec![])])).unwrap(),
+                Arc::new(RecordingExecutor {
+                    failing: None,
+                    calls: Mutex::new(vec![]),
+                }),
+            );
+        }
+        drop(runner);
+
+        let mut events = events.lock().unwrap().clone();
+        events.sort();
+        assert_eq!(
+            vec![("start", 0), ("start", 1), ("stop", 0), ("stop", 1)],
+            events
+        );
+    }
+
+    struct GatedExecutor {
+        gate: Mutex<bool>,
+        opened: Condvar,
+    }
+
+    impl CallableByID<usize> for GatedExecutor {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            if *id == 3 {
+                let mut open = self.gate.lock().unwrap();
+                while !*open {
+                    open = self.opened.wait(open).unwrap();
+                }
+            }
+            thread::sleep(Duration::from_millis(5));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_estimates_progress() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+        nodes.insert(4, vec![3]);
+
+        let executor = Arc::new(GatedExecutor {
+            gate: Mutex::new(false),
+            opened: Condvar::new(),
+        });
+        let handle = ThreadPoolRunner::new(2).submit_with_options(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            executor.clone(),
+            RunOptions::new().with_weights(Arc::new(|node| *node as f64)),
+        );
+
+        while handle.progress().unwrap().fraction < 0.3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let progress = handle.progress().unwrap();
+        assert_eq!(0.3, progress.fraction);
+        // 3 weight units took ~10ms, 7 are left on the critical path.
+        assert!(progress.eta.unwrap() >= Duration::from_millis(20));
+
+        *executor.gate.lock().unwrap() = true;
+        executor.opened.notify_all();
+        assert!(handle.wait().unwrap().is_success());
+
+        let custom = ThreadPoolRunner::new(1).submit_with_options(
+            SequenceProvider {
+                pending: VecDeque::new(),
+                running: false,
+            },
+            executor.clone(),
+            RunOptions::new().with_progress(),
+        );
+        assert_eq!(None, custom.progress());
+
+        let untracked = ThreadPoolRunner::new(1).submit(
+            TopologicalBatchProvider::new(HashMap::from([(1, vec![])])).unwrap(),
+            executor,
+        );
+        assert_eq!(None, untracked.progress());
+        untracked.wait().unwrap();
+    }
+
+    /// Executes the nodes one after the other, each waiting for the previous one.
+    struct SequenceProvider {
+        pending: VecDeque<usize>,
+        running: bool,
+    }
+
+    impl BatchProvider<usize> for SequenceProvider {
+        fn pop(&mut self) -> Result<Option<usize>, Error> {
+            if self.running {
+                return Ok(None);
+            }
+            self.running = !self.pending.is_empty();
+            Ok(self.pending.front().cloned())
+        }
+
+        fn complete(&mut self, _node: usize) -> Result<(), Error> {
+            self.running = false;
+            self.pending.pop_front();
+            Ok(())
+        }
+
+        fn fail(&mut self, _node: usize) -> Result<Vec<usize>, Error> {
+            self.running = false;
+            self.pending.pop_front();
+            Ok(self.pending.drain(..).collect())
+        }
+
+        fn is_empty(&self) -> bool {
+            self.pending.is_empty()
+        }
+
+        fn unfinished(&self) -> Vec<usize> {
+            self.pending.iter().copied().collect()
+        }
+    }
+
+    #[test]
+    fn it_runs_custom_providers() {
+        let runner = ThreadPoolRunner::new(4);
+        let executor = Arc::new(RecordingExecutor {
+            failing: Some(3),
+            calls: Mutex::new(vec![]),
+        });
+
+        let report = runner.run(
+            SequenceProvider {
+                pending: VecDeque::from(vec![1, 2, 3, 4, 5]),
+                running: false,
+            },
+            executor.clone(),
+        );
+
+        assert_eq!(vec![1, 2, 3], *executor.calls.lock().unwrap());
+        assert_eq!(vec![1, 2], report.completed);
+        assert_eq!(vec![4, 5], report.skipped);
+    }
+
+    struct SleepingRecorder {
+        calls: Mutex<Vec<usize>>,
+    }
+
+    impl CallableByID<usize> for SleepingRecorder {
+        fn call(&self, id: &usize) -> Result<(), Error> {
+            thread::sleep(Duration::from_millis(2));
+            self.calls.lock().unwrap().push(*id);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_tears_down_in_reverse_order() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+
+        let topological_batch_provider = TopologicalBatchProvider::new(nodes).unwrap();
+        let runner = ThreadPoolRunner::new(2);
+        let setup = Arc::new(RecordingExecutor {
+            failing: Some(3),
+            calls: Mutex::new(vec![]),
+        });
+        let teardown = Arc::new(RecordingExecutor {
+            failing: None,
+            calls: Mutex::new(vec![]),
+        });
+
+        let report = runner.run_setup_teardown(
+            topological_batch_provider,
+            setup.clone(),
+            teardown.clone(),
+            true,
+        );
+
+        assert_eq!(vec![1, 2, 3], *setup.calls.lock().unwrap());
+        assert_eq!(vec![2, 1], *teardown.calls.lock().unwrap());
+        assert_eq!(vec![2, 1], report.teardown.completed);
+        assert_eq!(vec![3], report.teardown.skipped);
+    }
+
+    #[test]
+    fn it_tears_down_past_failures() {
+        let nodes = HashMap::from([(1, vec![]), (2, vec![1]), (3, vec![2])]);
+
+        let teardown = Arc::new(RecordingExecutor {
+            failing: Some(2),
+            calls: Mutex::new(vec![]),
+        });
+
+        let report = ThreadPoolRunner::new(2).run_setup_teardown(
+            TopologicalBatchProvider::new(nodes).unwrap(),
+            Arc::new(RecordingExecutor {
+                failing: None,
+                calls: Mutex::new(vec![]),
+            }),
+            teardown.clone(),
+            false,
+        );
+
+        assert_eq!(vec![3, 2, 1], *teardown.calls.lock().unwrap());
+        assert_eq!(vec![3, 1], report.teardown.completed);
+        assert_eq!(2, report.teardown.failed[0].0);
+        assert!(report.teardown.skipped.is_empty());
     }
 }
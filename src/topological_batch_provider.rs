@@ -1,18 +1,410 @@
 //! The topological batch provider can be used independently from the runner. It has added circular dependency
 //! detection.
+//!
+//! Internally every ID is mapped to a dense `usize` index at construction, and all the bookkeeping is done on those
+//! indices. IDs are only hashed when they enter (`complete`) and only cloned when they leave (`pop`) the provider.
 
 use super::common::*;
-use std::{
-    collections::{HashMap, HashSet},
-    hash::Hash,
-};
+use super::plan::*;
+use super::profile::*;
+use super::scheduler::*;
+use super::validation::*;
+use std::{collections::HashMap, fmt, hash::Hash, thread, time::Duration};
 
-#[derive(Debug)]
+/// Lifecycle of a single node inside the provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    /// Some of its dependencies are not completed yet.
+    Pending,
+    /// All dependencies are completed, it can be popped.
+    Available,
+    /// Popped, but not completed yet.
+    Running,
+    /// Completed.
+    Completed,
+    /// Completed outside of the runner, see `TopologicalBatchProvider::mark_external_complete`. Counts as completed for
+    /// its dependees.
+    Satisfied,
+    /// Popped, then marked as failed.
+    Failed,
+    /// Never popped, as one of its dependencies (transitively) failed.
+    Skipped,
+}
+
+impl NodeStatus {
+    /// Completed, satisfied, failed or skipped - the node needs no more attention.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            NodeStatus::Completed
+                | NodeStatus::Satisfied
+                | NodeStatus::Failed
+                | NodeStatus::Skipped
+        )
+    }
+
+    /// Completed or satisfied - its dependees don't wait for it.
+    pub fn is_completed(&self) -> bool {
+        matches!(self, NodeStatus::Completed | NodeStatus::Satisfied)
+    }
+}
+
+/// Why a `complete` or `fail` call had no effect. The provider's state is left untouched, so duplicate
+/// acknowledgements (common with distributed workers) can simply be ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionError {
+    /// Not a node of the graph, or pruned.
+    UnknownNode,
+    /// Already completed, failed or skipped, holding that status.
+    AlreadyFinished(NodeStatus),
+    /// Only running (popped or taken) nodes can be completed or failed.
+    InvalidTransition { from: NodeStatus, to: NodeStatus },
+}
+
+impl fmt::Display for CompletionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompletionError::UnknownNode => write!(f, "Unknown node."),
+            CompletionError::AlreadyFinished(status) => {
+                write!(f, "Node already finished: {:?}.", status)
+            }
+            CompletionError::InvalidTransition { from, to } => {
+                write!(f, "Invalid node transition: {:?} -> {:?}.", from, to)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompletionError {}
+
+/// A node depending on a node of a later phase, directly or through nodes without a phase, so it could never run. See
+/// `TopologicalBatchProvider::with_phases`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseError<T> {
+    pub node: T,
+    pub dependency: T,
+}
+
+impl<T: fmt::Display> fmt::Display for PhaseError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Node {} depends on {} of a later phase.",
+            self.node, self.dependency
+        )
+    }
+}
+
+impl<T: fmt::Debug + fmt::Display> std::error::Error for PhaseError<T> {}
+
+/// State of a provider, see `TopologicalBatchProvider::snapshot`.
+#[derive(Debug, Clone)]
+pub struct Snapshot<T> {
+    ids: Vec<T>,
+    indices: HashMap<T, usize>,
+    dependents: Vec<Vec<usize>>,
+    pending_dependencies: Vec<usize>,
+    statuses: Vec<NodeStatus>,
+    available: Vec<usize>,
+    incomplete_count: usize,
+    scheduler: SchedulerSlot<T>,
+}
+
+/// What `remove_node` does with the dependees of the removed node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalMode {
+    /// Refuses to remove a node others depend on.
+    Reject,
+    /// Removes its transitive dependees as well.
+    Cascade,
+}
+
+/// Why a `remove_node` call had no effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalError {
+    /// Not a node of the graph, or pruned.
+    UnknownNode,
+    /// Other nodes depend on it, with `RemovalMode::Reject`.
+    HasDependents,
+    /// The node (or a dependee to cascade to) is popped and not finished yet.
+    Running,
+}
+
+impl fmt::Display for RemovalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemovalError::UnknownNode => write!(f, "Unknown node."),
+            RemovalError::HasDependents => write!(f, "Other nodes depend on the node."),
+            RemovalError::Running => write!(f, "Running nodes can't be removed."),
+        }
+    }
+}
+
+impl std::error::Error for RemovalError {}
+
+/// Which of the available nodes `pop` picks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchedulingPolicy {
+    /// Any of them, the cheapest.
+    #[default]
+    Arbitrary,
+    /// The one with the most transitive dependees, which keeps more nodes available on diamond-heavy graphs.
+    FanOutFirst,
+    /// The one made available last, descending into the dependents of the node just completed.
+    DepthFirst,
+    /// The one made available first, draining the current level before the next one.
+    BreadthFirst,
+}
+
+/// What the runners need from a provider, so alternative implementations (persistent, distributed, ...) can be run
+/// by them. See `TopologicalBatchProvider` for the semantics of the methods.
+///
+/// Popping, completing and failing can fail, eg on the I/O of providers keeping their state in a database. An error
+/// ends the run, see `RunError::Provider`.
+pub trait BatchProvider<T> {
+    /// A node whose dependencies are all completed, `None` if there is none right now.
+    fn pop(&mut self) -> Result<Option<T>, Error>;
+
+    /// Marks a popped node as successfully executed.
+    fn complete(&mut self, node: T) -> Result<(), Error>;
+
+    /// Marks a popped node as failed, returning the nodes skipped because of it.
+    fn fail(&mut self, node: T) -> Result<Vec<T>, Error>;
+
+    /// Every node is finished, nothing is left to pop or to wait for.
+    fn is_empty(&self) -> bool;
+
+    /// The nodes not finished yet, reported as cancelled when a run stops early.
+    fn unfinished(&self) -> Vec<T>;
+
+    /// Every node with its dependencies, dependencies first, when known upfront. Used to estimate the progress of runs.
+    fn graph(&self) -> Option<Vec<(T, Vec<T>)>> {
+        None
+    }
+
+    /// The nodes completed outside of the runner, reported as `RunReport::satisfied` of the runs they start.
+    fn satisfied(&self) -> Vec<T> {
+        vec![]
+    }
+
+    /// The nodes skipped after the fact since the last call, eg rolled back after completing (see
+    /// `SpeculativeProvider`). `ThreadPoolRunner` moves them to the skipped ones of its report.
+    fn take_rolled_back(&mut self) -> Vec<T> {
+        vec![]
+    }
+}
+
+impl<T, P: BatchProvider<T> + ?Sized> BatchProvider<T> for Box<P> {
+    fn pop(&mut self) -> Result<Option<T>, Error> {
+        (**self).pop()
+    }
+
+    fn complete(&mut self, node: T) -> Result<(), Error> {
+        (**self).complete(node)
+    }
+
+    fn fail(&mut self, node: T) -> Result<Vec<T>, Error> {
+        (**self).fail(node)
+    }
+
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+
+    fn unfinished(&self) -> Vec<T> {
+        (**self).unfinished()
+    }
+
+    fn graph(&self) -> Option<Vec<(T, Vec<T>)>> {
+        (**self).graph()
+    }
+
+    fn satisfied(&self) -> Vec<T> {
+        (**self).satisfied()
+    }
+
+    fn take_rolled_back(&mut self) -> Vec<T> {
+        (**self).take_rolled_back()
+    }
+}
+
+/// Cloning copies the graph, the progress and the scheduler (`Scheduler::clone_box`).
+#[derive(Debug, Clone)]
 pub struct TopologicalBatchProvider<T> {
-    unavailable: HashSet<T>,
-    rights: Vec<T>,
-    available: HashSet<T>,
-    inverse_dependency: HashMap<T, Vec<T>>,
+    /// Index to ID translation.
+    ids: Vec<T>,
+    /// ID to index translation.
+    indices: HashMap<T, usize>,
+    /// Inverse dependencies: for each node the indices of the nodes depending on it.
+    dependents: Vec<Vec<usize>>,
+    /// For each node the number of its dependencies not yet completed.
+    pending_dependencies: Vec<usize>,
+    /// Status of each node.
+    statuses: Vec<NodeStatus>,
+    /// Indices ready to be popped.
+    available: Vec<usize>,
+    /// Number of nodes not yet completed (including available and popped ones).
+    incomplete_count: usize,
+    scheduling_policy: SchedulingPolicy,
+    /// Custom or policy based choice of the next available node. When missing, the last available one is popped.
+    scheduler: SchedulerSlot<T>,
+    /// Maximum number of direct dependents of the node running at once, see `with_max_concurrent_dependents`.
+    dependent_limits: HashMap<T, usize>,
+    /// `dependent_limits` by index, built by the first `pop` after an edit of the graph.
+    throttle: Option<Throttle>,
+    /// Barriers between groups of nodes, see `with_phases`.
+    phases: Phases<T>,
+}
+
+/// Running dependents of the nodes limited by `TopologicalBatchProvider::with_max_concurrent_dependents`, by index.
+#[derive(Debug, Clone)]
+struct Throttle {
+    limits: Vec<DependentLimit>,
+    /// Positions in `limits` of the limits of every dependent of a limited node.
+    limited_by: HashMap<usize, Vec<usize>>,
+}
+
+#[derive(Debug, Clone)]
+struct DependentLimit {
+    max: usize,
+    /// Dependents started by the provider, some possibly finished since.
+    running: Vec<usize>,
+    /// Available dependents held back until running ones finish.
+    parked: Vec<usize>,
+}
+
+impl Throttle {
+    /// Forgets the dependents no longer running, handing the parked ones of the limits not reached anymore back to
+    /// `available`.
+    fn refresh(&mut self, statuses: &[NodeStatus], available: &mut Vec<usize>) {
+        for limit in &mut self.limits {
+            limit
+                .running
+                .retain(|&index| statuses[index] == NodeStatus::Running);
+            if limit.running.len() < limit.max {
+                available.append(&mut limit.parked);
+            }
+        }
+    }
+
+    /// Holds the node back if one of its limits is reached.
+    fn park(&mut self, index: usize) -> bool {
+        let Some(limits) = self.limited_by.get(&index) else {
+            return false;
+        };
+        let reached = limits.iter().find(|&&limit| {
+            let limit = &self.limits[limit];
+            limit.running.len() >= limit.max
+        });
+
+        match reached {
+            Some(&limit) => {
+                self.limits[limit].parked.push(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn start(&mut self, index: usize) {
+        for &limit in self.limited_by.get(&index).into_iter().flatten() {
+            let running = &mut self.limits[limit].running;
+            if !running.contains(&index) {
+                running.push(index);
+            }
+        }
+    }
+
+    fn parked(&self) -> impl Iterator<Item = &usize> {
+        self.limits.iter().flat_map(|limit| &limit.parked)
+    }
+}
+
+/// Nodes of ordered phases, a phase starting once the previous ones are finished.
+#[derive(Debug, Clone)]
+struct Phases<T> {
+    /// Nodes of each phase, in phase order.
+    members: Vec<Vec<T>>,
+    /// Position in `members` of the phase of each node having one.
+    phase_of: HashMap<T, usize>,
+    /// First phase with unfinished nodes, as far as known.
+    open: usize,
+    /// Number of nodes of the open phase known to be finished. Nodes only go back to unfinished on `invalidate` and
+    /// `restore`, which rewind the cursor.
+    finished: usize,
+    /// Available nodes of the phases after the open one, by index, held back until their phase opens.
+    parked: Vec<Vec<usize>>,
+}
+
+impl<T: Hash + PartialEq + Eq> Phases<T> {
+    fn new() -> Self {
+        Self {
+            members: vec![],
+            phase_of: HashMap::new(),
+            open: 0,
+            finished: 0,
+            parked: vec![],
+        }
+    }
+
+    fn rewind(&mut self) {
+        self.open = 0;
+        self.finished = 0;
+    }
+
+    /// Moves the cursor to the first phase with unfinished nodes, returning it, the parked nodes of the phases opened
+    /// on the way going to `available`. Removed and pruned nodes count as finished.
+    fn advance(
+        &mut self,
+        indices: &HashMap<T, usize>,
+        statuses: &[NodeStatus],
+        available: &mut Vec<usize>,
+    ) -> usize {
+        while let Some(members) = self.members.get(self.open) {
+            while let Some(node) = members.get(self.finished) {
+                match indices.get(node) {
+                    Some(&index) if !statuses[index].is_finished() => return self.open,
+                    _ => self.finished += 1,
+                }
+            }
+
+            self.open += 1;
+            self.finished = 0;
+            if let Some(parked) = self.parked.get_mut(self.open) {
+                available.append(parked);
+            }
+        }
+
+        self.open
+    }
+
+    /// Holds the node back if it belongs to a phase after the open one.
+    fn park(&mut self, node: &T, index: usize, open: usize) -> bool {
+        match self.phase_of.get(node) {
+            Some(&phase) if phase > open => {
+                self.parked[phase].push(index);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+struct SchedulerSlot<T>(Option<Box<dyn Scheduler<T> + Send>>);
+
+impl<T> Clone for SchedulerSlot<T> {
+    fn clone(&self) -> Self {
+        SchedulerSlot(self.0.as_ref().map(|scheduler| scheduler.clone_box()))
+    }
+}
+
+impl<T> fmt::Debug for SchedulerSlot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Some(Scheduler)"),
+            None => write!(f, "None"),
+        }
+    }
 }
 
 impl<T: Hash + PartialEq + Eq + Clone> TopologicalBatchProvider<T> {
@@ -28,168 +420,2469 @@ impl<T: Hash + PartialEq + Eq + Clone> TopologicalBatchProvider<T> {
     ///
     /// Says: 0 depends on 1 (1 must come before 0) and 1 has no dependency.
     ///
-    /// It returns an error when circular dependency is detected.
+    /// It returns an error when circular dependency is detected or when a dependency is not declared as a node.
     pub fn new(nodes: HashMap<T, Vec<T>>) -> Result<Self, Error> {
-        if Self::has_cycle(&nodes) {
-            return Err("Cycle detected.".into());
-        }
+        let capacity = nodes.len();
+        Self::with_capacity(nodes, capacity)
+    }
+
+    /// Same as `new`, but accepts any source of `(node, dependencies)` pairs, where the dependencies can be any
+    /// iterable collection (`Vec`, `HashSet`, slice iterator, ...).
+    ///
+    /// Besides the errors of `new` it also rejects a node that is declared more than once.
+    pub fn try_from_iter<I, D>(nodes: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (T, D)>,
+        D: IntoIterator<Item = T>,
+    {
+        let nodes = nodes.into_iter();
+        let capacity = nodes.size_hint().0;
+        Self::with_capacity(nodes, capacity)
+    }
+
+    /// Builds the graph from domain objects (owned or borrowed) implementing `Node`, with the errors of
+    /// `try_from_iter`.
+    pub fn from_nodes<N, I>(nodes: I) -> Result<Self, Error>
+    where
+        N: Node<T>,
+        I: IntoIterator<Item = N>,
+    {
+        Self::try_from_iter(nodes.into_iter().map(|node| {
+            (
+                node.id().clone(),
+                node.dependencies().cloned().collect::<Vec<_>>(),
+            )
+        }))
+    }
+
+    /// Same as `try_from_iter`, with errors naming the nodes involved. Finding the nodes of a cycle takes an extra
+    /// pass over the graph, only made when there is one.
+    pub fn new_with_diagnostics<I, D>(nodes: I) -> Result<Self, GraphError<T>>
+    where
+        I: IntoIterator<Item = (T, D)>,
+        D: IntoIterator<Item = T>,
+    {
+        let nodes = nodes.into_iter();
+        let capacity = nodes.size_hint().0;
+        Self::build_diagnosed(nodes, capacity)
+    }
+
+    /// Same as `try_from_iter`, but pre-allocates the internal storage for `capacity` nodes. Useful when the graph is
+    /// streamed in and its size is known upfront.
+    pub fn with_capacity<I, D>(nodes: I, capacity: usize) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (T, D)>,
+        D: IntoIterator<Item = T>,
+    {
+        Ok(Self::build(nodes, capacity)?)
+    }
+
+    /// Construction with the structural error kept typed.
+    pub(crate) fn build<I, D>(nodes: I, capacity: usize) -> Result<Self, TopoError>
+    where
+        I: IntoIterator<Item = (T, D)>,
+        D: IntoIterator<Item = T>,
+    {
+        Self::build_diagnosed(nodes, capacity).map_err(|error| error.kind())
+    }
+
+    fn build_diagnosed<I, D>(nodes: I, capacity: usize) -> Result<Self, GraphError<T>>
+    where
+        I: IntoIterator<Item = (T, D)>,
+        D: IntoIterator<Item = T>,
+    {
+        let mut ids = Vec::with_capacity(capacity);
+        let mut indices = HashMap::with_capacity(capacity);
+        let mut declared_dependencies = Vec::with_capacity(capacity);
+
+        for (id, dependencies) in nodes {
+            if indices.insert(id.clone(), ids.len()).is_some() {
+                return Err(GraphError::DuplicateNode(id));
+            }
 
-        let mut inverse_dependency: HashMap<T, Vec<T>> = HashMap::new();
-        let mut rights = vec![];
-        let mut unavailable = HashSet::new();
+            ids.push(id);
+            declared_dependencies.push(dependencies);
+        }
 
-        for (dependee, dependencies) in &nodes {
-            unavailable.insert(dependee.clone());
+        let mut dependents = vec![vec![]; ids.len()];
+        let mut pending_dependencies = vec![0; ids.len()];
 
+        for (dependee_index, dependencies) in declared_dependencies.into_iter().enumerate() {
             for dependency in dependencies {
-                inverse_dependency
-                    .entry(dependency.clone())
-                    .or_default()
-                    .push(dependee.clone());
+                let Some(&dependency_index) = indices.get(&dependency) else {
+                    return Err(GraphError::UnknownDependency {
+                        node: ids[dependee_index].clone(),
+                        dependency,
+                    });
+                };
 
-                rights.push(dependee.clone());
+                dependents[dependency_index].push(dependee_index);
+                pending_dependencies[dependee_index] += 1;
             }
         }
 
-        let available = unavailable
-            .difference(&HashSet::from_iter(rights.iter().cloned()))
-            .cloned()
-            .collect::<HashSet<T>>();
+        if Self::has_cycle(&dependents, &pending_dependencies) {
+            return Err(GraphError::Cycle(Self::find_cycle(&ids, &dependents)));
+        }
+
+        Ok(Self::from_parts(
+            ids,
+            indices,
+            dependents,
+            pending_dependencies,
+        ))
+    }
+
+    /// A provider where none of the nodes are popped yet.
+    pub(crate) fn from_parts(
+        ids: Vec<T>,
+        indices: HashMap<T, usize>,
+        dependents: Vec<Vec<usize>>,
+        pending_dependencies: Vec<usize>,
+    ) -> Self {
+        let statuses = pending_dependencies
+            .iter()
+            .map(|&pending| {
+                if pending == 0 {
+                    NodeStatus::Available
+                } else {
+                    NodeStatus::Pending
+                }
+            })
+            .collect::<Vec<_>>();
+        let available = (0..ids.len())
+            .filter(|&i| statuses[i] == NodeStatus::Available)
+            .collect();
 
-        Ok(Self {
-            unavailable,
-            rights,
+        Self {
+            statuses,
+            incomplete_count: ids.len(),
+            ids,
+            indices,
+            dependents,
+            pending_dependencies,
             available,
-            inverse_dependency,
-        })
+            scheduling_policy: SchedulingPolicy::Arbitrary,
+            scheduler: SchedulerSlot(None),
+            dependent_limits: HashMap::new(),
+            throttle: None,
+            phases: Phases::new(),
+        }
+    }
+
+    /// The graph as given to `from_parts`, valid as such only before any node is popped.
+    pub(crate) fn into_plan(self) -> Plan<T> {
+        Plan {
+            ids: self.ids,
+            indices: self.indices,
+            dependents: self.dependents,
+            pending_dependencies: self.pending_dependencies,
+        }
+    }
+
+    /// Checkpoint of the progress (and the graph, as it can be edited) to go back to with `restore`, with a copy of
+    /// the scheduler.
+    pub fn snapshot(&self) -> Snapshot<T> {
+        Snapshot {
+            ids: self.ids.clone(),
+            indices: self.indices.clone(),
+            dependents: self.dependents.clone(),
+            pending_dependencies: self.pending_dependencies.clone(),
+            statuses: self.statuses.clone(),
+            available: self
+                .available
+                .iter()
+                .chain(self.throttle.iter().flat_map(Throttle::parked))
+                .chain(self.phases.parked.iter().flatten())
+                .copied()
+                .collect(),
+            incomplete_count: self.incomplete_count,
+            scheduler: self.scheduler.clone(),
+        }
     }
 
-    fn has_cycle(nodes: &HashMap<T, Vec<T>>) -> bool {
-        let mut done: HashMap<&T, HashSet<&T>> = HashMap::new();
+    /// Rolls back to the snapshot, scheduler included.
+    pub fn restore(&mut self, snapshot: Snapshot<T>) {
+        self.unpark();
+        self.ids = snapshot.ids;
+        self.indices = snapshot.indices;
+        self.dependents = snapshot.dependents;
+        self.pending_dependencies = snapshot.pending_dependencies;
+        self.statuses = snapshot.statuses;
+        self.available = snapshot.available;
+        self.incomplete_count = snapshot.incomplete_count;
+        self.scheduler = snapshot.scheduler;
+        self.phases.rewind();
+    }
 
-        for (n, reqs) in nodes {
-            let mut stack = vec![];
-            for req in reqs {
-                stack.push(req);
+    /// Sets how `pop` picks among the available nodes. `FanOutFirst` counts the transitive dependees of every node
+    /// upfront, which takes `O(nodes * edges)` in the worst case.
+    pub fn with_scheduling_policy(mut self, scheduling_policy: SchedulingPolicy) -> Self {
+        self.scheduler = match scheduling_policy {
+            SchedulingPolicy::Arbitrary => SchedulerSlot(None),
+            SchedulingPolicy::FanOutFirst => SchedulerSlot(Some(Box::new(
+                PriorityScheduler::fan_out_first(&self.graph_view()),
+            ))),
+            SchedulingPolicy::DepthFirst => {
+                SchedulerSlot(Some(Box::new(TraversalScheduler::depth_first())))
+            }
+            SchedulingPolicy::BreadthFirst => {
+                SchedulerSlot(Some(Box::new(TraversalScheduler::breadth_first())))
             }
+        };
+        self.scheduling_policy = scheduling_policy;
+        self
+    }
+
+    /// Delegates the choice of the next node of `pop` to a custom scheduler. Picking costs `O(available)` per `pop`.
+    pub fn with_scheduler(mut self, scheduler: impl Scheduler<T> + Send + 'static) -> Self {
+        self.scheduler = SchedulerSlot(Some(Box::new(scheduler)));
+        self
+    }
 
-            done.insert(n, HashSet::new());
+    /// Pops the nodes with the longest remaining path first, the path being measured with the durations of `profile`
+    /// (see `ProfileRecorder`). Nodes missing from the profile are assumed to take `default_cost`.
+    pub fn with_profile(self, profile: &Profile, default_cost: Duration) -> Self
+    where
+        T: fmt::Display,
+    {
+        let mut priorities = vec![0; self.ids.len()];
+        for index in self.level_indices().into_iter().flatten().rev() {
+            let cost = profile.duration(&self.ids[index]).unwrap_or(default_cost);
+            let longest_dependent = self.dependents[index]
+                .iter()
+                .map(|&dependent| priorities[dependent])
+                .max()
+                .unwrap_or(0);
+            priorities[index] = cost.as_micros() as usize + longest_dependent;
+        }
 
-            while let Some(m) = stack.pop() {
-                if done[n].contains(&m) {
-                    continue;
-                }
+        self.with_scheduler(PriorityScheduler::new(priorities))
+    }
 
-                if m == n {
-                    return true;
+    /// Pops the nodes with the least slack first, for graphs mixing latency sensitive nodes with bulk ones. Nodes can
+    /// have a soft deadline (relative to any instant common to all of them, eg the start of the run), a node's slack
+    /// being the time left until the latest it can start for it and all its transitive dependees to meet theirs, given
+    /// the estimated `costs`. Nodes without a deadline downstream are popped last. Missed deadlines are not enforced.
+    pub fn with_deadlines(
+        self,
+        deadlines: impl Fn(&T) -> Option<Duration>,
+        costs: impl Fn(&T) -> Duration,
+    ) -> Self {
+        // Latest start of every node in microseconds, negative when already late.
+        let mut latest_starts: Vec<Option<i128>> = vec![None; self.ids.len()];
+        for index in self.level_indices().into_iter().flatten().rev() {
+            let own = deadlines(&self.ids[index]).map(|deadline| deadline.as_micros() as i128);
+            let earliest = self.dependents[index]
+                .iter()
+                .filter_map(|&dependent| latest_starts[dependent])
+                .chain(own)
+                .min();
+            latest_starts[index] =
+                earliest.map(|earliest| earliest - costs(&self.ids[index]).as_micros() as i128);
+        }
+
+        let mut ordered = latest_starts
+            .iter()
+            .filter_map(|latest_start| *latest_start)
+            .collect::<Vec<_>>();
+        ordered.sort_unstable();
+        ordered.dedup();
+        let priorities = latest_starts
+            .iter()
+            .map(|latest_start| match latest_start {
+                Some(latest_start) => {
+                    ordered.len() - ordered.partition_point(|other| other < latest_start)
                 }
+                None => 0,
+            })
+            .collect();
+
+        self.with_scheduler(PriorityScheduler::new(priorities))
+    }
+
+    /// Pops at random among the available nodes, with probabilities proportional to their `weights`, to interleave
+    /// bursty kinds of nodes with the others. See `WeightedRandomScheduler`.
+    pub fn with_weights(self, weights: impl Fn(&T) -> f64, seed: u64) -> Self {
+        let weights = self.ids.iter().map(weights).collect();
+        self.with_scheduler(WeightedRandomScheduler::new(weights, seed))
+    }
+
+    /// Lets at most `max` (at least 1) direct dependents of the node run at once, eg when they all read what the node
+    /// produced from a slow shared mount. The others stay available and are popped once running ones finish. Limits are
+    /// by ID, so they survive graph edits, but are not carried over by `reversed`. Each `pop` costs `O(max)` of every
+    /// limited node on top, the first one after an edit of the graph `O(dependents)` of them.
+    pub fn with_max_concurrent_dependents(mut self, node: T, max: usize) -> Self {
+        self.unpark();
+        self.dependent_limits.insert(node, max.max(1));
+        self
+    }
 
-                for dep_m in &nodes[&m] {
-                    stack.push(dep_m);
+    /// Groups the nodes into ordered phases: no node of a phase is popped before every node of the earlier phases is
+    /// finished (completed, failed or skipped). Nodes without a phase, including the ones added later, are not held back
+    /// and don't hold back any phase. Explicit edges apply on top, and phases cost `O(nodes)` over the whole run instead
+    /// of the `O(nodes²)` edges encoding the same barriers. Not carried over by `reversed`.
+    ///
+    /// Fails when a node depends on a node of a later phase, directly or through nodes without a phase, as it would
+    /// never run. Edges added afterwards are not checked.
+    pub fn with_phases(
+        mut self,
+        phases: impl Fn(&T) -> Option<usize>,
+    ) -> Result<Self, PhaseError<T>> {
+        let phase_by_index = self.ids.iter().map(&phases).collect::<Vec<_>>();
+        // Latest phase among the transitive dependencies of every node, with the node having it.
+        let mut latest: Vec<Option<(usize, usize)>> = vec![None; self.ids.len()];
+        for index in self.level_indices().into_iter().flatten() {
+            let own = phase_by_index[index].map(|phase| (phase, index));
+            let carried = latest[index].max(own);
+            if let (Some(phase), Some((later, dependency))) = (phase_by_index[index], latest[index])
+            {
+                if later > phase {
+                    return Err(PhaseError {
+                        node: self.ids[index].clone(),
+                        dependency: self.ids[dependency].clone(),
+                    });
                 }
+            }
+            for &dependent in &self.dependents[index] {
+                latest[dependent] = latest[dependent].max(carried);
+            }
+        }
 
-                done.get_mut(n).unwrap().insert(&m);
+        let mut grouped: Vec<(usize, &T)> = self
+            .ids
+            .iter()
+            .zip(&phase_by_index)
+            .filter_map(|(id, phase)| phase.map(|phase| (phase, id)))
+            .collect();
+        grouped.sort_by_key(|(phase, _)| *phase);
+
+        let mut members: Vec<Vec<T>> = vec![];
+        let mut phase_of = HashMap::new();
+        let mut last = None;
+        for (phase, id) in grouped {
+            if last != Some(phase) {
+                members.push(vec![]);
+                last = Some(phase);
             }
+            let position = members.len() - 1;
+            phase_of.insert(id.clone(), position);
+            members[position].push(id.clone());
         }
 
-        false
+        self.unpark();
+        self.phases = Phases {
+            parked: vec![vec![]; members.len()],
+            members,
+            phase_of,
+            open: 0,
+            finished: 0,
+        };
+        Ok(self)
     }
 
-    /// Empty is a global check over the batch provider, when it has no more ID to provide and all of the retrieved
-    /// IDs were marked as computed.
-    pub fn is_empty(&self) -> bool {
-        self.available.is_empty() && self.unavailable.is_empty()
+    /// Counts the running dependents of the limited nodes.
+    fn build_throttle(&self) -> Throttle {
+        let mut limits = vec![];
+        let mut limited_by: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (node, &max) in &self.dependent_limits {
+            let Some(&index) = self.indices.get(node) else {
+                continue;
+            };
+
+            let dependents = &self.dependents[index];
+            for &dependent in dependents {
+                limited_by.entry(dependent).or_default().push(limits.len());
+            }
+            limits.push(DependentLimit {
+                max,
+                running: dependents
+                    .iter()
+                    .copied()
+                    .filter(|&dependent| self.statuses[dependent] == NodeStatus::Running)
+                    .collect(),
+                parked: vec![],
+            });
+        }
+
+        Throttle { limits, limited_by }
     }
 
-    /// Complete is the signal the resolution of the dependency - all of it's dependees are now free of this dependency.
-    /// When all dependencies of a dependee are `complete`ed, the dependee is ready to be used.
-    pub fn complete(&mut self, node: T) {
-        if self.inverse_dependency.contains_key(&node) {
-            for rev_dep_node in self.inverse_dependency.get_mut(&node).unwrap().drain(0..) {
-                let i = self.rights.iter().position(|e| e == &rev_dep_node).unwrap();
-                self.rights.remove(i);
-
-                if !self.rights.contains(&rev_dep_node) {
-                    self.available.insert(rev_dep_node);
-                }
+    /// Hands the parked nodes back to `available` before an edit of the graph, the dependent limits being counted
+    /// again by the next `pop`.
+    fn unpark(&mut self) {
+        if let Some(mut throttle) = self.throttle.take() {
+            for limit in &mut throttle.limits {
+                self.available.append(&mut limit.parked);
             }
+        }
+        for parked in &mut self.phases.parked {
+            self.available.append(parked);
+        }
+    }
+
+    /// Sets the node aside if it is held back by a reached dependent limit or a later phase, until released.
+    fn park(
+        throttle: &mut Option<Throttle>,
+        phases: &mut Phases<T>,
+        id: &T,
+        index: usize,
+        open_phase: Option<usize>,
+    ) -> bool {
+        throttle
+            .as_mut()
+            .is_some_and(|throttle| throttle.park(index))
+            || open_phase.is_some_and(|open| phases.park(id, index, open))
+    }
 
-            self.inverse_dependency.remove(&node);
+    fn start(&mut self, index: usize) {
+        self.statuses[index] = NodeStatus::Running;
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.start(index);
         }
+    }
 
-        self.unavailable.remove(&node);
+    fn graph_view(&self) -> GraphView<'_, T> {
+        GraphView {
+            ids: &self.ids,
+            indices: &self.indices,
+            dependents: &self.dependents,
+            statuses: &self.statuses,
+        }
     }
 
-    /// Get an available ID to be computed. It picks one random from the available batch.
-    /// Getting a `None` only means that there is no more available in the current batch. Signaling `complete` on the
-    /// actively computed IDs might yield new available items.
-    pub fn pop(&mut self) -> Option<T> {
-        if let Some(popped) = self.available.iter().next().cloned() {
-            self.available.take(&popped)
-        } else {
-            None
+    /// A fresh provider over the same graph with every edge inverted: dependees come before their dependencies. This
+    /// is the teardown order of resources brought up in the normal order. The progress of `self` and a custom scheduler
+    /// are not carried over, the scheduling policy is.
+    ///
+    /// Edges of pruned nodes (see `prune_completed`) are no longer known, so they are missing from the result.
+    pub fn reversed(&self) -> Self {
+        let mut dependents = vec![vec![]; self.ids.len()];
+        let mut pending_dependencies = vec![0; self.ids.len()];
+
+        for (index, original_dependents) in self.dependents.iter().enumerate() {
+            for &original_dependent in original_dependents {
+                dependents[original_dependent].push(index);
+                pending_dependencies[index] += 1;
+            }
         }
+
+        let indices = self
+            .ids
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (id.clone(), index))
+            .collect();
+
+        Self::from_parts(self.ids.clone(), indices, dependents, pending_dependencies)
+            .with_scheduling_policy(self.scheduling_policy)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// A fresh provider where `node` runs a whole nested graph: the roots of `subgraph` wait for the dependencies of
+    /// `node`, and `node` waits for every node of `subgraph`. The nested nodes are handed out by `pop` as any other, so
+    /// the runner executes them on the same pool; `node` itself is executed last and completes the step (its executor
+    /// can be a no-op). A failure in the subgraph skips `node` and its dependees.
+    ///
+    /// The IDs of `subgraph` must not clash with the outer ones. As with `reversed`, the progress and a custom
+    /// scheduler are not carried over, the scheduling policy is.
+    pub fn with_nested(
+        &self,
+        node: T,
+        subgraph: TopologicalBatchProvider<T>,
+    ) -> Result<Self, Error> {
+        let mut nodes = self.dependencies_by_id();
+        let outer_dependencies = nodes
+            .get(&node)
+            .cloned()
+            .ok_or(TopoError::UnknownDependency)?;
 
-    #[test]
-    fn it_detects_cycles() {
-        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+        let sinks = (0..subgraph.ids.len())
+            .filter(|&index| subgraph.dependents[index].is_empty())
+            .map(|index| subgraph.ids[index].clone())
+            .collect::<Vec<_>>();
 
-        nodes.insert(1, vec![3, 4]);
-        nodes.insert(2, vec![1]);
-        nodes.insert(3, vec![2]);
-        nodes.insert(4, vec![]);
+        for (id, mut dependencies) in subgraph.dependencies_by_id() {
+            if dependencies.is_empty() {
+                dependencies.extend(outer_dependencies.iter().cloned());
+            }
+            if nodes.insert(id, dependencies).is_some() {
+                return Err(TopoError::DuplicateNode.into());
+            }
+        }
+        nodes.get_mut(&node).unwrap().extend(sinks);
 
-        assert!(TopologicalBatchProvider::new(nodes).is_err());
+        let capacity = nodes.len();
+        Ok(Self::build(nodes, capacity)?.with_scheduling_policy(self.scheduling_policy))
     }
 
-    #[test]
-    fn it_detects_cycles_not_at_the_beginning() {
-        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+    /// Kahn's algorithm on a scratch copy of the counters: when not every node can be released, some of them are
+    /// waiting on each other.
+    fn has_cycle(dependents: &[Vec<usize>], pending_dependencies: &[usize]) -> bool {
+        let mut pending_dependencies = pending_dependencies.to_vec();
+        let mut stack = (0..pending_dependencies.len())
+            .filter(|&i| pending_dependencies[i] == 0)
+            .collect::<Vec<_>>();
+        let mut released = 0;
 
-        nodes.insert(1, vec![3]);
-        nodes.insert(2, vec![3]);
-        nodes.insert(3, vec![2]);
+        while let Some(i) = stack.pop() {
+            released += 1;
 
-        assert!(TopologicalBatchProvider::new(nodes).is_err());
+            for &dependent in &dependents[i] {
+                pending_dependencies[dependent] -= 1;
+                if pending_dependencies[dependent] == 0 {
+                    stack.push(dependent);
+                }
+            }
+        }
+
+        released != pending_dependencies.len()
     }
 
-    #[test]
-    fn it_provides_batches() {
-        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+    /// Nodes of a cycle of a graph `has_cycle` rejected, a single one when it depends on itself.
+    fn find_cycle(ids: &[T], dependents: &[Vec<usize>]) -> Vec<T> {
+        let cycle = match (0..ids.len()).find(|&index| dependents[index].contains(&index)) {
+            Some(index) => vec![index],
+            None => strongly_connected_components(dependents)
+                .into_iter()
+                .find(|component| component.len() > 1)
+                .unwrap_or_default(),
+        };
 
-        nodes.insert(1, vec![]);
-        nodes.insert(2, vec![1]);
-        nodes.insert(3, vec![1]);
-        nodes.insert(4, vec![]);
-        nodes.insert(5, vec![]);
-        nodes.insert(6, vec![2, 3]);
-        nodes.insert(7, vec![3, 4]);
-        nodes.insert(8, vec![6]);
+        cycle.into_iter().map(|index| ids[index].clone()).collect()
+    }
 
-        let mut topological_batch_provider = TopologicalBatchProvider::new(nodes.clone()).unwrap();
+    /// Empty is a global check over the batch provider, when it has no more ID to provide and all of the retrieved
+    /// IDs were marked as computed.
+    pub fn is_empty(&self) -> bool {
+        self.incomplete_count == 0
+    }
 
-        let expected: Vec<Vec<usize>> = vec![vec![1, 4, 5], vec![2, 3], vec![6, 7], vec![8]];
-        for i in 0..4 {
-            let mut actual = HashSet::new();
-            while let Some(v) = topological_batch_provider.pop() {
-                actual.insert(v);
-            }
+    /// Complete is the signal the resolution of the dependency - all of it's dependees are now free of this dependency.
+    /// When all dependencies of a dependee are `complete`ed, the dependee is ready to be used.
+    ///
+    /// Only running nodes can be completed, anything else is reported as an error without changing anything.
+    pub fn complete(&mut self, node: T) -> Result<(), CompletionError> {
+        let index = self.running_index(&node, NodeStatus::Completed)?;
+        self.complete_index(index, NodeStatus::Completed);
+        Ok(())
+    }
 
-            assert_eq!(
-                HashSet::from_iter(expected.get(i).unwrap().into_iter().cloned()),
-                actual
-            );
-            for v in actual {
-                topological_batch_provider.complete(v);
+    /// Completes every node as with `complete`, for drivers acknowledging nodes in bulk. Returns the nodes which could
+    /// not be completed, with the reason, the others being completed regardless.
+    pub fn complete_many(
+        &mut self,
+        nodes: impl IntoIterator<Item = T>,
+    ) -> Vec<(T, CompletionError)> {
+        nodes
+            .into_iter()
+            .filter_map(|node| {
+                let err = self.complete(node.clone()).err()?;
+                Some((node, err))
+            })
+            .collect()
+    }
+
+    /// Treats the node as done without it being executed, as its work was performed outside of the runner (eg found
+    /// in a remote cache). Its dependees are released as with `complete`, but its status is `Satisfied`, so reports
+    /// can tell it from the executed nodes. Works for pending, available and running nodes alike.
+    pub fn mark_external_complete(&mut self, node: &T) -> Result<(), CompletionError> {
+        let &index = self.indices.get(node).ok_or(CompletionError::UnknownNode)?;
+
+        match self.statuses[index] {
+            status if status.is_finished() => Err(CompletionError::AlreadyFinished(status)),
+            _ => {
+                // A pending node's dependencies are no longer waited for, an available one is left behind in the queue.
+                self.complete_index(index, NodeStatus::Satisfied);
+                Ok(())
             }
         }
+    }
+
+    /// Nodes marked with `mark_external_complete`.
+    pub fn satisfied_externally(&self) -> Vec<T> {
+        self.statuses
+            .iter()
+            .enumerate()
+            .filter(|(_, status)| **status == NodeStatus::Satisfied)
+            .map(|(index, _)| self.ids[index].clone())
+            .collect()
+    }
+
+    /// Completes the node whatever its status is, for restoring persisted state. Finished nodes are left as they are.
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn force_complete(&mut self, node: &T) {
+        if let Some(&index) = self.indices.get(node) {
+            if !self.statuses[index].is_finished() {
+                self.complete_index(index, NodeStatus::Completed);
+            }
+        }
+    }
+
+    fn complete_index(&mut self, index: usize, status: NodeStatus) {
+        self.statuses[index] = status;
+        self.incomplete_count -= 1;
+
+        for &dependent in &self.dependents[index] {
+            self.pending_dependencies[dependent] -= 1;
+            if self.pending_dependencies[dependent] == 0
+                && self.statuses[dependent] == NodeStatus::Pending
+            {
+                self.statuses[dependent] = NodeStatus::Available;
+                self.available.push(dependent);
+            }
+        }
+    }
+
+    /// Fail is the signal that the node could not be computed. None of its dependees can be used anymore, so they
+    /// (and transitively their dependees) are marked as skipped and are never provided. Returns the skipped IDs.
+    ///
+    /// Only running nodes can be failed, anything else is reported as an error without changing anything.
+    pub fn fail(&mut self, node: T) -> Result<Vec<T>, CompletionError> {
+        let index = self.running_index(&node, NodeStatus::Failed)?;
+        Ok(self.fail_index(index))
+    }
+
+    /// Fails the node whatever its status is, see `force_complete`.
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn force_fail(&mut self, node: &T) -> Vec<T> {
+        match self.indices.get(node) {
+            Some(&index) if !self.statuses[index].is_finished() => self.fail_index(index),
+            _ => vec![],
+        }
+    }
+
+    /// Skips an unfinished node which is not running, with its unfinished transitive dependees. Returns their IDs,
+    /// the node first.
+    pub(crate) fn skip(&mut self, node: &T) -> Vec<T> {
+        match self.indices.get(node) {
+            Some(&index)
+                if !self.statuses[index].is_finished()
+                    && self.statuses[index] != NodeStatus::Running =>
+            {
+                self.statuses[index] = NodeStatus::Skipped;
+                self.incomplete_count -= 1;
+                let mut skipped = vec![node.clone()];
+                skipped.extend(self.skip_dependents(index));
+                skipped
+            }
+            _ => vec![],
+        }
+    }
+
+    fn fail_index(&mut self, index: usize) -> Vec<T> {
+        self.statuses[index] = NodeStatus::Failed;
+        self.incomplete_count -= 1;
+        self.skip_dependents(index)
+    }
+
+    /// Skips the unfinished transitive dependees of the node, returning their IDs.
+    fn skip_dependents(&mut self, index: usize) -> Vec<T> {
+        let mut skipped = vec![];
+        let mut stack = self.dependents[index].clone();
+
+        while let Some(dependent) = stack.pop() {
+            if self.statuses[dependent].is_finished() {
+                continue;
+            }
+
+            self.statuses[dependent] = NodeStatus::Skipped;
+            self.incomplete_count -= 1;
+            skipped.push(self.ids[dependent].clone());
+            stack.extend_from_slice(&self.dependents[dependent]);
+        }
+
+        skipped
+    }
+
+    fn running_index(&self, node: &T, to: NodeStatus) -> Result<usize, CompletionError> {
+        let &index = self.indices.get(node).ok_or(CompletionError::UnknownNode)?;
+
+        match self.statuses[index] {
+            NodeStatus::Running => Ok(index),
+            status if status.is_finished() => Err(CompletionError::AlreadyFinished(status)),
+            from => Err(CompletionError::InvalidTransition { from, to }),
+        }
+    }
+
+    /// Adds a node without dependencies, available right away. Can be called between runs, or during a run driven
+    /// manually. Fails with `TopoError::DuplicateNode` if the ID is already known.
+    pub fn add_node(&mut self, node: T) -> Result<(), TopoError> {
+        if self.indices.contains_key(&node) {
+            return Err(TopoError::DuplicateNode);
+        }
+
+        let index = self.ids.len();
+        self.indices.insert(node.clone(), index);
+        self.ids.push(node);
+        self.dependents.push(vec![]);
+        self.pending_dependencies.push(0);
+        self.statuses.push(NodeStatus::Available);
+        self.available.push(index);
+        self.incomplete_count += 1;
+        Ok(())
+    }
+
+    /// Makes `dependent` depend on `dependency`. Only the dependees of `dependent` are searched for a cycle, not the
+    /// whole graph. Adding an existing edge changes nothing.
+    ///
+    /// A dependent waiting to be popped waits for the new dependency too, and is skipped (along with its dependees) if
+    /// the dependency failed or was skipped: the skipped IDs are returned. Popped and finished dependents keep their
+    /// status. Fails with `TopoError::UnknownDependency` if either node is unknown (or pruned).
+    pub fn add_edge(&mut self, dependent: &T, dependency: &T) -> Result<Vec<T>, TopoError> {
+        let (Some(&dependent), Some(&dependency)) =
+            (self.indices.get(dependent), self.indices.get(dependency))
+        else {
+            return Err(TopoError::UnknownDependency);
+        };
+
+        if self.dependents[dependency].contains(&dependent) {
+            return Ok(vec![]);
+        }
+        if dependent == dependency || self.reaches(dependent, dependency) {
+            return Err(TopoError::Cycle);
+        }
+
+        self.unpark();
+        self.dependents[dependency].push(dependent);
+
+        let dependency_status = self.statuses[dependency];
+        if dependency_status.is_completed() {
+            return Ok(vec![]);
+        }
+        self.pending_dependencies[dependent] += 1;
+
+        match self.statuses[dependent] {
+            NodeStatus::Pending | NodeStatus::Available
+                if matches!(dependency_status, NodeStatus::Failed | NodeStatus::Skipped) =>
+            {
+                self.statuses[dependent] = NodeStatus::Skipped;
+                self.incomplete_count -= 1;
+
+                let mut skipped = vec![self.ids[dependent].clone()];
+                skipped.extend(self.skip_dependents(dependent));
+                Ok(skipped)
+            }
+            NodeStatus::Available => {
+                // Pushed again once released, it must not be queued twice.
+                self.available.retain(|&index| index != dependent);
+                self.statuses[dependent] = NodeStatus::Pending;
+                Ok(vec![])
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Removes the node from the graph, and with `RemovalMode::Cascade` everything depending on it. The dependees left
+    /// are not waiting for the removed nodes anymore. Returns the removed IDs, the node first.
+    ///
+    /// Internal indices are compacted, the scheduler being told through `Scheduler::compact`. The built-in ones keep
+    /// their state, as computed for the full graph.
+    pub fn remove_node(&mut self, node: &T, mode: RemovalMode) -> Result<Vec<T>, RemovalError> {
+        let &index = self.indices.get(node).ok_or(RemovalError::UnknownNode)?;
+
+        let mut removed = vec![false; self.ids.len()];
+        let mut order = vec![];
+        let mut stack = vec![index];
+        while let Some(current) = stack.pop() {
+            if removed[current] {
+                continue;
+            }
+            if current != index && mode == RemovalMode::Reject {
+                return Err(RemovalError::HasDependents);
+            }
+            if self.statuses[current] == NodeStatus::Running {
+                return Err(RemovalError::Running);
+            }
+
+            removed[current] = true;
+            order.push(current);
+            stack.extend_from_slice(&self.dependents[current]);
+        }
+
+        let ids = order
+            .into_iter()
+            .map(|current| self.ids[current].clone())
+            .collect();
+        self.compact(&removed);
+        Ok(ids)
+    }
+
+    /// Makes `dependent` independent of `dependency`, releasing it if that was its last pending dependency. Removing a
+    /// missing edge changes nothing. Skipped nodes stay skipped. Fails with `TopoError::UnknownDependency` if either
+    /// node is unknown (or pruned).
+    pub fn remove_edge(&mut self, dependent: &T, dependency: &T) -> Result<(), TopoError> {
+        let (Some(&dependent), Some(&dependency)) =
+            (self.indices.get(dependent), self.indices.get(dependency))
+        else {
+            return Err(TopoError::UnknownDependency);
+        };
+
+        let Some(position) = self.dependents[dependency]
+            .iter()
+            .position(|&other| other == dependent)
+        else {
+            return Ok(());
+        };
+        self.unpark();
+        self.dependents[dependency].swap_remove(position);
+
+        if !self.statuses[dependency].is_completed() {
+            self.release_dependency_of(dependent);
+        }
+
+        Ok(())
+    }
+
+    /// One less pending dependency for the node at `index`, making it available after the last one.
+    fn release_dependency_of(&mut self, index: usize) {
+        self.pending_dependencies[index] -= 1;
+        if self.pending_dependencies[index] == 0 && self.statuses[index] == NodeStatus::Pending {
+            self.statuses[index] = NodeStatus::Available;
+            self.available.push(index);
+        }
+    }
+
+    /// Drops the nodes flagged in `removed`, shifting the indices of the others down. The removed nodes must have no
+    /// dependees left outside of them, so no other node waits for them.
+    fn compact(&mut self, removed: &[bool]) {
+        self.unpark();
+        for index in (0..self.ids.len()).filter(|&i| removed[i]) {
+            if !self.statuses[index].is_finished() {
+                self.incomplete_count -= 1;
+            }
+        }
+
+        let mut remap = vec![usize::MAX; self.ids.len()];
+        let mut next = 0;
+        for (index, slot) in remap.iter_mut().enumerate() {
+            if !removed[index] {
+                *slot = next;
+                next += 1;
+            }
+        }
+
+        let keep = |index: &usize| !removed[*index];
+        retain_unremoved(&mut self.ids, removed);
+        retain_unremoved(&mut self.statuses, removed);
+        retain_unremoved(&mut self.pending_dependencies, removed);
+        retain_unremoved(&mut self.dependents, removed);
+
+        for dependents in &mut self.dependents {
+            dependents.retain(keep);
+            for dependent in dependents.iter_mut() {
+                *dependent = remap[*dependent];
+            }
+        }
+        self.available.retain(keep);
+        for index in &mut self.available {
+            *index = remap[*index];
+        }
+        self.indices.retain(|_, index| keep(index));
+        for index in self.indices.values_mut() {
+            *index = remap[*index];
+        }
+
+        if let Some(scheduler) = self.scheduler.0.as_mut() {
+            scheduler.compact(removed);
+        }
+    }
+
+    /// Whether `to` is a transitive dependee of `from`.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut visited = vec![false; self.ids.len()];
+        let mut stack = vec![from];
+
+        while let Some(current) = stack.pop() {
+            if current == to {
+                return true;
+            }
+            if !visited[current] {
+                visited[current] = true;
+                stack.extend_from_slice(&self.dependents[current]);
+            }
+        }
+
+        false
+    }
+
+    /// Invalidate marks the node and all of its transitive dependees as needing a re-execution, while everything else
+    /// keeps its state. Meant for watch-mode tools: after a change only the affected subgraph is provided again.
+    /// Running nodes are not interrupted, their completion is accepted as is. Returns the invalidated IDs.
+    ///
+    /// Edges of pruned nodes (see `prune_completed`) are no longer known, so invalidation does not go through them.
+    pub fn invalidate(&mut self, node: T) -> Vec<T> {
+        let Some(&index) = self.indices.get(&node) else {
+            return vec![];
+        };
+
+        let mut affected = vec![false; self.ids.len()];
+        let mut stack = vec![index];
+        while let Some(current) = stack.pop() {
+            if !affected[current] {
+                affected[current] = true;
+                stack.extend_from_slice(&self.dependents[current]);
+            }
+        }
+
+        // Affected nodes are reset, so only the unaffected completed dependencies remain resolved.
+        let mut pending_dependencies = vec![0; self.ids.len()];
+        for (dependency, dependents) in self.dependents.iter().enumerate() {
+            let dependency_done = !affected[dependency] && self.statuses[dependency].is_completed();
+            if dependency_done {
+                continue;
+            }
+
+            for &dependent in dependents {
+                if affected[dependent] {
+                    pending_dependencies[dependent] += 1;
+                }
+            }
+        }
+
+        self.phases.rewind();
+        let mut invalidated = vec![];
+        for current in (0..self.ids.len()).filter(|&i| affected[i]) {
+            if self.statuses[current] == NodeStatus::Running {
+                continue;
+            }
+
+            if self.statuses[current].is_finished() {
+                self.incomplete_count += 1;
+            }
+
+            self.pending_dependencies[current] = pending_dependencies[current];
+            if pending_dependencies[current] == 0 {
+                if self.statuses[current] != NodeStatus::Available {
+                    self.available.push(current);
+                }
+                self.statuses[current] = NodeStatus::Available;
+            } else {
+                self.statuses[current] = NodeStatus::Pending;
+            }
+
+            invalidated.push(self.ids[current].clone());
+        }
+
+        invalidated
+    }
+
+    /// Releases the memory held for finished (completed, satisfied, failed or skipped) nodes, so it only grows with
+    /// the unfinished ones. Meant for very large or long-running providers, where most of the graph is already done.
+    /// Pruned nodes are forgotten, completing them again is an `UnknownNode` error. Internal indices are compacted as
+    /// for `remove_node`.
+    ///
+    /// Whatever was derived from the graph before (`levels`, `stats`, the priorities of `with_profile`, the levels
+    /// of a `LevelObserver`) describes the full graph and is not updated. Computed again afterwards, it only sees the
+    /// nodes left: the dependees of pruned nodes then count as roots.
+    pub fn prune_completed(&mut self) {
+        let removed = self
+            .statuses
+            .iter()
+            .map(|status| status.is_finished())
+            .collect::<Vec<_>>();
+        self.compact(&removed);
+
+        self.ids.shrink_to_fit();
+        self.statuses.shrink_to_fit();
+        self.pending_dependencies.shrink_to_fit();
+        self.dependents.shrink_to_fit();
+        self.indices.shrink_to_fit();
+        self.available.shrink_to_fit();
+    }
+
+    /// Get an available ID to be computed. It picks one random from the available batch.
+    /// Getting a `None` only means that there is no more available in the current batch. Signaling `complete` on the
+    /// actively computed IDs might yield new available items.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.throttle.is_none() && !self.dependent_limits.is_empty() {
+            self.throttle = Some(self.build_throttle());
+        }
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.refresh(&self.statuses, &mut self.available);
+        }
+        let open_phase = if self.phases.members.is_empty() {
+            None
+        } else {
+            Some(
+                self.phases
+                    .advance(&self.indices, &self.statuses, &mut self.available),
+            )
+        };
+
+        if let Self {
+            scheduler: SchedulerSlot(Some(scheduler)),
+            ids,
+            indices,
+            dependents,
+            statuses,
+            available,
+            throttle,
+            phases,
+            ..
+        } = self
+        {
+            // Held back nodes are parked until released, the others are offered to the scheduler.
+            available.retain(|&index| {
+                statuses[index] == NodeStatus::Available
+                    && !Self::park(throttle, phases, &ids[index], index, open_phase)
+            });
+            if available.is_empty() {
+                return None;
+            }
+
+            let ready = available
+                .iter()
+                .map(|&index| ReadyNode {
+                    index,
+                    id: &ids[index],
+                })
+                .collect::<Vec<_>>();
+            let graph = GraphView {
+                ids,
+                indices,
+                dependents,
+                statuses,
+            };
+
+            let position = scheduler.select(&ready, &graph);
+            assert!(
+                position < ready.len(),
+                "Scheduler selected a node out of the available ones."
+            );
+
+            let index = available.swap_remove(position);
+            self.start(index);
+            return Some(self.ids[index].clone());
+        }
+
+        while let Some(index) = self.available.pop() {
+            // Nodes completed or failed without being popped are left behind in the queue.
+            if self.statuses[index] == NodeStatus::Available
+                && !Self::park(
+                    &mut self.throttle,
+                    &mut self.phases,
+                    &self.ids[index],
+                    index,
+                    open_phase,
+                )
+            {
+                self.start(index);
+                return Some(self.ids[index].clone());
+            }
+        }
+
+        None
+    }
+
+    /// Pops up to `n` nodes at once, fewer when not as many are available. For drivers submitting nodes in batches.
+    pub fn pop_up_to(&mut self, n: usize) -> Vec<T> {
+        std::iter::from_fn(|| self.pop()).take(n).collect()
+    }
+
+    /// Hands a popped node back: it becomes available again, to be popped later. Returns false if the node is not
+    /// running.
+    pub fn requeue(&mut self, node: &T) -> bool {
+        let Some(&index) = self.indices.get(node) else {
+            return false;
+        };
+
+        if self.statuses[index] != NodeStatus::Running {
+            return false;
+        }
+
+        self.statuses[index] = NodeStatus::Available;
+        self.available.push(index);
+        true
+    }
+
+    /// Same as `pop`, but the node is completed when the guard is dropped, so an early return in a manual driver can't
+    /// leave it running forever (and `is_empty` false). Dropped while unwinding from a panic, the node is failed instead.
+    /// See `NodeGuard` for failing or requeueing it explicitly.
+    pub fn pop_guard(&mut self) -> Option<NodeGuard<'_, T>> {
+        let node = self.pop()?;
+        Some(NodeGuard {
+            provider: self,
+            node: Some(node),
+            requeue_on_drop: false,
+        })
+    }
+
+    /// Pops the given node when it is available, instead of letting the scheduling decide. Returns whether the node
+    /// was taken - in that case it must be completed or failed as if it was popped.
+    pub fn take(&mut self, node: &T) -> bool {
+        let Some(&index) = self.indices.get(node) else {
+            return false;
+        };
+
+        if self.statuses[index] != NodeStatus::Available {
+            return false;
+        }
+
+        // Left behind in the queue, `pop` passes over it.
+        self.start(index);
+        true
+    }
+
+    /// All batches of the graph, computed without touching the provider's state: the first batch is the nodes without
+    /// dependencies, every further batch is the nodes whose dependencies are all in the previous batches. Edges of
+    /// pruned nodes (see `prune_completed`) are no longer known, so their dependees start over in the first batch.
+    pub fn levels(&self) -> Vec<Vec<T>> {
+        self.level_indices()
+            .into_iter()
+            .map(|level| {
+                level
+                    .into_iter()
+                    .map(|index| self.ids[index].clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Current status of a node, `None` for unknown (or pruned) IDs.
+    pub fn status(&self, node: &T) -> Option<NodeStatus> {
+        self.indices.get(node).map(|&index| self.statuses[index])
+    }
+
+    /// Direct dependencies of the node, `None` for unknown (or pruned) IDs. Takes `O(edges)`, as only the inverse
+    /// dependencies are indexed.
+    pub fn dependencies_of(&self, node: &T) -> Option<Vec<T>> {
+        let &index = self.indices.get(node)?;
+        Some(
+            self.dependency_indices(index)
+                .map(|dependency| self.ids[dependency].clone())
+                .collect(),
+        )
+    }
+
+    /// Direct dependees of the node, `None` for unknown (or pruned) IDs.
+    pub fn dependents_of(&self, node: &T) -> Option<Vec<T>> {
+        let &index = self.indices.get(node)?;
+        Some(
+            self.dependents[index]
+                .iter()
+                .map(|&dependent| self.ids[dependent].clone())
+                .collect(),
+        )
+    }
+
+    /// Everything the node needs, directly or not, `None` for unknown (or pruned) IDs.
+    pub fn transitive_dependencies_of(&self, node: &T) -> Option<Vec<T>> {
+        let &index = self.indices.get(node)?;
+
+        let mut dependencies = vec![vec![]; self.ids.len()];
+        for (dependency, dependents) in self.dependents.iter().enumerate() {
+            for &dependent in dependents {
+                dependencies[dependent].push(dependency);
+            }
+        }
+
+        Some(self.reachable(index, &dependencies))
+    }
+
+    /// Everything depending on the node, directly or not: what has to re-run when it changes. `None` for unknown (or
+    /// pruned) IDs.
+    pub fn transitive_dependents_of(&self, node: &T) -> Option<Vec<T>> {
+        let &index = self.indices.get(node)?;
+        Some(self.reachable(index, &self.dependents))
+    }
+
+    /// IDs reachable from `index` over `edges`, excluding itself.
+    fn reachable(&self, index: usize, edges: &[Vec<usize>]) -> Vec<T> {
+        let mut visited = vec![false; self.ids.len()];
+        let mut reached = vec![];
+        let mut stack = edges[index].clone();
+
+        while let Some(current) = stack.pop() {
+            if !visited[current] {
+                visited[current] = true;
+                reached.push(self.ids[current].clone());
+                stack.extend_from_slice(&edges[current]);
+            }
+        }
+
+        reached
+    }
+
+    fn dependency_indices(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+        self.dependents
+            .iter()
+            .enumerate()
+            .filter(move |(_, dependents)| dependents.contains(&index))
+            .map(|(dependency, _)| dependency)
+    }
+
+    /// All IDs, the position being their internal index.
+    pub(crate) fn ids(&self) -> &[T] {
+        &self.ids
+    }
+
+    /// Inverse dependencies of the node at `index`.
+    pub(crate) fn dependents_of_index(&self, index: usize) -> &[usize] {
+        &self.dependents[index]
+    }
+
+    /// Dependencies of every node, by ID.
+    pub(crate) fn dependencies_by_id(&self) -> HashMap<T, Vec<T>> {
+        let mut dependencies: HashMap<T, Vec<T>> =
+            self.ids.iter().map(|id| (id.clone(), vec![])).collect();
+
+        for (index, dependents) in self.dependents.iter().enumerate() {
+            for &dependent in dependents {
+                dependencies
+                    .get_mut(&self.ids[dependent])
+                    .unwrap()
+                    .push(self.ids[index].clone());
+            }
+        }
+
+        dependencies
+    }
+
+    /// Indices grouped into batches: a node is in the batch right after the last batch of its dependencies. Computed
+    /// over the full graph, regardless of the progress.
+    pub(crate) fn level_indices(&self) -> Vec<Vec<usize>> {
+        let mut dependency_counts = vec![0; self.ids.len()];
+        for dependents in &self.dependents {
+            for &dependent in dependents {
+                dependency_counts[dependent] += 1;
+            }
+        }
+
+        let mut levels = vec![];
+        let mut level = (0..self.ids.len())
+            .filter(|&i| dependency_counts[i] == 0)
+            .collect::<Vec<_>>();
+
+        while !level.is_empty() {
+            let mut next_level = vec![];
+            for &index in &level {
+                for &dependent in &self.dependents[index] {
+                    dependency_counts[dependent] -= 1;
+                    if dependency_counts[dependent] == 0 {
+                        next_level.push(dependent);
+                    }
+                }
+            }
+
+            levels.push(level);
+            level = next_level;
+        }
+
+        levels
+    }
+
+    /// Status of the node at `index`.
+    pub(crate) fn status_of_index(&self, index: usize) -> NodeStatus {
+        self.statuses[index]
+    }
+}
+
+/// Keeps the values whose position is not flagged in `removed`.
+fn retain_unremoved<V>(values: &mut Vec<V>, removed: &[bool]) {
+    let mut index = 0;
+    values.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+}
+
+/// A popped node, completed when dropped (failed when dropped by a panic). See `TopologicalBatchProvider::pop_guard`.
+#[derive(Debug)]
+pub struct NodeGuard<'a, T: Hash + PartialEq + Eq + Clone> {
+    provider: &'a mut TopologicalBatchProvider<T>,
+    node: Option<T>,
+    requeue_on_drop: bool,
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> NodeGuard<'_, T> {
+    pub fn id(&self) -> &T {
+        self.node.as_ref().unwrap()
+    }
+
+    /// Requeues the node on drop instead of completing it, for drivers that retry work interrupted by a panic.
+    pub fn requeue_on_drop(mut self) -> Self {
+        self.requeue_on_drop = true;
+        self
+    }
+
+    /// Completes the node now.
+    pub fn complete(mut self) -> Result<(), CompletionError> {
+        let node = self.node.take().unwrap();
+        self.provider.complete(node)
+    }
+
+    /// Fails the node now, see `TopologicalBatchProvider::fail`.
+    pub fn fail(mut self) -> Result<Vec<T>, CompletionError> {
+        let node = self.node.take().unwrap();
+        self.provider.fail(node)
+    }
+
+    /// Gives the node back, see `TopologicalBatchProvider::requeue`.
+    pub fn requeue(mut self) {
+        let node = self.node.take().unwrap();
+        self.provider.requeue(&node);
+    }
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> Drop for NodeGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(node) = self.node.take() {
+            // Errors mean that the node was finished in the meantime (eg via `fail` on the provider), nothing to do.
+            if self.requeue_on_drop {
+                self.provider.requeue(&node);
+            } else if thread::panicking() {
+                let _ = self.provider.fail(node);
+            } else {
+                let _ = self.provider.complete(node);
+            }
+        }
+    }
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> BatchProvider<T> for TopologicalBatchProvider<T> {
+    fn pop(&mut self) -> Result<Option<T>, Error> {
+        Ok(TopologicalBatchProvider::pop(self))
+    }
+
+    fn complete(&mut self, node: T) -> Result<(), Error> {
+        Ok(TopologicalBatchProvider::complete(self, node)?)
+    }
+
+    fn fail(&mut self, node: T) -> Result<Vec<T>, Error> {
+        Ok(TopologicalBatchProvider::fail(self, node)?)
+    }
+
+    fn is_empty(&self) -> bool {
+        TopologicalBatchProvider::is_empty(self)
+    }
+
+    fn unfinished(&self) -> Vec<T> {
+        self.statuses
+            .iter()
+            .enumerate()
+            .filter(|(_, status)| !status.is_finished())
+            .map(|(index, _)| self.ids[index].clone())
+            .collect()
+    }
+
+    fn graph(&self) -> Option<Vec<(T, Vec<T>)>> {
+        let mut dependencies = self.dependencies_by_id();
+        Some(
+            self.levels()
+                .into_iter()
+                .flatten()
+                .map(|node| {
+                    let node_dependencies = dependencies.remove(&node).unwrap_or_default();
+                    (node, node_dependencies)
+                })
+                .collect(),
+        )
+    }
+
+    fn satisfied(&self) -> Vec<T> {
+        self.satisfied_externally()
+    }
+}
+
+impl<T: Hash + PartialEq + Eq + Clone> TryFrom<HashMap<T, Vec<T>>> for TopologicalBatchProvider<T> {
+    type Error = Error;
+
+    fn try_from(nodes: HashMap<T, Vec<T>>) -> Result<Self, Self::Error> {
+        Self::new(nodes)
+    }
+}
+
+/// Fallible collection of `(node, dependencies)` pairs into a provider, as `FromIterator` cannot report errors:
+///
+/// ```ignore
+/// let provider = jobs.iter().map(|job| (job.id, job.deps.clone())).try_collect_provider()?;
+/// ```
+pub trait TryCollectProvider<T, D>: Iterator<Item = (T, D)> + Sized
+where
+    D: IntoIterator<Item = T>,
+{
+    fn try_collect_provider(self) -> Result<TopologicalBatchProvider<T>, Error>;
+}
+
+impl<T, D, I> TryCollectProvider<T, D> for I
+where
+    T: Hash + PartialEq + Eq + Clone,
+    D: IntoIterator<Item = T>,
+    I: Iterator<Item = (T, D)>,
+{
+    fn try_collect_provider(self) -> Result<TopologicalBatchProvider<T>, Error> {
+        TopologicalBatchProvider::try_from_iter(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        panic::{self, AssertUnwindSafe},
+    };
+
+    use super::*;
+
+    #[test]
+    fn it_detects_cycles() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![3, 4]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+        nodes.insert(4, vec![]);
+
+        assert!(TopologicalBatchProvider::new(nodes).is_err());
+    }
+
+    #[test]
+    fn it_detects_cycles_not_at_the_beginning() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![3]);
+        nodes.insert(2, vec![3]);
+        nodes.insert(3, vec![2]);
+
+        assert!(TopologicalBatchProvider::new(nodes).is_err());
+    }
+
+    #[test]
+    fn it_provides_batches() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1]);
+        nodes.insert(4, vec![]);
+        nodes.insert(5, vec![]);
+        nodes.insert(6, vec![2, 3]);
+        nodes.insert(7, vec![3, 4]);
+        nodes.insert(8, vec![6]);
+
+        let mut topological_batch_provider = TopologicalBatchProvider::new(nodes.clone()).unwrap();
+
+        let expected: Vec<Vec<usize>> = vec![vec![1, 4, 5], vec![2, 3], vec![6, 7], vec![8]];
+        for i in 0..4 {
+            let mut actual = HashSet::new();
+            while let Some(v) = topological_batch_provider.pop() {
+                actual.insert(v);
+            }
+
+            assert_eq!(
+                HashSet::from_iter(expected.get(i).unwrap().iter().cloned()),
+                actual
+            );
+            for v in actual {
+                topological_batch_provider.complete(v).unwrap();
+            }
+        }
+
+        assert!(topological_batch_provider.is_empty());
+    }
+
+    #[test]
+    fn it_computes_levels_without_consuming() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1]);
+        nodes.insert(4, vec![]);
+        nodes.insert(5, vec![2, 4]);
+
+        let topological_batch_provider = TopologicalBatchProvider::new(nodes).unwrap();
+
+        let levels = topological_batch_provider
+            .levels()
+            .into_iter()
+            .map(HashSet::<usize>::from_iter)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                HashSet::from([1, 4]),
+                HashSet::from([2, 3]),
+                HashSet::from([5])
+            ],
+            levels
+        );
+        assert_eq!(
+            Some(NodeStatus::Available),
+            topological_batch_provider.status(&1)
+        );
+    }
+
+    #[test]
+    fn it_skips_dependees_of_failed_nodes() {
+        let nodes = vec![(1, vec![]), (2, vec![1]), (3, vec![2]), (4, vec![])];
+
+        let mut topological_batch_provider =
+            TopologicalBatchProvider::with_capacity(nodes, 4).unwrap();
+
+        let mut first_batch = HashSet::new();
+        while let Some(v) = topological_batch_provider.pop() {
+            first_batch.insert(v);
+        }
+        assert_eq!(HashSet::from([1, 4]), first_batch);
+
+        let mut skipped = topological_batch_provider.fail(1).unwrap();
+        skipped.sort();
+        assert_eq!(vec![2, 3], skipped);
+        assert_eq!(
+            Some(NodeStatus::Failed),
+            topological_batch_provider.status(&1)
+        );
+        assert_eq!(
+            Some(NodeStatus::Skipped),
+            topological_batch_provider.status(&3)
+        );
+
+        assert!(!topological_batch_provider.is_empty());
+        topological_batch_provider.complete(4).unwrap();
+        assert_eq!(None, topological_batch_provider.pop());
+        assert!(topological_batch_provider.is_empty());
+    }
+
+    #[test]
+    fn it_invalidates_dependees() {
+        let nodes = vec![(1, vec![]), (2, vec![1]), (3, vec![2]), (4, vec![])];
+
+        let mut topological_batch_provider =
+            TopologicalBatchProvider::with_capacity(nodes, 4).unwrap();
+        while let Some(v) = topological_batch_provider.pop() {
+            topological_batch_provider.complete(v).unwrap();
+        }
+        assert!(topological_batch_provider.is_empty());
+
+        let mut invalidated = topological_batch_provider.invalidate(2);
+        invalidated.sort();
+        assert_eq!(vec![2, 3], invalidated);
+        assert_eq!(
+            Some(NodeStatus::Completed),
+            topological_batch_provider.status(&1)
+        );
+        assert_eq!(
+            Some(NodeStatus::Pending),
+            topological_batch_provider.status(&3)
+        );
+
+        assert_eq!(Some(2), topological_batch_provider.pop());
+        assert_eq!(None, topological_batch_provider.pop());
+        topological_batch_provider.complete(2).unwrap();
+        assert_eq!(Some(3), topological_batch_provider.pop());
+        topological_batch_provider.complete(3).unwrap();
+        assert!(topological_batch_provider.is_empty());
+    }
+
+    #[test]
+    fn it_pops_fan_out_first() {
+        let nodes = vec![
+            (1, vec![]),
+            (2, vec![]),
+            (3, vec![]),
+            (4, vec![2]),
+            (5, vec![2]),
+            (6, vec![4, 5]),
+            (7, vec![3]),
+        ];
+
+        let mut topological_batch_provider = TopologicalBatchProvider::with_capacity(nodes, 7)
+            .unwrap()
+            .with_scheduling_policy(SchedulingPolicy::FanOutFirst);
+
+        assert_eq!(Some(2), topological_batch_provider.pop());
+        assert_eq!(Some(3), topological_batch_provider.pop());
+        assert_eq!(Some(1), topological_batch_provider.pop());
+        assert_eq!(None, topological_batch_provider.pop());
+    }
+
+    #[test]
+    fn it_traverses_depth_or_breadth_first() {
+        let nodes = vec![
+            (0, vec![]),
+            (1, vec![0]),
+            (2, vec![0]),
+            (3, vec![1]),
+            (4, vec![2]),
+        ];
+        let order = |policy| {
+            let mut provider = TopologicalBatchProvider::with_capacity(nodes.clone(), 5)
+                .unwrap()
+                .with_scheduling_policy(policy);
+            let mut order = vec![];
+            while let Some(node) = provider.pop() {
+                provider.complete(node).unwrap();
+                order.push(node);
+            }
+            order
+        };
+
+        let depth_first = order(SchedulingPolicy::DepthFirst);
+        let child = if depth_first[1] == 1 { 3 } else { 4 };
+        assert_eq!(child, depth_first[2]);
+
+        let breadth_first = order(SchedulingPolicy::BreadthFirst);
+        let mut levels = breadth_first[1..3].to_vec();
+        levels.sort();
+        assert_eq!(vec![1, 2], levels);
+        assert_eq!(0, breadth_first[0]);
+    }
+
+    #[test]
+    fn it_pops_the_least_slack_first() {
+        let nodes = vec![
+            (1, vec![]),
+            (2, vec![]),
+            (3, vec![]),
+            (4, vec![3]),
+            (5, vec![]),
+        ];
+        let deadlines = HashMap::from([(4, Duration::from_secs(10)), (5, Duration::from_secs(60))]);
+
+        let mut topological_batch_provider = TopologicalBatchProvider::with_capacity(nodes, 5)
+            .unwrap()
+            .with_deadlines(|id| deadlines.get(id).copied(), |_| Duration::from_secs(1));
+
+        assert_eq!(Some(3), topological_batch_provider.pop());
+        assert_eq!(Some(5), topological_batch_provider.pop());
+        topological_batch_provider.complete(3).unwrap();
+        assert_eq!(Some(4), topological_batch_provider.pop());
+        let mut bulk = vec![
+            topological_batch_provider.pop().unwrap(),
+            topological_batch_provider.pop().unwrap(),
+        ];
+        bulk.sort();
+        assert_eq!(vec![1, 2], bulk);
+    }
+
+    #[test]
+    fn it_pops_proportionally_to_the_weights() {
+        let order = |seed| {
+            let nodes = (0..200).map(|id| (id, vec![])).collect::<Vec<_>>();
+            let mut topological_batch_provider =
+                TopologicalBatchProvider::with_capacity(nodes, 200)
+                    .unwrap()
+                    .with_weights(
+                        |&id| match id {
+                            0..=99 => 9.0,
+                            100..=197 => 1.0,
+                            _ => 0.0,
+                        },
+                        seed,
+                    );
+            std::iter::from_fn(|| topological_batch_provider.pop()).collect::<Vec<_>>()
+        };
+
+        let popped = order(7);
+        assert_eq!(popped, order(7));
+        assert!(popped[..50].iter().filter(|&&id| id < 100).count() > 35);
+        let mut unweighted = popped[198..].to_vec();
+        unweighted.sort();
+        assert_eq!(vec![198, 199], unweighted);
+    }
+
+    #[test]
+    fn it_limits_the_concurrent_dependents() {
+        let nodes = vec![
+            (1, vec![]),
+            (2, vec![1]),
+            (3, vec![1]),
+            (4, vec![1]),
+            (5, vec![]),
+        ];
+
+        for scheduled in [false, true] {
+            let mut topological_batch_provider =
+                TopologicalBatchProvider::with_capacity(nodes.clone(), 5)
+                    .unwrap()
+                    .with_max_concurrent_dependents(1, 2);
+            if scheduled {
+                topological_batch_provider = topological_batch_provider
+                    .with_scheduling_policy(SchedulingPolicy::BreadthFirst);
+            }
+
+            let mut popped = topological_batch_provider.pop_up_to(2);
+            popped.sort();
+            assert_eq!(vec![1, 5], popped);
+            topological_batch_provider.complete(1).unwrap();
+
+            let running = topological_batch_provider.pop_up_to(3);
+            assert_eq!(2, running.len());
+            assert_eq!(None, topological_batch_provider.pop());
+            let snapshot = topological_batch_provider.snapshot();
+
+            topological_batch_provider.complete(running[0]).unwrap();
+            let last = topological_batch_provider.pop().unwrap();
+            assert!(!running.contains(&last));
+            assert_eq!(None, topological_batch_provider.pop());
+
+            topological_batch_provider.restore(snapshot);
+            assert_eq!(None, topological_batch_provider.pop());
+            topological_batch_provider.complete(running[1]).unwrap();
+            assert_eq!(Some(last), topological_batch_provider.pop());
+        }
+    }
+
+    #[test]
+    fn it_pops_the_phases_in_order() {
+        let nodes = vec![
+            (1, vec![]),
+            (2, vec![]),
+            (3, vec![]),
+            (4, vec![3]),
+            (5, vec![]),
+        ];
+        let mut topological_batch_provider = TopologicalBatchProvider::with_capacity(nodes, 5)
+            .unwrap()
+            .with_phases(|id| match id {
+                1 | 2 => Some(10),
+                3 | 4 => Some(20),
+                _ => None,
+            })
+            .unwrap();
+
+        let mut popped = topological_batch_provider.pop_up_to(5);
+        popped.sort();
+        assert_eq!(vec![1, 2, 5], popped);
+
+        topological_batch_provider.complete(1).unwrap();
+        assert_eq!(None, topological_batch_provider.pop());
+        assert_eq!(Ok(vec![]), topological_batch_provider.fail(2));
+        assert_eq!(Some(3), topological_batch_provider.pop());
+
+        topological_batch_provider.invalidate(1);
+        assert_eq!(Some(1), topological_batch_provider.pop());
+        topological_batch_provider.complete(3).unwrap();
+        assert_eq!(None, topological_batch_provider.pop());
+        topological_batch_provider.complete(1).unwrap();
+        assert_eq!(Some(4), topological_batch_provider.pop());
+    }
+
+    #[test]
+    fn it_rejects_dependencies_on_later_phases() {
+        let phases = |id: &usize| match id {
+            1 => Some(2),
+            2 => Some(1),
+            _ => None,
+        };
+
+        let direct = TopologicalBatchProvider::try_from_iter(vec![(1, vec![]), (2, vec![1])])
+            .unwrap()
+            .with_phases(phases);
+        assert_eq!(
+            Some(PhaseError {
+                node: 2,
+                dependency: 1
+            }),
+            direct.err()
+        );
+
+        let through_unphased =
+            TopologicalBatchProvider::try_from_iter(vec![(1, vec![]), (3, vec![1]), (2, vec![3])])
+                .unwrap()
+                .with_phases(phases);
+        assert_eq!(
+            "Node 2 depends on 1 of a later phase.",
+            through_unphased.err().unwrap().to_string()
+        );
+
+        let forward = TopologicalBatchProvider::try_from_iter(vec![(2, vec![]), (1, vec![2])])
+            .unwrap()
+            .with_phases(phases);
+        assert!(forward.is_ok());
+    }
+
+    struct LowestIdFirst;
+
+    impl Scheduler<usize> for LowestIdFirst {
+        fn select(
+            &mut self,
+            ready: &[ReadyNode<'_, usize>],
+            _graph: &GraphView<'_, usize>,
+        ) -> usize {
+            (0..ready.len()).min_by_key(|&i| *ready[i].id).unwrap()
+        }
+
+        fn clone_box(&self) -> Box<dyn Scheduler<usize> + Send> {
+            Box::new(LowestIdFirst)
+        }
+    }
+
+    #[test]
+    fn it_delegates_pop_to_the_scheduler() {
+        let nodes = (1..=5)
+            .map(|i| (i, vec![]))
+            .collect::<HashMap<usize, Vec<usize>>>();
+
+        let mut topological_batch_provider = TopologicalBatchProvider::new(nodes)
+            .unwrap()
+            .with_scheduler(LowestIdFirst);
+
+        for expected in 1..=5 {
+            assert_eq!(Some(expected), topological_batch_provider.pop());
+        }
+        assert_eq!(None, topological_batch_provider.pop());
+    }
+
+    #[test]
+    fn it_provides_reversed_order() {
+        let nodes = vec![(1, vec![]), (2, vec![1]), (3, vec![1]), (4, vec![2, 3])];
+
+        let mut topological_batch_provider =
+            TopologicalBatchProvider::with_capacity(nodes, 4).unwrap();
+        assert_eq!(Some(1), topological_batch_provider.pop());
+
+        let mut reversed = topological_batch_provider.reversed();
+
+        assert_eq!(Some(4), reversed.pop());
+        assert_eq!(None, reversed.pop());
+        reversed.complete(4).unwrap();
+
+        let mut middle = HashSet::new();
+        while let Some(v) = reversed.pop() {
+            middle.insert(v);
+        }
+        assert_eq!(HashSet::from([2, 3]), middle);
+        for v in middle {
+            reversed.complete(v).unwrap();
+        }
+
+        assert_eq!(Some(1), reversed.pop());
+        reversed.complete(1).unwrap();
+        assert!(reversed.is_empty());
+    }
+
+    #[test]
+    fn it_can_be_built_with_capacity() {
+        let nodes = vec![(1, vec![]), (2, vec![1]), (3, vec![1, 2])];
+
+        let mut topological_batch_provider =
+            TopologicalBatchProvider::with_capacity(nodes, 3).unwrap();
+
+        for expected in [1, 2, 3] {
+            assert_eq!(Some(expected), topological_batch_provider.pop());
+            assert_eq!(None, topological_batch_provider.pop());
+            topological_batch_provider.complete(expected).unwrap();
+        }
+
+        assert!(topological_batch_provider.is_empty());
+    }
+
+    #[test]
+    fn it_can_be_built_from_any_iterable() {
+        let topological_batch_provider = TopologicalBatchProvider::try_from_iter([
+            ("b", HashSet::from(["a"])),
+            ("a", HashSet::new()),
+        ]);
+        assert!(topological_batch_provider.is_ok());
+
+        let topological_batch_provider = [(1, [2]), (2, [1])].into_iter().try_collect_provider();
+        assert!(topological_batch_provider.is_err());
+
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+        nodes.insert(1, vec![]);
+        assert!(TopologicalBatchProvider::try_from(nodes).is_ok());
+    }
+
+    #[cfg(feature = "derive")]
+    #[derive(crate::TopoNode)]
+    struct Task<'a> {
+        #[topo(id)]
+        name: &'a str,
+        #[topo(deps)]
+        after: HashSet<&'a str>,
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn it_can_be_built_from_derived_nodes() {
+        let tasks = vec![
+            Task {
+                name: "test",
+                after: HashSet::from(["build"]),
+            },
+            Task {
+                name: "build",
+                after: HashSet::new(),
+            },
+        ];
+
+        let mut topological_batch_provider = TopologicalBatchProvider::from_nodes(&tasks).unwrap();
+
+        assert_eq!(Some("build"), topological_batch_provider.pop());
+        topological_batch_provider.complete("build").unwrap();
+        assert_eq!(Some("test"), topological_batch_provider.pop());
+    }
+
+    #[test]
+    fn it_runs_nested_graphs_before_their_node() {
+        let outer = vec![
+            ("prepare", vec![]),
+            ("deploy", vec!["prepare"]),
+            ("notify", vec!["deploy"]),
+        ];
+        let nested = vec![
+            ("upload", vec![]),
+            ("migrate", vec!["upload"]),
+            ("restart", vec![]),
+        ];
+
+        let mut topological_batch_provider = TopologicalBatchProvider::try_from_iter(outer)
+            .unwrap()
+            .with_nested(
+                "deploy",
+                TopologicalBatchProvider::try_from_iter(nested.clone()).unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(Some("prepare"), topological_batch_provider.pop());
+        assert_eq!(None, topological_batch_provider.pop());
+        topological_batch_provider.complete("prepare").unwrap();
+
+        let mut order = vec![];
+        while let Some(node) = topological_batch_provider.pop() {
+            order.push(node);
+            topological_batch_provider.complete(node).unwrap();
+        }
+
+        assert_eq!(5, order.len());
+        assert_eq!(&["deploy", "notify"], &order[3..]);
+        assert!(
+            order.iter().position(|&node| node == "upload")
+                < order.iter().position(|&node| node == "migrate")
+        );
+
+        let provider = TopologicalBatchProvider::try_from_iter(nested).unwrap();
+        assert!(provider.with_nested("upload", provider.reversed()).is_err());
+    }
+
+    #[test]
+    fn it_completes_guarded_nodes_on_drop() {
+        let nodes = vec![(1, vec![]), (2, vec![1]), (3, vec![2])];
+        let mut topological_batch_provider =
+            TopologicalBatchProvider::try_from_iter(nodes).unwrap();
+
+        {
+            let guard = topological_batch_provider.pop_guard().unwrap();
+            assert_eq!(&1, guard.id());
+        }
+        assert_eq!(
+            Some(NodeStatus::Completed),
+            topological_batch_provider.status(&1)
+        );
+
+        topological_batch_provider
+            .pop_guard()
+            .unwrap()
+            .requeue_on_drop();
+        assert_eq!(
+            Some(NodeStatus::Available),
+            topological_batch_provider.status(&2)
+        );
+
+        let guard = topological_batch_provider.pop_guard().unwrap();
+        assert_eq!(&2, guard.id());
+        assert_eq!(vec![3], guard.fail().unwrap());
+        assert!(topological_batch_provider.is_empty());
+        assert!(!topological_batch_provider.requeue(&3));
+    }
+
+    #[test]
+    fn it_fails_guarded_nodes_dropped_by_a_panic() {
+        let nodes = vec![(1, vec![]), (2, vec![1])];
+        let mut topological_batch_provider =
+            TopologicalBatchProvider::try_from_iter(nodes).unwrap();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = topological_batch_provider.pop_guard().unwrap();
+            panic!("driver crashed");
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(
+            Some(NodeStatus::Failed),
+            topological_batch_provider.status(&1)
+        );
+        assert_eq!(
+            Some(NodeStatus::Skipped),
+            topological_batch_provider.status(&2)
+        );
+    }
+
+    #[test]
+    fn it_rejects_duplicate_nodes() {
+        let nodes = vec![(1, vec![]), (1, vec![])];
+
+        assert!(TopologicalBatchProvider::with_capacity(nodes, 2).is_err());
+    }
+
+    #[test]
+    fn it_keeps_working_after_pruning() {
+        let nodes = vec![(1, vec![]), (2, vec![1]), (3, vec![2])];
+
+        let mut topological_batch_provider =
+            TopologicalBatchProvider::with_capacity(nodes, 3).unwrap();
+
+        assert_eq!(Some(1), topological_batch_provider.pop());
+        topological_batch_provider.complete(1).unwrap();
+        assert_eq!(Some(2), topological_batch_provider.pop());
+        topological_batch_provider.complete(2).unwrap();
+
+        topological_batch_provider.prune_completed();
+        assert_eq!(vec![vec![3]], topological_batch_provider.levels());
+        assert_eq!(
+            Err(CompletionError::UnknownNode),
+            topological_batch_provider.complete(1)
+        );
+        assert_eq!(None, topological_batch_provider.status(&1));
+
+        assert_eq!(Some(3), topological_batch_provider.pop());
+        topological_batch_provider.complete(3).unwrap();
+        assert!(topological_batch_provider.is_empty());
+    }
+
+    #[test]
+    fn it_only_retains_unfinished_nodes_after_pruning() {
+        let nodes = (0..100)
+            .map(|node| (node, if node < 50 { vec![] } else { vec![node - 50] }))
+            .collect::<Vec<_>>();
+        let mut topological_batch_provider =
+            TopologicalBatchProvider::try_from_iter(nodes).unwrap();
+
+        for _ in 0..40 {
+            let node = topological_batch_provider.pop().unwrap();
+            topological_batch_provider.complete(node).unwrap();
+        }
+        let failed = topological_batch_provider.pop().unwrap();
+        assert_eq!(1, topological_batch_provider.fail(failed).unwrap().len());
+        let running = topological_batch_provider.pop().unwrap();
+
+        topological_batch_provider.prune_completed();
+        assert_eq!(58, topological_batch_provider.ids.len());
+        assert_eq!(58, topological_batch_provider.statuses.len());
+        assert_eq!(58, topological_batch_provider.pending_dependencies.len());
+        assert_eq!(58, topological_batch_provider.dependents.len());
+        assert_eq!(58, topological_batch_provider.indices.len());
+
+        topological_batch_provider.complete(running).unwrap();
+        let mut executed = 0;
+        while let Some(node) = topological_batch_provider.pop() {
+            topological_batch_provider.complete(node).unwrap();
+            executed += 1;
+        }
+        assert_eq!(57, executed);
+        assert!(topological_batch_provider.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_unknown_dependencies() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1, 3]);
+
+        assert!(TopologicalBatchProvider::new(nodes).is_err());
+    }
+
+    #[test]
+    fn it_works_with_string_ids() {
+        let mut nodes: HashMap<String, Vec<String>> = HashMap::new();
+
+        nodes.insert("build".to_string(), vec!["fetch".to_string()]);
+        nodes.insert("fetch".to_string(), vec![]);
+
+        let mut topological_batch_provider = TopologicalBatchProvider::new(nodes).unwrap();
+
+        assert_eq!(Some("fetch".to_string()), topological_batch_provider.pop());
+        assert_eq!(None, topological_batch_provider.pop());
+        assert_eq!(
+            Some(NodeStatus::Running),
+            topological_batch_provider.status(&"fetch".to_string())
+        );
+        assert_eq!(
+            Some(NodeStatus::Pending),
+            topological_batch_provider.status(&"build".to_string())
+        );
+
+        topological_batch_provider
+            .complete("fetch".to_string())
+            .unwrap();
+        assert_eq!(Some("build".to_string()), topological_batch_provider.pop());
+
+        topological_batch_provider
+            .complete("build".to_string())
+            .unwrap();
+        assert!(topological_batch_provider.is_empty());
+    }
+
+    #[test]
+    fn it_prioritizes_by_profile() {
+        let mut nodes: HashMap<&str, Vec<&str>> = HashMap::new();
+
+        nodes.insert("quick", vec![]);
+        nodes.insert("slow", vec![]);
+        nodes.insert("after_quick", vec!["quick"]);
+
+        let mut profile = Profile::new();
+        profile.record(&"slow", Duration::from_secs(2));
+        profile.record(&"after_quick", Duration::from_secs(3));
+
+        let mut provider = TopologicalBatchProvider::new(nodes)
+            .unwrap()
+            .with_profile(&profile, Duration::from_secs(1));
+
+        assert_eq!(Some("quick"), provider.pop());
+        assert_eq!(Some("slow"), provider.pop());
+    }
+
+    #[test]
+    fn it_answers_dependency_queries() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1, 2]);
+        nodes.insert(4, vec![3]);
+        nodes.insert(5, vec![]);
+
+        let provider = TopologicalBatchProvider::new(nodes).unwrap();
+        let sorted = |nodes: Option<Vec<usize>>| {
+            let mut nodes = nodes.unwrap();
+            nodes.sort();
+            nodes
+        };
+
+        assert_eq!(vec![1, 2], sorted(provider.dependencies_of(&3)));
+        assert_eq!(vec![2, 3], sorted(provider.dependents_of(&1)));
+        assert_eq!(
+            vec![1, 2, 3],
+            sorted(provider.transitive_dependencies_of(&4))
+        );
+        assert_eq!(vec![2, 3, 4], sorted(provider.transitive_dependents_of(&1)));
+        assert_eq!(Some(vec![]), provider.transitive_dependents_of(&5));
+        assert_eq!(None, provider.dependencies_of(&6));
+    }
+
+    #[test]
+    fn it_adds_nodes_and_edges() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+
+        let mut provider = TopologicalBatchProvider::new(nodes).unwrap();
+
+        assert_eq!(Err(TopoError::DuplicateNode), provider.add_node(2));
+        provider.add_node(3).unwrap();
+        provider.add_node(4).unwrap();
+        assert_eq!(Ok(vec![]), provider.add_edge(&3, &2));
+        assert_eq!(Ok(vec![]), provider.add_edge(&3, &2));
+        assert_eq!(Err(TopoError::Cycle), provider.add_edge(&1, &3));
+        assert_eq!(Err(TopoError::Cycle), provider.add_edge(&4, &4));
+        assert_eq!(Err(TopoError::UnknownDependency), provider.add_edge(&5, &1));
+        assert_eq!(Some(NodeStatus::Pending), provider.status(&3));
+
+        let mut popped = vec![provider.pop().unwrap(), provider.pop().unwrap()];
+        popped.sort();
+        assert_eq!(vec![1, 4], popped);
+        provider.fail(1).unwrap();
+
+        provider.add_node(5).unwrap();
+        assert_eq!(Ok(vec![5]), provider.add_edge(&5, &2));
+        provider.complete(4).unwrap();
+        assert_eq!(None, provider.pop());
+        assert!(provider.is_empty());
+    }
+
+    #[test]
+    fn it_removes_nodes_and_edges() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+        nodes.insert(4, vec![1]);
+        nodes.insert(5, vec![4]);
+
+        let mut provider = TopologicalBatchProvider::new(nodes).unwrap();
+
+        assert_eq!(
+            Err(RemovalError::HasDependents),
+            provider.remove_node(&2, RemovalMode::Reject)
+        );
+        assert_eq!(
+            Err(RemovalError::UnknownNode),
+            provider.remove_node(&6, RemovalMode::Reject)
+        );
+        assert_eq!(
+            Ok(vec![2, 3]),
+            provider.remove_node(&2, RemovalMode::Cascade)
+        );
+
+        provider.remove_edge(&4, &1).unwrap();
+        assert_eq!(Ok(()), provider.remove_edge(&4, &1));
+        assert_eq!(Some(NodeStatus::Available), provider.status(&4));
+        assert_eq!(vec![vec![1, 4], vec![5]], {
+            let mut levels = provider.levels();
+            levels[0].sort();
+            levels
+        });
+
+        let mut popped = vec![provider.pop().unwrap(), provider.pop().unwrap()];
+        popped.sort();
+        assert_eq!(vec![1, 4], popped);
+        assert_eq!(
+            Err(RemovalError::Running),
+            provider.remove_node(&1, RemovalMode::Cascade)
+        );
+
+        provider.complete(4).unwrap();
+        assert_eq!(Ok(vec![5]), provider.remove_node(&5, RemovalMode::Reject));
+        provider.complete(1).unwrap();
+        assert!(provider.is_empty());
+        assert_eq!(None, provider.status(&3));
+    }
+
+    #[test]
+    fn it_keeps_scheduler_state_across_removals() {
+        let nodes = vec![(1, vec![]), (2, vec![]), (3, vec![]), (4, vec![])];
+
+        let mut provider = TopologicalBatchProvider::try_from_iter(nodes)
+            .unwrap()
+            .with_scheduler(PriorityScheduler::new(vec![0, 5, 1, 3]));
+        let snapshot = provider.snapshot();
+
+        provider.remove_node(&1, RemovalMode::Reject).unwrap();
+        assert_eq!(Some(2), provider.pop());
+        assert_eq!(Some(4), provider.pop());
+        assert_eq!(Some(3), provider.pop());
+
+        provider.restore(snapshot);
+        assert_eq!(Some(2), provider.pop());
+        assert_eq!(Some(4), provider.pop());
+        assert_eq!(Some(3), provider.pop());
+        assert_eq!(Some(1), provider.pop());
+    }
+
+    #[test]
+    fn it_rolls_back_to_snapshots() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1]);
+
+        let mut provider = TopologicalBatchProvider::new(nodes)
+            .unwrap()
+            .with_scheduling_policy(SchedulingPolicy::FanOutFirst);
+        assert_eq!(Some(1), provider.pop());
+
+        let snapshot = provider.snapshot();
+        let mut clone = provider.clone();
+
+        assert_eq!(vec![2, 3], {
+            let mut skipped = provider.fail(1).unwrap();
+            skipped.sort();
+            skipped
+        });
+        provider.add_node(4).unwrap();
+        assert_eq!(None, clone.status(&4));
+
+        provider.restore(snapshot);
+        assert_eq!(None, provider.status(&4));
+        provider.complete(1).unwrap();
+        clone.complete(1).unwrap();
+        for provider in [&mut provider, &mut clone] {
+            let mut popped = vec![provider.pop().unwrap(), provider.pop().unwrap()];
+            popped.sort();
+            assert_eq!(vec![2, 3], popped);
+        }
+    }
+
+    #[test]
+    fn it_names_the_nodes_of_graph_errors() {
+        let error = |nodes: Vec<(&'static str, Vec<&'static str>)>| {
+            TopologicalBatchProvider::new_with_diagnostics(nodes)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            "Unknown dependency lib of app.",
+            error(vec![("app", vec!["lib"])])
+        );
+        assert_eq!(
+            "Duplicate node app.",
+            error(vec![("app", vec![]), ("app", vec![])])
+        );
+        assert_eq!("Cycle detected between: a.", error(vec![("a", vec!["a"])]));
+
+        let cycle = TopologicalBatchProvider::new_with_diagnostics(vec![
+            ("a", vec!["b"]),
+            ("b", vec!["a"]),
+            ("c", vec![]),
+        ])
+        .unwrap_err();
+        assert_eq!(TopoError::Cycle, cycle.kind());
+        let GraphError::Cycle(mut nodes) = cycle else {
+            unreachable!()
+        };
+        nodes.sort();
+        assert_eq!(vec!["a", "b"], nodes);
+
+        assert!(TopologicalBatchProvider::new_with_diagnostics(vec![(1, vec![])]).is_ok());
+    }
+
+    #[test]
+    fn it_summarizes_reports() {
+        let mut report = RunReport::new();
+        report.completed.push("a");
+        report.failed.push(("b", "broken".into()));
+        report.skipped.extend(["c", "d"]);
+
+        assert_eq!(
+            "1 completed, 1 failed, 2 skipped, 0 cached, 0 satisfied, 0 cancelled.\nFailed b: broken\nSkipped: c d",
+            report.to_string()
+        );
+    }
+
+    #[test]
+    fn it_pops_and_completes_in_bulk() {
+        let mut topological_batch_provider = TopologicalBatchProvider::new(HashMap::from([
+            (1, vec![]),
+            (2, vec![]),
+            (3, vec![]),
+            (4, vec![1, 2, 3]),
+        ]))
+        .unwrap();
+
+        let mut batch = topological_batch_provider.pop_up_to(2);
+        assert_eq!(2, batch.len());
+        batch.extend(topological_batch_provider.pop_up_to(5));
+        batch.sort();
+        assert_eq!(vec![1, 2, 3], batch);
+        assert!(topological_batch_provider.pop_up_to(5).is_empty());
+
+        assert_eq!(
+            vec![
+                (1, CompletionError::AlreadyFinished(NodeStatus::Completed)),
+                (9, CompletionError::UnknownNode)
+            ],
+            topological_batch_provider.complete_many([1, 2, 1, 9, 3])
+        );
+        assert_eq!(vec![4], topological_batch_provider.pop_up_to(5));
+    }
+
+    #[test]
+    fn it_marks_nodes_complete_externally() {
+        let mut topological_batch_provider = TopologicalBatchProvider::new(HashMap::from([
+            (1, vec![]),
+            (2, vec![1]),
+            (3, vec![2]),
+            (4, vec![]),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            Ok(()),
+            topological_batch_provider.mark_external_complete(&2)
+        );
+        assert_eq!(
+            Ok(()),
+            topological_batch_provider.mark_external_complete(&4)
+        );
+        assert_eq!(
+            Err(CompletionError::AlreadyFinished(NodeStatus::Satisfied)),
+            topological_batch_provider.mark_external_complete(&4)
+        );
+        assert_eq!(
+            Err(CompletionError::UnknownNode),
+            topological_batch_provider.mark_external_complete(&7)
+        );
+
+        let mut popped = vec![];
+        while let Some(node) = topological_batch_provider.pop() {
+            popped.push(node);
+            topological_batch_provider.complete(node).unwrap();
+        }
+        popped.sort();
+
+        assert_eq!(vec![1, 3], popped);
+        assert!(topological_batch_provider.is_empty());
+        let mut satisfied = topological_batch_provider.satisfied_externally();
+        satisfied.sort();
+        assert_eq!(vec![2, 4], satisfied);
+        assert_eq!(
+            Some(NodeStatus::Completed),
+            topological_batch_provider.status(&3)
+        );
+    }
+
+    #[test]
+    fn it_reports_duplicate_acknowledgements() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+
+        let mut topological_batch_provider = TopologicalBatchProvider::new(nodes).unwrap();
+
+        assert_eq!(Some(1), topological_batch_provider.pop());
+        assert_eq!(Ok(()), topological_batch_provider.complete(1));
+        assert_eq!(
+            Err(CompletionError::AlreadyFinished(NodeStatus::Completed)),
+            topological_batch_provider.complete(1)
+        );
+        assert_eq!(
+            Err(CompletionError::AlreadyFinished(NodeStatus::Completed)),
+            topological_batch_provider.fail(1)
+        );
+        assert_eq!(
+            Err(CompletionError::UnknownNode),
+            topological_batch_provider.complete(7)
+        );
+
+        assert_eq!(Some(2), topological_batch_provider.pop());
+        assert_eq!(Ok(vec![]), topological_batch_provider.fail(2));
+        assert_eq!(
+            Err(CompletionError::AlreadyFinished(NodeStatus::Failed)),
+            topological_batch_provider.complete(2)
+        );
+        assert!(topological_batch_provider.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_completing_unpopped_nodes() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+
+        let mut topological_batch_provider = TopologicalBatchProvider::new(nodes).unwrap();
+
+        assert_eq!(
+            Err(CompletionError::InvalidTransition {
+                from: NodeStatus::Available,
+                to: NodeStatus::Completed
+            }),
+            topological_batch_provider.complete(1)
+        );
+        assert_eq!(
+            Err(CompletionError::InvalidTransition {
+                from: NodeStatus::Pending,
+                to: NodeStatus::Failed
+            }),
+            topological_batch_provider.fail(2)
+        );
+        assert_eq!(
+            Some(NodeStatus::Available),
+            topological_batch_provider.status(&1)
+        );
 
+        assert_eq!(Some(1), topological_batch_provider.pop());
+        assert_eq!(Ok(()), topological_batch_provider.complete(1));
+        assert_eq!(Some(2), topological_batch_provider.pop());
+        assert_eq!(Ok(()), topological_batch_provider.complete(2));
         assert!(topological_batch_provider.is_empty());
     }
 }
@@ -0,0 +1,250 @@
+//! Live terminal dashboard of a run: the current node of every worker, overall progress, recent failures and the
+//! frontier of ready nodes. Available with the `tui` feature.
+//!
+//! The dashboard is an observer: pass a clone of it to `RunOptions::with_observer`, and draw it from another thread
+//! with `Dashboard::run` (or `Dashboard::render` inside an existing ratatui application).
+
+use super::common::*;
+use super::observer::*;
+use super::topological_batch_provider::*;
+use ratatui::{
+    backend::Backend,
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    widgets::{Block, Gauge, List},
+    Frame, Terminal,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+    hash::Hash,
+    io,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// Number of failures kept for display, the oldest ones are dropped first.
+pub const RECENT_FAILURES: usize = 8;
+
+/// Shared state of the run, cloning gives another handle to the same dashboard.
+pub struct Dashboard<T> {
+    state: Arc<Mutex<DashboardState<T>>>,
+}
+
+struct DashboardState<T> {
+    dependencies: HashMap<T, Vec<T>>,
+    /// Current node of each worker, by worker index.
+    workers: Vec<Option<T>>,
+    started: HashSet<T>,
+    /// Completed or cached, so no longer holding back its dependents.
+    completed: HashSet<T>,
+    failed: usize,
+    skipped: usize,
+    recent_failures: VecDeque<(T, String)>,
+    run_finished: bool,
+}
+
+impl<T> Clone for Dashboard<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone + Display> Dashboard<T> {
+    /// `provider` is only read for the dependencies, create the dashboard before running it.
+    pub fn new(provider: &TopologicalBatchProvider<T>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(DashboardState {
+                dependencies: provider.dependencies_by_id(),
+                workers: vec![],
+                started: HashSet::new(),
+                completed: HashSet::new(),
+                failed: 0,
+                skipped: 0,
+                recent_failures: VecDeque::new(),
+                run_finished: false,
+            })),
+        }
+    }
+
+    /// Whether the observed run reported its end.
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().run_finished
+    }
+
+    /// Draws the dashboard over the whole frame.
+    pub fn render(&self, frame: &mut Frame) {
+        let state = self.state.lock().unwrap();
+
+        let total = state.dependencies.len();
+        let finished = state.completed.len() + state.failed + state.skipped;
+        let ratio = match total {
+            0 => 1.0,
+            total => finished as f64 / total as f64,
+        };
+
+        let [progress_area, main_area] =
+            Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(frame.area());
+        let [workers_area, side_area] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(main_area);
+        let [failures_area, frontier_area] =
+            Layout::vertical([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(side_area);
+
+        let progress = Gauge::default()
+            .block(Block::bordered().title("Progress"))
+            .gauge_style(Style::default().fg(Color::Green))
+            .ratio(ratio)
+            .label(format!(
+                "{}/{} ({} failed, {} skipped)",
+                finished, total, state.failed, state.skipped
+            ));
+        frame.render_widget(progress, progress_area);
+
+        let workers = List::new(state.workers.iter().enumerate().map(
+            |(worker, node)| match node {
+                Some(node) => format!("#{}: {}", worker, node),
+                None => format!("#{}: idle", worker),
+            },
+        ))
+        .block(Block::bordered().title("Workers"));
+        frame.render_widget(workers, workers_area);
+
+        let failures = List::new(
+            state
+                .recent_failures
+                .iter()
+                .rev()
+                .map(|(node, error)| format!("{}: {}", node, error)),
+        )
+        .style(Style::default().fg(Color::Red))
+        .block(Block::bordered().title("Recent failures"));
+        frame.render_widget(failures, failures_area);
+
+        let frontier = List::new(state.frontier()).block(Block::bordered().title("Ready"));
+        frame.render_widget(frontier, frontier_area);
+    }
+
+    /// Redraws the dashboard every `refresh` until the run finishes, drawing the final state once more at the end.
+    pub fn run<B: Backend>(&self, terminal: &mut Terminal<B>, refresh: Duration) -> io::Result<()> {
+        loop {
+            let finished = self.is_finished();
+            terminal.draw(|frame| self.render(frame))?;
+            if finished {
+                return Ok(());
+            }
+            thread::sleep(refresh);
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone + Display> DashboardState<T> {
+    /// Nodes not started yet with all of their dependencies completed, sorted by their display form.
+    fn frontier(&self) -> Vec<String> {
+        let mut frontier = self
+            .dependencies
+            .iter()
+            .filter(|(node, dependencies)| {
+                !self.started.contains(*node)
+                    && dependencies
+                        .iter()
+                        .all(|dependency| self.completed.contains(dependency))
+            })
+            .map(|(node, _)| node.to_string())
+            .collect::<Vec<_>>();
+        frontier.sort();
+        frontier
+    }
+}
+
+impl<T: Hash + Eq + Clone> RunObserver<T> for Dashboard<T> {
+    fn on_node_start(&self, worker: usize, id: &T) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.workers.len() <= worker {
+            state.workers.resize(worker + 1, None);
+        }
+        state.workers[worker] = Some(id.clone());
+        state.started.insert(id.clone());
+    }
+
+    fn on_node_finish(&self, worker: usize, id: &T, outcome: NodeOutcome<'_>, _duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(current) = state.workers.get_mut(worker) {
+            *current = None;
+        }
+        state.started.insert(id.clone());
+
+        match outcome {
+            NodeOutcome::Completed | NodeOutcome::Cached => {
+                state.completed.insert(id.clone());
+            }
+            NodeOutcome::Failed(error) => {
+                state.failed += 1;
+                if state.recent_failures.len() == RECENT_FAILURES {
+                    state.recent_failures.pop_front();
+                }
+                state
+                    .recent_failures
+                    .push_back((id.clone(), error.to_string()));
+            }
+        }
+    }
+
+    fn on_node_skipped(&self, id: &T) {
+        let mut state = self.state.lock().unwrap();
+
+        state.skipped += 1;
+        state.started.insert(id.clone());
+    }
+
+    fn on_run_finish(&self, _report: &RunReport<T>) {
+        self.state.lock().unwrap().run_finished = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::thread_pool_runner::*;
+    use ratatui::backend::TestBackend;
+
+    #[test]
+    fn it_draws_the_run() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![2]);
+        nodes.insert(4, vec![]);
+
+        let provider = TopologicalBatchProvider::new(nodes).unwrap();
+        let dashboard = Dashboard::new(&provider);
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 16)).unwrap();
+        terminal.draw(|frame| dashboard.render(frame)).unwrap();
+        let screen = terminal.backend().to_string();
+        assert!(screen.contains("0/4 (0 failed, 0 skipped)"));
+
+        let runner = ThreadPoolRunner::new(2);
+        runner.run_with_options(
+            provider,
+            Arc::new(FailingExecutor(2)),
+            RunOptions::new().with_observer(Arc::new(dashboard.clone())),
+        );
+
+        dashboard
+            .run(&mut terminal, Duration::from_millis(1))
+            .unwrap();
+        let screen = terminal.backend().to_string();
+        assert!(dashboard.is_finished());
+        assert!(screen.contains("4/4 (1 failed, 1 skipped)"));
+        assert!(screen.contains("2: broken"));
+    }
+}
@@ -0,0 +1,199 @@
+//! Validation of a raw dependency map, collecting every problem at once instead of stopping at the first one like
+//! the provider's constructor does.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+/// All problems found in a dependency map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationReport<T> {
+    /// Groups of nodes depending on each other (strongly connected components with more than one node).
+    pub cycles: Vec<Vec<T>>,
+    /// `(dependee, dependency)` pairs where the dependency is not declared as a node.
+    pub dangling_dependencies: Vec<(T, T)>,
+    /// Nodes listing themselves as dependency.
+    pub self_dependencies: Vec<T>,
+    /// `(dependee, dependency)` pairs declared more than once.
+    pub duplicate_dependencies: Vec<(T, T)>,
+}
+
+impl<T> ValidationReport<T> {
+    /// True when no problem was found - the map can be turned into a provider.
+    pub fn is_valid(&self) -> bool {
+        self.cycles.is_empty()
+            && self.dangling_dependencies.is_empty()
+            && self.self_dependencies.is_empty()
+            && self.duplicate_dependencies.is_empty()
+    }
+}
+
+/// Checks the dependency map (same shape as `TopologicalBatchProvider::new` expects) and reports all of its
+/// problems. Cycles are found with Tarjan's strongly connected components algorithm.
+pub fn validate<T: Hash + PartialEq + Eq + Clone>(
+    nodes: &HashMap<T, Vec<T>>,
+) -> ValidationReport<T> {
+    let ids = nodes.keys().collect::<Vec<_>>();
+    let indices = ids
+        .iter()
+        .enumerate()
+        .map(|(index, &id)| (id, index))
+        .collect::<HashMap<_, _>>();
+
+    let mut report = ValidationReport {
+        cycles: vec![],
+        dangling_dependencies: vec![],
+        self_dependencies: vec![],
+        duplicate_dependencies: vec![],
+    };
+    let mut adjacency = vec![vec![]; ids.len()];
+
+    for (index, &id) in ids.iter().enumerate() {
+        let mut seen = HashSet::new();
+
+        for dependency in &nodes[id] {
+            if !seen.insert(dependency) {
+                report
+                    .duplicate_dependencies
+                    .push((id.clone(), dependency.clone()));
+                continue;
+            }
+
+            if dependency == id {
+                report.self_dependencies.push(id.clone());
+                continue;
+            }
+
+            match indices.get(dependency) {
+                Some(&dependency_index) => adjacency[index].push(dependency_index),
+                None => report
+                    .dangling_dependencies
+                    .push((id.clone(), dependency.clone())),
+            }
+        }
+    }
+
+    report.cycles = strongly_connected_components(&adjacency)
+        .into_iter()
+        .filter(|component| component.len() > 1)
+        .map(|component| {
+            component
+                .into_iter()
+                .map(|index| ids[index].clone())
+                .collect()
+        })
+        .collect();
+
+    report
+}
+
+/// Iterative Tarjan, so deep graphs don't overflow the stack.
+pub(crate) fn strongly_connected_components(adjacency: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut next_order = 0;
+    let mut order: Vec<Option<usize>> = vec![None; adjacency.len()];
+    let mut lowlink = vec![0; adjacency.len()];
+    let mut on_stack = vec![false; adjacency.len()];
+    let mut stack = vec![];
+    let mut components = vec![];
+
+    for root in 0..adjacency.len() {
+        if order[root].is_some() {
+            continue;
+        }
+
+        let mut calls = vec![(root, 0)];
+        order[root] = Some(next_order);
+        lowlink[root] = next_order;
+        next_order += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        while let Some((v, edge)) = calls.last_mut() {
+            let v = *v;
+
+            if let Some(&w) = adjacency[v].get(*edge) {
+                *edge += 1;
+
+                match order[w] {
+                    None => {
+                        order[w] = Some(next_order);
+                        lowlink[w] = next_order;
+                        next_order += 1;
+                        stack.push(w);
+                        on_stack[w] = true;
+                        calls.push((w, 0));
+                    }
+                    Some(w_order) if on_stack[w] => lowlink[v] = lowlink[v].min(w_order),
+                    Some(_) => {}
+                }
+
+                continue;
+            }
+
+            calls.pop();
+            if let Some(&(parent, _)) = calls.last() {
+                lowlink[parent] = lowlink[parent].min(lowlink[v]);
+            }
+
+            if Some(lowlink[v]) == order[v] {
+                let mut component = vec![];
+                while let Some(w) = stack.pop() {
+                    on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+        }
+    }
+
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_accepts_valid_graphs() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![]);
+        nodes.insert(2, vec![1]);
+        nodes.insert(3, vec![1, 2]);
+
+        assert!(validate(&nodes).is_valid());
+    }
+
+    #[test]
+    fn it_reports_every_problem() {
+        let mut nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+
+        nodes.insert(1, vec![2]);
+        nodes.insert(2, vec![3]);
+        nodes.insert(3, vec![1]);
+        nodes.insert(4, vec![5]);
+        nodes.insert(5, vec![4, 9]);
+        nodes.insert(6, vec![6, 1, 1]);
+
+        let report = validate(&nodes);
+
+        let mut cycles = report
+            .cycles
+            .into_iter()
+            .map(|mut cycle| {
+                cycle.sort();
+                cycle
+            })
+            .collect::<Vec<_>>();
+        cycles.sort();
+
+        assert_eq!(vec![vec![1, 2, 3], vec![4, 5]], cycles);
+        assert_eq!(vec![(5, 9)], report.dangling_dependencies);
+        assert_eq!(vec![6], report.self_dependencies);
+        assert_eq!(vec![(6, 1)], report.duplicate_dependencies);
+    }
+}
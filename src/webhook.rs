@@ -0,0 +1,146 @@
+//! Executor calling a remote service per node: the ID (and payload) is POSTed as JSON to a configured URL and a 2xx
+//! response completes the node. Available with the `webhook` feature.
+//!
+//! The request body is `{"id":"<id>"}`, or `{"id":"<id>","payload":"<payload>"}` through `CallableWithPayload`.
+
+use super::common::*;
+use super::json;
+use super::payload::*;
+use std::{error, fmt, fmt::Display, time::Duration};
+
+/// Error of a non-2xx response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookFailed {
+    pub status: u16,
+}
+
+impl fmt::Display for WebhookFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Webhook responded with status {}.", self.status)
+    }
+}
+
+impl error::Error for WebhookFailed {}
+
+#[derive(Debug, Clone)]
+pub struct WebhookExecutor {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl WebhookExecutor {
+    /// Requests time out after 30 seconds.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self::with_timeout(url, Duration::from_secs(30))
+    }
+
+    pub fn with_timeout(url: impl Into<String>, timeout: Duration) -> Self {
+        Self {
+            url: url.into(),
+            agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+        }
+    }
+
+    fn post(&self, body: String) -> Result<(), Error> {
+        let response = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json")
+            .send_string(&body);
+
+        match response {
+            Ok(response) if (200..300).contains(&response.status()) => Ok(()),
+            Ok(response) => Err(WebhookFailed {
+                status: response.status(),
+            }
+            .into()),
+            Err(ureq::Error::Status(status, _)) => Err(WebhookFailed { status }.into()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl<T: Display> CallableByID<T> for WebhookExecutor {
+    fn call(&self, id: &T) -> Result<(), Error> {
+        self.post(format!(r#"{{"id":{}}}"#, json::string(&id.to_string())))
+    }
+}
+
+impl<T: Display, P: Display> CallableWithPayload<T, P> for WebhookExecutor {
+    fn call(&self, id: &T, payload: &P) -> Result<(), Error> {
+        self.post(format!(
+            r#"{{"id":{},"payload":{}}}"#,
+            json::string(&id.to_string()),
+            json::string(&payload.to_string())
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    /// Answers each request with the next status, returning the received bodies.
+    fn serve(statuses: Vec<u16>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            statuses
+                .into_iter()
+                .map(|status| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream);
+
+                    let mut content_length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body).unwrap();
+                    write!(
+                        reader.get_mut(),
+                        "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    )
+                    .unwrap();
+
+                    String::from_utf8(body).unwrap()
+                })
+                .collect()
+        });
+
+        (url, handle)
+    }
+
+    #[test]
+    fn it_posts_nodes() {
+        let (url, server) = serve(vec![204, 500]);
+        let executor = WebhookExecutor::new(url);
+
+        assert!(CallableByID::call(&executor, &"build").is_ok());
+        let err = CallableWithPayload::call(&executor, &"test", &"fast").unwrap_err();
+        assert_eq!(Some(&WebhookFailed { status: 500 }), err.downcast_ref());
+
+        assert_eq!(
+            vec![
+                r#"{"id":"build"}"#.to_string(),
+                r#"{"id":"test","payload":"fast"}"#.to_string()
+            ],
+            server.join().unwrap()
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Derive macro for the `Node` trait of `topological_batch`, re-exported there with the `derive` feature.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Field, Fields};
+
+/// Implements `Node<T>` for a struct, `T` being the type of the field marked with `#[topo(id)]`. The field marked with
+/// `#[topo(deps)]` holds the dependencies, in any collection iterable by reference (`Vec<T>`, `HashSet<T>`, `&[T]`,
+/// ...).
+///
+/// ```ignore
+/// #[derive(TopoNode)]
+/// struct Task {
+///     #[topo(id)]
+///     name: String,
+///     #[topo(deps)]
+///     after: Vec<String>,
+/// }
+/// ```
+#[proc_macro_derive(TopoNode, attributes(topo))]
+pub fn derive_topo_node(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new(input.span(), "TopoNode requires named fields.")),
+        },
+        _ => {
+            return Err(Error::new(
+                input.span(),
+                "TopoNode can only be derived for structs.",
+            ))
+        }
+    };
+
+    let mut id_field = None;
+    let mut deps_field = None;
+
+    for field in fields {
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("topo"))
+        {
+            attr.parse_nested_meta(|meta| {
+                let slot = if meta.path.is_ident("id") {
+                    &mut id_field
+                } else if meta.path.is_ident("deps") {
+                    &mut deps_field
+                } else {
+                    return Err(meta.error("Expected `id` or `deps`."));
+                };
+
+                if slot.is_some() {
+                    return Err(meta.error("Field marked more than once."));
+                }
+                *slot = Some(field);
+                Ok(())
+            })?;
+        }
+    }
+
+    let missing = |name| Error::new(input.span(), format!("Missing `#[topo({})]` field.", name));
+    let id_field: &Field = id_field.ok_or_else(|| missing("id"))?;
+    let deps_field: &Field = deps_field.ok_or_else(|| missing("deps"))?;
+
+    let name = &input.ident;
+    let id_name = &id_field.ident;
+    let id_type = &id_field.ty;
+    let deps_name = &deps_field.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::topological_batch::Node<#id_type> for #name #type_generics #where_clause {
+            fn id(&self) -> &#id_type {
+                &self.#id_name
+            }
+
+            fn dependencies<'topo>(&'topo self) -> impl ::std::iter::Iterator<Item = &'topo #id_type>
+            where
+                #id_type: 'topo,
+            {
+                ::std::iter::IntoIterator::into_iter(&self.#deps_name)
+            }
+        }
+    })
+}